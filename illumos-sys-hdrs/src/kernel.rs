@@ -67,6 +67,17 @@ pub enum krw_t {
     RW_READER_STARVEWRITER,
 }
 
+#[repr(C)]
+pub struct kcondvar_t {
+    pub _opaque: u16,
+}
+
+#[repr(C)]
+pub enum kcv_type_t {
+    CV_DEFAULT = 0,
+    CV_DRIVER = 1,
+}
+
 // Not all of these callback signatures are filled out completely, the
 // unused ones leave out the function parameters.
 //
@@ -376,6 +387,17 @@ extern "C" {
 
     pub fn cmn_err(code: c_int, msg: *const c_char, ...);
 
+    pub fn cv_broadcast(cvp: *mut kcondvar_t);
+    pub fn cv_destroy(cvp: *mut kcondvar_t);
+    pub fn cv_init(
+        cvp: *mut kcondvar_t,
+        name: *const c_char,
+        typ: kcv_type_t,
+        arg: *const c_void,
+    );
+    pub fn cv_signal(cvp: *mut kcondvar_t);
+    pub fn cv_wait_sig(cvp: *mut kcondvar_t, mp: *mut kmutex_t) -> c_int;
+
     pub fn ddi_copyin(
         buf: *const c_void,
         driverbuf: *mut c_void,
@@ -44,6 +44,7 @@ pub type ssize_t = isize;
 // uts/common/sys/errno.h
 // ======================================================================
 pub const ENOENT: c_int = 2;
+pub const EINTR: c_int = 4;
 pub const EAGAIN: c_int = 11;
 pub const ENOMEM: c_int = 12;
 pub const EFAULT: c_int = 14;
@@ -56,6 +57,7 @@ pub const ENOMSG: c_int = 35;
 pub const ENOTSUP: c_int = 48;
 pub const EPROTO: c_int = 71;
 pub const ENOBUFS: c_int = 132;
+pub const ESTALE: c_int = 151;
 
 // ======================================================================
 // uts/common/sys/kstat.h
@@ -27,21 +27,43 @@ pub const XDE_DLD_OPTE_CMD: i32 = XDE_DLD_PREFIX | 7777;
 #[derive(Clone, Copy, Debug)]
 #[repr(C)]
 pub enum OpteCmd {
-    ListPorts = 1,       // list all ports
-    AddFwRule = 20,      // add firewall rule
-    RemFwRule = 21,      // remove firewall rule
-    SetFwRules = 22,     // set/replace all firewall rules at once
-    DumpTcpFlows = 30,   // dump TCP flows
-    DumpLayer = 31,      // dump the specified Layer
-    DumpUft = 32,        // dump the Unified Flow Table
-    ListLayers = 33,     // list the layers on a given port
-    ClearUft = 40,       // clear the UFT
-    SetVirt2Phys = 50,   // set a v2p mapping
-    DumpVirt2Phys = 51,  // dump the v2p mappings
-    AddRouterEntry = 60, // add a router entry for IP dest
-    CreateXde = 70,      // create a new xde device
-    DeleteXde = 71,      // delete an xde device
-    SetXdeUnderlay = 72, // set xde underlay devices
+    ListPorts = 1,          // list all ports
+    AddFwRule = 20,         // add firewall rule
+    RemFwRule = 21,         // remove firewall rule
+    SetFwRules = 22,        // set/replace all firewall rules at once
+    SetAddrSet = 23,        // set/replace a VPC address set
+    DelAddrSet = 24,        // delete a VPC address set
+    DumpAddrSets = 25,      // dump the VPC address sets
+    DumpTcpFlows = 30,      // dump TCP flows
+    DumpLayer = 31,         // dump the specified Layer
+    DumpUft = 32,           // dump the Unified Flow Table
+    ListLayers = 33,        // list the layers on a given port
+    SetDefaultAction = 34,  // set a layer's default action
+    ClearUft = 40,          // clear the UFT
+    SetVirt2Phys = 50,      // set a v2p mapping
+    DumpVirt2Phys = 51,     // dump the v2p mappings
+    DelVirt2Phys = 52,      // delete a v2p mapping
+    SyncVirt2Phys = 53,     // replace a VPC's v2p mappings at once
+    ReadV2pMisses = 54,     // read the queued v2p misses
+    AddRouterEntry = 60,    // add a router entry for IP dest
+    DelRouterEntry = 61,    // delete a router entry for IP dest
+    ListRouterEntries = 62, // list the router entries
+    CreateXde = 70,         // create a new xde device
+    DeleteXde = 71,         // delete an xde device
+    SetXdeUnderlay = 72,    // set xde underlay devices
+    SetCapture = 80,        // enable/disable packet capture
+    DumpCapture = 81,       // dump the packet capture ring
+    DumpFlowRecords = 82,   // dump/ack the flow records
+    DumpRuleLog = 83,       // dump a layer's rule log
+    SetMirrors = 90,        // set/replace all port mirrors
+    SetBsvcState = 100,     // mark a boundary services endpoint up/down
+    DumpBsvcState = 101,    // dump the boundary services endpoint state
+    AttachFloatingIp = 110, // attach a floating IP to a port
+    DetachFloatingIp = 111, // detach a floating IP from a port
+    AddPortForward = 112,   // add a port forwarding rule
+    DelPortForward = 113,   // delete a port forwarding rule
+    ListPortForwards = 114, // list the port forwarding rules
+    DumpSnat = 115,         // dump the SNAT mappings and their flows
 }
 
 impl TryFrom<c_int> for OpteCmd {
@@ -53,17 +75,39 @@ impl TryFrom<c_int> for OpteCmd {
             20 => Ok(Self::AddFwRule),
             21 => Ok(Self::RemFwRule),
             22 => Ok(Self::SetFwRules),
+            23 => Ok(Self::SetAddrSet),
+            24 => Ok(Self::DelAddrSet),
+            25 => Ok(Self::DumpAddrSets),
             30 => Ok(Self::DumpTcpFlows),
             31 => Ok(Self::DumpLayer),
             32 => Ok(Self::DumpUft),
             33 => Ok(Self::ListLayers),
+            34 => Ok(Self::SetDefaultAction),
             40 => Ok(Self::ClearUft),
             50 => Ok(Self::SetVirt2Phys),
             51 => Ok(Self::DumpVirt2Phys),
+            52 => Ok(Self::DelVirt2Phys),
+            53 => Ok(Self::SyncVirt2Phys),
+            54 => Ok(Self::ReadV2pMisses),
             60 => Ok(Self::AddRouterEntry),
+            61 => Ok(Self::DelRouterEntry),
+            62 => Ok(Self::ListRouterEntries),
             70 => Ok(Self::CreateXde),
             71 => Ok(Self::DeleteXde),
             72 => Ok(Self::SetXdeUnderlay),
+            80 => Ok(Self::SetCapture),
+            81 => Ok(Self::DumpCapture),
+            82 => Ok(Self::DumpFlowRecords),
+            83 => Ok(Self::DumpRuleLog),
+            90 => Ok(Self::SetMirrors),
+            100 => Ok(Self::SetBsvcState),
+            101 => Ok(Self::DumpBsvcState),
+            110 => Ok(Self::AttachFloatingIp),
+            111 => Ok(Self::DetachFloatingIp),
+            112 => Ok(Self::AddPortForward),
+            113 => Ok(Self::DelPortForward),
+            114 => Ok(Self::ListPortForwards),
+            115 => Ok(Self::DumpSnat),
             _ => Err(()),
         }
     }
@@ -140,6 +184,7 @@ impl OpteCmdIoctl {
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum OpteError {
+    ActionNotFound(String),
     BadApiVersion {
         user: u64,
         kernel: u64,
@@ -150,11 +195,15 @@ pub enum OpteError {
     },
     BadName,
     BadState(String),
+    CaptureNotEnabled(String),
     CopyinReq,
     CopyoutResp,
     DeserCmdErr(String),
     DeserCmdReq(String),
     FlowExists(String),
+    InvalidFloatingIp(String),
+    InvalidNatPorts(String),
+    InvalidPortForward(String),
     InvalidRouterEntry {
         dest: IpCidr,
         target: String,
@@ -182,6 +231,14 @@ pub enum OpteError {
     RuleNotFound(u64),
     SerCmdErr(String),
     SerCmdResp(String),
+
+    /// A request carried a generation number older than the one
+    /// already in effect.
+    StaleGeneration {
+        current: u64,
+        given: u64,
+    },
+
     System {
         errno: c_int,
         msg: String,
@@ -200,15 +257,20 @@ impl OpteError {
         use illumos_sys_hdrs::*;
 
         match self {
+            Self::ActionNotFound(_) => ENOENT,
             Self::BadApiVersion { .. } => EPROTO,
             Self::BadLayerPos { .. } => EINVAL,
             Self::BadName => EINVAL,
             Self::BadState(_) => EINVAL,
+            Self::CaptureNotEnabled(_) => ENOENT,
             Self::CopyinReq => EFAULT,
             Self::CopyoutResp => EFAULT,
             Self::DeserCmdErr(_) => ENOMSG,
             Self::DeserCmdReq(_) => ENOMSG,
             Self::FlowExists(_) => EEXIST,
+            Self::InvalidFloatingIp(_) => EINVAL,
+            Self::InvalidNatPorts(_) => EINVAL,
+            Self::InvalidPortForward(_) => EINVAL,
             Self::InvalidRouterEntry { .. } => EINVAL,
             Self::LayerNotFound(_) => ENOENT,
             Self::MacExists { .. } => EEXIST,
@@ -221,6 +283,7 @@ impl OpteError {
             Self::RuleNotFound(_) => ENOENT,
             Self::SerCmdErr(_) => ENOMSG,
             Self::SerCmdResp(_) => ENOMSG,
+            Self::StaleGeneration { .. } => ESTALE,
             Self::System { errno, .. } => *errno,
         }
     }
@@ -306,6 +306,55 @@ impl From<Protocol> for smoltcp::wire::IpProtocol {
     }
 }
 
+/// How source NAT maps the flows of a private IP to external ports,
+/// in the terms of RFC 4787.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum NatMapping {
+    /// Each flow is given its own external port; also known as
+    /// "address and port-dependent mapping".
+    PerFlow,
+
+    /// All flows from the same private port and protocol share one
+    /// external port, whatever their remote endpoint (RFC 4787
+    /// REQ-1). This is what allows UDP hole punching to work.
+    EndpointIndependent,
+
+    /// Each flow is given its own external port while there are free
+    /// ports. After that, a port in use is reused for flows to remote
+    /// endpoints it is not already used with.
+    PortReuse,
+}
+
+impl Default for NatMapping {
+    fn default() -> Self {
+        Self::PerFlow
+    }
+}
+
+impl FromStr for NatMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "per-flow" => Ok(Self::PerFlow),
+            "eim" | "endpoint-independent" => Ok(Self::EndpointIndependent),
+            "port-reuse" => Ok(Self::PortReuse),
+            _ => Err(format!("invalid NAT mapping: {}", s)),
+        }
+    }
+}
+
+impl Display for NatMapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Self::PerFlow => "per-flow",
+            Self::EndpointIndependent => "endpoint-independent",
+            Self::PortReuse => "port-reuse",
+        };
+        write!(f, "{}", s)
+    }
+}
+
 /// An IPv4 or IPv6 address.
 #[derive(
     Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize,
@@ -611,6 +660,25 @@ impl Ipv6Addr {
         self.inner[0] == 0xFF
     }
 
+    /// Return the IPv4-embedded IPv6 address of `ip4` under the NAT64
+    /// Well-Known Prefix, see [`Ipv6Cidr::NAT64_WKP`].
+    pub fn from_nat64(ip4: Ipv4Addr) -> Self {
+        let mut bytes = Ipv6Cidr::NAT64_WKP.ip().bytes();
+        bytes[12..].copy_from_slice(&ip4.bytes());
+        Self::from(bytes)
+    }
+
+    /// Return the IPv4 address embedded in this address, if it is under
+    /// the NAT64 Well-Known Prefix.
+    pub fn nat64_ipv4(&self) -> Option<Ipv4Addr> {
+        if !Ipv6Cidr::NAT64_WKP.is_member(*self) {
+            return None;
+        }
+
+        let bytes: [u8; 4] = self.inner[12..].try_into().unwrap();
+        Some(Ipv4Addr::from(bytes))
+    }
+
     /// Return the bytes of the address.
     pub fn bytes(&self) -> [u8; 16] {
         self.inner
@@ -1006,6 +1074,16 @@ impl Ipv6Cidr {
         prefix_len: Ipv6PrefixLen(64),
     };
 
+    /// The NAT64 Well-Known Prefix, `64:ff9b::/96`.
+    ///
+    /// See [RFC 6052 §2.1] for details.
+    ///
+    /// [RFC 6052 §2.1]: https://www.rfc-editor.org/rfc/rfc6052#section-2.1
+    pub const NAT64_WKP: Self = Self {
+        ip: Ipv6Addr::from_const([0x64, 0xff9b, 0, 0, 0, 0, 0, 0]),
+        prefix_len: Ipv6PrefixLen(96),
+    };
+
     pub fn new(ip: Ipv6Addr, prefix_len: Ipv6PrefixLen) -> Self {
         let ip = ip.safe_mask(prefix_len);
         Ipv6Cidr { ip, prefix_len }
@@ -1134,6 +1212,17 @@ mod test {
         );
     }
 
+    #[test]
+    fn nat64_embedding() {
+        let ip4: Ipv4Addr = "192.0.2.33".parse().unwrap();
+        let ip6 = Ipv6Addr::from_nat64(ip4);
+        assert_eq!(ip6, "64:ff9b::c000:221".parse().unwrap());
+        assert_eq!(ip6.nat64_ipv4(), Some(ip4));
+
+        let other: Ipv6Addr = "fd00::c000:221".parse().unwrap();
+        assert_eq!(other.nat64_ipv4(), None);
+    }
+
     #[test]
     fn ipv4_addr_bad() {
         assert!("192.168.33.1O".parse::<Ipv4Addr>().is_err());
@@ -5,6 +5,8 @@
 // Copyright 2022 Oxide Computer Company
 
 use opte::api::CmdOk;
+use opte::api::IpAddr;
+use opte::api::Ipv6Addr;
 use opte::api::NoResp;
 use opte::api::OpteCmd;
 use opte::api::OpteCmdIoctl;
@@ -12,13 +14,35 @@ pub use opte::api::OpteError;
 use opte::api::SetXdeUnderlayReq;
 use opte::api::API_VERSION;
 use opte::api::XDE_DLD_OPTE_CMD;
+use oxide_vpc::api::AddPortForwardReq;
 use oxide_vpc::api::AddRouterEntryReq;
+use oxide_vpc::api::AttachFloatingIpReq;
 use oxide_vpc::api::CreateXdeReq;
+use oxide_vpc::api::DelPortForwardReq;
+use oxide_vpc::api::DelPortForwardResp;
+use oxide_vpc::api::DelRouterEntryReq;
+use oxide_vpc::api::DelRouterEntryResp;
+use oxide_vpc::api::DelVirt2PhysReq;
+use oxide_vpc::api::DelVirt2PhysResp;
 use oxide_vpc::api::DeleteXdeReq;
+use oxide_vpc::api::DetachFloatingIpReq;
+use oxide_vpc::api::DetachFloatingIpResp;
+use oxide_vpc::api::ListPortForwardsReq;
+use oxide_vpc::api::ListPortForwardsResp;
 use oxide_vpc::api::ListPortsResp;
+use oxide_vpc::api::ListRouterEntriesReq;
+use oxide_vpc::api::ListRouterEntriesResp;
+use oxide_vpc::api::PortForward;
+use oxide_vpc::api::ReadV2pMissesReq;
+use oxide_vpc::api::ReadV2pMissesResp;
+use oxide_vpc::api::SetBsvcStateReq;
 use oxide_vpc::api::SetFwRulesReq;
+use oxide_vpc::api::SetFwRulesResp;
 use oxide_vpc::api::SetVirt2PhysReq;
+use oxide_vpc::api::SyncVirt2PhysReq;
 use oxide_vpc::api::VpcCfg;
+use oxide_vpc::engine::nat::DumpSnatReq;
+use oxide_vpc::engine::nat::DumpSnatResp;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::fs::File;
@@ -142,6 +166,106 @@ impl OpteHdl {
         run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
     }
 
+    pub fn del_v2p(
+        &self,
+        req: &DelVirt2PhysReq,
+    ) -> Result<DelVirt2PhysResp, Error> {
+        let cmd = OpteCmd::DelVirt2Phys;
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
+
+    /// Replace all Virtual-to-Physical mappings of a VPC.
+    pub fn sync_v2p(&self, req: &SyncVirt2PhysReq) -> Result<NoResp, Error> {
+        let cmd = OpteCmd::SyncVirt2Phys;
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
+
+    /// Read the queued V2P misses, waiting for one if `block` is set
+    /// and the queue is empty.
+    pub fn read_v2p_misses(
+        &self,
+        block: bool,
+    ) -> Result<ReadV2pMissesResp, Error> {
+        let cmd = OpteCmd::ReadV2pMisses;
+        let req = ReadV2pMissesReq { block };
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
+
+    /// Mark a Boundary Services endpoint as up or down.
+    pub fn set_bsvc_state(
+        &self,
+        ip: Ipv6Addr,
+        up: bool,
+    ) -> Result<NoResp, Error> {
+        let cmd = OpteCmd::SetBsvcState;
+        let req = SetBsvcStateReq { ip, up };
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
+
+    /// Attach a floating IP to a port.
+    pub fn attach_floating_ip(
+        &self,
+        port_name: &str,
+        ip: IpAddr,
+    ) -> Result<NoResp, Error> {
+        let cmd = OpteCmd::AttachFloatingIp;
+        let req = AttachFloatingIpReq { port_name: port_name.to_string(), ip };
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
+
+    /// Detach a floating IP from a port.
+    pub fn detach_floating_ip(
+        &self,
+        port_name: &str,
+        ip: IpAddr,
+    ) -> Result<DetachFloatingIpResp, Error> {
+        let cmd = OpteCmd::DetachFloatingIp;
+        let req = DetachFloatingIpReq { port_name: port_name.to_string(), ip };
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
+
+    /// Add a port forwarding rule to a port.
+    pub fn add_port_forward(
+        &self,
+        port_name: &str,
+        forward: PortForward,
+    ) -> Result<NoResp, Error> {
+        let cmd = OpteCmd::AddPortForward;
+        let req =
+            AddPortForwardReq { port_name: port_name.to_string(), forward };
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
+
+    /// Delete a port forwarding rule from a port.
+    pub fn del_port_forward(
+        &self,
+        port_name: &str,
+        forward: PortForward,
+    ) -> Result<DelPortForwardResp, Error> {
+        let cmd = OpteCmd::DelPortForward;
+        let req =
+            DelPortForwardReq { port_name: port_name.to_string(), forward };
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
+
+    /// List the port forwarding rules of a port.
+    pub fn list_port_forwards(
+        &self,
+        port_name: &str,
+    ) -> Result<ListPortForwardsResp, Error> {
+        let cmd = OpteCmd::ListPortForwards;
+        let req = ListPortForwardsReq { port_name: port_name.to_string() };
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
+
+    /// Dump the SNAT mappings of a port, along with the flows using
+    /// their ports.
+    pub fn dump_snat(&self, port_name: &str) -> Result<DumpSnatResp, Error> {
+        let cmd = OpteCmd::DumpSnat;
+        let req = DumpSnatReq { port_name: port_name.to_string() };
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
+
     /// Set xde underlay devices.
     pub fn set_xde_underlay(
         &self,
@@ -161,7 +285,27 @@ impl OpteHdl {
         run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
     }
 
-    pub fn set_fw_rules(&self, req: &SetFwRulesReq) -> Result<NoResp, Error> {
+    pub fn del_router_entry(
+        &self,
+        req: &DelRouterEntryReq,
+    ) -> Result<DelRouterEntryResp, Error> {
+        let cmd = OpteCmd::DelRouterEntry;
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
+
+    pub fn list_router_entries(
+        &self,
+        port_name: &str,
+    ) -> Result<ListRouterEntriesResp, Error> {
+        let cmd = OpteCmd::ListRouterEntries;
+        let req = ListRouterEntriesReq { port_name: port_name.to_string() };
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
+
+    pub fn set_fw_rules(
+        &self,
+        req: &SetFwRulesReq,
+    ) -> Result<SetFwRulesResp, Error> {
         let cmd = OpteCmd::SetFwRules;
         run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
     }
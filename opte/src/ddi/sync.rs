@@ -16,11 +16,14 @@ cfg_if! {
         use core::cell::UnsafeCell;
         use core::ptr;
         use illumos_sys_hdrs::{
+            cv_broadcast, cv_destroy, cv_init, cv_wait_sig, kcondvar_t, kcv_type_t,
             kmutex_t, krw_t, krw_type_t, krwlock_t, mutex_enter, mutex_exit,
             mutex_init, rw_enter, rw_exit, rw_init,
         };
     } else {
+        use std::sync::Condvar;
         use std::sync::Mutex;
+        use std::sync::RwLock;
     }
 }
 
@@ -315,3 +318,159 @@ impl<T> Drop for KRwLockWriteGuard<'_, T> {
         unsafe { rw_exit(self.lock.rwl.get()) };
     }
 }
+
+// In a std environment we just wrap `RwLock`.
+#[cfg(any(feature = "std", test))]
+pub struct KRwLock<T> {
+    inner: RwLock<T>,
+}
+
+#[cfg(any(feature = "std", test))]
+pub enum KRwLockType {
+    Driver,
+    Default,
+}
+
+#[cfg(any(feature = "std", test))]
+impl<T> KRwLock<T> {
+    pub const fn new(val: T) -> Self {
+        KRwLock { inner: RwLock::new(val) }
+    }
+
+    pub fn init(&mut self, _typ: KRwLockType) {}
+
+    pub fn read(&self) -> KRwLockReadGuard<T> {
+        KRwLockReadGuard { guard: self.inner.read().unwrap() }
+    }
+
+    pub fn write(&self) -> KRwLockWriteGuard<T> {
+        KRwLockWriteGuard { guard: self.inner.write().unwrap() }
+    }
+}
+
+#[cfg(any(feature = "std", test))]
+pub struct KRwLockReadGuard<'a, T: 'a> {
+    guard: std::sync::RwLockReadGuard<'a, T>,
+}
+
+#[cfg(any(feature = "std", test))]
+impl<T> Deref for KRwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.deref()
+    }
+}
+
+#[cfg(any(feature = "std", test))]
+pub struct KRwLockWriteGuard<'a, T: 'a> {
+    guard: std::sync::RwLockWriteGuard<'a, T>,
+}
+
+#[cfg(any(feature = "std", test))]
+impl<T> Deref for KRwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.deref()
+    }
+}
+
+#[cfg(any(feature = "std", test))]
+impl<T> DerefMut for KRwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.deref_mut()
+    }
+}
+
+/// A wrapper around illumos condvar(9F).
+///
+/// A condition variable is always waited on along with a [`KMutex`]:
+/// the waiter passes in the guard of the mutex it holds, which is
+/// released for the duration of the wait and handed back on wakeup.
+#[cfg(all(not(feature = "std"), not(test)))]
+pub struct KCondvar {
+    cv: UnsafeCell<kcondvar_t>,
+}
+
+#[cfg(all(not(feature = "std"), not(test)))]
+impl KCondvar {
+    /// Create and initialize a new kernel condition variable. Like
+    /// `KMutex`, there are no self referential pointers, so the
+    /// condvar may be moved after initialization.
+    pub fn new() -> Self {
+        let mut cv = kcondvar_t { _opaque: 0 };
+        // We never use the name argument, and a driver is meant to
+        // use the `CV_DRIVER` type.
+        unsafe {
+            cv_init(&mut cv, ptr::null(), kcv_type_t::CV_DRIVER, ptr::null());
+        }
+        KCondvar { cv: UnsafeCell::new(cv) }
+    }
+
+    /// Wake all threads currently waiting on this condvar.
+    pub fn broadcast(&self) {
+        unsafe { cv_broadcast(self.cv.get()) };
+    }
+
+    /// Wait to be woken by [`KCondvar::broadcast()`], or by a signal
+    /// sent to the waiting thread.
+    ///
+    /// Return the guard on wakeup, or `None` if the wait was
+    /// interrupted by a signal, in which case the mutex has been
+    /// released.
+    pub fn wait_sig<'a, T>(
+        &self,
+        guard: KMutexGuard<'a, T>,
+    ) -> Option<KMutexGuard<'a, T>> {
+        // Safety: The guard is proof that the mutex is held, as
+        // required by cv_wait_sig(9F), which reacquires it before
+        // returning.
+        let ret = unsafe { cv_wait_sig(self.cv.get(), guard.lock.mutex.get()) };
+
+        if ret == 0 {
+            None
+        } else {
+            Some(guard)
+        }
+    }
+}
+
+#[cfg(all(not(feature = "std"), not(test)))]
+impl Drop for KCondvar {
+    fn drop(&mut self) {
+        // Safety: The condvar was initialized in `new()`, and no
+        // thread can be waiting on it as we have the only reference.
+        unsafe { cv_destroy(self.cv.get()) };
+    }
+}
+
+#[cfg(all(not(feature = "std"), not(test)))]
+unsafe impl Send for KCondvar {}
+#[cfg(all(not(feature = "std"), not(test)))]
+unsafe impl Sync for KCondvar {}
+
+// In a std environment we just wrap `Condvar`.
+#[cfg(any(feature = "std", test))]
+pub struct KCondvar {
+    cv: Condvar,
+}
+
+#[cfg(any(feature = "std", test))]
+impl KCondvar {
+    pub fn new() -> Self {
+        KCondvar { cv: Condvar::new() }
+    }
+
+    pub fn broadcast(&self) {
+        self.cv.notify_all();
+    }
+
+    pub fn wait_sig<'a, T>(
+        &self,
+        guard: KMutexGuard<'a, T>,
+    ) -> Option<KMutexGuard<'a, T>> {
+        let guard = self.cv.wait(guard.guard).unwrap();
+        Some(KMutexGuard { guard })
+    }
+}
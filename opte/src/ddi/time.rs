@@ -66,6 +66,19 @@ impl Moment {
         }
     }
 
+    /// Compute the delta between `self - earlier` and return as
+    /// nanoseconds.
+    pub fn delta_as_nanos(&self, earlier: Moment) -> u64 {
+        cfg_if! {
+            if #[cfg(all(not(feature = "std"), not(test)))] {
+                self.inner as u64 - earlier.inner as u64
+            } else {
+                let delta = self.inner.duration_since(earlier.inner);
+                delta.as_secs() * NANOS + delta.subsec_nanos() as u64
+            }
+        }
+    }
+
     pub fn now() -> Self {
         cfg_if! {
             if #[cfg(all(not(feature = "std"), not(test)))] {
@@ -0,0 +1,262 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! Per-port packet capture.
+//!
+//! A port may optionally have a capture ring attached to it. The ring
+//! records a copy of each packet seen at the configured
+//! [`CapturePoint`]s which also matches the configured predicates.
+//! The ring is bounded: once it is full the oldest packet is evicted
+//! to make room for the newest one.
+use super::ioctl::DumpCaptureResp;
+use super::packet::Packet;
+use super::packet::Parsed;
+use super::port::meta::ActionMeta;
+use super::port::DropReason;
+use super::predicate::Predicate;
+use crate::ddi::time::Moment;
+use core::fmt;
+use core::fmt::Display;
+use core::result;
+use core::str::FromStr;
+use opte_api::Direction;
+use serde::Deserialize;
+use serde::Serialize;
+
+cfg_if! {
+    if #[cfg(all(not(feature = "std"), not(test)))] {
+        use alloc::collections::VecDeque;
+        use alloc::string::String;
+        use alloc::vec::Vec;
+    } else {
+        use std::collections::VecDeque;
+        use std::string::String;
+        use std::vec::Vec;
+    }
+}
+
+/// The default number of packets held by a capture ring.
+pub const CAPTURE_CAPACITY_DEFAULT: u32 = 1024;
+
+/// The maximum number of packets held by a capture ring.
+pub const CAPTURE_CAPACITY_MAX: u32 = 4096;
+
+/// The default number of bytes copied from each captured packet.
+pub const CAPTURE_SNAPLEN_DEFAULT: u32 = 256;
+
+/// The maximum number of bytes copied from each captured packet.
+pub const CAPTURE_SNAPLEN_MAX: u32 = 9216;
+
+/// The maximum number of packet bytes held by a capture ring. The
+/// capacity is lowered so that capacity times snaplen stays within
+/// this bound.
+pub const CAPTURE_RING_BYTES_MAX: u32 = 8 * 1024 * 1024;
+
+/// The maximum number of packets returned by a single dump.
+pub const CAPTURE_DUMP_MAX: u32 = 256;
+
+/// A point in the port's pipeline at which a packet may be captured.
+///
+/// * Guest: The packet as seen on the guest side of the port. For
+/// outbound traffic this is before processing, for inbound traffic
+/// this is after processing.
+///
+/// * Underlay: The packet as seen on the underlay side of the port.
+/// For outbound traffic this is after processing, for inbound
+/// traffic this is before processing.
+///
+/// * Drop: The packet was dropped by the port. The packet is
+/// recorded as it was at the time of the drop.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum CapturePoint {
+    Guest,
+    Underlay,
+    Drop,
+}
+
+impl Display for CapturePoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Self::Guest => "guest",
+            Self::Underlay => "underlay",
+            Self::Drop => "drop",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl FromStr for CapturePoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "guest" => Ok(Self::Guest),
+            "underlay" => Ok(Self::Underlay),
+            "drop" => Ok(Self::Drop),
+            _ => Err(format!("invalid capture point: {}", s)),
+        }
+    }
+}
+
+/// The configuration of a port's capture ring.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CaptureConfig {
+    /// The maximum number of packets held by the ring.
+    pub capacity: u32,
+    /// The maximum number of bytes copied from each packet.
+    pub snaplen: u32,
+    /// The points at which packets are captured.
+    pub points: Vec<CapturePoint>,
+    /// The predicates a packet must match to be captured. An empty
+    /// list matches all packets.
+    pub preds: Vec<Predicate>,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            capacity: CAPTURE_CAPACITY_DEFAULT,
+            snaplen: CAPTURE_SNAPLEN_DEFAULT,
+            points: vec![
+                CapturePoint::Guest,
+                CapturePoint::Underlay,
+                CapturePoint::Drop,
+            ],
+            preds: vec![],
+        }
+    }
+}
+
+/// A single captured packet.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CapturedPacket {
+    /// The point at which the packet was captured.
+    pub point: CapturePoint,
+    /// The direction the packet was traveling.
+    pub dir: Direction,
+    /// The reason the packet was dropped, if it was captured at
+    /// [`CapturePoint::Drop`].
+    pub drop_reason: Option<String>,
+    /// The time of capture, as nanoseconds since the capture was
+    /// enabled.
+    pub ts_ns: u64,
+    /// The original length of the packet.
+    pub orig_len: u32,
+    /// The captured bytes, at most `snaplen` of them.
+    pub bytes: Vec<u8>,
+}
+
+/// A bounded ring of captured packets.
+pub(crate) struct CaptureRing {
+    cfg: CaptureConfig,
+    start: Moment,
+    evicted: u64,
+    ring: VecDeque<CapturedPacket>,
+}
+
+impl CaptureRing {
+    pub(crate) fn new(mut cfg: CaptureConfig) -> Self {
+        cfg.snaplen = cfg.snaplen.clamp(1, CAPTURE_SNAPLEN_MAX);
+        let cap_max =
+            CAPTURE_CAPACITY_MAX.min(CAPTURE_RING_BYTES_MAX / cfg.snaplen);
+        cfg.capacity = cfg.capacity.clamp(1, cap_max);
+
+        Self {
+            ring: VecDeque::with_capacity(cfg.capacity as usize),
+            cfg,
+            start: Moment::now(),
+            evicted: 0,
+        }
+    }
+
+    /// Return a page of at most `max_pkts` packets from the ring,
+    /// starting at the packet numbered `start`.
+    ///
+    /// Packets are numbered from zero in the order they were
+    /// recorded, so a packet keeps its number as older packets are
+    /// evicted. If `start` was already evicted, the page begins with
+    /// the oldest packet in the ring.
+    ///
+    /// The ring is left intact: an ioctl command may be run more than
+    /// once when the response buffer is too small, and draining here
+    /// would lose the packets of the first attempt. To start over
+    /// with an empty ring, set the capture configuration again.
+    pub(crate) fn dump(&self, start: u64, max_pkts: u32) -> DumpCaptureResp {
+        let first = usize::try_from(start.saturating_sub(self.evicted))
+            .unwrap_or(usize::MAX)
+            .min(self.ring.len());
+        let count = max_pkts.clamp(1, CAPTURE_DUMP_MAX) as usize;
+        let pkts: Vec<CapturedPacket> =
+            self.ring.range(first..).take(count).cloned().collect();
+        let end = first + pkts.len();
+
+        DumpCaptureResp {
+            config: self.cfg.clone(),
+            elapsed_ns: Moment::now().delta_as_nanos(self.start),
+            evicted: self.evicted,
+            next: self.evicted + end as u64,
+            remaining: (self.ring.len() - end) as u64,
+            pkts,
+        }
+    }
+
+    /// Is the ring capturing packets at `point`?
+    pub(crate) fn wants(&self, point: CapturePoint) -> bool {
+        self.cfg.points.contains(&point)
+    }
+
+    /// Record the packet if the ring is capturing at `point` and the
+    /// packet matches the configured predicates.
+    pub(crate) fn record(
+        &mut self,
+        point: CapturePoint,
+        dir: Direction,
+        pkt: &Packet<Parsed>,
+        drop_reason: Option<&DropReason>,
+    ) {
+        if !self.wants(point) {
+            return;
+        }
+
+        // Predicates which look at action metadata never match, as
+        // there is no metadata available outside of layer
+        // processing.
+        let ameta = ActionMeta::new();
+        for pred in &self.cfg.preds {
+            if !pred.is_match(pkt.meta(), &ameta) {
+                return;
+            }
+        }
+
+        if self.ring.len() >= self.cfg.capacity as usize {
+            self.ring.pop_front();
+            self.evicted += 1;
+        }
+
+        // Copy only the bytes within the snaplen, this runs with the
+        // port lock held.
+        let orig_len = pkt.len();
+        let cap_len = orig_len.min(self.cfg.snaplen as usize);
+        let mut bytes = Vec::with_capacity(cap_len);
+        for i in 0..pkt.num_segs() {
+            let seg = pkt.seg_bytes(i);
+            let remaining = cap_len - bytes.len();
+            if remaining == 0 {
+                break;
+            }
+            bytes.extend_from_slice(&seg[..seg.len().min(remaining)]);
+        }
+
+        self.ring.push_back(CapturedPacket {
+            point,
+            dir,
+            drop_reason: drop_reason.map(|r| format!("{:?}", r)),
+            ts_ns: Moment::now().delta_as_nanos(self.start),
+            orig_len: orig_len as u32,
+            bytes,
+        });
+    }
+}
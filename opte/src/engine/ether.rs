@@ -226,6 +226,7 @@ impl<'a> From<&EtherHdr<'a>> for EtherMeta {
 pub struct EtherMod {
     pub src: Option<MacAddr>,
     pub dst: Option<MacAddr>,
+    pub ether_type: Option<EtherType>,
 }
 
 impl ModifyAction<EtherMeta> for EtherMod {
@@ -237,6 +238,10 @@ impl ModifyAction<EtherMeta> for EtherMod {
         if let Some(dst) = self.dst {
             meta.dst = dst
         }
+
+        if let Some(ether_type) = self.ether_type {
+            meta.ether_type = ether_type;
+        }
     }
 }
 
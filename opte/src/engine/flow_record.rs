@@ -0,0 +1,333 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! Flow records.
+//!
+//! Each port accounts the packets it processes to a bidirectional
+//! flow, keyed by the flow as seen from the guest: the source is
+//! always the guest side of the flow, regardless of the direction
+//! the packet was traveling. Along with the packet and byte counts,
+//! the flow records the addresses as seen on the network (i.e.,
+//! after any NAT), the TCP flags seen and the verdict of the last
+//! packet.
+//!
+//! A [`FlowRecord`] is generated when a flow expires (idle timeout)
+//! and periodically for long-lived flows (active timeout). In the
+//! latter case the counters are reset, making each record a delta
+//! from the previous one. The records are queued on the port until
+//! a consumer retrieves them via [`DumpFlowRecordsReq`].
+//!
+//! [`DumpFlowRecordsReq`]: super::ioctl::DumpFlowRecordsReq
+use super::flow_table::Dump;
+use super::flow_table::FlowTable;
+use super::ioctl::DumpFlowRecordsResp;
+use super::packet::InnerFlowId;
+use crate::ddi::time::Moment;
+use crate::ddi::time::MILLIS;
+use core::fmt;
+use core::fmt::Display;
+use core::num::NonZeroU32;
+use opte_api::Direction;
+use serde::Deserialize;
+use serde::Serialize;
+
+cfg_if! {
+    if #[cfg(all(not(feature = "std"), not(test)))] {
+        use alloc::collections::VecDeque;
+        use alloc::vec::Vec;
+    } else {
+        use std::collections::VecDeque;
+        use std::vec::Vec;
+    }
+}
+
+/// The number of seconds after which a record is generated for a
+/// flow which is still active.
+pub const FLOW_RECORD_ACTIVE_SECS: u64 = 60;
+
+/// The maximum number of records a port holds while waiting for the
+/// consumer to retrieve them.
+pub const FLOW_RECORD_QUEUE_MAX: usize = 4096;
+
+/// The verdict of the last packet of a flow.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum FlowVerdict {
+    Allow,
+    Deny,
+}
+
+impl Display for FlowVerdict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Self::Allow => "ALLOW",
+            Self::Deny => "DENY",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// The reason a [`FlowRecord`] was generated.
+///
+/// * IdleTimeout: No packets were seen for the flow within the
+/// table's TTL; this is the flow's final record.
+///
+/// * ActiveTimeout: The flow has been active for
+/// [`FLOW_RECORD_ACTIVE_SECS`] since its last record.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum FlowEndReason {
+    IdleTimeout,
+    ActiveTimeout,
+}
+
+impl Display for FlowEndReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Self::IdleTimeout => "IDLE",
+            Self::ActiveTimeout => "ACTIVE",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// The packet and byte counts of a flow, split by direction.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub struct FlowCounters {
+    pub pkts_out: u64,
+    pub bytes_out: u64,
+    pub pkts_in: u64,
+    pub bytes_in: u64,
+    /// The union of all TCP flags seen, in either direction.
+    pub tcp_flags: u8,
+}
+
+impl FlowCounters {
+    /// Return the number of packets, in either direction.
+    pub fn pkts(&self) -> u64 {
+        self.pkts_out + self.pkts_in
+    }
+}
+
+/// A record of a flow's activity.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FlowRecord {
+    /// The sequence number of the record, starting at 1 and unique
+    /// for the life of the port.
+    pub seq: u64,
+    /// The flow as seen from the guest.
+    pub guest_flow: InnerFlowId,
+    /// The flow as seen on the network, after any NAT.
+    pub net_flow: InnerFlowId,
+    /// The time of the first packet covered by this record, as
+    /// nanoseconds since the port was created.
+    pub start_ns: u64,
+    /// The time of the last packet covered by this record, as
+    /// nanoseconds since the port was created.
+    pub end_ns: u64,
+    pub counters: FlowCounters,
+    pub verdict: FlowVerdict,
+    pub end_reason: FlowEndReason,
+}
+
+impl Display for FlowRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} -> {} out={}/{} in={}/{} flags=0x{:02X} {} {}",
+            self.seq,
+            self.guest_flow,
+            self.net_flow,
+            self.counters.pkts_out,
+            self.counters.bytes_out,
+            self.counters.pkts_in,
+            self.counters.bytes_in,
+            self.counters.tcp_flags,
+            self.verdict,
+            self.end_reason,
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+struct FlowStat {
+    guest_flow: InnerFlowId,
+    net_flow: InnerFlowId,
+    verdict: FlowVerdict,
+    // The first and last packet since the previous record.
+    start: Moment,
+    last: Moment,
+    counters: FlowCounters,
+}
+
+impl Dump for FlowStat {
+    type DumpVal = FlowCounters;
+
+    fn dump(&self, _hits: u64) -> FlowCounters {
+        self.counters
+    }
+}
+
+/// The flow accounting of a port.
+pub(crate) struct FlowRecorder {
+    start: Moment,
+    flows: FlowTable<FlowStat>,
+    records: VecDeque<FlowRecord>,
+    next_seq: u64,
+    dropped: u64,
+}
+
+impl FlowRecorder {
+    pub(crate) fn new(port: &str, limit: NonZeroU32) -> Self {
+        Self {
+            start: Moment::now(),
+            flows: FlowTable::new(port, "flow_records", limit, None),
+            records: VecDeque::new(),
+            next_seq: 1,
+            dropped: 0,
+        }
+    }
+
+    /// Account a packet of `len` bytes to its flow.
+    ///
+    /// The `flow_before` and `flow_after` are the flow IDs of the
+    /// packet before and after processing, respectively. A packet
+    /// which was already accounted, as is the case for a packet
+    /// processed anew, passes `count` as false: only the flow's
+    /// verdict and network flow are updated.
+    pub(crate) fn account(
+        &mut self,
+        dir: Direction,
+        flow_before: &InnerFlowId,
+        flow_after: &InnerFlowId,
+        len: u64,
+        tcp_flags: Option<u8>,
+        verdict: FlowVerdict,
+        count: bool,
+    ) {
+        let (guest_flow, net_flow) = match dir {
+            Direction::Out => (*flow_before, *flow_after),
+            Direction::In => (flow_after.mirror(), flow_before.mirror()),
+        };
+
+        let now = Moment::now();
+        if self.flows.get_mut(&guest_flow).is_none() {
+            let stat = FlowStat {
+                guest_flow,
+                net_flow,
+                verdict,
+                start: now,
+                last: now,
+                counters: FlowCounters::default(),
+            };
+
+            if self.flows.add(guest_flow, stat).is_err() {
+                self.dropped += 1;
+                return;
+            }
+        }
+
+        // Unwrap: The entry either existed or was just added.
+        let entry = self.flows.get_mut(&guest_flow).unwrap();
+        entry.hit();
+        let stat = entry.state_mut();
+
+        stat.net_flow = net_flow;
+        stat.verdict = verdict;
+        if !count {
+            return;
+        }
+
+        // This is the first packet since the previous record.
+        if stat.counters.pkts() == 0 {
+            stat.start = now;
+        }
+
+        stat.last = now;
+        match dir {
+            Direction::Out => {
+                stat.counters.pkts_out += 1;
+                stat.counters.bytes_out += len;
+            }
+
+            Direction::In => {
+                stat.counters.pkts_in += 1;
+                stat.counters.bytes_in += len;
+            }
+        }
+        stat.counters.tcp_flags |= tcp_flags.unwrap_or(0);
+    }
+
+    /// Return the queued records, first removing those with a
+    /// sequence number up to and including `ack`, if specified.
+    ///
+    /// The remaining records are left queued: an ioctl command may be
+    /// run more than once when the response buffer is too small, and
+    /// draining here would lose the records of the first attempt.
+    /// Instead, the consumer acknowledges the records it has
+    /// received on its next call.
+    pub(crate) fn dump(&mut self, ack: Option<u64>) -> DumpFlowRecordsResp {
+        if let Some(ack) = ack {
+            while self.records.front().map(|r| r.seq <= ack).unwrap_or(false) {
+                self.records.pop_front();
+            }
+        }
+
+        DumpFlowRecordsResp {
+            elapsed_ns: Moment::now().delta_as_nanos(self.start),
+            dropped: self.dropped,
+            records: self.records.iter().cloned().collect(),
+        }
+    }
+
+    /// Generate the records of all flows which have either expired or
+    /// have been active for longer than [`FLOW_RECORD_ACTIVE_SECS`].
+    pub(crate) fn expire(&mut self, now: Moment) {
+        let expired = self.flows.expire_flows(now, |stat| stat.clone());
+        for stat in expired {
+            // There is nothing to report if the flow's previous
+            // record already covered all of its packets.
+            if stat.counters.pkts() > 0 {
+                self.push_record(&stat, FlowEndReason::IdleTimeout);
+            }
+        }
+
+        let mut active = Vec::new();
+        for (_, entry) in self.flows.iter_mut() {
+            let stat = entry.state_mut();
+            if stat.counters.pkts() == 0
+                || now.delta_as_millis(stat.start)
+                    < FLOW_RECORD_ACTIVE_SECS * MILLIS
+            {
+                continue;
+            }
+
+            active.push(stat.clone());
+            stat.counters = FlowCounters::default();
+        }
+
+        for stat in active {
+            self.push_record(&stat, FlowEndReason::ActiveTimeout);
+        }
+    }
+
+    fn push_record(&mut self, stat: &FlowStat, end_reason: FlowEndReason) {
+        if self.records.len() >= FLOW_RECORD_QUEUE_MAX {
+            self.dropped += 1;
+            return;
+        }
+
+        self.records.push_back(FlowRecord {
+            seq: self.next_seq,
+            guest_flow: stat.guest_flow,
+            net_flow: stat.net_flow,
+            start_ns: stat.start.delta_as_nanos(self.start),
+            end_ns: stat.last.delta_as_nanos(self.start),
+            counters: stat.counters,
+            verdict: stat.verdict,
+            end_reason,
+        });
+        self.next_seq += 1;
+    }
+}
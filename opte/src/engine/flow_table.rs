@@ -122,9 +122,11 @@ where
         self.map.remove(flowid);
     }
 
-    pub fn expire_flows<F>(&mut self, now: Moment, f: F) -> Vec<InnerFlowId>
+    /// Remove all expired flows, returning the result of `f` for each
+    /// of them.
+    pub fn expire_flows<F, T>(&mut self, now: Moment, f: F) -> Vec<T>
     where
-        F: Fn(&S) -> InnerFlowId,
+        F: Fn(&S) -> T,
     {
         let name_c = &self.name_c;
         let port_c = &self.port_c;
@@ -181,6 +183,14 @@ where
         }
     }
 
+    /// Return an iterator over all flows, allowing their state to be
+    /// modified.
+    pub fn iter_mut(
+        &mut self,
+    ) -> impl Iterator<Item = (&InnerFlowId, &mut FlowEntry<S>)> {
+        self.map.iter_mut()
+    }
+
     /// Get the number of flows in this table.
     pub fn num_flows(&self) -> u32 {
         self.map.len() as u32
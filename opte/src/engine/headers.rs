@@ -212,11 +212,39 @@ impl From<Ipv6Push> for IpPush {
 pub enum IpMod {
     Ip4(Ipv4Mod),
     Ip6(Ipv6Mod),
+
+    /// Replace the header with the one described by the push spec,
+    /// which may be of the other IP version, as is done by NAT64. The
+    /// TTL/Hop Limit is carried over, and any IPv6 extension headers
+    /// are dropped.
+    Translate(IpPush),
 }
 
 impl ModifyAction<IpMeta> for IpMod {
     fn modify(&self, meta: &mut IpMeta) {
         match (self, meta) {
+            (IpMod::Translate(spec), meta) => {
+                let ttl = match *meta {
+                    IpMeta::Ip4(ip4) => ip4.ttl,
+                    IpMeta::Ip6(ip6) => ip6.hop_limit,
+                };
+
+                let mut new_meta = spec.push();
+                match &mut new_meta {
+                    IpMeta::Ip4(ip4) => {
+                        ip4.ttl = ttl;
+                        // A translated IPv4 header has no checksum to
+                        // go by. Give it one, so that the checksum is
+                        // filled in when the header is emitted.
+                        ip4.compute_hdr_csum();
+                    }
+
+                    IpMeta::Ip6(ip6) => ip6.hop_limit = ttl,
+                }
+
+                *meta = new_meta;
+            }
+
             (IpMod::Ip4(spec), IpMeta::Ip4(meta)) => {
                 spec.modify(meta);
             }
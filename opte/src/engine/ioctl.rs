@@ -7,13 +7,19 @@
 //! The ioctl interface.
 //!
 //! XXX This stuff needs to be moved to oxide-api.
+use super::capture::CaptureConfig;
+use super::capture::CapturedPacket;
+use super::flow_record::FlowRecord;
+use super::layer::DefaultAction;
 use super::layer::RuleId;
 use super::packet::InnerFlowId;
 use super::port::Port;
 use super::predicate::DataPredicate;
+use super::rule_log::RuleLogEntry;
 use super::tcp::TcpState;
 use core::fmt::Debug;
 use opte_api::CmdOk;
+use opte_api::Direction;
 use opte_api::OpteError;
 use serde::Deserialize;
 use serde::Serialize;
@@ -91,6 +97,119 @@ pub struct ListLayersResp {
 
 impl CmdOk for ListLayersResp {}
 
+/// Set the default action of a layer for one direction.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SetDefaultActionReq {
+    /// The name of the port whose layer you want to modify.
+    pub port_name: String,
+    /// The name of the layer to modify.
+    pub layer_name: String,
+    /// The direction of the default action.
+    pub dir: Direction,
+    /// The new default action.
+    pub action: DefaultAction,
+}
+
+/// Enable or disable packet capture on a port.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SetCaptureReq {
+    /// The name of the port to capture on.
+    pub port_name: String,
+    /// The capture configuration, or `None` to disable capture.
+    pub config: Option<CaptureConfig>,
+}
+
+/// Dump the capture ring of a port.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DumpCaptureReq {
+    /// The name of the port whose capture ring you want to dump.
+    pub port_name: String,
+    /// The number of the first packet to dump; see
+    /// [`DumpCaptureResp::next`].
+    pub start: u64,
+    /// The maximum number of packets to dump, at most
+    /// [`CAPTURE_DUMP_MAX`](super::capture::CAPTURE_DUMP_MAX).
+    pub max_pkts: u32,
+}
+
+/// The response to a [`DumpCaptureReq`].
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DumpCaptureResp {
+    /// The current capture configuration.
+    pub config: CaptureConfig,
+    /// The number of nanoseconds elapsed between enabling the
+    /// capture and this dump. This allows the consumer to convert
+    /// each packet's `ts_ns` to a wall-clock time.
+    pub elapsed_ns: u64,
+    /// The number of packets evicted from the ring since the capture
+    /// was enabled.
+    pub evicted: u64,
+    /// The number of the packet following the last one in `pkts`;
+    /// pass it as the `start` of the next request to continue the
+    /// dump.
+    pub next: u64,
+    /// The number of packets in the ring after the last one in
+    /// `pkts`.
+    pub remaining: u64,
+    /// The captured packets, oldest first.
+    pub pkts: Vec<CapturedPacket>,
+}
+
+impl CmdOk for DumpCaptureResp {}
+
+/// Dump the queued flow records of a port.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DumpFlowRecordsReq {
+    /// The name of the port whose flow records you want to dump.
+    pub port_name: String,
+    /// Acknowledge all records with a sequence number up to and
+    /// including this one, removing them from the port before the
+    /// dump is taken.
+    pub ack: Option<u64>,
+}
+
+/// The response to a [`DumpFlowRecordsReq`].
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DumpFlowRecordsResp {
+    /// The number of nanoseconds elapsed between the creation of the
+    /// port and this dump. This allows the consumer to convert each
+    /// record's `start_ns` and `end_ns` to a wall-clock time.
+    pub elapsed_ns: u64,
+    /// The number of records lost since the creation of the port,
+    /// either because the flow could not be tracked or because the
+    /// queue was full.
+    pub dropped: u64,
+    /// The unacknowledged records, oldest first.
+    pub records: Vec<FlowRecord>,
+}
+
+impl CmdOk for DumpFlowRecordsResp {}
+
+/// Dump the rule log of a port's layer.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DumpRuleLogReq {
+    /// The name of the port.
+    pub port_name: String,
+    /// The name of the layer whose log you want to dump.
+    pub layer_name: String,
+}
+
+/// The response to a [`DumpRuleLogReq`].
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DumpRuleLogResp {
+    /// The number of nanoseconds elapsed between the creation of the
+    /// layer and this dump. This allows the consumer to convert each
+    /// entry's `time_ns` to a wall-clock time.
+    pub elapsed_ns: u64,
+    /// The number of entries overwritten since the creation of the
+    /// layer.
+    pub lost: u64,
+    /// The entries of the log, oldest first.
+    pub entries: Vec<RuleLogEntry>,
+}
+
+impl CmdOk for DumpRuleLogResp {}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ClearUftReq {
     pub port_name: String,
@@ -179,9 +298,45 @@ pub fn dump_layer(
     port.dump_layer(&req.name)
 }
 
+pub fn set_default_action(
+    port: &Port<impl crate::engine::NetworkImpl>,
+    req: &SetDefaultActionReq,
+) -> Result<(), OpteError> {
+    port.set_default_action(&req.layer_name, req.dir, req.action.clone())
+}
+
 pub fn dump_tcp_flows(
     port: &Port<impl crate::engine::NetworkImpl>,
     _req: &DumpTcpFlowsReq,
 ) -> Result<DumpTcpFlowsResp, OpteError> {
     port.dump_tcp_flows()
 }
+
+pub fn set_capture(
+    port: &Port<impl crate::engine::NetworkImpl>,
+    req: &SetCaptureReq,
+) -> Result<(), OpteError> {
+    port.set_capture(req.config.clone());
+    Ok(())
+}
+
+pub fn dump_capture(
+    port: &Port<impl crate::engine::NetworkImpl>,
+    req: &DumpCaptureReq,
+) -> Result<DumpCaptureResp, OpteError> {
+    port.dump_capture(req.start, req.max_pkts)
+}
+
+pub fn dump_flow_records(
+    port: &Port<impl crate::engine::NetworkImpl>,
+    req: &DumpFlowRecordsReq,
+) -> Result<DumpFlowRecordsResp, OpteError> {
+    Ok(port.dump_flow_records(req.ack))
+}
+
+pub fn dump_rule_log(
+    port: &Port<impl crate::engine::NetworkImpl>,
+    req: &DumpRuleLogReq,
+) -> Result<DumpRuleLogResp, OpteError> {
+    port.dump_rule_log(&req.layer_name)
+}
@@ -30,6 +30,7 @@ use super::rule::Finalized;
 use super::rule::GenBtError;
 use super::rule::HdrTransformError;
 use super::rule::Rule;
+use super::rule_log::RuleLog;
 use crate::ddi::kstat;
 use crate::ddi::kstat::KStatNamed;
 use crate::ddi::kstat::KStatProvider;
@@ -41,10 +42,14 @@ use core::fmt;
 use core::fmt::Display;
 use core::num::NonZeroU32;
 use core::result;
+use core::str::FromStr;
 use illumos_sys_hdrs::c_char;
 use illumos_sys_hdrs::uintptr_t;
 use kstat_macro::KStatProvider;
 use opte_api::Direction;
+use opte_api::OpteError;
+use serde::Deserialize;
+use serde::Serialize;
 
 cfg_if! {
     if #[cfg(all(not(feature = "std"), not(test)))] {
@@ -225,6 +230,36 @@ impl LayerFlowTable {
         LftDump { ft_in: self.ft_in.dump(), ft_out: self.ft_out.dump() }
     }
 
+    /// Remove all flow pairs for which `f` returns true, given the
+    /// flow ID of the side in direction `dir`. Return the number of
+    /// pairs removed.
+    fn remove_matching<F>(&mut self, dir: Direction, f: F) -> u32
+    where
+        F: Fn(&InnerFlowId) -> bool,
+    {
+        let mut to_remove = vec![];
+        for (out_flow, entry) in self.ft_out.iter_mut() {
+            let in_flow = &entry.state().in_flow_pair;
+            let flow = match dir {
+                Direction::In => in_flow,
+                Direction::Out => out_flow,
+            };
+
+            if f(flow) {
+                to_remove.push((*out_flow, *in_flow));
+            }
+        }
+
+        // Just like adding and expiring, the pair is removed as one.
+        for (out_flow, in_flow) in &to_remove {
+            self.ft_out.remove(out_flow);
+            self.ft_in.remove(in_flow);
+        }
+
+        self.count = self.ft_out.num_flows();
+        to_remove.len() as u32
+    }
+
     fn expire_flows(&mut self, now: Moment) {
         // XXX The two sides can have different traffic patterns and
         // thus one side could be considered expired while the other
@@ -287,18 +322,33 @@ impl LayerFlowTable {
     }
 }
 
+/// A summary of the changes made by [`Layer::sync_rules()`].
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize,
+)]
+pub struct RuleSync {
+    /// The number of rules added.
+    pub added: u32,
+    /// The number of rules removed.
+    pub removed: u32,
+    /// The number of rules left in place.
+    pub unchanged: u32,
+    /// The number of flow pairs invalidated.
+    pub flows_invalidated: u32,
+}
+
 /// The default action of a layer.
 ///
-/// At the moment this can only be allow or deny. That should cover
-/// all use cases for the time being. However, it's probably
-/// reasonable to open this up to be any [`Action`], if such a use
-/// case were to present itself. For now, we stay conservative, and
-/// supply only what the current consumers need.
-#[derive(Copy, Clone, Debug)]
+/// This is usually allow or deny. A layer may also default to one of
+/// the named actions given in its [`LayerActions`]. The default action
+/// may be changed on a running port via
+/// [`super::port::Port::set_default_action()`].
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub enum DefaultAction {
     Allow,
     StatefulAllow,
     Deny,
+    Named(String),
 }
 
 impl Display for DefaultAction {
@@ -307,10 +357,46 @@ impl Display for DefaultAction {
             Self::Allow => write!(f, "allow"),
             Self::StatefulAllow => write!(f, "stateful allow"),
             Self::Deny => write!(f, "deny"),
+            Self::Named(name) => write!(f, "action {}", name),
         }
     }
 }
 
+impl FromStr for DefaultAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "allow" => Ok(Self::Allow),
+            "stateful allow" | "stateful-allow" => Ok(Self::StatefulAllow),
+            "deny" => Ok(Self::Deny),
+            other => {
+                // Action names are matched as given, only the prefix
+                // is case-insensitive.
+                let rest = other
+                    .strip_prefix("action=")
+                    .or_else(|| other.strip_prefix("action "))
+                    .ok_or(format!("bad default action: {}", s))?;
+                let name = s.trim()[other.len() - rest.len()..].trim();
+
+                if name.is_empty() {
+                    return Err(format!("bad default action: {}", s));
+                }
+
+                Ok(Self::Named(name.to_string()))
+            }
+        }
+    }
+}
+
+// Find the action with the given name.
+fn find_named<'a>(
+    named: &'a [(&'static str, Action)],
+    name: &str,
+) -> Option<&'a Action> {
+    named.iter().find(|(n, _)| *n == name).map(|(_, action)| action)
+}
+
 #[derive(Clone, Debug)]
 pub enum ActionDescEntry {
     NoOp,
@@ -346,6 +432,10 @@ pub struct LayerActions {
     /// share the same action.
     pub actions: Vec<Action>,
 
+    /// The actions which a [`DefaultAction::Named`] may refer to, by
+    /// name.
+    pub named: Vec<(&'static str, Action)>,
+
     /// The default action to take if no rule matches in the inbound
     /// direction.
     pub default_in: DefaultAction,
@@ -375,6 +465,26 @@ struct LayerStats {
     /// resulting in the default action being applied.
     in_rule_nomatch: KStatU64,
 
+    /// The number of inbound packets which took the current default
+    /// action. This is reset when the default action is changed.
+    in_default_hits: KStatU64,
+
+    /// The number of inbound packets allowed by [`Action::Allow`], the
+    /// stateless allow which leaves no entry in the LFT, e.g. that of
+    /// a stateless firewall rule. Other actions which leave no entry,
+    /// such as static header transformations, are not counted.
+    in_stateless: KStatU64,
+
+    /// The number of inbound packets allowed by a stateful action,
+    /// which creates an entry in the LFT. The remaining packets of
+    /// the flow are counted by `in_lft_hit`.
+    in_stateful: KStatU64,
+
+    /// The number of inbound packets dropped because a stateful
+    /// action failed to generate a descriptor, e.g. due to resource
+    /// exhaustion.
+    in_gen_desc_fail: KStatU64,
+
     /// The current number of inbound rules.
     in_rules: KStatU64,
 
@@ -396,6 +506,26 @@ struct LayerStats {
     /// resulting in the default action being applied.
     out_rule_nomatch: KStatU64,
 
+    /// The number of outbound packets which took the current default
+    /// action. This is reset when the default action is changed.
+    out_default_hits: KStatU64,
+
+    /// The number of outbound packets allowed by [`Action::Allow`], the
+    /// stateless allow which leaves no entry in the LFT, e.g. that of
+    /// a stateless firewall rule. Other actions which leave no entry,
+    /// such as static header transformations, are not counted.
+    out_stateless: KStatU64,
+
+    /// The number of outbound packets allowed by a stateful action,
+    /// which creates an entry in the LFT. The remaining packets of
+    /// the flow are counted by `out_lft_hit`.
+    out_stateful: KStatU64,
+
+    /// The number of outbound packets dropped because a stateful
+    /// action failed to generate a descriptor, e.g. due to SNAT port
+    /// exhaustion.
+    out_gen_desc_fail: KStatU64,
+
     /// The current number of outbound rules.
     out_rules: KStatU64,
 
@@ -417,6 +547,12 @@ struct LayerStats {
 
     /// The number of times set_rules() has been called.
     set_rules_called: KStatU64,
+
+    /// The number of times sync_rules() has been called.
+    sync_rules_called: KStatU64,
+
+    /// The number of times set_default_action() has been called.
+    set_default_action_called: KStatU64,
 }
 
 pub struct Layer {
@@ -424,15 +560,15 @@ pub struct Layer {
     name: &'static str,
     name_c: CString,
     actions: Vec<Action>,
+    named: Vec<(&'static str, Action)>,
     default_in: DefaultAction,
-    default_in_hits: u64,
     default_out: DefaultAction,
-    default_out_hits: u64,
     ft: LayerFlowTable,
     ft_cstr: CString,
     rules_in: RuleTable,
     rules_out: RuleTable,
     rt_cstr: CString,
+    rule_log: RuleLog,
     stats: KStatNamed<LayerStats>,
 }
 
@@ -454,6 +590,7 @@ impl Layer {
             }
         }
 
+        self.rule_log.clear_logged();
         self.stats.vals.add_rule_called += 1;
     }
 
@@ -463,10 +600,38 @@ impl Layer {
         self.stats.vals.flows.set(0);
     }
 
-    pub(crate) fn default_action(&self, dir: Direction) -> DefaultAction {
+    /// Remove the flow pairs for which `f` returns true, given the
+    /// flow ID of the side in direction `dir`. Return the number of
+    /// pairs removed.
+    pub(crate) fn remove_flows<F>(&mut self, dir: Direction, f: F) -> u32
+    where
+        F: Fn(&InnerFlowId) -> bool,
+    {
+        let removed = self.ft.remove_matching(dir, f);
+        self.stats.vals.flows.set(self.ft.num_flows() as u64);
+        removed
+    }
+
+    pub(crate) fn default_action(&self, dir: Direction) -> &DefaultAction {
         match dir {
-            Direction::In => self.default_in,
-            Direction::Out => self.default_out,
+            Direction::In => &self.default_in,
+            Direction::Out => &self.default_out,
+        }
+    }
+
+    /// Describe the default action for the given direction. A default
+    /// which refers to one of the layer's named actions includes that
+    /// action's description.
+    pub(crate) fn default_action_desc(&self, dir: Direction) -> String {
+        let def = self.default_action(dir);
+
+        match def {
+            DefaultAction::Named(name) => match self.named_action(name) {
+                Some(action) => format!("{} ({})", def, action),
+                None => def.to_string(),
+            },
+
+            _ => def.to_string(),
         }
     }
 
@@ -482,13 +647,18 @@ impl Layer {
             ft_out: ftd.ft_out,
             rules_in,
             rules_out,
-            default_in: self.default_in.to_string(),
-            default_in_hits: self.default_in_hits,
-            default_out: self.default_out.to_string(),
-            default_out_hits: self.default_out_hits,
+            default_in: self.default_action_desc(Direction::In),
+            default_in_hits: self.stats.vals.in_default_hits.val(),
+            default_out: self.default_action_desc(Direction::Out),
+            default_out_hits: self.stats.vals.out_default_hits.val(),
         }
     }
 
+    /// Dump the log of the rule matches of this layer.
+    pub(crate) fn dump_rule_log(&self) -> ioctl::DumpRuleLogResp {
+        self.rule_log.dump()
+    }
+
     fn gen_desc_fail_probe(
         &self,
         dir: Direction,
@@ -564,6 +734,7 @@ impl Layer {
     /// passed in moment.
     pub(crate) fn expire_flows(&mut self, now: Moment) {
         self.ft.expire_flows(now);
+        self.rule_log.expire(now);
         self.stats.vals.flows.set(self.ft.num_flows() as u64);
     }
 
@@ -652,12 +823,42 @@ impl Layer {
         &self.name
     }
 
+    /// Return the named action with the given name, if there is one.
+    pub fn named_action(&self, name: &str) -> Option<&Action> {
+        find_named(&self.named, name)
+    }
+
+    // Verify that a default action which refers to a named action
+    // refers to one which exists.
+    fn check_default(
+        named: &[(&'static str, Action)],
+        action: &DefaultAction,
+    ) -> result::Result<(), OpteError> {
+        match action {
+            DefaultAction::Named(name) => match find_named(named, name) {
+                Some(_) => Ok(()),
+                None => Err(OpteError::ActionNotFound(name.clone())),
+            },
+
+            _ => Ok(()),
+        }
+    }
+
+    /// Create a new layer.
+    ///
+    /// # Errors
+    ///
+    /// If either default action refers to a named action which is not
+    /// in `actions`, then an error is returned.
     pub fn new(
         name: &'static str,
         port: &str,
         actions: LayerActions,
         ft_limit: NonZeroU32,
-    ) -> Self {
+    ) -> result::Result<Self, OpteError> {
+        Self::check_default(&actions.named, &actions.default_in)?;
+        Self::check_default(&actions.named, &actions.default_out)?;
+
         let port_c = CString::new(port).unwrap();
         let name_c = CString::new(name).unwrap();
 
@@ -672,12 +873,11 @@ impl Layer {
         stats.vals.lft_capacity.set(ft_limit.get() as u64);
         stats.vals.flow_ttl.set(FLOW_DEF_EXPIRE_SECS);
 
-        Layer {
+        Ok(Layer {
             actions: actions.actions,
+            named: actions.named,
             default_in: actions.default_in,
-            default_in_hits: 0,
             default_out: actions.default_out,
-            default_out_hits: 0,
             name,
             name_c,
             port_c: port_c.clone(),
@@ -686,8 +886,9 @@ impl Layer {
             rules_in: RuleTable::new(port, name, Direction::In),
             rules_out: RuleTable::new(port, name, Direction::Out),
             rt_cstr: CString::new(format!("rt-{}", name)).unwrap(),
+            rule_log: RuleLog::new(port, name, ft_limit),
             stats,
-        }
+        })
     }
 
     /// Return the number of active flows.
@@ -735,6 +936,16 @@ impl Layer {
             return self.process_in_rules(ectx, pkt, xforms, ameta);
         }
 
+        // An ICMP error about a flow of this layer is handled by that
+        // flow's action.
+        if let Some(err_flow) = pkt.icmp_err_flow() {
+            if let Some(res) =
+                self.process_icmp_err(Direction::In, &err_flow, pkt, xforms)?
+            {
+                return Ok(res);
+            }
+        }
+
         // Do we have a FlowTable entry? If so, use it.
         match self.ft.get_in(pkt.flow()) {
             Some(ActionDescEntry::NoOp) => {
@@ -775,6 +986,72 @@ impl Layer {
         }
     }
 
+    // Process an ICMP error carrying `err_flow`, the flow ID of the
+    // packet in error. That packet traveled in the opposite direction
+    // of the error, and thus its mirror is the key to this layer's
+    // flow table.
+    //
+    // Return `None` if the packet in error belongs to no flow of this
+    // layer, or if the flow's action has no translation for ICMP
+    // errors, in which case the error is processed as a flow of its
+    // own.
+    fn process_icmp_err(
+        &mut self,
+        dir: Direction,
+        err_flow: &InnerFlowId,
+        pkt: &mut Packet<Parsed>,
+        xforms: &mut Transforms,
+    ) -> result::Result<Option<LayerResult>, LayerError> {
+        let entry = match dir {
+            Direction::In => self.ft.get_in(&err_flow.mirror()),
+            Direction::Out => self.ft.get_out(&err_flow.mirror()),
+        };
+
+        let desc = match entry {
+            Some(entry) => {
+                match dir {
+                    Direction::In => self.stats.vals.in_lft_hit += 1,
+                    Direction::Out => self.stats.vals.out_lft_hit += 1,
+                }
+
+                match entry {
+                    ActionDescEntry::NoOp => {
+                        return Ok(Some(LayerResult::Allow));
+                    }
+
+                    ActionDescEntry::Desc(desc) => desc,
+                }
+            }
+
+            None => return Ok(None),
+        };
+
+        let body = pkt.get_body_rdr().copy_remaining();
+        match desc.gen_icmp_err(dir, pkt.meta(), &body) {
+            Some(AllowOrDeny::Allow(xlate)) => {
+                let flow_before = *pkt.flow();
+                pkt.hdr_transform(&xlate.ht)?;
+                pkt.replace_body(&xlate.body)?;
+                ht_probe(
+                    &self.port_c,
+                    self.ft_cstr.as_c_str(),
+                    dir,
+                    &flow_before,
+                    pkt.flow(),
+                );
+                xforms.hdr.push(xlate.ht);
+                Ok(Some(LayerResult::Allow))
+            }
+
+            Some(AllowOrDeny::Deny) => Ok(Some(LayerResult::Deny {
+                name: self.name,
+                reason: DenyReason::Action,
+            })),
+
+            None => Ok(None),
+        }
+    }
+
     fn process_in_rules(
         &mut self,
         ectx: &ExecCtx,
@@ -792,9 +1069,9 @@ impl Layer {
 
         let action = if rule.is_none() {
             self.stats.vals.in_rule_nomatch += 1;
-            self.default_in_hits += 1;
+            self.stats.vals.in_default_hits += 1;
 
-            match self.default_in {
+            match &self.default_in {
                 DefaultAction::Deny => {
                     return Ok(LayerResult::Deny {
                         name: self.name.clone(),
@@ -804,14 +1081,34 @@ impl Layer {
 
                 DefaultAction::Allow => &Action::Allow,
                 DefaultAction::StatefulAllow => &Action::StatefulAllow,
+
+                // The name is checked when the default action is
+                // set, but err on the side of denying the packet.
+                DefaultAction::Named(name) => {
+                    match find_named(&self.named, name) {
+                        Some(action) => action,
+                        None => {
+                            return Ok(LayerResult::Deny {
+                                name: self.name.clone(),
+                                reason: DenyReason::Default,
+                            });
+                        }
+                    }
+                }
             }
         } else {
+            // Unwrap: We know there is a match.
+            let (id, rule) = rule.unwrap();
             self.stats.vals.in_rule_match += 1;
-            rule.unwrap().action()
+            if rule.log() {
+                self.rule_log.record(In, id, rule.action(), pkt.flow());
+            }
+            rule.action()
         };
 
         match action {
             Action::Allow => {
+                self.stats.vals.in_stateless += 1;
                 return Ok(LayerResult::Allow);
             }
 
@@ -832,6 +1129,7 @@ impl Layer {
                 let desc = ActionDescEntry::NoOp;
                 self.ft.add_pair(desc, pkt.flow().clone(), flow_out);
                 self.stats.vals.flows += 1;
+                self.stats.vals.in_stateful += 1;
                 return Ok(LayerResult::Allow);
             }
 
@@ -945,6 +1243,7 @@ impl Layer {
                     },
 
                     Err(e) => {
+                        self.stats.vals.in_gen_desc_fail += 1;
                         self.record_gen_desc_failure(&ectx, In, pkt.flow(), &e);
                         return Err(LayerError::GenDesc(e));
                     }
@@ -983,6 +1282,7 @@ impl Layer {
                     flow_out,
                 );
                 self.stats.vals.flows += 1;
+                self.stats.vals.in_stateful += 1;
                 return Ok(LayerResult::Allow);
             }
 
@@ -1014,6 +1314,11 @@ impl Layer {
             Action::HandlePacket => {
                 return Ok(LayerResult::HandlePkt);
             }
+
+            Action::Mirror(mirror) => {
+                xforms.mirror.push(mirror.clone());
+                return Ok(LayerResult::Allow);
+            }
         }
     }
 
@@ -1029,6 +1334,16 @@ impl Layer {
             return self.process_out_rules(ectx, pkt, xforms, ameta);
         }
 
+        // An ICMP error about a flow of this layer is handled by that
+        // flow's action.
+        if let Some(err_flow) = pkt.icmp_err_flow() {
+            if let Some(res) =
+                self.process_icmp_err(Direction::Out, &err_flow, pkt, xforms)?
+            {
+                return Ok(res);
+            }
+        }
+
         // Do we have a FlowTable entry? If so, use it.
         match self.ft.get_out(pkt.flow()) {
             Some(ActionDescEntry::NoOp) => {
@@ -1086,9 +1401,9 @@ impl Layer {
 
         let action = if rule.is_none() {
             self.stats.vals.out_rule_nomatch += 1;
-            self.default_out_hits += 1;
+            self.stats.vals.out_default_hits += 1;
 
-            match self.default_out {
+            match &self.default_out {
                 DefaultAction::Deny => {
                     return Ok(LayerResult::Deny {
                         name: self.name.clone(),
@@ -1098,14 +1413,34 @@ impl Layer {
 
                 DefaultAction::Allow => &Action::Allow,
                 DefaultAction::StatefulAllow => &Action::StatefulAllow,
+
+                // The name is checked when the default action is
+                // set, but err on the side of denying the packet.
+                DefaultAction::Named(name) => {
+                    match find_named(&self.named, name) {
+                        Some(action) => action,
+                        None => {
+                            return Ok(LayerResult::Deny {
+                                name: self.name.clone(),
+                                reason: DenyReason::Default,
+                            });
+                        }
+                    }
+                }
             }
         } else {
+            // Unwrap: We know there is a match.
+            let (id, rule) = rule.unwrap();
             self.stats.vals.out_rule_match += 1;
-            rule.unwrap().action()
+            if rule.log() {
+                self.rule_log.record(Out, id, rule.action(), pkt.flow());
+            }
+            rule.action()
         };
 
         match action {
             Action::Allow => {
+                self.stats.vals.out_stateless += 1;
                 return Ok(LayerResult::Allow);
             }
 
@@ -1132,6 +1467,7 @@ impl Layer {
                     pkt.flow().clone(),
                 );
                 self.stats.vals.flows += 1;
+                self.stats.vals.out_stateful += 1;
                 return Ok(LayerResult::Allow);
             }
 
@@ -1245,6 +1581,7 @@ impl Layer {
                     },
 
                     Err(e) => {
+                        self.stats.vals.out_gen_desc_fail += 1;
                         self.record_gen_desc_failure(
                             &ectx,
                             Out,
@@ -1291,6 +1628,7 @@ impl Layer {
                     flow_before,
                 );
                 self.stats.vals.flows += 1;
+                self.stats.vals.out_stateful += 1;
                 return Ok(LayerResult::Allow);
             }
 
@@ -1322,6 +1660,11 @@ impl Layer {
             Action::HandlePacket => {
                 return Ok(LayerResult::HandlePkt);
             }
+
+            Action::Mirror(mirror) => {
+                xforms.mirror.push(mirror.clone());
+                return Ok(LayerResult::Allow);
+            }
         }
     }
 
@@ -1332,7 +1675,6 @@ impl Layer {
         flow: &InnerFlowId,
         err: &rule::GenDescError,
     ) {
-        // XXX increment stat
         ectx.log.log(
             LogLevel::Error,
             &format!(
@@ -1380,6 +1722,7 @@ impl Layer {
             }
         }
 
+        self.rule_log.clear_logged();
         self.stats.vals.remove_rule_called += 1;
         Ok(())
     }
@@ -1430,6 +1773,20 @@ impl Layer {
         }
     }
 
+    /// Return a copy of the rules in the given direction, along with
+    /// their IDs.
+    pub(crate) fn rules(
+        &self,
+        dir: Direction,
+    ) -> Vec<(RuleId, Rule<Finalized>)> {
+        let rt = match dir {
+            Direction::Out => &self.rules_out,
+            Direction::In => &self.rules_in,
+        };
+
+        rt.rules.iter().map(|rte| (rte.id, rte.rule.clone())).collect()
+    }
+
     /// Set all rules at once, in an atomic manner.
     ///
     /// Updating the ruleset immediately invalidates all flows
@@ -1442,11 +1799,98 @@ impl Layer {
         self.ft.clear();
         self.rules_in.set_rules(in_rules);
         self.rules_out.set_rules(out_rules);
+        self.rule_log.clear_logged();
         self.stats.vals.set_rules_called += 1;
         self.stats.vals.in_rules.set(self.rules_in.num_rules() as u64);
         self.stats.vals.out_rules.set(self.rules_out.num_rules() as u64);
     }
 
+    /// Set all rules at once, in an atomic manner, keeping the rules
+    /// which are in both the current and new sets.
+    ///
+    /// Unlike [`Self::set_rules()`], only the flows which may be
+    /// matched by an added or removed rule are invalidated, or, if
+    /// `invalidate_in` is given, the flow pairs for which it returns
+    /// true given the flow ID of their inbound side. The remaining
+    /// flows, and the hit counts of the rules kept, are preserved.
+    pub(crate) fn sync_rules(
+        &mut self,
+        in_rules: Vec<Rule<Finalized>>,
+        out_rules: Vec<Rule<Finalized>>,
+        invalidate_in: Option<&dyn Fn(&InnerFlowId) -> bool>,
+    ) -> RuleSync {
+        let mut res = RuleSync::default();
+
+        for (dir, rules) in
+            [(Direction::In, in_rules), (Direction::Out, out_rules)]
+        {
+            let rt = match dir {
+                Direction::In => &mut self.rules_in,
+                Direction::Out => &mut self.rules_out,
+            };
+
+            let (removed, added, unchanged) = rt.sync(rules);
+            res.added += added.len() as u32;
+            res.removed += removed.len() as u32;
+            res.unchanged += unchanged;
+
+            let changed: Vec<_> = removed.iter().chain(added.iter()).collect();
+            if !changed.is_empty() && invalidate_in.is_none() {
+                res.flows_invalidated += self.ft.remove_matching(dir, |flow| {
+                    changed.iter().any(|r| r.may_match_flow(flow))
+                });
+            }
+        }
+
+        if let Some(f) = invalidate_in {
+            res.flows_invalidated += self.ft.remove_matching(Direction::In, f);
+        }
+
+        if res.added > 0 || res.removed > 0 {
+            self.rule_log.clear_logged();
+        }
+
+        self.stats.vals.sync_rules_called += 1;
+        self.stats.vals.in_rules.set(self.rules_in.num_rules() as u64);
+        self.stats.vals.out_rules.set(self.rules_out.num_rules() as u64);
+        self.stats.vals.flows.set(self.ft.num_flows() as u64);
+        res
+    }
+
+    /// Set the default action for the given direction.
+    ///
+    /// The layer's flows are cleared, as they may have been created
+    /// by the previous default action. The hit count for the default
+    /// action in this direction is reset.
+    ///
+    /// # Errors
+    ///
+    /// If the action refers to a named action which this layer does
+    /// not have, then an error is returned and no change is made.
+    pub(crate) fn set_default_action(
+        &mut self,
+        dir: Direction,
+        action: DefaultAction,
+    ) -> result::Result<(), OpteError> {
+        Self::check_default(&self.named, &action)?;
+
+        match dir {
+            Direction::In => {
+                self.default_in = action;
+                self.stats.vals.in_default_hits.set(0);
+            }
+
+            Direction::Out => {
+                self.default_out = action;
+                self.stats.vals.out_default_hits.set(0);
+            }
+        }
+
+        self.clear_flows();
+        self.stats.vals.set_default_action_called += 1;
+        Ok(())
+    }
+
     pub fn stats_snap(&self) -> LayerStatsSnap {
         self.stats.vals.snapshot()
     }
@@ -1519,7 +1963,7 @@ impl<'a> RuleTable {
         pmeta: &PacketMeta,
         ameta: &ActionMeta,
         rdr: &'b mut R,
-    ) -> Option<&Rule<rule::Finalized>>
+    ) -> Option<(RuleId, &Rule<rule::Finalized>)>
     where
         R: PacketRead<'a>,
     {
@@ -1533,7 +1977,7 @@ impl<'a> RuleTable {
                     ifid,
                     &rte.rule,
                 );
-                return Some(&rte.rule);
+                return Some((rte.id, &rte.rule));
             }
         }
 
@@ -1684,6 +2128,48 @@ impl<'a> RuleTable {
             self.add(r);
         }
     }
+
+    // Replace the rules with `new_rules`, keeping the entries of those
+    // rules which are in both sets. Return the rules removed, the
+    // rules added, and the number of rules kept.
+    fn sync(
+        &mut self,
+        new_rules: Vec<Rule<rule::Finalized>>,
+    ) -> (Vec<Rule<rule::Finalized>>, Vec<Rule<rule::Finalized>>, u32) {
+        let mut keep = vec![false; self.rules.len()];
+        let mut added = vec![];
+
+        for rule in new_rules {
+            let found = self
+                .rules
+                .iter()
+                .enumerate()
+                .position(|(i, rte)| !keep[i] && rte.rule.is_same(&rule));
+
+            match found {
+                Some(i) => keep[i] = true,
+                None => added.push(rule),
+            }
+        }
+
+        let mut removed = vec![];
+        let mut kept = keep.iter();
+        self.rules.retain(|rte| {
+            // Unwrap: There is one flag per entry.
+            let keep = *kept.next().unwrap();
+            if !keep {
+                removed.push(rte.rule.clone());
+            }
+            keep
+        });
+        let unchanged = self.rules.len() as u32;
+
+        for rule in &added {
+            self.add(rule.clone());
+        }
+
+        (removed, added, unchanged)
+    }
 }
 
 #[cfg(all(not(feature = "std"), not(test)))]
@@ -1815,6 +2301,40 @@ mod test {
             .find_match(&ifid, &pmeta, &ameta, &mut rdr)
             .is_some());
     }
+
+    #[test]
+    fn named_default_action() {
+        use crate::engine::rule;
+
+        let ft_limit = NonZeroU32::new(8).unwrap();
+        let ident =
+            Action::Static(Arc::new(rule::Identity::new("named_default")));
+        let actions = |default_in| LayerActions {
+            actions: vec![],
+            named: vec![("ident", ident.clone())],
+            default_in,
+            default_out: DefaultAction::Allow,
+        };
+
+        let bogus = DefaultAction::Named("bogus".to_string());
+        assert!(Layer::new("test", "port", actions(bogus.clone()), ft_limit)
+            .is_err());
+
+        let named = DefaultAction::Named("ident".to_string());
+        let mut layer =
+            Layer::new("test", "port", actions(named.clone()), ft_limit)
+                .unwrap();
+        assert_eq!(layer.default_action(Direction::In), &named);
+        assert!(layer.named_action("ident").is_some());
+
+        assert!(layer.set_default_action(Direction::Out, bogus).is_err());
+        assert_eq!(layer.default_action(Direction::Out), &DefaultAction::Allow);
+        layer.set_default_action(Direction::Out, named.clone()).unwrap();
+        assert_eq!(layer.default_action(Direction::Out), &named);
+
+        assert_eq!("action=ident".parse::<DefaultAction>(), Ok(named));
+        assert!("action=".parse::<DefaultAction>().is_err());
+    }
 }
 // TODO Reinstate
 // #[test]
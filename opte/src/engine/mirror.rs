@@ -0,0 +1,166 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! Port mirroring.
+//!
+//! A [`Mirror`] is used via [`Action::Mirror`] to copy the packets
+//! matching a rule to a [`MirrorTarget`]. The action itself does not
+//! modify the packet, it allows it to pass and records the mirror as
+//! part of the flow's transformations. Once the port has finished
+//! processing the packet, it samples each of the flow's mirrors and
+//! queues a [`MirroredPacket`] for the consumer (e.g. xde) to
+//! deliver.
+//!
+//! The copy is always of the inner frame as it exists after
+//! processing. For inbound traffic this is the frame as delivered to
+//! the guest, for outbound traffic this is the frame as sent onto
+//! the network, minus any encapsulation.
+//!
+//! [`Action::Mirror`]: super::rule::Action::Mirror
+use super::packet::Packet;
+use super::packet::Parsed;
+use super::rule::HdrTransform;
+use super::GenericUlp;
+use core::fmt;
+use core::fmt::Display;
+use core::num::NonZeroU32;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering::Relaxed;
+use opte_api::Direction;
+
+cfg_if! {
+    if #[cfg(all(not(feature = "std"), not(test)))] {
+        use alloc::string::String;
+        use alloc::string::ToString;
+        use alloc::sync::Arc;
+    } else {
+        use std::string::String;
+        use std::string::ToString;
+        use std::sync::Arc;
+    }
+}
+
+/// The destination of a mirrored packet.
+#[derive(Clone, Debug)]
+pub enum MirrorTarget {
+    /// Deliver the copy to the guest attached to the named port.
+    Port(String),
+
+    /// Send the copy over the underlay. The header transformation is
+    /// run against the copy in order to encapsulate it, e.g. as
+    /// generated by the network's encap action.
+    Underlay(HdrTransform),
+}
+
+impl Display for MirrorTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Port(name) => write!(f, "port {}", name),
+            Self::Underlay(ht) => write!(f, "underlay ({})", ht),
+        }
+    }
+}
+
+/// A port mirror.
+#[derive(Debug)]
+pub struct Mirror {
+    name: String,
+    target: MirrorTarget,
+    // The maximum number of bytes of the inner frame to copy; zero
+    // means the entire frame. The headers are always copied.
+    trunc_len: u32,
+    // Copy one out of every `sample_rate` packets.
+    sample_rate: NonZeroU32,
+    seen: AtomicU64,
+}
+
+impl Mirror {
+    /// Create a new mirror named `name`, copying at most `trunc_len`
+    /// bytes (or the entire frame when zero) of one out of every
+    /// `sample_rate` matching packets to `target`.
+    pub fn new(
+        name: &str,
+        target: MirrorTarget,
+        trunc_len: u32,
+        sample_rate: NonZeroU32,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            target,
+            trunc_len,
+            sample_rate,
+            seen: AtomicU64::new(0),
+        }
+    }
+
+    /// Generate the copy of `pkt` to be sent to the target.
+    ///
+    /// The packet must already have its new headers emitted. If the
+    /// copy cannot be parsed or encapsulated, then `None` is
+    /// returned.
+    pub(crate) fn gen_packet(
+        self: &Arc<Self>,
+        pkt: &Packet<Parsed>,
+    ) -> Option<MirroredPacket> {
+        let bytes = pkt.all_bytes();
+        let start = pkt.hdr_offsets().inner.ether.pkt_pos;
+        // The headers are always copied in full, regardless of the
+        // truncation length, so that the copy may still be parsed and
+        // encapsulated.
+        let end = match self.trunc_len {
+            0 => bytes.len(),
+            len => {
+                let end =
+                    core::cmp::max(start + len as usize, pkt.body_offset());
+                core::cmp::min(bytes.len(), end)
+            }
+        };
+
+        let mut copy = Packet::copy(&bytes[start..end])
+            .parse(Direction::Out, GenericUlp {})
+            .ok()?;
+
+        if let MirrorTarget::Underlay(ht) = &self.target {
+            copy.hdr_transform(ht).ok()?;
+            copy.emit_new_headers().ok()?;
+        }
+
+        Some(MirroredPacket { mirror: self.clone(), pkt: copy })
+    }
+
+    /// Return the name of the mirror.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Decide whether the current packet should be copied, based on
+    /// the sample rate.
+    pub(crate) fn sample(&self) -> bool {
+        self.seen.fetch_add(1, Relaxed) % self.sample_rate.get() as u64 == 0
+    }
+
+    /// Return the target of the mirror.
+    pub fn target(&self) -> &MirrorTarget {
+        &self.target
+    }
+}
+
+impl Display for Mirror {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} -> {} trunc={} rate=1/{}",
+            self.name, self.target, self.trunc_len, self.sample_rate
+        )
+    }
+}
+
+/// A mirrored copy of a packet, waiting to be delivered to the
+/// mirror's target.
+pub struct MirroredPacket {
+    pub mirror: Arc<Mirror>,
+    pub pkt: Packet<Parsed>,
+}
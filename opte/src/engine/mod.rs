@@ -8,11 +8,13 @@
 //!
 //! All code under this namespace is guarded by the `engine` feature flag.
 pub mod arp;
+pub mod capture;
 pub mod checksum;
 pub mod dhcp;
 pub mod dhcpv6;
 #[macro_use]
 pub mod ether;
+pub mod flow_record;
 pub mod flow_table;
 pub mod geneve;
 #[macro_use]
@@ -25,7 +27,9 @@ pub mod ip4;
 #[macro_use]
 pub mod ip6;
 pub mod layer;
+pub mod mirror;
 pub mod nat;
+pub mod nat64;
 #[macro_use]
 pub mod packet;
 pub mod port;
@@ -33,6 +37,7 @@ pub mod predicate;
 #[cfg(any(feature = "std", test))]
 pub mod print;
 pub mod rule;
+pub mod rule_log;
 pub mod snat;
 #[macro_use]
 pub mod tcp;
@@ -4,10 +4,13 @@
 
 // Copyright 2022 Oxide Computer Company
 
-use super::ether::EtherMod;
 use super::headers::HeaderAction;
 use super::headers::IpMod;
+use super::headers::UlpGenericModify;
+use super::headers::UlpHeaderAction;
+use super::headers::UlpMetaModify;
 use super::ip4::Ipv4Mod;
+use super::ip4::Protocol;
 use super::ip6::Ipv6Mod;
 use super::packet::InnerFlowId;
 use super::packet::Packet;
@@ -21,11 +24,12 @@ use super::rule::AllowOrDeny;
 use super::rule::HdrTransform;
 use super::rule::StatefulAction;
 use crate::engine::snat::ConcreteIpAddr;
+use core::any::Any;
 use core::fmt;
 use core::marker::PhantomData;
+use core::ops::RangeInclusive;
 use opte_api::Direction;
 use opte_api::IpAddr;
-use opte_api::MacAddr;
 
 cfg_if! {
     if #[cfg(all(not(feature = "std"), not(test)))] {
@@ -44,22 +48,17 @@ cfg_if! {
 pub struct Nat {
     priv_ip: IpAddr,
     external_ip: IpAddr,
-    // XXX-EXT-IP Remove
-    phys_gw_mac: Option<MacAddr>,
 }
 
 impl Nat {
     /// Create a new NAT mapping from a private to public IP address.
-    pub fn new<T: ConcreteIpAddr>(
-        priv_ip: T,
-        external_ip: T,
-        phys_gw_mac: Option<MacAddr>,
-    ) -> Self {
-        Self {
-            priv_ip: priv_ip.into(),
-            external_ip: external_ip.into(),
-            phys_gw_mac,
-        }
+    pub fn new<T: ConcreteIpAddr>(priv_ip: T, external_ip: T) -> Self {
+        Self { priv_ip: priv_ip.into(), external_ip: external_ip.into() }
+    }
+
+    /// Return the external IP of this mapping.
+    pub fn external_ip(&self) -> IpAddr {
+        self.external_ip
     }
 }
 
@@ -76,15 +75,8 @@ impl StatefulAction for Nat {
         _pkt: &Packet<Parsed>,
         _meta: &mut ActionMeta,
     ) -> rule::GenDescResult {
-        let desc = NatDesc {
-            priv_ip: self.priv_ip,
-            external_ip: self.external_ip,
-            // XXX-EXT-IP This is assuming ext_ip_hack. All packets
-            // outbound for IG will have their dest mac rewritten to
-            // go to physical gateway, which will then properly route
-            // the destination IP.
-            phys_gw_mac: self.phys_gw_mac.clone(),
-        };
+        let desc =
+            NatDesc { priv_ip: self.priv_ip, external_ip: self.external_ip };
         Ok(AllowOrDeny::Allow(Arc::new(desc)))
     }
 
@@ -94,6 +86,10 @@ impl StatefulAction for Nat {
     fn implicit_preds(&self) -> (Vec<Predicate>, Vec<DataPredicate>) {
         (vec![], vec![])
     }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        Some(self)
+    }
 }
 
 /// An action descriptor for a NAT action.
@@ -101,8 +97,6 @@ impl StatefulAction for Nat {
 pub struct NatDesc {
     priv_ip: IpAddr,
     external_ip: IpAddr,
-    // XXX-EXT-IP
-    phys_gw_mac: Option<MacAddr>,
 }
 
 pub const NAT_NAME: &'static str = "NAT";
@@ -121,26 +115,176 @@ impl ActionDesc for NatDesc {
                         ..Default::default()
                     }),
                 };
-                let mut ht = HdrTransform {
+                HdrTransform {
                     name: NAT_NAME.to_string(),
                     inner_ip: HeaderAction::Modify(ip, PhantomData),
                     ..Default::default()
+                }
+            }
+
+            Direction::In => {
+                let ip = match self.priv_ip {
+                    IpAddr::Ip4(ipv4) => IpMod::from(Ipv4Mod {
+                        dst: Some(ipv4),
+                        ..Default::default()
+                    }),
+                    IpAddr::Ip6(ipv6) => IpMod::from(Ipv6Mod {
+                        dst: Some(ipv6),
+                        ..Default::default()
+                    }),
                 };
+                HdrTransform {
+                    name: NAT_NAME.to_string(),
+                    inner_ip: HeaderAction::Modify(ip, PhantomData),
+                    ..Default::default()
+                }
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        NAT_NAME
+    }
+}
+
+/// A port forwarding mapping, translating traffic to a range of ports
+/// on an external IP address to the same number of ports on a private
+/// IP, starting at `priv_port`.
+///
+/// Unlike [`Nat`] this only applies to inbound connections; the
+/// replies are translated by the flow this action creates.
+#[derive(Debug, Clone)]
+pub struct DNat {
+    external_ip: IpAddr,
+    proto: Protocol,
+    ext_ports: RangeInclusive<u16>,
+    priv_ip: IpAddr,
+    priv_port: u16,
+}
+
+impl DNat {
+    /// Create a new port forwarding mapping.
+    ///
+    /// The caller must make sure `priv_port` leaves room for all of
+    /// `ext_ports`.
+    pub fn new<T: ConcreteIpAddr>(
+        external_ip: T,
+        proto: Protocol,
+        ext_ports: RangeInclusive<u16>,
+        priv_ip: T,
+        priv_port: u16,
+    ) -> Self {
+        Self {
+            external_ip: external_ip.into(),
+            proto,
+            ext_ports,
+            priv_ip: priv_ip.into(),
+            priv_port,
+        }
+    }
+
+    pub fn external_ip(&self) -> IpAddr {
+        self.external_ip
+    }
+
+    pub fn proto(&self) -> Protocol {
+        self.proto
+    }
+
+    pub fn ext_ports(&self) -> RangeInclusive<u16> {
+        self.ext_ports.clone()
+    }
+
+    pub fn priv_ip(&self) -> IpAddr {
+        self.priv_ip
+    }
+
+    pub fn priv_port(&self) -> u16 {
+        self.priv_port
+    }
+}
+
+impl fmt::Display for DNat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {}:{}-{} => {}:{}",
+            self.proto,
+            self.external_ip,
+            self.ext_ports.start(),
+            self.ext_ports.end(),
+            self.priv_ip,
+            self.priv_port,
+        )
+    }
+}
+
+impl StatefulAction for DNat {
+    fn gen_desc(
+        &self,
+        flow_id: &InnerFlowId,
+        _pkt: &Packet<Parsed>,
+        _meta: &mut ActionMeta,
+    ) -> rule::GenDescResult {
+        // The rule's predicates limit the destination port to
+        // `ext_ports`, keep its offset into the range.
+        let offset = flow_id.dst_port.wrapping_sub(*self.ext_ports.start());
+        let desc = DNatDesc {
+            external_ip: self.external_ip,
+            ext_port: flow_id.dst_port,
+            priv_ip: self.priv_ip,
+            priv_port: self.priv_port.wrapping_add(offset),
+        };
+        Ok(AllowOrDeny::Allow(Arc::new(desc)))
+    }
+
+    fn implicit_preds(&self) -> (Vec<Predicate>, Vec<DataPredicate>) {
+        (vec![], vec![])
+    }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        Some(self)
+    }
+}
 
-                // XXX-EXT-IP hack to rewrite destination MAC adress
-                // from virtual gateway addr to the real gateway addr
-                // on the same subnet as the external IP.
-                if self.phys_gw_mac.is_some() {
-                    ht.inner_ether = HeaderAction::Modify(
-                        EtherMod {
-                            dst: Some(self.phys_gw_mac.unwrap()),
+/// An action descriptor for a port forwarding action.
+#[derive(Debug, Clone, Copy)]
+pub struct DNatDesc {
+    external_ip: IpAddr,
+    ext_port: u16,
+    priv_ip: IpAddr,
+    priv_port: u16,
+}
+
+pub const DNAT_NAME: &'static str = "DNAT";
+
+impl ActionDesc for DNatDesc {
+    fn gen_ht(&self, dir: Direction) -> HdrTransform {
+        match dir {
+            // Reply from the guest: restore the external address.
+            Direction::Out => {
+                let ip = match self.external_ip {
+                    IpAddr::Ip4(ipv4) => IpMod::from(Ipv4Mod {
+                        src: Some(ipv4),
+                        ..Default::default()
+                    }),
+                    IpAddr::Ip6(ipv6) => IpMod::from(Ipv6Mod {
+                        src: Some(ipv6),
+                        ..Default::default()
+                    }),
+                };
+                HdrTransform {
+                    name: DNAT_NAME.to_string(),
+                    inner_ip: HeaderAction::Modify(ip, PhantomData),
+                    inner_ulp: UlpHeaderAction::Modify(UlpMetaModify {
+                        generic: UlpGenericModify {
+                            src_port: Some(self.ext_port),
                             ..Default::default()
                         },
-                        core::marker::PhantomData,
-                    )
+                        ..Default::default()
+                    }),
+                    ..Default::default()
                 }
-
-                ht
             }
 
             Direction::In => {
@@ -155,8 +299,15 @@ impl ActionDesc for NatDesc {
                     }),
                 };
                 HdrTransform {
-                    name: NAT_NAME.to_string(),
+                    name: DNAT_NAME.to_string(),
                     inner_ip: HeaderAction::Modify(ip, PhantomData),
+                    inner_ulp: UlpHeaderAction::Modify(UlpMetaModify {
+                        generic: UlpGenericModify {
+                            dst_port: Some(self.priv_port),
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    }),
                     ..Default::default()
                 }
             }
@@ -164,7 +315,7 @@ impl ActionDesc for NatDesc {
     }
 
     fn name(&self) -> &str {
-        NAT_NAME
+        DNAT_NAME
     }
 }
 
@@ -194,8 +345,7 @@ mod test {
         let pub_ip = "52.10.128.69".parse().unwrap();
         let outside_ip = "76.76.21.21".parse().unwrap();
         let outside_port = 80;
-        let gw_mac = MacAddr::from([0x78, 0x23, 0xae, 0x5d, 0x4f, 0x0d]);
-        let nat = Nat::new(priv_ip, pub_ip, Some(gw_mac));
+        let nat = Nat::new(priv_ip, pub_ip);
         let mut ameta = ActionMeta::new();
 
         // ================================================================
@@ -243,7 +393,7 @@ mod test {
 
         let ether_meta = pmo.inner.ether;
         assert_eq!(ether_meta.src, priv_mac);
-        assert_eq!(ether_meta.dst, gw_mac);
+        assert_eq!(ether_meta.dst, dest_mac);
 
         let ip4_meta = match pmo.inner.ip.as_ref().unwrap() {
             IpMeta::Ip4(v) => v,
@@ -316,4 +466,115 @@ mod test {
         assert_eq!(tcp_meta.dst, priv_port);
         assert_eq!(tcp_meta.flags, 0);
     }
+
+    #[test]
+    fn dnat4_rewrite() {
+        use crate::engine::ether::EtherHdr;
+        use crate::engine::ether::EtherType;
+        use crate::engine::headers::IpMeta;
+        use crate::engine::headers::UlpMeta;
+        use crate::engine::ip4::Ipv4Addr;
+        use crate::engine::ip4::Ipv4Hdr;
+        use crate::engine::ip4::Ipv4Meta;
+        use crate::engine::tcp::TcpMeta;
+        use opte_api::MacAddr;
+
+        let priv_mac = MacAddr::from([0xA8, 0x40, 0x25, 0xF0, 0x00, 0x01]);
+        let gw_mac = MacAddr::from([0xA8, 0x40, 0x25, 0xFF, 0x77, 0x77]);
+        let priv_ip: Ipv4Addr = "10.0.0.220".parse().unwrap();
+        let pub_ip: Ipv4Addr = "52.10.128.69".parse().unwrap();
+        let outside_ip: Ipv4Addr = "76.76.21.21".parse().unwrap();
+        let outside_port = 44490;
+        let dnat = DNat::new(pub_ip, Protocol::TCP, 8080..=8089, priv_ip, 80);
+        let mut ameta = ActionMeta::new();
+
+        let build = |eth: EtherMeta, ip4: Ipv4Meta, tcp: TcpMeta| {
+            let mut ip4 = Ipv4Meta {
+                total_len: (Ipv4Hdr::BASE_SIZE + tcp.hdr_len()) as u16,
+                ..ip4
+            };
+            ip4.compute_hdr_csum();
+            let mut pkt = Packet::alloc_and_expand(128);
+            let mut wtr = pkt.seg0_wtr();
+            eth.emit(wtr.slice_mut(EtherHdr::SIZE).unwrap());
+            ip4.emit(wtr.slice_mut(ip4.hdr_len()).unwrap());
+            tcp.emit(wtr.slice_mut(tcp.hdr_len()).unwrap());
+            pkt.parse(In, GenericUlp {}).unwrap()
+        };
+
+        // ================================================================
+        // Verify the inbound connection is forwarded, keeping its
+        // offset into the port range.
+        // ================================================================
+        let mut pkt = build(
+            EtherMeta {
+                dst: priv_mac,
+                src: gw_mac,
+                ether_type: EtherType::Ipv4,
+            },
+            Ipv4Meta {
+                src: outside_ip,
+                dst: pub_ip,
+                proto: Protocol::TCP,
+                ..Default::default()
+            },
+            TcpMeta { src: outside_port, dst: 8082, ..Default::default() },
+        );
+        let flow_in = InnerFlowId::from(pkt.meta());
+        let desc = match dnat.gen_desc(&flow_in, &pkt, &mut ameta) {
+            Ok(AllowOrDeny::Allow(desc)) => desc,
+            _ => panic!("expected AllowOrDeny::Allow(desc) result"),
+        };
+
+        let mut pmi = pkt.meta_mut();
+        desc.gen_ht(Direction::In).run(&mut pmi).unwrap();
+
+        let ip4_meta = match pmi.inner.ip.as_ref().unwrap() {
+            IpMeta::Ip4(v) => v,
+            _ => panic!("expect Ipv4Meta"),
+        };
+        assert_eq!(ip4_meta.src, outside_ip);
+        assert_eq!(ip4_meta.dst, priv_ip);
+
+        let tcp_meta = match pmi.inner.ulp.as_ref().unwrap() {
+            UlpMeta::Tcp(v) => v,
+            _ => panic!("expect TcpMeta"),
+        };
+        assert_eq!(tcp_meta.src, outside_port);
+        assert_eq!(tcp_meta.dst, 82);
+
+        // ================================================================
+        // Verify the reply is translated back.
+        // ================================================================
+        let mut pkt = build(
+            EtherMeta {
+                dst: gw_mac,
+                src: priv_mac,
+                ether_type: EtherType::Ipv4,
+            },
+            Ipv4Meta {
+                src: priv_ip,
+                dst: outside_ip,
+                proto: Protocol::TCP,
+                ..Default::default()
+            },
+            TcpMeta { src: 82, dst: outside_port, ..Default::default() },
+        );
+        let mut pmo = pkt.meta_mut();
+        desc.gen_ht(Direction::Out).run(&mut pmo).unwrap();
+
+        let ip4_meta = match pmo.inner.ip.as_ref().unwrap() {
+            IpMeta::Ip4(v) => v,
+            _ => panic!("expect Ipv4Meta"),
+        };
+        assert_eq!(ip4_meta.src, pub_ip);
+        assert_eq!(ip4_meta.dst, outside_ip);
+
+        let tcp_meta = match pmo.inner.ulp.as_ref().unwrap() {
+            UlpMeta::Tcp(v) => v,
+            _ => panic!("expect TcpMeta"),
+        };
+        assert_eq!(tcp_meta.src, 8082);
+        assert_eq!(tcp_meta.dst, outside_port);
+    }
 }
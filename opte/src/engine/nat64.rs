@@ -0,0 +1,1053 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! Stateful NAT64, allowing IPv6-only guests to reach IPv4 hosts.
+//!
+//! The guest addresses an IPv4 host by its IPv4-embedded IPv6 address
+//! under the Well-Known Prefix `64:ff9b::/96`. Outbound packets are
+//! translated to IPv4, sourced from an IPv4 address and port taken
+//! from a [`NatPool`], and the replies are translated back to IPv6.
+//! The header translation follows [RFC 7915]; ICMPv6 is translated to
+//! ICMP for Echo Request/Reply messages, and the ICMP errors about the
+//! packets of a flow are translated along with the packet in error
+//! they carry. See [RFC 6146] for the stateful translation as a
+//! whole.
+//!
+//! [RFC 6146]: https://www.rfc-editor.org/rfc/rfc6146
+//! [RFC 7915]: https://www.rfc-editor.org/rfc/rfc7915
+
+use super::checksum::Checksum;
+use super::checksum::HeaderChecksum;
+use super::ether::EtherMod;
+use super::ether::EtherType;
+use super::headers::HeaderAction;
+use super::headers::IpMod;
+use super::headers::IpPush;
+use super::headers::UlpGenericModify;
+use super::headers::UlpHeaderAction;
+use super::headers::UlpMetaModify;
+use super::ip4::Ipv4Hdr;
+use super::ip4::Ipv4Meta;
+use super::ip4::Ipv4Push;
+use super::ip4::IPV4_HDR_LEN_MASK;
+use super::ip6::Ipv6Hdr;
+use super::ip6::Ipv6Meta;
+use super::ip6::Ipv6Push;
+use super::packet::BodyTransform;
+use super::packet::BodyTransformError;
+use super::packet::InnerFlowId;
+use super::packet::Packet;
+use super::packet::PacketMeta;
+use super::packet::Parsed;
+use super::port::meta::ActionMeta;
+use super::predicate::DataPredicate;
+use super::predicate::Predicate;
+use super::rule::ActionDesc;
+use super::rule::AllowOrDeny;
+use super::rule::FiniteResource;
+use super::rule::GenBtError;
+use super::rule::GenDescError;
+use super::rule::GenDescResult;
+use super::rule::HdrTransform;
+use super::rule::IcmpErrXlate;
+use super::rule::ResourceError;
+use super::rule::StatefulAction;
+use super::snat::NatFlow;
+use super::snat::NatPool;
+use super::snat::NatPoolEntry;
+use super::tcp::TcpHdr;
+use super::udp::UdpHdr;
+use core::any::Any;
+use core::fmt;
+use core::fmt::Display;
+use core::marker::PhantomData;
+use opte_api::Direction;
+use opte_api::IpAddr;
+use opte_api::Ipv4Addr;
+use opte_api::Ipv6Addr;
+use opte_api::Ipv6Cidr;
+use opte_api::Protocol;
+use smoltcp::wire::Icmpv4Message;
+use smoltcp::wire::Icmpv4Packet;
+use smoltcp::wire::Icmpv6Message;
+use smoltcp::wire::Icmpv6Packet;
+use smoltcp::wire::IpAddress;
+use smoltcp::wire::IpProtocol;
+
+cfg_if! {
+    if #[cfg(all(not(feature = "std"), not(test)))] {
+        use alloc::boxed::Box;
+        use alloc::string::ToString;
+        use alloc::sync::Arc;
+        use alloc::vec::Vec;
+    } else {
+        use std::boxed::Box;
+        use std::string::ToString;
+        use std::sync::Arc;
+        use std::vec::Vec;
+    }
+}
+
+pub const NAT64_NAME: &'static str = "NAT64";
+
+/// A NAT64 mapping of a private IPv6 address to an IPv4 address and
+/// the ports of a [`NatPool`] (only outbound connections).
+///
+/// The pool's mapping is keyed by the external IP itself, as the
+/// private IP is not of the pool's IP version.
+#[derive(Clone)]
+pub struct Nat64 {
+    priv_ip: Ipv6Addr,
+    external_ip: Ipv4Addr,
+    ip_pool: Arc<NatPool<Ipv4Addr>>,
+}
+
+impl Nat64 {
+    pub fn new(
+        priv_ip: Ipv6Addr,
+        external_ip: Ipv4Addr,
+        ip_pool: Arc<NatPool<Ipv4Addr>>,
+    ) -> Self {
+        Self { priv_ip, external_ip, ip_pool }
+    }
+
+    /// The pool from which this action obtains its ports.
+    pub fn pool(&self) -> &Arc<NatPool<Ipv4Addr>> {
+        &self.ip_pool
+    }
+
+    // Return the ICMPv6 Echo Identifier of an Echo Request, which
+    // takes the place of the source port.
+    fn echo_ident(pkt: &Packet<Parsed>) -> Result<u16, GenDescError> {
+        let body_segs = match pkt.body_segs() {
+            Some(body_segs) => body_segs,
+            None => {
+                return Err(GenDescError::Unexpected {
+                    msg: format!("No ICMPv6 body found"),
+                });
+            }
+        };
+
+        let icmp = match Icmpv6Packet::new_checked(body_segs[0]) {
+            Ok(icmp) => icmp,
+            Err(e) => {
+                return Err(GenDescError::Unexpected {
+                    msg: format!("Failed to parse ICMPv6: {}", e),
+                });
+            }
+        };
+
+        if icmp.msg_type() != Icmpv6Message::EchoRequest {
+            return Err(GenDescError::Unexpected {
+                msg: format!(
+                    "Expected ICMPv6 Echo Request, found: {}",
+                    icmp.msg_type()
+                ),
+            });
+        }
+
+        // Panic: We know this is safe because we make it here only if
+        // this ICMPv6 message is an Echo Request.
+        Ok(icmp.echo_ident())
+    }
+}
+
+impl Display for Nat64 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (pub_ip, ports) = self.ip_pool.mapping(self.external_ip).unwrap();
+        write!(
+            f,
+            "{} => {}:{}-{}",
+            Ipv6Cidr::NAT64_WKP,
+            pub_ip,
+            ports.start(),
+            ports.end()
+        )
+    }
+}
+
+impl StatefulAction for Nat64 {
+    fn gen_desc(
+        &self,
+        flow_id: &InnerFlowId,
+        pkt: &Packet<Parsed>,
+        _meta: &mut ActionMeta,
+    ) -> GenDescResult {
+        let remote_ip = match flow_id.dst_ip {
+            IpAddr::Ip6(ip6) => ip6.nat64_ipv4(),
+            IpAddr::Ip4(_) => None,
+        };
+
+        let remote_ip = match remote_ip {
+            Some(ip4) => ip4,
+            None => {
+                return Err(GenDescError::Unexpected {
+                    msg: format!(
+                        "NAT64 destination not in {}: {}",
+                        Ipv6Cidr::NAT64_WKP,
+                        flow_id.dst_ip
+                    ),
+                });
+            }
+        };
+
+        // Anything but TCP, UDP, and ICMPv6 Echo has no translation
+        // and is dropped.
+        let echo_ident = match flow_id.proto {
+            Protocol::TCP | Protocol::UDP => None,
+            Protocol::ICMPv6 => Some(Self::echo_ident(pkt)?),
+            _ => return Ok(AllowOrDeny::Deny),
+        };
+
+        let flow = NatFlow {
+            priv_ip: self.external_ip,
+            proto: flow_id.proto,
+            priv_port: flow_id.src_port,
+            remote_ip: IpAddr::Ip4(remote_ip),
+            remote_port: flow_id.dst_port,
+        };
+
+        match self.ip_pool.obtain(&flow) {
+            Ok(nat) => {
+                let desc = Nat64Desc {
+                    pool: self.ip_pool.clone(),
+                    priv_ip: self.priv_ip,
+                    remote_ip,
+                    nat,
+                    flow,
+                    echo_ident,
+                };
+
+                Ok(AllowOrDeny::Allow(Arc::new(desc)))
+            }
+
+            Err(ResourceError::Exhausted) => {
+                return Err(GenDescError::ResourceExhausted {
+                    name: "NAT64 Pool (exhausted)".to_string(),
+                });
+            }
+
+            Err(ResourceError::LimitReached) => {
+                return Err(GenDescError::ResourceExhausted {
+                    name: "NAT64 Pool (port limit)".to_string(),
+                });
+            }
+
+            Err(ResourceError::NoMatch(ip)) => {
+                return Err(GenDescError::Unexpected {
+                    msg: format!("NAT64 pool (no match: {})", ip),
+                });
+            }
+        }
+    }
+
+    fn implicit_preds(&self) -> (Vec<Predicate>, Vec<DataPredicate>) {
+        (vec![], vec![])
+    }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        Some(self)
+    }
+}
+
+#[derive(Clone)]
+pub struct Nat64Desc {
+    pool: Arc<NatPool<Ipv4Addr>>,
+    priv_ip: Ipv6Addr,
+    remote_ip: Ipv4Addr,
+    nat: NatPoolEntry<Ipv4Addr>,
+    flow: NatFlow<Ipv4Addr>,
+    // The ICMPv6 Echo Identifier, for an ICMPv6 Echo flow.
+    echo_ident: Option<u16>,
+}
+
+impl ActionDesc for Nat64Desc {
+    fn gen_ht(&self, dir: Direction) -> HdrTransform {
+        match dir {
+            // Outbound traffic is translated to IPv4, with the source
+            // IP and port taken from the pool.
+            Direction::Out => {
+                let proto = match self.flow.proto {
+                    Protocol::ICMPv6 => Protocol::ICMP,
+                    proto => proto,
+                };
+                let ip = IpMod::Translate(IpPush::from(Ipv4Push {
+                    src: self.nat.ip(),
+                    dst: self.remote_ip,
+                    proto,
+                }));
+
+                let ulp = match self.echo_ident {
+                    Some(_) => UlpHeaderAction::Ignore,
+                    None => UlpHeaderAction::Modify(UlpMetaModify {
+                        generic: UlpGenericModify {
+                            src_port: Some(self.nat.port()),
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    }),
+                };
+
+                HdrTransform {
+                    name: NAT64_NAME.to_string(),
+                    inner_ether: HeaderAction::Modify(
+                        EtherMod {
+                            ether_type: Some(EtherType::Ipv4),
+                            ..Default::default()
+                        },
+                        PhantomData,
+                    ),
+                    inner_ip: HeaderAction::Modify(ip, PhantomData),
+                    inner_ulp: ulp,
+                    ..Default::default()
+                }
+            }
+
+            // Inbound traffic is translated back to IPv6, with the
+            // source IP embedded in the NAT64 prefix and the
+            // destination IP and port mapped back to the private
+            // values that the guest expects to see.
+            //
+            // An IPv4 UDP datagram may omit its checksum, which IPv6
+            // requires. Such a checksum is computed from scratch when
+            // the new headers are emitted.
+            Direction::In => {
+                let ip = IpMod::Translate(IpPush::from(Ipv6Push {
+                    src: Ipv6Addr::from_nat64(self.remote_ip),
+                    dst: self.priv_ip,
+                    proto: self.flow.proto,
+                }));
+
+                let ulp = match self.echo_ident {
+                    Some(_) => UlpHeaderAction::Ignore,
+                    None => UlpHeaderAction::Modify(UlpMetaModify {
+                        generic: UlpGenericModify {
+                            dst_port: Some(self.flow.priv_port),
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    }),
+                };
+
+                HdrTransform {
+                    name: NAT64_NAME.to_string(),
+                    inner_ether: HeaderAction::Modify(
+                        EtherMod {
+                            ether_type: Some(EtherType::Ipv6),
+                            ..Default::default()
+                        },
+                        PhantomData,
+                    ),
+                    inner_ip: HeaderAction::Modify(ip, PhantomData),
+                    inner_ulp: ulp,
+                    ..Default::default()
+                }
+            }
+        }
+    }
+
+    // ICMPv6 and ICMP differ in their message types and checksum, so
+    // the Echo message must be translated along with its Identifier.
+    fn gen_bt(
+        &self,
+        _dir: Direction,
+        _meta: &PacketMeta,
+        _payload_segs: &[&[u8]],
+    ) -> Result<Option<Box<dyn BodyTransform>>, GenBtError> {
+        Ok(self.echo_ident.map(|ident| {
+            Box::new(Nat64IcmpEchoBt {
+                ident,
+                nat: self.nat,
+                priv_ip: self.priv_ip,
+                remote_ip: self.remote_ip,
+            }) as Box<dyn BodyTransform>
+        }))
+    }
+
+    // The ICMP errors of both protocols differ in their message types
+    // and carry the packet in error, which must be translated as well
+    // for the receiver to match the error to its flow.
+    fn gen_icmp_err(
+        &self,
+        dir: Direction,
+        meta: &PacketMeta,
+        body: &[u8],
+    ) -> Option<AllowOrDeny<IcmpErrXlate>> {
+        let xlate = match dir {
+            Direction::In => self.icmp_err_to_icmp6(meta, body),
+            Direction::Out => self.icmp6_err_to_icmp(meta, body),
+        };
+
+        // An error without translation is dropped, as per RFC 7915.
+        Some(match xlate {
+            Some(xlate) => AllowOrDeny::Allow(xlate),
+            None => AllowOrDeny::Deny,
+        })
+    }
+
+    fn name(&self) -> &str {
+        NAT64_NAME
+    }
+}
+
+impl Nat64Desc {
+    // Translate an inbound ICMP error about a packet of this flow to
+    // ICMPv6, as per RFC 7915 §4.2.
+    fn icmp_err_to_icmp6(
+        &self,
+        meta: &PacketMeta,
+        body: &[u8],
+    ) -> Option<IcmpErrXlate> {
+        let router = meta.inner_ip4()?.src;
+        let inner = body.get(ICMP_HDR_LEN..)?;
+        let (inner_ip4, inner_ip6) = self.inner_ip4_to_ip6(inner)?;
+        let (msg_type, code, rest) = icmp_err_to_icmp6_msg(
+            body[0],
+            body[1],
+            &body[4..ICMP_HDR_LEN],
+            inner_ip4.total_len,
+        )?;
+
+        let mut ulp = inner[usize::from(inner_ip4.hdr_len)..].to_vec();
+        match inner_ip6.proto {
+            Protocol::TCP | Protocol::UDP => {
+                let mut pseudo4 = [0; 12];
+                inner_ip4.pseudo_bytes(&mut pseudo4);
+                let mut pseudo6 = [0; 40];
+                inner_ip6.pseudo_bytes(&mut pseudo6);
+                let port = self.flow.priv_port.to_be_bytes();
+                let old_port: [u8; 2] = ulp.get(0..2)?.try_into().ok()?;
+                let csum_off = ulp_csum_offset(inner_ip6.proto);
+                update_csum(&mut ulp, csum_off, &pseudo4, &pseudo6);
+                update_csum(&mut ulp, csum_off, &old_port, &port);
+                ulp[0..2].copy_from_slice(&port);
+            }
+
+            // The Echo Request this flow sent out, of which the
+            // ICMPv6 checksum also covers the pseudo header.
+            Protocol::ICMPv6 => {
+                let ident = self.echo_ident?.to_be_bytes();
+                if ulp.len() < ICMP_HDR_LEN || ulp[0] != ICMP_ECHO_REQ {
+                    return None;
+                }
+
+                let mut pseudo6 = [0; 40];
+                inner_ip6.pseudo_bytes(&mut pseudo6);
+                let old = [ulp[0], ulp[1], ulp[4], ulp[5]];
+                let new = [ICMPV6_ECHO_REQ, ulp[1], ident[0], ident[1]];
+                update_csum(&mut ulp, 2, &[], &pseudo6);
+                update_csum(&mut ulp, 2, &old, &new);
+                ulp[0] = ICMPV6_ECHO_REQ;
+                ulp[4..6].copy_from_slice(&ident);
+            }
+
+            _ => return None,
+        }
+
+        let mut new_body = vec![msg_type, code, 0, 0];
+        new_body.extend_from_slice(&rest);
+        let mut ip6_bytes = [0; Ipv6Hdr::BASE_SIZE];
+        inner_ip6.emit(&mut ip6_bytes);
+        new_body.extend_from_slice(&ip6_bytes);
+        new_body.extend_from_slice(&ulp);
+        // The error must fit in the IPv6 minimum MTU.
+        new_body.truncate(IPV6_MIN_MTU - Ipv6Hdr::BASE_SIZE);
+
+        let src = Ipv6Addr::from_nat64(router);
+        Icmpv6Packet::new_unchecked(&mut new_body[..]).fill_checksum(
+            &IpAddress::Ipv6(src.into()),
+            &IpAddress::Ipv6(self.priv_ip.into()),
+        );
+
+        let ip = IpMod::Translate(IpPush::from(Ipv6Push {
+            src,
+            dst: self.priv_ip,
+            proto: Protocol::ICMPv6,
+        }));
+
+        Some(IcmpErrXlate {
+            ht: HdrTransform {
+                name: NAT64_NAME.to_string(),
+                inner_ether: HeaderAction::Modify(
+                    EtherMod {
+                        ether_type: Some(EtherType::Ipv6),
+                        ..Default::default()
+                    },
+                    PhantomData,
+                ),
+                inner_ip: HeaderAction::Modify(ip, PhantomData),
+                ..Default::default()
+            },
+            body: new_body,
+        })
+    }
+
+    // Translate an outbound ICMPv6 error about a packet of this flow
+    // to ICMP, as per RFC 7915 §5.2.
+    fn icmp6_err_to_icmp(
+        &self,
+        meta: &PacketMeta,
+        body: &[u8],
+    ) -> Option<IcmpErrXlate> {
+        let dst = meta.inner_ip6()?.dst.nat64_ipv4()?;
+        let inner = body.get(ICMP_HDR_LEN..)?;
+        let (inner_ip6, inner_ip4) = self.inner_ip6_to_ip4(inner)?;
+        let (msg_type, code, rest) =
+            icmp6_err_to_icmp_msg(body[0], body[1], &body[4..ICMP_HDR_LEN])?;
+
+        let mut ulp = inner[Ipv6Hdr::BASE_SIZE..].to_vec();
+        match inner_ip4.proto {
+            Protocol::TCP | Protocol::UDP => {
+                let mut pseudo6 = [0; 40];
+                inner_ip6.pseudo_bytes(&mut pseudo6);
+                let mut pseudo4 = [0; 12];
+                inner_ip4.pseudo_bytes(&mut pseudo4);
+                let port = self.nat.port().to_be_bytes();
+                let old_port: [u8; 2] = ulp.get(2..4)?.try_into().ok()?;
+                let csum_off = ulp_csum_offset(inner_ip4.proto);
+                update_csum(&mut ulp, csum_off, &pseudo6, &pseudo4);
+                update_csum(&mut ulp, csum_off, &old_port, &port);
+                ulp[2..4].copy_from_slice(&port);
+            }
+
+            // The Echo Reply this flow sent in, of which the ICMP
+            // checksum doesn't cover the pseudo header.
+            Protocol::ICMP => {
+                let ident = self.nat.port().to_be_bytes();
+                if ulp.len() < ICMP_HDR_LEN || ulp[0] != ICMPV6_ECHO_REPLY {
+                    return None;
+                }
+
+                let mut pseudo6 = [0; 40];
+                inner_ip6.pseudo_bytes(&mut pseudo6);
+                let old = [ulp[0], ulp[1], ulp[4], ulp[5]];
+                let new = [ICMP_ECHO_REPLY, ulp[1], ident[0], ident[1]];
+                update_csum(&mut ulp, 2, &pseudo6, &[]);
+                update_csum(&mut ulp, 2, &old, &new);
+                ulp[0] = ICMP_ECHO_REPLY;
+                ulp[4..6].copy_from_slice(&ident);
+            }
+
+            _ => return None,
+        }
+
+        let mut new_body = vec![msg_type, code, 0, 0];
+        new_body.extend_from_slice(&rest);
+        let mut ip4_bytes = [0; Ipv4Hdr::BASE_SIZE];
+        inner_ip4.emit(&mut ip4_bytes);
+        new_body.extend_from_slice(&ip4_bytes);
+        new_body.extend_from_slice(&ulp);
+        // The error must fit in the IPv4 minimum datagram size.
+        new_body.truncate(IPV4_MIN_DGRAM - Ipv4Hdr::BASE_SIZE);
+        Icmpv4Packet::new_unchecked(&mut new_body[..]).fill_checksum();
+
+        let ip = IpMod::Translate(IpPush::from(Ipv4Push {
+            src: self.nat.ip(),
+            dst,
+            proto: Protocol::ICMP,
+        }));
+
+        Some(IcmpErrXlate {
+            ht: HdrTransform {
+                name: NAT64_NAME.to_string(),
+                inner_ether: HeaderAction::Modify(
+                    EtherMod {
+                        ether_type: Some(EtherType::Ipv4),
+                        ..Default::default()
+                    },
+                    PhantomData,
+                ),
+                inner_ip: HeaderAction::Modify(ip, PhantomData),
+                ..Default::default()
+            },
+            body: new_body,
+        })
+    }
+
+    // Parse the IPv4 header of a packet in error, which this flow sent
+    // out, and translate it back to the IPv6 header the guest sent.
+    fn inner_ip4_to_ip6(&self, inner: &[u8]) -> Option<(Ipv4Meta, Ipv6Meta)> {
+        let ihl = *inner.get(0)? & IPV4_HDR_LEN_MASK;
+        let hdr_len = u16::from(ihl) * 4;
+        if usize::from(hdr_len) < Ipv4Hdr::BASE_SIZE
+            || inner.len() < usize::from(hdr_len)
+        {
+            return None;
+        }
+
+        let total_len = u16::from_be_bytes([inner[2], inner[3]]);
+        if total_len < hdr_len {
+            return None;
+        }
+
+        let ip4 = Ipv4Meta {
+            src: self.nat.ip(),
+            dst: self.remote_ip,
+            proto: Protocol::from(inner[9]),
+            ttl: inner[8],
+            ident: u16::from_be_bytes([inner[4], inner[5]]),
+            hdr_len,
+            total_len,
+            ..Default::default()
+        };
+
+        let proto = match ip4.proto {
+            Protocol::ICMP => Protocol::ICMPv6,
+            proto => proto,
+        };
+
+        let ip6 = Ipv6Meta {
+            src: self.priv_ip,
+            dst: Ipv6Addr::from_nat64(self.remote_ip),
+            next_hdr: IpProtocol::from(u8::from(proto)),
+            proto,
+            hop_limit: ip4.ttl,
+            pay_len: total_len - hdr_len,
+            ..Default::default()
+        };
+
+        Some((ip4, ip6))
+    }
+
+    // Parse the IPv6 header of a packet in error, which this flow sent
+    // in, and translate it back to the IPv4 header the remote sent.
+    fn inner_ip6_to_ip4(&self, inner: &[u8]) -> Option<(Ipv6Meta, Ipv4Meta)> {
+        let hdr = inner.get(0..Ipv6Hdr::BASE_SIZE)?;
+        let pay_len = u16::from_be_bytes([hdr[4], hdr[5]]);
+        let ip6 = Ipv6Meta {
+            src: Ipv6Addr::from_nat64(self.remote_ip),
+            dst: self.priv_ip,
+            next_hdr: IpProtocol::from(hdr[6]),
+            proto: Protocol::from(hdr[6]),
+            hop_limit: hdr[7],
+            pay_len,
+            ..Default::default()
+        };
+
+        let proto = match ip6.proto {
+            Protocol::ICMPv6 => Protocol::ICMP,
+            proto => proto,
+        };
+
+        let mut ip4 = Ipv4Meta {
+            src: self.remote_ip,
+            dst: self.nat.ip(),
+            proto,
+            ttl: ip6.hop_limit,
+            total_len: pay_len.checked_add(Ipv4Hdr::BASE_SIZE as u16)?,
+            ..Default::default()
+        };
+        ip4.compute_hdr_csum();
+
+        Some((ip6, ip4))
+    }
+}
+
+// The length of the ICMP/ICMPv6 header of an Echo or error message.
+// The latter carries the packet in error after its header.
+const ICMP_HDR_LEN: usize = 8;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQ: u8 = 8;
+const ICMPV6_ECHO_REQ: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+// The largest datagram every IPv4 host must accept (RFC 791), and
+// the IPv6 minimum MTU (RFC 8200). An ICMP error never exceeds them.
+const IPV4_MIN_DGRAM: usize = 576;
+const IPV6_MIN_MTU: usize = 1280;
+
+// The plateau table of RFC 1191 §7, for guessing the MTU of an
+// ICMP Fragmentation Needed message from a router which omits it.
+const MTU_PLATEAUS: [u16; 10] =
+    [32000, 17914, 8166, 4352, 2002, 1492, 1006, 508, 296, 68];
+
+// Map the type and code of an ICMP error to those of ICMPv6, along
+// with the rest of the header, as per RFC 7915 §4.2. Return `None`
+// for an error without translation.
+fn icmp_err_to_icmp6_msg(
+    msg_type: u8,
+    code: u8,
+    rest: &[u8],
+    inner_total_len: u16,
+) -> Option<(u8, u8, [u8; 4])> {
+    let (msg_type, code, rest) = match (msg_type, code) {
+        // Destination Unreachable
+        (3, 0 | 1 | 5..=8 | 11 | 12) => (1, 0, 0),
+        (3, 2) => (4, 1, 6),
+        (3, 3) => (1, 4, 0),
+        (3, 9 | 10 | 13 | 15) => (1, 1, 0),
+        // Fragmentation Needed, to Packet Too Big. A router which
+        // predates RFC 1191 leaves the MTU as zero.
+        (3, 4) => {
+            let mtu = match u16::from_be_bytes([rest[2], rest[3]]) {
+                0 => MTU_PLATEAUS
+                    .iter()
+                    .copied()
+                    .find(|mtu| *mtu < inner_total_len)
+                    .unwrap_or(MTU_PLATEAUS[MTU_PLATEAUS.len() - 1]),
+                mtu => mtu,
+            };
+            let diff = Ipv6Hdr::BASE_SIZE - Ipv4Hdr::BASE_SIZE;
+            (2, 0, u32::from(mtu) + diff as u32)
+        }
+        // Time Exceeded
+        (11, code) => (3, code, 0),
+        // Parameter Problem, with the pointer mapped to the IPv6
+        // header field.
+        (12, 0 | 2) => {
+            let ptr = match rest[0] {
+                0 => 0,
+                1 => 1,
+                2 | 3 => 4,
+                8 => 7,
+                9 => 6,
+                12..=15 => 8,
+                16..=19 => 24,
+                _ => return None,
+            };
+            (4, 0, ptr)
+        }
+        _ => return None,
+    };
+
+    Some((msg_type, code, rest.to_be_bytes()))
+}
+
+// Map the type and code of an ICMPv6 error to those of ICMP, along
+// with the rest of the header, as per RFC 7915 §5.2. Return `None`
+// for an error without translation.
+fn icmp6_err_to_icmp_msg(
+    msg_type: u8,
+    code: u8,
+    rest: &[u8],
+) -> Option<(u8, u8, [u8; 4])> {
+    let rest = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]);
+    let (msg_type, code, rest) = match (msg_type, code) {
+        // Destination Unreachable
+        (1, 0 | 2 | 3) => (3, 1, [0; 4]),
+        (1, 1) => (3, 10, [0; 4]),
+        (1, 4) => (3, 3, [0; 4]),
+        // Packet Too Big, to Fragmentation Needed.
+        (2, _) => {
+            let diff = (Ipv6Hdr::BASE_SIZE - Ipv4Hdr::BASE_SIZE) as u32;
+            let mtu = u16::try_from(rest.saturating_sub(diff))
+                .unwrap_or(u16::MAX)
+                .to_be_bytes();
+            (3, 4, [0, 0, mtu[0], mtu[1]])
+        }
+        // Time Exceeded
+        (3, code) => (11, code, [0; 4]),
+        // Parameter Problem, with the pointer mapped to the IPv4
+        // header field.
+        (4, 0) => {
+            let ptr = match rest {
+                0 => 0,
+                1 => 1,
+                4 | 5 => 2,
+                6 => 9,
+                7 => 8,
+                8..=23 => 12,
+                24..=39 => 16,
+                _ => return None,
+            };
+            (12, 0, [ptr, 0, 0, 0])
+        }
+        // Unrecognized Next Header, to Protocol Unreachable.
+        (4, 1) => (3, 2, [0; 4]),
+        _ => return None,
+    };
+
+    Some((msg_type, code, rest))
+}
+
+// The offset of the TCP or UDP checksum.
+fn ulp_csum_offset(proto: Protocol) -> usize {
+    match proto {
+        Protocol::TCP => TcpHdr::CSUM_BEGIN_OFFSET,
+        _ => UdpHdr::CSUM_BEGIN_OFFSET,
+    }
+}
+
+// Incrementally update the checksum at `csum_off` of `ulp` for the
+// replacement of the bytes `old` by `new`. The packet in error may
+// be truncated before the checksum, in which case there is nothing to
+// update.
+fn update_csum(ulp: &mut [u8], csum_off: usize, old: &[u8], new: &[u8]) {
+    if let Some(bytes) = ulp.get_mut(csum_off..csum_off + 2) {
+        let mut csum =
+            Checksum::from(HeaderChecksum::wrap([bytes[0], bytes[1]]));
+        csum.sub_bytes(old);
+        csum.add_bytes(new);
+        bytes.copy_from_slice(&HeaderChecksum::from(csum).bytes());
+    }
+}
+
+impl Drop for Nat64Desc {
+    fn drop(&mut self) {
+        self.pool.release(&self.flow, self.nat);
+    }
+}
+
+/// Translate ICMPv6 Echo/Reply messages to ICMP and back, treating
+/// the Identifier as a source port.
+#[derive(Clone)]
+pub struct Nat64IcmpEchoBt {
+    ident: u16,
+    nat: NatPoolEntry<Ipv4Addr>,
+    priv_ip: Ipv6Addr,
+    remote_ip: Ipv4Addr,
+}
+
+impl BodyTransform for Nat64IcmpEchoBt {
+    fn run(
+        &self,
+        dir: Direction,
+        body: &mut [&mut [u8]],
+    ) -> Result<(), BodyTransformError> {
+        // The Echo messages of both protocols share the same layout,
+        // so the message is translated in place.
+        match dir {
+            Direction::Out => {
+                let msg_type = {
+                    let icmp6 = Icmpv6Packet::new_checked(&*body[0])?;
+                    match icmp6.msg_type() {
+                        Icmpv6Message::EchoRequest => {
+                            Icmpv4Message::EchoRequest
+                        }
+                        Icmpv6Message::EchoReply => Icmpv4Message::EchoReply,
+                        msg_type => {
+                            return Err(BodyTransformError::UnexpectedBody(
+                                format!(
+                                    "Expected ICMPv6 Echo/Reply, found: {}",
+                                    msg_type
+                                ),
+                            ));
+                        }
+                    }
+                };
+
+                let mut icmp = Icmpv4Packet::new_checked(&mut *body[0])?;
+                icmp.set_msg_type(msg_type);
+                icmp.set_echo_ident(self.nat.port());
+                icmp.fill_checksum();
+            }
+
+            Direction::In => {
+                let msg_type = {
+                    let icmp4 = Icmpv4Packet::new_checked(&*body[0])?;
+                    match icmp4.msg_type() {
+                        Icmpv4Message::EchoRequest => {
+                            Icmpv6Message::EchoRequest
+                        }
+                        Icmpv4Message::EchoReply => Icmpv6Message::EchoReply,
+                        msg_type => {
+                            return Err(BodyTransformError::UnexpectedBody(
+                                format!(
+                                    "Expected ICMP Echo/Reply, found: {}",
+                                    msg_type
+                                ),
+                            ));
+                        }
+                    }
+                };
+
+                // The ICMPv6 checksum covers the IPv6 pseudo-header
+                // the packet ends up with.
+                let src = Ipv6Addr::from_nat64(self.remote_ip);
+                let mut icmp = Icmpv6Packet::new_checked(&mut *body[0])?;
+                icmp.set_msg_type(msg_type);
+                icmp.set_echo_ident(self.ident);
+                icmp.fill_checksum(
+                    &IpAddress::Ipv6(src.into()),
+                    &IpAddress::Ipv6(self.priv_ip.into()),
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for Nat64IcmpEchoBt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ICMPv6/ICMP Echo Ident/NAT64 {} <=> {}",
+            self.ident,
+            self.nat.port()
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::engine::ether::EtherHdr;
+    use crate::engine::ether::EtherMeta;
+    use crate::engine::headers::IpMeta;
+    use crate::engine::headers::UlpMeta;
+    use crate::engine::ip4::Ipv4Hdr;
+    use crate::engine::ip4::Ipv4Meta;
+    use crate::engine::ip6::Ipv6Meta;
+    use crate::engine::tcp::TcpMeta;
+    use crate::engine::GenericUlp;
+    use opte_api::MacAddr;
+    use opte_api::NatMapping;
+
+    #[test]
+    fn nat64_rewrite() {
+        let priv_mac = MacAddr::from([0xA8, 0x40, 0x25, 0xF0, 0x00, 0x01]);
+        let dest_mac = MacAddr::from([0xA8, 0x40, 0x25, 0xFF, 0x77, 0x77]);
+        let priv_ip: Ipv6Addr = "fd00::5".parse().unwrap();
+        let priv_port = 4999;
+        let pub_ip: Ipv4Addr = "52.10.128.69".parse().unwrap();
+        let outside_ip: Ipv4Addr = "76.76.21.21".parse().unwrap();
+        let outside_port = 80;
+        let pool = NatPool::new("test");
+        pool.add(pub_ip, pub_ip, 8765..=8765, NatMapping::PerFlow, None);
+        let nat64 = Nat64::new(priv_ip, pub_ip, Arc::new(pool));
+        let mut ameta = ActionMeta::new();
+
+        // ================================================================
+        // Build the packet metadata
+        // ================================================================
+        let tcp =
+            TcpMeta { src: priv_port, dst: outside_port, ..Default::default() };
+        let ip6 = Ipv6Meta {
+            src: priv_ip,
+            dst: Ipv6Addr::from_nat64(outside_ip),
+            next_hdr: IpProtocol::Tcp,
+            proto: Protocol::TCP,
+            hop_limit: 33,
+            pay_len: tcp.hdr_len() as u16,
+            ..Default::default()
+        };
+        let eth = EtherMeta {
+            ether_type: EtherType::Ipv6,
+            src: priv_mac,
+            dst: dest_mac,
+        };
+        let mut pkt = Packet::alloc_and_expand(128);
+        let mut wtr = pkt.seg0_wtr();
+        eth.emit(wtr.slice_mut(EtherHdr::SIZE).unwrap());
+        ip6.emit(wtr.slice_mut(ip6.hdr_len()).unwrap());
+        tcp.emit(wtr.slice_mut(tcp.hdr_len()).unwrap());
+        let mut pkt = pkt.parse(Direction::Out, GenericUlp {}).unwrap();
+
+        // ================================================================
+        // Verify descriptor generation.
+        // ================================================================
+        let flow_out = InnerFlowId::from(pkt.meta());
+        let desc = match nat64.gen_desc(&flow_out, &pkt, &mut ameta) {
+            Ok(AllowOrDeny::Allow(desc)) => desc,
+            _ => panic!("expected AllowOrDeny::Allow(desc) result"),
+        };
+
+        // ================================================================
+        // Verify outbound header transformation
+        // ================================================================
+        let out_ht = desc.gen_ht(Direction::Out);
+        let mut pmo = pkt.meta_mut();
+        out_ht.run(&mut pmo).unwrap();
+
+        assert_eq!(pmo.inner.ether.ether_type, EtherType::Ipv4);
+        let ip4_meta = match pmo.inner.ip.as_ref().unwrap() {
+            IpMeta::Ip4(v) => v,
+            _ => panic!("expect Ipv4Meta"),
+        };
+
+        assert_eq!(ip4_meta.src, pub_ip);
+        assert_eq!(ip4_meta.dst, outside_ip);
+        assert_eq!(ip4_meta.proto, Protocol::TCP);
+        assert_eq!(ip4_meta.ttl, 33);
+        assert_ne!(ip4_meta.csum, [0; 2]);
+
+        let tcp_meta = match pmo.inner.ulp.as_ref().unwrap() {
+            UlpMeta::Tcp(v) => v,
+            _ => panic!("expect TcpMeta"),
+        };
+
+        assert_eq!(tcp_meta.src, 8765);
+        assert_eq!(tcp_meta.dst, outside_port);
+
+        // ================================================================
+        // Verify inbound header transformation.
+        // ================================================================
+        let tcp =
+            TcpMeta { src: outside_port, dst: 8765, ..Default::default() };
+        let mut ip4 = Ipv4Meta {
+            src: outside_ip,
+            dst: pub_ip,
+            proto: Protocol::TCP,
+            ttl: 40,
+            total_len: (Ipv4Hdr::BASE_SIZE + tcp.hdr_len()) as u16,
+            ..Default::default()
+        };
+        ip4.compute_hdr_csum();
+        let eth = EtherMeta {
+            dst: priv_mac,
+            src: dest_mac,
+            ether_type: EtherType::Ipv4,
+        };
+        let mut pkt = Packet::alloc_and_expand(128);
+        let mut wtr = pkt.seg0_wtr();
+        eth.emit(wtr.slice_mut(EtherHdr::SIZE).unwrap());
+        ip4.emit(wtr.slice_mut(ip4.hdr_len()).unwrap());
+        tcp.emit(wtr.slice_mut(tcp.hdr_len()).unwrap());
+        let mut pkt = pkt.parse(Direction::Out, GenericUlp {}).unwrap();
+
+        let mut pmi = pkt.meta_mut();
+        let in_ht = desc.gen_ht(Direction::In);
+        in_ht.run(&mut pmi).unwrap();
+
+        assert_eq!(pmi.inner.ether.ether_type, EtherType::Ipv6);
+        let ip6_meta = match pmi.inner.ip.as_ref().unwrap() {
+            IpMeta::Ip6(v) => v,
+            _ => panic!("expect Ipv6Meta"),
+        };
+
+        assert_eq!(ip6_meta.src, Ipv6Addr::from_nat64(outside_ip));
+        assert_eq!(ip6_meta.dst, priv_ip);
+        assert_eq!(ip6_meta.proto, Protocol::TCP);
+        assert_eq!(ip6_meta.next_hdr, IpProtocol::Tcp);
+        assert_eq!(ip6_meta.hop_limit, 40);
+
+        let tcp_meta = match pmi.inner.ulp.as_ref().unwrap() {
+            UlpMeta::Tcp(v) => v,
+            _ => panic!("expect TcpMeta"),
+        };
+
+        assert_eq!(tcp_meta.src, outside_port);
+        assert_eq!(tcp_meta.dst, priv_port);
+    }
+
+    #[test]
+    fn icmp_err_msg_xlate() {
+        // Fragmentation Needed <=> Packet Too Big, with the MTU
+        // adjusted for the header sizes.
+        let ptb = icmp_err_to_icmp6_msg(3, 4, &[0, 0, 0x05, 0x78], 1500);
+        assert_eq!(ptb, Some((2, 0, 1420u32.to_be_bytes())));
+        let frag = icmp6_err_to_icmp_msg(2, 0, &1420u32.to_be_bytes());
+        assert_eq!(frag, Some((3, 4, [0, 0, 0x05, 0x78])));
+
+        // A router which omits the MTU has it guessed from the
+        // length of the packet in error.
+        let ptb = icmp_err_to_icmp6_msg(3, 4, &[0; 4], 1500);
+        assert_eq!(ptb, Some((2, 0, 1512u32.to_be_bytes())));
+
+        // The Parameter Problem pointer follows the header field.
+        let pp = icmp_err_to_icmp6_msg(12, 0, &[9, 0, 0, 0], 1500);
+        assert_eq!(pp, Some((4, 0, 6u32.to_be_bytes())));
+        let pp = icmp6_err_to_icmp_msg(4, 0, &24u32.to_be_bytes());
+        assert_eq!(pp, Some((12, 0, [16, 0, 0, 0])));
+
+        // Errors without translation are dropped.
+        assert_eq!(icmp_err_to_icmp6_msg(5, 1, &[0; 4], 1500), None);
+        assert_eq!(icmp_err_to_icmp6_msg(12, 0, &[4, 0, 0, 0], 1500), None);
+        assert_eq!(icmp6_err_to_icmp_msg(4, 2, &[0; 4]), None);
+    }
+}
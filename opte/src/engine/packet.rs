@@ -35,9 +35,15 @@ use super::ip4::Ipv4Hdr;
 use super::ip4::Ipv4HdrError;
 use super::ip4::Ipv4Meta;
 use super::ip4::Protocol;
+use super::ip4::IPV4_HDR_LEN_MASK;
+use super::ip4::IPV4_HDR_VER_SHIFT;
+use super::ip4::IPV4_VERSION;
+use super::ip6::Ipv6Addr;
 use super::ip6::Ipv6Hdr;
 use super::ip6::Ipv6HdrError;
 use super::ip6::Ipv6Meta;
+use super::ip6::IPV6_HDR_VSN_SHIFT;
+use super::ip6::IPV6_VERSION;
 use super::NetworkParser;
 use core::convert::TryInto;
 use core::fmt;
@@ -526,16 +532,20 @@ impl<S: PacketState> Drop for Packet<S> {
         if self.segs.len() != 0 {
             let head_mp = self.segs[0].mp;
             drop(&mut self.segs);
-            cfg_if! {
-                if #[cfg(all(not(feature = "std"), not(test)))] {
-                    // Safety: This is safe as long as the original
-                    // `mblk_t` came from a call to `allocb(9F)` (or
-                    // similar API).
-                    unsafe { ddi::freemsg(head_mp) };
-                } else {
-                    mock_freemsg(head_mp);
-                }
-            }
+            freemsg(head_mp);
+        }
+    }
+}
+
+// Free the `mblk_t` segment chain starting at `mp`.
+fn freemsg(mp: *mut mblk_t) {
+    cfg_if! {
+        if #[cfg(all(not(feature = "std"), not(test)))] {
+            // Safety: This is safe as long as the original `mblk_t`
+            // came from a call to `allocb(9F)` (or similar API).
+            unsafe { ddi::freemsg(mp) };
+        } else {
+            mock_freemsg(mp);
         }
     }
 }
@@ -834,19 +844,6 @@ impl From<smoltcp::Error> for BodyTransformError {
 }
 
 impl Packet<Parsed> {
-    /// XXX-EXT-IP This is here purely for the use by the external IP
-    /// hack.
-    pub fn write_dst_mac(&mut self, addr: MacAddr) {
-        self.state.meta.inner.ether.dst = addr.into();
-        let off = self.state.hdr_offsets.inner.ether.seg_pos;
-        let mut rdr = PacketReaderMut::new(&mut self.segs[0..1]);
-        // Unwrap: Assuming we didn't mess up calculating the offsets,
-        // we know we can seek forward by this amount.
-        rdr.seek(off).unwrap();
-        let mut ether = EtherHdr::parse(&mut rdr).unwrap();
-        ether.set_dst(addr);
-    }
-
     pub fn body_csum(&self) -> Option<Checksum> {
         self.state.body_csum
     }
@@ -937,9 +934,77 @@ impl Packet<Parsed> {
         Some(body_segs)
     }
 
+    /// Replace the body of this packet with `body`, which may differ
+    /// in length from the current body.
+    ///
+    /// Unlike a [`BodyTransform`], this may add or remove bytes; e.g.,
+    /// to translate an ICMP error along with the packet it carries.
+    /// The new body is written in place when it fits in the body's
+    /// segment, and to a new segment otherwise.
+    ///
+    /// # Errors
+    ///
+    /// Return [`BodyTransformError::Todo`] if the new body requires a
+    /// segment and the packet has no room for another one. In that
+    /// case the original body may have been partially removed.
+    pub fn replace_body(
+        &mut self,
+        body: &[u8],
+    ) -> Result<(), BodyTransformError> {
+        let seg_idx = self.state.body.seg_index;
+        let seg_offset = self.state.body.seg_offset;
+
+        // Free any segments past the one the body starts in.
+        if seg_idx + 1 < self.segs.len() {
+            let next_mp = self.segs[seg_idx + 1].mp;
+            // Safety: This pointer was handed to us by the system.
+            unsafe { (*self.segs[seg_idx].mp).b_cont = ptr::null_mut() };
+            self.segs.truncate(seg_idx + 1);
+            freemsg(next_mp);
+        }
+
+        self.segs[seg_idx].truncate(seg_offset);
+
+        if self.segs[seg_idx].expand_end(body.len()).is_ok() {
+            self.segs[seg_idx]
+                .slice_mut_unchecked(seg_offset, None)
+                .copy_from_slice(body);
+        } else {
+            if self.segs.is_full() {
+                return Err(BodyTransformError::Todo(format!(
+                    "no room for a new body segment: {}",
+                    self.segs.len()
+                )));
+            }
+
+            let mut seg = PacketSeg::alloc(body.len());
+            // Unwrap: We just allocated a segment large enough to
+            // hold the body.
+            seg.expand_end(body.len()).unwrap();
+            seg.slice_mut().copy_from_slice(body);
+            self.segs[seg_idx].link(&seg);
+            // Unwrap: We verified there is room above.
+            self.segs.push(seg).unwrap();
+
+            // The body now starts at the new segment.
+            self.state.body.seg_index += 1;
+            self.state.body.seg_offset = 0;
+        }
+
+        self.avail = self.segs.iter().map(|s| s.avail).sum();
+        self.state.len = self.segs.iter().map(|s| s.len).sum();
+        self.state.body.len = body.len();
+        self.state.body_csum =
+            self.state.body_csum.map(|_| Checksum::compute(body));
+        self.state.body_modified = true;
+        Ok(())
+    }
+
     /// Compute ULP and IP header checksum from scratch.
     ///
-    /// This should really only be used for testing.
+    /// Outside of testing, this should only be used when there is no
+    /// existing checksum to update; e.g., an IPv4 UDP datagram
+    /// without a checksum translated to IPv6.
     pub fn compute_checksums(&mut self) {
         match self.state.hdr_offsets.inner.ulp {
             Some(ulp_off) => {
@@ -1028,7 +1093,12 @@ impl Packet<Parsed> {
         // Then we can add the ULP header bytes to the checksum.
         csum.add_bytes(ulp);
         // Convert the checksum to its final form.
-        let ulp_csum = HeaderChecksum::from(csum).bytes();
+        let mut ulp_csum = HeaderChecksum::from(csum).bytes();
+        // A computed checksum of zero is transmitted as all ones, as
+        // zero means no checksum was provided.
+        if ulp_csum == [0; 2] {
+            ulp_csum = [0xFF; 2];
+        }
         // Update the UDP metadata.
         udp.csum = ulp_csum;
         // Update the UDP header bytes.
@@ -1119,6 +1189,85 @@ impl Packet<Parsed> {
         &self.state.flow
     }
 
+    /// If this packet is an ICMP error (ICMPv6 for an IPv6 packet),
+    /// return the flow ID of the packet in error that it carries, as
+    /// found in its body.
+    ///
+    /// The ports of the packet in error are zero for anything but TCP
+    /// and UDP, like those of any other flow. Return `None` if this
+    /// isn't an ICMP error, or if it's too short to carry the IP
+    /// header of the packet in error.
+    pub fn icmp_err_flow(&self) -> Option<InnerFlowId> {
+        let icmp_proto = self.state.flow.proto;
+        if icmp_proto != Protocol::ICMP && icmp_proto != Protocol::ICMPv6 {
+            return None;
+        }
+
+        let msg_type = self.get_body_rdr().slice(1).ok()?[0];
+        let is_err = match icmp_proto {
+            // Destination Unreachable, Source Quench, Redirect, Time
+            // Exceeded, and Parameter Problem.
+            Protocol::ICMP => matches!(msg_type, 3 | 4 | 5 | 11 | 12),
+            // All ICMPv6 error messages have a type below 128.
+            _ => msg_type < 128,
+        };
+
+        if !is_err {
+            return None;
+        }
+
+        // The packet in error starts after the 8-byte ICMP header.
+        let body = self.get_body_rdr().copy_remaining();
+        let inner = body.get(8..)?;
+
+        let (proto, src_ip, dst_ip, ulp) = match icmp_proto {
+            Protocol::ICMP => {
+                let ihl = usize::from(*inner.get(0)? & IPV4_HDR_LEN_MASK) * 4;
+                let version = inner[0] >> IPV4_HDR_VER_SHIFT;
+                if version != IPV4_VERSION || ihl < Ipv4Hdr::BASE_SIZE {
+                    return None;
+                }
+
+                let src: [u8; 4] = inner.get(12..16)?.try_into().ok()?;
+                let dst: [u8; 4] = inner.get(16..20)?.try_into().ok()?;
+                (
+                    Protocol::from(inner[9]),
+                    IpAddr::Ip4(Ipv4Addr::from(src)),
+                    IpAddr::Ip4(Ipv4Addr::from(dst)),
+                    inner.get(ihl..),
+                )
+            }
+
+            _ => {
+                if *inner.get(0)? >> IPV6_HDR_VSN_SHIFT != IPV6_VERSION {
+                    return None;
+                }
+
+                let src: [u8; 16] = inner.get(8..24)?.try_into().ok()?;
+                let dst: [u8; 16] = inner.get(24..40)?.try_into().ok()?;
+                (
+                    Protocol::from(inner[6]),
+                    IpAddr::Ip6(Ipv6Addr::from(src)),
+                    IpAddr::Ip6(Ipv6Addr::from(dst)),
+                    inner.get(Ipv6Hdr::BASE_SIZE..),
+                )
+            }
+        };
+
+        // The packet in error carries at least the first 8 bytes of
+        // its ULP, which covers the TCP and UDP ports.
+        let (src_port, dst_port) = match (proto, ulp) {
+            (Protocol::TCP | Protocol::UDP, Some(ulp)) if ulp.len() >= 4 => (
+                u16::from_be_bytes([ulp[0], ulp[1]]),
+                u16::from_be_bytes([ulp[2], ulp[3]]),
+            ),
+            (Protocol::TCP | Protocol::UDP, _) => return None,
+            _ => (0, 0),
+        };
+
+        Some(InnerFlowId { proto, src_ip, src_port, dst_ip, dst_port })
+    }
+
     pub fn get_body_rdr(&self) -> PacketReader {
         let mut rdr = PacketReader::new(&self.segs);
         // XXX While this works for now it might be nice to have a
@@ -1287,6 +1436,12 @@ impl Packet<Parsed> {
         let inner_ip_csum = innerm.has_ip_csum();
         let inner_ulp_csum = innerm.has_ulp_csum();
 
+        // An IPv4 UDP datagram may omit its checksum, but IPv6
+        // requires one. If such a datagram is being translated to
+        // IPv6 (NAT64), then the checksum must be computed from
+        // scratch once the new headers are in place.
+        let compute_udp6_csum = !inner_ulp_csum && self.is_udp4_to_udp6();
+
         // The length of the new headers.
         let new_hdr_len = self.state.meta.hdr_len();
         // The total length of the new packet, including headers and
@@ -1322,9 +1477,37 @@ impl Packet<Parsed> {
 
         // Update the ULP and IP header checksums.
         self.update_checksums(inner_ip_csum, inner_ulp_csum);
+
+        if compute_udp6_csum {
+            self.compute_checksums();
+        }
+
         Ok(())
     }
 
+    /// Is the inner UDP datagram being translated from IPv4 to IPv6?
+    ///
+    /// This must be called before the new headers are emitted, as it
+    /// inspects the original inner IP header.
+    fn is_udp4_to_udp6(&self) -> bool {
+        let inner = &self.state.meta.inner;
+
+        if !matches!(inner.ip, Some(IpMeta::Ip6(_)))
+            || !matches!(inner.ulp, Some(UlpMeta::Udp(_)))
+        {
+            return false;
+        }
+
+        match self.state.hdr_offsets.inner.ip {
+            Some(ip_off) => {
+                let bytes = self.segs[ip_off.seg_idx].slice();
+                bytes[ip_off.seg_pos] >> 4 == 4
+            }
+
+            None => false,
+        }
+    }
+
     fn emit_outer_headers<'a>(
         wtr: &mut PacketSegWriter,
         meta: &mut OuterMeta,
@@ -1647,6 +1830,20 @@ impl PacketSeg {
         PacketSegWriter::new(self, 0, self.len).unwrap()
     }
 
+    /// Shrink the writable/readable area to `len` bytes by pulling
+    /// `b_wptr` back; effectively removing bytes from the end of the
+    /// packet. A `len` past the end of the segment has no effect.
+    fn truncate(&mut self, len: usize) {
+        if len >= self.len {
+            return;
+        }
+
+        unsafe {
+            (*self.mp).b_wptr = (*self.mp).b_rptr.add(len);
+        }
+        self.len = len;
+    }
+
     pub fn len(&self) -> usize {
         self.len
     }
@@ -6,18 +6,28 @@
 
 /// A virtual switch port.
 use self::meta::ActionMeta;
+use super::capture::CaptureConfig;
+use super::capture::CapturePoint;
+use super::capture::CaptureRing;
+use super::flow_record::FlowRecorder;
+use super::flow_record::FlowVerdict;
 use super::flow_table::Dump;
 use super::flow_table::FlowTable;
+use super::headers::UlpMeta;
 use super::ioctl;
 use super::ioctl::TcpFlowEntryDump;
 use super::ioctl::TcpFlowStateDump;
 use super::ioctl::UftEntryDump;
 use super::layer;
+use super::layer::DefaultAction;
 use super::layer::Layer;
 use super::layer::LayerError;
 use super::layer::LayerResult;
 use super::layer::LayerStatsSnap;
 use super::layer::RuleId;
+use super::layer::RuleSync;
+use super::mirror::Mirror;
+use super::mirror::MirroredPacket;
 use super::packet::BodyTransform;
 use super::packet::BodyTransformError;
 use super::packet::Initialized;
@@ -48,6 +58,7 @@ use core::fmt::Display;
 use core::num::NonZeroU32;
 use core::result;
 use core::str::FromStr;
+use core::sync::atomic::AtomicBool;
 use core::sync::atomic::AtomicU64;
 use core::sync::atomic::Ordering::SeqCst;
 use kstat_macro::KStatProvider;
@@ -75,6 +86,10 @@ cfg_if! {
 
 pub type Result<T> = result::Result<T, OpteError>;
 
+/// The maximum number of mirrored packets a port holds while waiting
+/// for the consumer to take them.
+pub const MIRROR_QUEUE_MAX: usize = 64;
+
 #[derive(Debug)]
 pub enum ProcessError {
     BadState(PortState),
@@ -267,6 +282,9 @@ impl PortBuilder {
             uft_in: FlowTable::new(&self.name, "uft_in", uft_limit, None),
             uft_out: FlowTable::new(&self.name, "uft_out", uft_limit, None),
             tcp_flows: FlowTable::new(&self.name, "tcp_flows", tcp_limit, None),
+            capture: None,
+            mirror_queue: Vec::new(),
+            flow_records: FlowRecorder::new(&self.name, uft_limit),
         };
 
         Ok(Port {
@@ -276,6 +294,7 @@ impl PortBuilder {
             ectx: self.ectx,
             epoch: AtomicU64::new(1),
             net,
+            mirror_pending: AtomicBool::new(false),
             data: KMutex::new(data, KMutexType::Driver),
         })
     }
@@ -303,8 +322,8 @@ impl PortBuilder {
                 name: layer.name().to_string(),
                 rules_in: layer.num_rules(Direction::In),
                 rules_out: layer.num_rules(Direction::Out),
-                default_in: layer.default_action(Direction::In).to_string(),
-                default_out: layer.default_action(Direction::Out).to_string(),
+                default_in: layer.default_action_desc(Direction::In),
+                default_out: layer.default_action_desc(Direction::Out),
                 flows: layer.num_flows(),
             });
         }
@@ -487,7 +506,20 @@ impl<Id> Display for UftEntry<Id> {
             .map(|bt| bt.to_string())
             .collect::<Vec<String>>()
             .join(",");
-        write!(f, "hdr: {hdr}, body: {body}")
+        write!(f, "hdr: {hdr}, body: {body}")?;
+
+        if !self.xforms.mirror.is_empty() {
+            let mirror = self
+                .xforms
+                .mirror
+                .iter()
+                .map(|m| m.name().to_string())
+                .collect::<Vec<String>>()
+                .join(",");
+            write!(f, ", mirror: {mirror}")?;
+        }
+
+        Ok(())
     }
 }
 
@@ -534,6 +566,17 @@ struct PortStats {
     /// and resulted in rule processing.
     in_uft_miss: KStatU64,
 
+    /// The number of mirrored packets queued for delivery.
+    mirror: KStatU64,
+
+    /// The number of packets which could not be mirrored because the
+    /// copy could not be parsed or encapsulated.
+    mirror_err: KStatU64,
+
+    /// The number of mirrored packets dropped because the mirror
+    /// queue was full.
+    mirror_queue_full: KStatU64,
+
     /// The number of outbound packets marked as
     /// [`ProcessResult::Bypass`].
     out_bypass: KStatU64,
@@ -585,6 +628,13 @@ struct PortData {
     // that we know which inbound UFT/FT entries to retire upon
     // connection termination.
     tcp_flows: FlowTable<TcpFlowEntryState>,
+    // The optional packet capture ring.
+    capture: Option<CaptureRing>,
+    // Mirrored packets waiting to be taken by the consumer.
+    mirror_queue: Vec<MirroredPacket>,
+    // The accounting of the port's flows, and their records waiting
+    // to be retrieved by the consumer.
+    flow_records: FlowRecorder,
 }
 
 pub struct Port<N: crate::engine::NetworkImpl> {
@@ -596,6 +646,9 @@ pub struct Port<N: crate::engine::NetworkImpl> {
     name_cstr: CString,
     mac: MacAddr,
     net: N,
+    // Set when the mirror queue is non-empty, allowing the consumer to
+    // skip taking the lock in the common case.
+    mirror_pending: AtomicBool,
     data: KMutex<PortData>,
 }
 
@@ -816,6 +869,24 @@ impl<N: NetworkImpl> Port<N> {
         Err(OpteError::LayerNotFound(name.to_string()))
     }
 
+    /// Dump the rule log of the layer named `name`, if such a layer
+    /// exists.
+    ///
+    /// # States
+    ///
+    /// This command is valid for any [`PortState`].
+    pub fn dump_rule_log(&self, name: &str) -> Result<ioctl::DumpRuleLogResp> {
+        let data = self.data.lock();
+
+        for l in &data.layers {
+            if l.name() == name {
+                return Ok(l.dump_rule_log());
+            }
+        }
+
+        Err(OpteError::LayerNotFound(name.to_string()))
+    }
+
     /// Dump the contents of the TCP flow connection tracking table.
     ///
     /// # States
@@ -901,6 +972,7 @@ impl<N: NetworkImpl> Port<N> {
         }
         let _ = data.uft_in.expire_flows(now, |_| FLOW_ID_DEFAULT.clone());
         let _ = data.uft_out.expire_flows(now, |_| FLOW_ID_DEFAULT.clone());
+        data.flow_records.expire(now);
         Ok(())
     }
 
@@ -934,6 +1006,28 @@ impl<N: NetworkImpl> Port<N> {
         Err(OpteError::LayerNotFound(layer_name.to_string()))
     }
 
+    /// Return a copy of the rules of the given layer in the given
+    /// direction, along with their IDs.
+    ///
+    /// # States
+    ///
+    /// This command is valid for any [`PortState`].
+    pub fn layer_rules(
+        &self,
+        layer_name: &str,
+        dir: Direction,
+    ) -> Result<Vec<(RuleId, Rule<Finalized>)>> {
+        let data = self.data.lock();
+
+        for layer in &data.layers {
+            if layer.name() == layer_name {
+                return Ok(layer.rules(dir));
+            }
+        }
+
+        Err(OpteError::LayerNotFound(layer_name.to_string()))
+    }
+
     /// Return a reference to the [`Action`] defined in the given
     /// [`Layer`] at the given index. If the layer does not exist, or
     /// has no action at that index, then `None` is returned.
@@ -952,6 +1046,41 @@ impl<N: NetworkImpl> Port<N> {
         None
     }
 
+    /// Return a page of at most `max_pkts` packets from the port's
+    /// capture ring, starting at the packet numbered `start`. See
+    /// [`ioctl::DumpCaptureReq`].
+    ///
+    /// # Errors
+    ///
+    /// If capture is not enabled on this port, an error is returned.
+    ///
+    /// # States
+    ///
+    /// This command is valid for any [`PortState`].
+    pub fn dump_capture(
+        &self,
+        start: u64,
+        max_pkts: u32,
+    ) -> Result<ioctl::DumpCaptureResp> {
+        match self.data.lock().capture.as_ref() {
+            Some(ring) => Ok(ring.dump(start, max_pkts)),
+            None => Err(OpteError::CaptureNotEnabled(self.name.clone())),
+        }
+    }
+
+    /// Return the port's queued flow records, after removing those
+    /// acknowledged by `ack`. See [`ioctl::DumpFlowRecordsReq`].
+    ///
+    /// # States
+    ///
+    /// This command is valid for any [`PortState`].
+    pub fn dump_flow_records(
+        &self,
+        ack: Option<u64>,
+    ) -> ioctl::DumpFlowRecordsResp {
+        self.data.lock().flow_records.dump(ack)
+    }
+
     /// Return a snapshot of the layer-level statistics.
     ///
     /// # States
@@ -983,8 +1112,8 @@ impl<N: NetworkImpl> Port<N> {
                 name: layer.name().to_string(),
                 rules_in: layer.num_rules(Direction::In),
                 rules_out: layer.num_rules(Direction::Out),
-                default_in: layer.default_action(Direction::In).to_string(),
-                default_out: layer.default_action(Direction::Out).to_string(),
+                default_in: layer.default_action_desc(Direction::In),
+                default_out: layer.default_action_desc(Direction::Out),
                 flows: layer.num_flows(),
             });
         }
@@ -992,6 +1121,25 @@ impl<N: NetworkImpl> Port<N> {
         ioctl::ListLayersResp { layers: tmp }
     }
 
+    /// Take all mirrored packets waiting for delivery.
+    ///
+    /// The consumer should call this after each call to
+    /// [`Port::process()`] and deliver each packet to its mirror's
+    /// target.
+    ///
+    /// # States
+    ///
+    /// This command is valid for any [`PortState`].
+    pub fn take_mirrored(&self) -> Vec<MirroredPacket> {
+        if !self.mirror_pending.load(SeqCst) {
+            return Vec::new();
+        }
+
+        let mut data = self.data.lock();
+        self.mirror_pending.store(false, SeqCst);
+        core::mem::take(&mut data.mirror_queue)
+    }
+
     /// Return the MAC address of this port.
     pub fn mac_addr(&self) -> MacAddr {
         self.mac
@@ -1013,10 +1161,41 @@ impl<N: NetworkImpl> Port<N> {
     ///
     /// This command is valid only for [`PortState::Running`].
     pub fn process(
+        &self,
+        dir: Direction,
+        pkt: &mut Packet<Parsed>,
+        ameta: ActionMeta,
+    ) -> result::Result<ProcessResult, ProcessError> {
+        self.process_pkt(dir, pkt, ameta, false)
+    }
+
+    /// Process a packet which this port has already processed and
+    /// dropped, e.g. an outbound packet the consumer held until the
+    /// V2P mapping it lacked became available.
+    ///
+    /// The packet was captured and counted in its flow record when
+    /// first processed. It is not captured again before processing,
+    /// nor when dropped again, and it does not add to the counters
+    /// of its flow record.
+    ///
+    /// # States
+    ///
+    /// This command is valid only for [`PortState::Running`].
+    pub fn reprocess(
+        &self,
+        dir: Direction,
+        pkt: &mut Packet<Parsed>,
+        ameta: ActionMeta,
+    ) -> result::Result<ProcessResult, ProcessError> {
+        self.process_pkt(dir, pkt, ameta, true)
+    }
+
+    fn process_pkt(
         &self,
         dir: Direction,
         pkt: &mut Packet<Parsed>,
         mut ameta: ActionMeta,
+        replay: bool,
     ) -> result::Result<ProcessResult, ProcessError> {
         let flow_before = pkt.flow().clone();
         let epoch = self.epoch.load(SeqCst);
@@ -1025,19 +1204,48 @@ impl<N: NetworkImpl> Port<N> {
             .map_err(|_| ProcessError::BadState(data.state))?;
 
         self.port_process_entry_probe(dir, &flow_before, epoch, &pkt);
+        let (pre_point, post_point) = match dir {
+            Direction::Out => (CapturePoint::Guest, CapturePoint::Underlay),
+            Direction::In => (CapturePoint::Underlay, CapturePoint::Guest),
+        };
+        let capturing = match data.capture.as_mut() {
+            Some(ring) => {
+                if !replay {
+                    ring.record(pre_point, dir, pkt, None);
+                }
+                true
+            }
+
+            None => false,
+        };
+
+        let mut mirrors = Vec::new();
         let res = match dir {
             Direction::Out => {
-                let res = self.process_out(&mut data, epoch, pkt, &mut ameta);
+                let res = self.process_out(
+                    &mut data,
+                    epoch,
+                    pkt,
+                    &mut ameta,
+                    &mut mirrors,
+                );
                 Self::update_stats_out(&mut data.stats.vals, &res);
                 res
             }
 
             Direction::In => {
-                let res = self.process_in(&mut data, epoch, pkt, &mut ameta);
+                let res = self.process_in(
+                    &mut data,
+                    epoch,
+                    pkt,
+                    &mut ameta,
+                    &mut mirrors,
+                );
                 Self::update_stats_in(&mut data.stats.vals, &res);
                 res
             }
         };
+        Self::record_flow(&mut data, dir, &flow_before, pkt, &res, replay);
         drop(data);
 
         // Emit the updated headers if the packet was modified as part
@@ -1046,10 +1254,91 @@ impl<N: NetworkImpl> Port<N> {
             pkt.emit_new_headers()?;
         }
 
+        // Make a copy for each of the flow's sampled mirrors. This is
+        // done outside of the lock as it involves copying the packet.
+        let mut mirrored = Vec::new();
+        let mut mirror_errs = 0;
+        if let Ok(ProcessResult::Modified) = res {
+            for mirror in mirrors.iter().filter(|m| m.sample()) {
+                match mirror.gen_packet(pkt) {
+                    Some(mpkt) => mirrored.push(mpkt),
+                    None => mirror_errs += 1,
+                }
+            }
+        }
+
+        // The post-processing capture must wait until the new headers
+        // are emitted, so the lock is reacquired only if a capture
+        // was active at the start of processing or there are mirrored
+        // packets to queue.
+        if capturing || !mirrored.is_empty() || mirror_errs > 0 {
+            let mut data = self.data.lock();
+
+            if let Some(ring) = data.capture.as_mut() {
+                match &res {
+                    Ok(ProcessResult::Modified) => {
+                        ring.record(post_point, dir, pkt, None);
+                    }
+
+                    Ok(ProcessResult::Drop { reason }) if !replay => {
+                        ring.record(CapturePoint::Drop, dir, pkt, Some(reason));
+                    }
+
+                    _ => (),
+                }
+            }
+
+            data.stats.vals.mirror_err += mirror_errs;
+            self.queue_mirrored(&mut data, mirrored);
+        }
+
         self.port_process_return_probe(dir, &flow_before, epoch, &pkt, &res);
         res
     }
 
+    /// Remove the flow pairs of the given layer for which `f` returns
+    /// true, given the flow ID of the side in direction `dir`. Return
+    /// the number of pairs removed.
+    ///
+    /// This is for when the state a layer's rules depend on changes
+    /// outside of the rules themselves. The port's epoch is moved
+    /// forward, even if no pair is removed, as a UFT entry may have
+    /// been computed from that state; UFT entries are then
+    /// recomputed lazily on the next packet to arrive.
+    ///
+    /// # Errors
+    ///
+    /// If the layer does not exist, an error is returned.
+    ///
+    /// # States
+    ///
+    /// This command is valid for the following states:
+    ///
+    /// * [`PortState::Ready`]
+    /// * [`PortState::Running`]
+    pub fn remove_flows<F>(
+        &self,
+        layer_name: &str,
+        dir: Direction,
+        f: F,
+    ) -> Result<u32>
+    where
+        F: Fn(&InnerFlowId) -> bool,
+    {
+        let mut data = self.data.lock();
+        check_state!(data.state, [PortState::Ready, PortState::Running])?;
+
+        for layer in &mut data.layers {
+            if layer.name() == layer_name {
+                let removed = layer.remove_flows(dir, f);
+                self.epoch.fetch_add(1, SeqCst);
+                return Ok(removed);
+            }
+        }
+
+        Err(OpteError::LayerNotFound(layer_name.to_string()))
+    }
+
     /// Remove the rule identified by the `dir`, `layer_name`, `id`
     /// combination, if such a rule exists.
     ///
@@ -1098,6 +1387,63 @@ impl<N: NetworkImpl> Port<N> {
         Err(OpteError::LayerNotFound(layer_name.to_string()))
     }
 
+    /// Enable packet capture on this port with the given
+    /// configuration, or disable it when `cfg` is `None`.
+    ///
+    /// Any previously captured packets are discarded.
+    ///
+    /// # States
+    ///
+    /// This command is valid for any [`PortState`].
+    pub fn set_capture(&self, cfg: Option<CaptureConfig>) {
+        self.data.lock().capture = cfg.map(CaptureRing::new);
+    }
+
+    /// Set the [`DefaultAction`] of the given layer and direction.
+    ///
+    /// The layer's flows are cleared and the port's epoch is moved
+    /// forward; flows processed after this call will have their UFT
+    /// entry invalidated and recomputed lazily on the next packet to
+    /// arrive.
+    ///
+    /// # Errors
+    ///
+    /// * [`OpteError::BadState`]: The port is not in one of the
+    ///   states listed below.
+    ///
+    /// * [`OpteError::LayerNotFound`]: There is no layer named
+    ///   `layer_name`.
+    ///
+    /// * [`OpteError::ActionNotFound`]: The action is
+    ///   [`DefaultAction::Named`] and names an action which the layer
+    ///   does not have.
+    ///
+    /// # States
+    ///
+    /// This command is valid for the following states:
+    ///
+    /// * [`PortState::Ready`]
+    /// * [`PortState::Running`]
+    pub fn set_default_action(
+        &self,
+        layer_name: &str,
+        dir: Direction,
+        action: DefaultAction,
+    ) -> Result<()> {
+        let mut data = self.data.lock();
+        check_state!(data.state, [PortState::Ready, PortState::Running])?;
+
+        for layer in &mut data.layers {
+            if layer.name() == layer_name {
+                layer.set_default_action(dir, action)?;
+                self.epoch.fetch_add(1, SeqCst);
+                return Ok(());
+            }
+        }
+
+        Err(OpteError::LayerNotFound(layer_name.to_string()))
+    }
+
     /// For the given layer, set both the inbound and outbound rules
     /// atomically.
     ///
@@ -1138,6 +1484,117 @@ impl<N: NetworkImpl> Port<N> {
         Err(OpteError::LayerNotFound(layer_name.to_string()))
     }
 
+    /// For the given layer, replace the inbound and outbound rules
+    /// atomically, keeping the rules present in both the current and
+    /// new sets.
+    ///
+    /// This is the incremental version of [`Self::set_rules()`]: only
+    /// the layer flows which may be matched by an added or removed
+    /// rule are invalidated. If any rule was added or removed, the
+    /// port's epoch is moved forward; UFT entries are then recomputed
+    /// lazily on the next packet, reusing the layer flows which were
+    /// kept. Otherwise, the epoch is left as is and no flow is
+    /// touched.
+    ///
+    /// # Errors
+    ///
+    /// If the layer does not exist, an error is returned.
+    ///
+    /// # States
+    ///
+    /// This command is valid for the following states:
+    ///
+    /// * [`PortState::Ready`]
+    /// * [`PortState::Running`]
+    pub fn sync_rules(
+        &self,
+        layer_name: &str,
+        in_rules: Vec<Rule<Finalized>>,
+        out_rules: Vec<Rule<Finalized>>,
+    ) -> Result<RuleSync> {
+        let mut data = self.data.lock();
+        check_state!(data.state, [PortState::Ready, PortState::Running])?;
+
+        for layer in &mut data.layers {
+            if layer.name() == layer_name {
+                let res = layer.sync_rules(in_rules, out_rules, None);
+                if res.added > 0 || res.removed > 0 {
+                    self.epoch.fetch_add(1, SeqCst);
+                }
+                return Ok(res);
+            }
+        }
+
+        Err(OpteError::LayerNotFound(layer_name.to_string()))
+    }
+
+    /// For the given layer, read and replace the inbound and outbound
+    /// rules as one atomic operation.
+    ///
+    /// The closure `f` is handed a copy of the current rules and
+    /// returns the new ones, or `None` to leave the layer as is. The
+    /// new rules are synced as by [`Self::sync_rules()`], unless the
+    /// update specifies which flows to invalidate. The port lock is
+    /// held throughout, serializing concurrent updates of the layer,
+    /// which a call to [`Self::layer_rules()`] followed by one to
+    /// [`Self::sync_rules()`] does not; `f` must not call back into
+    /// the port.
+    ///
+    /// Return the summary of the sync, or `None` if `f` left the
+    /// layer as is.
+    ///
+    /// # Errors
+    ///
+    /// If the layer does not exist, or if `f` returns an error, an
+    /// error is returned and the layer is left as is.
+    ///
+    /// # States
+    ///
+    /// This command is valid for the following states:
+    ///
+    /// * [`PortState::Ready`]
+    /// * [`PortState::Running`]
+    pub fn update_rules<F>(
+        &self,
+        layer_name: &str,
+        f: F,
+    ) -> Result<Option<RuleSync>>
+    where
+        F: FnOnce(
+            Vec<Rule<Finalized>>,
+            Vec<Rule<Finalized>>,
+        ) -> Result<Option<RuleUpdate>>,
+    {
+        let mut data = self.data.lock();
+        check_state!(data.state, [PortState::Ready, PortState::Running])?;
+
+        for layer in &mut data.layers {
+            if layer.name() == layer_name {
+                let rules = |dir| -> Vec<Rule<Finalized>> {
+                    layer.rules(dir).into_iter().map(|(_, rule)| rule).collect()
+                };
+
+                let update =
+                    match f(rules(Direction::In), rules(Direction::Out))? {
+                        Some(update) => update,
+                        None => return Ok(None),
+                    };
+
+                let res = layer.sync_rules(
+                    update.in_rules,
+                    update.out_rules,
+                    update.invalidate_in.as_deref(),
+                );
+                if res.added > 0 || res.removed > 0 {
+                    self.epoch.fetch_add(1, SeqCst);
+                }
+                return Ok(Some(res));
+            }
+        }
+
+        Err(OpteError::LayerNotFound(layer_name.to_string()))
+    }
+
     /// Grab a snapshot of the port statistics.
     pub fn stats_snap(&self) -> PortStatsSnap {
         self.data.lock().stats.vals.snapshot()
@@ -1153,23 +1610,48 @@ impl<N: NetworkImpl> Port<N> {
     }
 }
 
+/// The new rules of a layer, as returned by the closure passed to
+/// [`Port::update_rules()`].
+pub struct RuleUpdate {
+    pub in_rules: Vec<Rule<Finalized>>,
+    pub out_rules: Vec<Rule<Finalized>>,
+
+    /// The flow pairs to invalidate, given the flow ID of their
+    /// inbound side. If `None`, the pairs which may be matched by an
+    /// added or removed rule are invalidated.
+    pub invalidate_in: Option<Box<dyn Fn(&InnerFlowId) -> bool>>,
+}
+
 enum TcpMaybeClosed {
     Closed { ufid_inbound: Option<InnerFlowId> },
     NewState(TcpState),
 }
 
 // This is a convenience wrapper for keeping the header and body
-// transformations under one structure, allowing them to be passes as
-// one argument.
+// transformations, along with any mirrors, under one structure,
+// allowing them to be passes as one argument.
 #[derive(Clone)]
 pub(crate) struct Transforms {
     pub(crate) hdr: Vec<HdrTransform>,
     pub(crate) body: Vec<Box<dyn BodyTransform>>,
+    pub(crate) mirror: Vec<Arc<Mirror>>,
 }
 
 impl Transforms {
     fn new() -> Self {
-        Self { hdr: Vec::with_capacity(8), body: Vec::with_capacity(2) }
+        Self {
+            hdr: Vec::with_capacity(8),
+            body: Vec::with_capacity(2),
+            mirror: Vec::new(),
+        }
+    }
+
+    /// Return `true` if none of the header transforms were generated
+    /// from state which has since changed, see [`HtCheck`].
+    ///
+    /// [`HtCheck`]: super::rule::HtCheck
+    fn is_current(&self) -> bool {
+        self.hdr.iter().all(|ht| ht.is_current())
     }
 }
 
@@ -1177,9 +1659,15 @@ impl fmt::Debug for Transforms {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let body_strs =
             self.body.iter().map(ToString::to_string).collect::<Vec<String>>();
+        let mirror_strs = self
+            .mirror
+            .iter()
+            .map(|m| m.name().to_string())
+            .collect::<Vec<String>>();
         f.debug_struct("Transforms")
             .field("hdr", &self.hdr)
             .field("body", &body_strs)
+            .field("mirror", &mirror_strs)
             .finish()
     }
 }
@@ -1487,6 +1975,8 @@ impl<N: NetworkImpl> Port<N> {
         epoch: u64,
         pkt: &mut Packet<Parsed>,
         ameta: &mut ActionMeta,
+        mirrors: &mut Vec<Arc<Mirror>>,
+        icmp_err: bool,
     ) -> result::Result<ProcessResult, ProcessError> {
         use Direction::In;
 
@@ -1496,9 +1986,11 @@ impl<N: NetworkImpl> Port<N> {
         let res = self.layers_process(data, In, pkt, &mut xforms, ameta);
         match res {
             Ok(LayerResult::Allow) => {
-                // If there is no flow ID, then do not create a UFT
-                // entry.
-                if flow_before == FLOW_ID_DEFAULT {
+                mirrors.extend(xforms.mirror.iter().cloned());
+
+                // If there is no flow ID, or this is an ICMP error,
+                // then do not create a UFT entry.
+                if flow_before == FLOW_ID_DEFAULT || icmp_err {
                     return Ok(ProcessResult::Modified);
                 }
             }
@@ -1590,13 +2082,24 @@ impl<N: NetworkImpl> Port<N> {
         epoch: u64,
         pkt: &mut Packet<Parsed>,
         ameta: &mut ActionMeta,
+        mirrors: &mut Vec<Arc<Mirror>>,
     ) -> result::Result<ProcessResult, ProcessError> {
         use Direction::In;
 
+        // An ICMP error is processed by the flow of the packet in
+        // error, which may differ between errors of the same flow ID.
+        if pkt.icmp_err_flow().is_some() {
+            return self
+                .process_in_miss(data, epoch, pkt, ameta, mirrors, true);
+        }
+
         // Use the compiled UFT entry if one exists. Otherwise
         // fallback to layer processing.
         match data.uft_in.get_mut(pkt.flow()) {
-            Some(entry) if entry.state().epoch == epoch => {
+            Some(entry)
+                if entry.state().epoch == epoch
+                    && entry.state().xforms.is_current() =>
+            {
                 // TODO At the moment I'm holding the UFT locks not
                 // just for lookup, but for the entire duration of
                 // processing. It might be better to ht.clone() or
@@ -1613,6 +2116,7 @@ impl<N: NetworkImpl> Port<N> {
                     pkt.body_transform(In, bt)?;
                 }
 
+                mirrors.extend(entry.state().xforms.mirror.iter().cloned());
                 drop(entry);
 
                 // For inbound traffic the TCP flow table must be
@@ -1636,8 +2140,9 @@ impl<N: NetworkImpl> Port<N> {
                 }
             }
 
-            // The entry is from a previous epoch; invalidate its UFT
-            // entries and proceed to rule processing.
+            // The entry is from a previous epoch, or one of its
+            // transforms is stale; invalidate its UFT entries and
+            // proceed to rule processing.
             Some(entry) => {
                 let epoch = entry.state().epoch;
                 let ufid_in = Some(pkt.flow());
@@ -1649,7 +2154,7 @@ impl<N: NetworkImpl> Port<N> {
             None => (),
         };
 
-        self.process_in_miss(data, epoch, pkt, ameta)
+        self.process_in_miss(data, epoch, pkt, ameta, mirrors, false)
     }
 
     // Process the TCP packet for the purposes of connection tracking
@@ -1772,6 +2277,8 @@ impl<N: NetworkImpl> Port<N> {
         epoch: u64,
         pkt: &mut Packet<Parsed>,
         ameta: &mut ActionMeta,
+        mirrors: &mut Vec<Arc<Mirror>>,
+        icmp_err: bool,
     ) -> result::Result<ProcessResult, ProcessError> {
         use Direction::Out;
 
@@ -1811,12 +2318,16 @@ impl<N: NetworkImpl> Port<N> {
         let mut xforms = Transforms::new();
         let flow_before = pkt.flow().clone();
         let res = self.layers_process(data, Out, pkt, &mut xforms, ameta);
+        if let Ok(LayerResult::Allow) = res {
+            mirrors.extend(xforms.mirror.iter().cloned());
+        }
         let hte = UftEntry { pair: None, xforms, epoch };
 
         match res {
             Ok(LayerResult::Allow) => {
-                // If there is no Flow ID, then there is no UFT entry.
-                if flow_before == FLOW_ID_DEFAULT || tcp_closed {
+                // If there is no Flow ID, or this is an ICMP error,
+                // then there is no UFT entry.
+                if flow_before == FLOW_ID_DEFAULT || tcp_closed || icmp_err {
                     return Ok(ProcessResult::Modified);
                 }
 
@@ -1852,15 +2363,26 @@ impl<N: NetworkImpl> Port<N> {
         epoch: u64,
         pkt: &mut Packet<Parsed>,
         ameta: &mut ActionMeta,
+        mirrors: &mut Vec<Arc<Mirror>>,
     ) -> result::Result<ProcessResult, ProcessError> {
         use Direction::Out;
 
+        // An ICMP error is processed by the flow of the packet in
+        // error, which may differ between errors of the same flow ID.
+        if pkt.icmp_err_flow().is_some() {
+            return self
+                .process_out_miss(data, epoch, pkt, ameta, mirrors, true);
+        }
+
         let uft_out = &mut data.uft_out;
 
         // Use the compiled UFT entry if one exists. Otherwise
         // fallback to layer processing.
         match uft_out.get_mut(pkt.flow()) {
-            Some(entry) if entry.state().epoch == epoch => {
+            Some(entry)
+                if entry.state().epoch == epoch
+                    && entry.state().xforms.is_current() =>
+            {
                 entry.hit();
                 data.stats.vals.out_uft_hit += 1;
                 let mut invalidated = false;
@@ -1902,6 +2424,7 @@ impl<N: NetworkImpl> Port<N> {
                     pkt.body_transform(Out, bt)?;
                 }
 
+                mirrors.extend(entry.state().xforms.mirror.iter().cloned());
                 drop(entry);
 
                 if invalidated {
@@ -1911,8 +2434,9 @@ impl<N: NetworkImpl> Port<N> {
                 return Ok(ProcessResult::Modified);
             }
 
-            // The entry is from a previous epoch; invalidate its UFT
-            // entries and proceed to rule processing.
+            // The entry is from a previous epoch, or one of its
+            // transforms is stale; invalidate its UFT entries and
+            // proceed to rule processing.
             Some(entry) => {
                 let epoch = entry.state().epoch;
                 let ufid_out = Some(pkt.flow());
@@ -1924,7 +2448,62 @@ impl<N: NetworkImpl> Port<N> {
             None => (),
         }
 
-        self.process_out_miss(data, epoch, pkt, ameta)
+        self.process_out_miss(data, epoch, pkt, ameta, mirrors, false)
+    }
+
+    fn record_flow(
+        data: &mut PortData,
+        dir: Direction,
+        flow_before: &InnerFlowId,
+        pkt: &Packet<Parsed>,
+        res: &result::Result<ProcessResult, ProcessError>,
+        replay: bool,
+    ) {
+        let verdict = match res {
+            Ok(ProcessResult::Modified) => FlowVerdict::Allow,
+            Ok(ProcessResult::Drop { .. }) => FlowVerdict::Deny,
+            // Hairpinned and bypassed packets are not part of any
+            // guest flow.
+            _ => return,
+        };
+
+        // The new headers have yet to be emitted, so the length and
+        // offsets are still those of the packet as it was received.
+        let len = pkt.len() - pkt.hdr_offsets().inner.ether.pkt_pos;
+        let tcp_flags = match pkt.meta().inner.ulp.as_ref() {
+            Some(UlpMeta::Tcp(tcp)) => Some(tcp.flags),
+            _ => None,
+        };
+
+        data.flow_records.account(
+            dir,
+            flow_before,
+            pkt.flow(),
+            len as u64,
+            tcp_flags,
+            verdict,
+            !replay,
+        );
+    }
+
+    fn queue_mirrored(
+        &self,
+        data: &mut PortData,
+        mirrored: Vec<MirroredPacket>,
+    ) {
+        for mpkt in mirrored {
+            if data.mirror_queue.len() >= MIRROR_QUEUE_MAX {
+                data.stats.vals.mirror_queue_full += 1;
+                continue;
+            }
+
+            data.mirror_queue.push(mpkt);
+            data.stats.vals.mirror += 1;
+        }
+
+        if !data.mirror_queue.is_empty() {
+            self.mirror_pending.store(true, SeqCst);
+        }
     }
 
     fn uft_invalidate(
@@ -20,13 +20,22 @@ use super::ip4::Protocol;
 use super::ip6::Ipv6Addr;
 use super::ip6::Ipv6Cidr;
 use super::ip6::Ipv6Meta;
+use super::packet::InnerFlowId;
 use super::packet::PacketMeta;
 use super::packet::PacketRead;
 use super::port::meta::ActionMeta;
 use super::tcp::TcpMeta;
 use super::udp::UdpMeta;
+#[cfg(any(feature = "std", test))]
+use super::ParseErr;
+#[cfg(any(feature = "std", test))]
+use super::ParseResult;
 use core::fmt;
 use core::fmt::Display;
+use core::ops::RangeInclusive;
+#[cfg(any(feature = "std", test))]
+use core::str::FromStr;
+use opte_api::IpAddr;
 use opte_api::MacAddr;
 use serde::Deserialize;
 use serde::Serialize;
@@ -199,6 +208,9 @@ impl Display for IpProtoMatch {
         use IpProtoMatch::*;
 
         match self {
+            // Print unknown protocols by number so that the value can
+            // be parsed back into the same predicate.
+            Exact(Protocol::Unknown(num)) => write!(f, "{}", num),
             Exact(proto) => write!(f, "{}", proto),
         }
     }
@@ -215,12 +227,15 @@ impl MatchExact<u16> for u16 {
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub enum PortMatch {
     Exact(u16),
+    /// Match any port in the inclusive range.
+    Range(RangeInclusive<u16>),
 }
 
 impl PortMatch {
     fn matches(&self, flow_port: u16) -> bool {
         match self {
             Self::Exact(port) => flow_port.match_exact(port),
+            Self::Range(range) => range.contains(&flow_port),
         }
     }
 }
@@ -231,11 +246,12 @@ impl Display for PortMatch {
 
         match self {
             Exact(port) => write!(f, "{}", port),
+            Range(range) => write!(f, "{}-{}", range.start(), range.end()),
         }
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub enum Predicate {
     InnerEtherType(Vec<EtherTypeMatch>),
     InnerEtherDst(Vec<EtherAddrMatch>),
@@ -358,6 +374,249 @@ impl Display for Predicate {
     }
 }
 
+// Split a predicate of the form `<field>=<values>` or `<field> in
+// <values>` into its field and values, using whichever separator
+// comes first.
+#[cfg(any(feature = "std", test))]
+fn split_pred(s: &str) -> ParseResult<(&str, &str)> {
+    let eq = s.find('=').map(|i| (i, 1));
+    let within = s.find(" in ").map(|i| (i, 4));
+
+    let (idx, len) = match (eq, within) {
+        (Some(a), Some(b)) => core::cmp::min(a, b),
+        (Some(a), None) => a,
+        (None, Some(b)) => b,
+        (None, None) => {
+            return Err(ParseErr::Other(format!(
+                "expected '<field>=<values>' or '<field> in <values>': {}",
+                s
+            )));
+        }
+    };
+
+    let field = s[..idx].trim();
+    let vals = s[idx + len..].trim();
+
+    if field.is_empty() {
+        return Err(ParseErr::Other(format!("missing field: {}", s)));
+    }
+
+    if vals.is_empty() {
+        return Err(ParseErr::Other(format!("missing value for {}", field)));
+    }
+
+    Ok((field, vals))
+}
+
+// Parse a comma-separated list of values, reporting the offending
+// field and value on error.
+#[cfg(any(feature = "std", test))]
+fn parse_list<T, F>(field: &str, vals: &str, f: F) -> ParseResult<Vec<T>>
+where
+    F: Fn(&str) -> Result<T, String>,
+{
+    vals.split(',')
+        .map(|v| {
+            let v = v.trim();
+            f(v).map_err(|e| {
+                ParseErr::BadToken(format!("{}: '{}': {}", field, v, e))
+            })
+        })
+        .collect()
+}
+
+#[cfg(any(feature = "std", test))]
+fn parse_ether_type(s: &str) -> Result<EtherTypeMatch, String> {
+    use super::ether::ETHER_TYPE_ARP;
+    use super::ether::ETHER_TYPE_IPV4;
+    use super::ether::ETHER_TYPE_IPV6;
+
+    let et = match s.to_ascii_lowercase().as_str() {
+        "arp" => ETHER_TYPE_ARP,
+        "ipv4" => ETHER_TYPE_IPV4,
+        "ipv6" => ETHER_TYPE_IPV6,
+        lower => match lower.strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16)
+                .map_err(|e| format!("bad hex ether type: {}", e))?,
+            None => lower
+                .parse::<u16>()
+                .map_err(|e| format!("bad ether type: {}", e))?,
+        },
+    };
+
+    Ok(EtherTypeMatch::Exact(et))
+}
+
+#[cfg(any(feature = "std", test))]
+fn parse_proto(s: &str) -> Result<IpProtoMatch, String> {
+    let proto = match s.to_ascii_lowercase().as_str() {
+        "icmp" => Protocol::ICMP,
+        "igmp" => Protocol::IGMP,
+        "tcp" => Protocol::TCP,
+        "udp" => Protocol::UDP,
+        "icmpv6" => Protocol::ICMPv6,
+        num => Protocol::from(
+            num.parse::<u8>().map_err(|_| "unknown protocol".to_string())?,
+        ),
+    };
+
+    Ok(IpProtoMatch::Exact(proto))
+}
+
+#[cfg(any(feature = "std", test))]
+fn parse_ip4(s: &str) -> Result<Ipv4AddrMatch, String> {
+    if s.contains('/') {
+        Ok(Ipv4AddrMatch::Prefix(s.parse::<Ipv4Cidr>()?))
+    } else {
+        Ok(Ipv4AddrMatch::Exact(s.parse::<Ipv4Addr>()?))
+    }
+}
+
+#[cfg(any(feature = "std", test))]
+fn parse_ip6(s: &str) -> Result<Ipv6AddrMatch, String> {
+    if s.contains('/') {
+        Ok(Ipv6AddrMatch::Prefix(s.parse::<Ipv6Cidr>()?))
+    } else {
+        Ok(Ipv6AddrMatch::Exact(s.parse::<Ipv6Addr>()?))
+    }
+}
+
+#[cfg(any(feature = "std", test))]
+fn parse_port(s: &str) -> Result<PortMatch, String> {
+    let parse = |p: &str| {
+        p.trim().parse::<u16>().map_err(|e| format!("bad port: {}", e))
+    };
+
+    match s.split_once('-') {
+        Some((lo, hi)) => {
+            let (lo, hi) = (parse(lo)?, parse(hi)?);
+            if lo > hi {
+                return Err(format!("bad port range: {}", s));
+            }
+            Ok(PortMatch::Range(lo..=hi))
+        }
+
+        None => parse(s).map(PortMatch::Exact),
+    }
+}
+
+#[cfg(any(feature = "std", test))]
+fn parse_mac(s: &str) -> Result<EtherAddrMatch, String> {
+    Ok(EtherAddrMatch::Exact(s.parse::<MacAddr>()?))
+}
+
+/// Parse a [`Predicate`] from its textual form.
+///
+/// A predicate is written as `<field>=<values>` or `<field> in
+/// <values>`, where `<values>` is a comma-separated list. A leading
+/// `!` negates the predicate. The form produced by [`Display`] is
+/// always accepted, along with the following shorter aliases.
+///
+/// * `ether.type`: `inner.ether.ether_type`; a hex or decimal value,
+///   or one of `arp`, `ipv4`, `ipv6`.
+/// * `ether.src`, `ether.dst`: `inner.ether.src`, `inner.ether.dst`.
+/// * `ip.proto`: `inner.ip.proto`; a protocol name or number.
+/// * `ip.src`, `ip.dst`: `inner.ip.src`, `inner.ip.dst`; an address or
+///   CIDR. IPv6 values select `inner.ip6.src` and `inner.ip6.dst`.
+/// * `ip6.src`, `ip6.dst`: `inner.ip6.src`, `inner.ip6.dst`.
+/// * `ulp.sport`, `tcp.sport`, `udp.sport`: `inner.ulp.src`.
+/// * `ulp.dport`, `tcp.dport`, `udp.dport`: `inner.ulp.dst`.
+///
+/// Ports may be given as a single port or an inclusive range, e.g.
+/// `ulp.dport=22,30000-32767`.
+/// * `meta.<key>=<value>`: `meta: <key>=<value>`.
+///
+/// Note that the `tcp` and `udp` port aliases do not imply a protocol
+/// match; add an `ip.proto` predicate for that.
+///
+/// ```text
+/// ip.dst in 10.0.0.0/8
+/// inner.ulp.dst=80,443
+/// !ip.proto=udp
+/// meta: router-target=ig
+/// ```
+#[cfg(any(feature = "std", test))]
+impl FromStr for Predicate {
+    type Err = ParseErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        if let Some(rest) = s.strip_prefix('!') {
+            return Ok(Predicate::Not(Box::new(rest.parse()?)));
+        }
+
+        if let Some(rest) =
+            s.strip_prefix("meta:").or_else(|| s.strip_prefix("meta."))
+        {
+            let (key, val) = split_pred(rest.trim())?;
+            return Ok(Predicate::Meta(key.to_string(), val.to_string()));
+        }
+
+        let (field, vals) = split_pred(s)?;
+
+        let pred = match field {
+            "inner.ether.ether_type" | "ether.type" => {
+                let list = parse_list(field, vals, parse_ether_type)?;
+                Predicate::InnerEtherType(list)
+            }
+
+            "inner.ether.dst" | "ether.dst" => {
+                Predicate::InnerEtherDst(parse_list(field, vals, parse_mac)?)
+            }
+
+            "inner.ether.src" | "ether.src" => {
+                Predicate::InnerEtherSrc(parse_list(field, vals, parse_mac)?)
+            }
+
+            "inner.ip.proto" | "ip.proto" => {
+                Predicate::InnerIpProto(parse_list(field, vals, parse_proto)?)
+            }
+
+            "inner.ip.src" | "ip.src" if vals.contains(':') => {
+                Predicate::InnerSrcIp6(parse_list(field, vals, parse_ip6)?)
+            }
+
+            "inner.ip.src" | "ip.src" => {
+                Predicate::InnerSrcIp4(parse_list(field, vals, parse_ip4)?)
+            }
+
+            "inner.ip.dst" | "ip.dst" if vals.contains(':') => {
+                Predicate::InnerDstIp6(parse_list(field, vals, parse_ip6)?)
+            }
+
+            "inner.ip.dst" | "ip.dst" => {
+                Predicate::InnerDstIp4(parse_list(field, vals, parse_ip4)?)
+            }
+
+            "inner.ip6.src" | "ip6.src" => {
+                Predicate::InnerSrcIp6(parse_list(field, vals, parse_ip6)?)
+            }
+
+            "inner.ip6.dst" | "ip6.dst" => {
+                Predicate::InnerDstIp6(parse_list(field, vals, parse_ip6)?)
+            }
+
+            "inner.ulp.src" | "ulp.sport" | "tcp.sport" | "udp.sport" => {
+                Predicate::InnerSrcPort(parse_list(field, vals, parse_port)?)
+            }
+
+            "inner.ulp.dst" | "ulp.dport" | "tcp.dport" | "udp.dport" => {
+                Predicate::InnerDstPort(parse_list(field, vals, parse_port)?)
+            }
+
+            _ => {
+                return Err(ParseErr::UnknownToken(format!(
+                    "unknown predicate field: {}",
+                    field
+                )));
+            }
+        };
+
+        Ok(pred)
+    }
+}
+
 impl Predicate {
     pub(crate) fn is_match(
         &self,
@@ -514,11 +773,69 @@ impl Predicate {
     }
 }
 
+impl Predicate {
+    /// Determine if this predicate matches packets of the given flow.
+    ///
+    /// A flow ID carries only the IP addresses, protocol, and ULP
+    /// ports of a packet. If the predicate depends on anything else,
+    /// then `None` is returned, as the answer can only be known by
+    /// inspecting the packet.
+    pub(crate) fn flow_match(&self, flow: &InnerFlowId) -> Option<bool> {
+        let has_ports =
+            flow.proto == Protocol::TCP || flow.proto == Protocol::UDP;
+
+        let res = match self {
+            Self::InnerEtherType(_)
+            | Self::InnerEtherDst(_)
+            | Self::InnerEtherSrc(_)
+            | Self::Meta(_, _) => return None,
+
+            Self::Not(pred) => return pred.flow_match(flow).map(|m| !m),
+
+            Self::InnerIpProto(list) => {
+                list.iter().any(|m| m.matches(flow.proto))
+            }
+
+            Self::InnerSrcIp4(list) => match flow.src_ip {
+                IpAddr::Ip4(ip) => list.iter().any(|m| m.matches(ip)),
+                _ => false,
+            },
+
+            Self::InnerDstIp4(list) => match flow.dst_ip {
+                IpAddr::Ip4(ip) => list.iter().any(|m| m.matches(ip)),
+                _ => false,
+            },
+
+            Self::InnerSrcIp6(list) => match flow.src_ip {
+                IpAddr::Ip6(ip) => list.iter().any(|m| m.matches(ip)),
+                _ => false,
+            },
+
+            Self::InnerDstIp6(list) => match flow.dst_ip {
+                IpAddr::Ip6(ip) => list.iter().any(|m| m.matches(ip)),
+                _ => false,
+            },
+
+            Self::InnerSrcPort(list) => {
+                has_ports && list.iter().any(|m| m.matches(flow.src_port))
+            }
+
+            Self::InnerDstPort(list) => {
+                has_ports && list.iter().any(|m| m.matches(flow.dst_port))
+            }
+        };
+
+        Some(res)
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub enum DataPredicate {
     DhcpMsgType(DhcpMessageType),
     IcmpMsgType(IcmpMessageType),
+    IcmpMsgCode(u8),
     Icmpv6MsgType(Icmpv6MessageType),
+    Icmpv6MsgCode(u8),
     Dhcpv6MsgType(Dhcpv6MessageType),
     Not(Box<DataPredicate>),
 }
@@ -536,10 +853,18 @@ impl Display for DataPredicate {
                 write!(f, "icmp.msg_type={}", mt)
             }
 
+            IcmpMsgCode(code) => {
+                write!(f, "icmp.code={}", code)
+            }
+
             Icmpv6MsgType(mt) => {
                 write!(f, "icmpv6.msg_type={}", mt)
             }
 
+            Icmpv6MsgCode(code) => {
+                write!(f, "icmpv6.code={}", code)
+            }
+
             Dhcpv6MsgType(mt) => {
                 write!(f, "dhcpv6.msg_type={}", mt)
             }
@@ -552,6 +877,107 @@ impl Display for DataPredicate {
     }
 }
 
+// The fields which name a [`DataPredicate`] rather than a [`Predicate`].
+#[cfg(any(feature = "std", test))]
+const DATA_PRED_FIELDS: [&str; 6] = [
+    "dhcp.msg_type",
+    "dhcpv6.msg_type",
+    "icmp.msg_type",
+    "icmp.code",
+    "icmpv6.msg_type",
+    "icmpv6.code",
+];
+
+/// Does this textual predicate describe a [`DataPredicate`]?
+#[cfg(any(feature = "std", test))]
+pub fn is_data_predicate(s: &str) -> bool {
+    let s = s.trim().trim_start_matches('!').trim_start();
+    DATA_PRED_FIELDS.iter().any(|f| s.starts_with(f))
+}
+
+// Parse a message type by either its numeric value or the name
+// produced by its `Display` implementation.
+#[cfg(any(feature = "std", test))]
+fn parse_msg_type<T>(field: &str, s: &str) -> ParseResult<T>
+where
+    T: Display + From<u8>,
+{
+    if let Ok(num) = s.parse::<u8>() {
+        return Ok(T::from(num));
+    }
+
+    for num in 0..=u8::MAX {
+        let mt = T::from(num);
+        if mt.to_string().eq_ignore_ascii_case(s) {
+            return Ok(mt);
+        }
+    }
+
+    Err(ParseErr::BadToken(format!("{}: unknown message type: '{}'", field, s)))
+}
+
+#[cfg(any(feature = "std", test))]
+fn parse_code(field: &str, s: &str) -> ParseResult<u8> {
+    s.parse::<u8>().map_err(|_| {
+        ParseErr::BadToken(format!("{}: bad message code: '{}'", field, s))
+    })
+}
+
+/// Parse a [`DataPredicate`] from its textual form.
+///
+/// This is the form produced by [`Display`]: `<field>=<type>`, where
+/// `<type>` is either the message type's name or its numeric value.
+/// The fields are `dhcp.msg_type`, `dhcpv6.msg_type`, `icmp.msg_type`,
+/// and `icmpv6.msg_type`. The `icmp.code` and `icmpv6.code` fields
+/// take a numeric message code. A leading `!` negates the predicate.
+#[cfg(any(feature = "std", test))]
+impl FromStr for DataPredicate {
+    type Err = ParseErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        if let Some(rest) = s.strip_prefix('!') {
+            return Ok(DataPredicate::Not(Box::new(rest.parse()?)));
+        }
+
+        let (field, val) = split_pred(s)?;
+
+        let pred = match field {
+            "dhcp.msg_type" => {
+                DataPredicate::DhcpMsgType(parse_msg_type(field, val)?)
+            }
+
+            "dhcpv6.msg_type" => {
+                DataPredicate::Dhcpv6MsgType(parse_msg_type(field, val)?)
+            }
+
+            "icmp.msg_type" => {
+                DataPredicate::IcmpMsgType(parse_msg_type(field, val)?)
+            }
+
+            "icmp.code" => DataPredicate::IcmpMsgCode(parse_code(field, val)?),
+
+            "icmpv6.msg_type" => {
+                DataPredicate::Icmpv6MsgType(parse_msg_type(field, val)?)
+            }
+
+            "icmpv6.code" => {
+                DataPredicate::Icmpv6MsgCode(parse_code(field, val)?)
+            }
+
+            _ => {
+                return Err(ParseErr::UnknownToken(format!(
+                    "unknown data predicate field: {}",
+                    field
+                )));
+            }
+        };
+
+        Ok(pred)
+    }
+}
+
 impl DataPredicate {
     // Determine if the given `DataPredicate` matches the payload. We
     // use `PacketMeta` to determine if there is a suitable payload to
@@ -622,6 +1048,33 @@ impl DataPredicate {
                 return IcmpMessageType::from(pkt.msg_type()) == *mt;
             }
 
+            // Unlike the message type, the code is matched without
+            // validating the entire message, as not all message types
+            // are understood by `Icmpv4Repr`.
+            Self::IcmpMsgCode(code) => {
+                if meta.inner_ip4().is_none() {
+                    return false;
+                }
+
+                let bytes = rdr.copy_remaining();
+                return match Icmpv4Packet::new_checked(&bytes) {
+                    Ok(pkt) => pkt.msg_code() == *code,
+                    Err(_) => false,
+                };
+            }
+
+            Self::Icmpv6MsgCode(code) => {
+                if meta.inner_ip6().is_none() {
+                    return false;
+                }
+
+                let bytes = rdr.copy_remaining();
+                return match Icmpv6Packet::new_checked(&bytes) {
+                    Ok(pkt) => pkt.msg_code() == *code,
+                    Err(_) => false,
+                };
+            }
+
             Self::Icmpv6MsgType(mt) => {
                 // Pull out the IPv6 source / destination addresses. This checks
                 // that this is actually an IPv6 packet, and these are needed
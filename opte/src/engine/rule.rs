@@ -18,6 +18,7 @@ use super::headers::IpMeta;
 use super::headers::IpMod;
 use super::headers::IpPush;
 use super::headers::UlpHeaderAction;
+use super::mirror::Mirror;
 use super::packet::BodyTransform;
 use super::packet::Initialized;
 use super::packet::InnerFlowId;
@@ -27,12 +28,19 @@ use super::packet::PacketRead;
 use super::packet::PacketReader;
 use super::packet::Parsed;
 use super::port::meta::ActionMeta;
+#[cfg(any(feature = "std", test))]
+use super::predicate::is_data_predicate;
 use super::predicate::DataPredicate;
 use super::predicate::Predicate;
+#[cfg(any(feature = "std", test))]
+use super::ParseErr;
+use core::any::Any;
 use core::ffi::CStr;
 use core::fmt;
 use core::fmt::Debug;
 use core::fmt::Display;
+#[cfg(any(feature = "std", test))]
+use core::str::FromStr;
 use illumos_sys_hdrs::c_char;
 use illumos_sys_hdrs::uintptr_t;
 use opte_api::Direction;
@@ -124,9 +132,36 @@ pub trait ActionDesc {
         Ok(None)
     }
 
+    /// Generate the translation of an ICMP error about this flow,
+    /// traveling in direction `dir`, whose body is `body`: the ICMP
+    /// header followed by the leading bytes of the packet in error.
+    ///
+    /// Return `None` if this descriptor has no translation for ICMP
+    /// errors, in which case the error is processed as a flow of its
+    /// own.
+    fn gen_icmp_err(
+        &self,
+        _dir: Direction,
+        _meta: &PacketMeta,
+        _body: &[u8],
+    ) -> Option<AllowOrDeny<IcmpErrXlate>> {
+        None
+    }
+
     fn name(&self) -> &str;
 }
 
+/// The translation of an ICMP error, see
+/// [`ActionDesc::gen_icmp_err()`].
+pub struct IcmpErrXlate {
+    /// The transformation of the error's own headers.
+    pub ht: HdrTransform,
+
+    /// The error's new body, which may differ in length from the
+    /// original.
+    pub body: Vec<u8>,
+}
+
 impl fmt::Debug for dyn ActionDesc {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "dyn ActionDesc {}", self.name())
@@ -225,6 +260,21 @@ pub enum ModifyAction<T> {
     Modify(T),
 }
 
+/// A check of whether the state a [`HdrTransform`] was generated
+/// from is unchanged.
+///
+/// An action which generates its transform from shared state, such as
+/// a mapping table, may attach one of these to the transform. The
+/// port runs the check each time it reuses the transform from the
+/// UFT; once the check fails, the flow's UFT entries are invalidated
+/// and its next packet is processed by the layers again, generating a
+/// transform from the current state. A change to the state thus only
+/// affects the flows which were generated from it, without moving the
+/// port's epoch forward.
+pub trait HtCheck: Debug + Send + Sync {
+    fn is_current(&self) -> bool;
+}
+
 /// A collection of header transformations to take on each part of the
 /// header stack.
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
@@ -237,6 +287,21 @@ pub struct HdrTransform {
     pub inner_ip: HeaderAction<IpMeta, IpPush, IpMod>,
     // We don't support push/pop for inner_ulp.
     pub inner_ulp: UlpHeaderAction<super::headers::UlpMetaModify>,
+    /// A check of whether this transform is still valid, see
+    /// [`HtCheck`].
+    #[serde(skip)]
+    pub check: Option<Arc<dyn HtCheck>>,
+}
+
+impl HdrTransform {
+    /// Return `true` if the state this transform was generated from
+    /// is unchanged.
+    pub fn is_current(&self) -> bool {
+        match &self.check {
+            Some(check) => check.is_current(),
+            None => true,
+        }
+    }
 }
 
 impl StateSummary for Vec<HdrTransform> {
@@ -411,6 +476,9 @@ pub enum HdrTransformError {
 #[derive(Debug)]
 pub enum ResourceError {
     Exhausted,
+    /// The key has reached its limit of entries, though the resource
+    /// itself is not exhausted.
+    LimitReached,
     NoMatch(String),
 }
 
@@ -442,6 +510,14 @@ pub trait StatefulAction: Display {
     ) -> GenDescResult;
 
     fn implicit_preds(&self) -> (Vec<Predicate>, Vec<DataPredicate>);
+
+    /// Return this action as [`Any`], allowing the code which created
+    /// it to recover the concrete type from an [`Action::Stateful`].
+    ///
+    /// By default an action is opaque.
+    fn as_any(&self) -> Option<&dyn Any> {
+        None
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -488,6 +564,14 @@ pub trait MetaAction: Display {
         flow_id: &InnerFlowId,
         meta: &mut ActionMeta,
     ) -> ModMetaResult;
+
+    /// Return this action as [`Any`], allowing the code which created
+    /// it to recover the concrete type from an [`Action::Meta`].
+    ///
+    /// By default an action is opaque.
+    fn as_any(&self) -> Option<&dyn Any> {
+        None
+    }
 }
 
 #[derive(Debug)]
@@ -608,6 +692,14 @@ pub enum Action {
     /// A hairpin action generates a response packet and "hairpins" it
     /// back to the source.
     Hairpin(Arc<dyn HairpinAction>),
+
+    /// Allow the packet to pass, sending a copy of it to the mirror's
+    /// target.
+    ///
+    /// The mirror becomes part of the flow's transformations, so that
+    /// all packets of the flow are subject to it, not just those
+    /// going through rule processing.
+    Mirror(Arc<Mirror>),
 }
 
 impl Action {
@@ -624,6 +716,7 @@ impl Action {
             Self::Static(act) => act.implicit_preds(),
             Self::Stateful(act) => act.implicit_preds(),
             Self::Hairpin(act) => act.implicit_preds(),
+            Self::Mirror(_) => (vec![], vec![]),
         }
     }
 
@@ -645,6 +738,7 @@ pub enum ActionDump {
     Static(String),
     Stateful(String),
     Hairpin(String),
+    Mirror(String),
 }
 
 impl From<&Action> for ActionDump {
@@ -658,6 +752,7 @@ impl From<&Action> for ActionDump {
             Action::Static(sa) => Self::Static(sa.to_string()),
             Action::Stateful(sa) => Self::Stateful(sa.to_string()),
             Action::Hairpin(ha) => Self::Hairpin(ha.to_string()),
+            Action::Mirror(m) => Self::Mirror(m.to_string()),
         }
     }
 }
@@ -673,6 +768,30 @@ impl fmt::Display for Action {
             Self::Static(a) => write!(f, "Static: {}", a),
             Self::Stateful(a) => write!(f, "Stateful: {}", a),
             Self::Hairpin(a) => write!(f, "Hairpin: {}", a),
+            Self::Mirror(a) => write!(f, "Mirror: {}", a),
+        }
+    }
+}
+
+/// Parse an [`Action`] from its textual form.
+///
+/// Only the actions which carry no state may be written as text:
+/// `allow`, `stateful allow` (or `stateful-allow`), and `deny`. The
+/// comparison is case-insensitive so that the [`Display`] form is
+/// also accepted.
+#[cfg(any(feature = "std", test))]
+impl FromStr for Action {
+    type Err = ParseErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "allow" => Ok(Self::Allow),
+            "stateful allow" | "stateful-allow" => Ok(Self::StatefulAllow),
+            "deny" => Ok(Self::Deny),
+            _ => Err(ParseErr::BadToken(format!(
+                "action: '{}': expected one of allow, stateful allow, deny",
+                s.trim()
+            ))),
         }
     }
 }
@@ -740,6 +859,7 @@ pub struct Rule<S: RuleState> {
     state: S,
     action: Action,
     priority: u16,
+    log: bool,
 }
 
 impl PartialEq for Rule<Finalized> {
@@ -754,6 +874,19 @@ impl<S: RuleState> Rule<S> {
     pub fn action(&self) -> &Action {
         &self.action
     }
+
+    /// Return whether the matches of this rule are logged.
+    pub fn log(&self) -> bool {
+        self.log
+    }
+
+    /// Log the first packet of each flow matching this rule to the
+    /// layer's [`RuleLog`].
+    ///
+    /// [`RuleLog`]: super::rule_log::RuleLog
+    pub fn set_log(&mut self, log: bool) {
+        self.log = log;
+    }
 }
 
 impl Rule<Ready> {
@@ -765,7 +898,12 @@ impl Rule<Ready> {
     pub fn new(priority: u16, action: Action) -> Self {
         let (hdr_preds, data_preds) = action.implicit_preds();
 
-        Rule { state: Ready { hdr_preds, data_preds }, action, priority }
+        Rule {
+            state: Ready { hdr_preds, data_preds },
+            action,
+            priority,
+            log: false,
+        }
     }
 
     /// Create a new rule that matches anything.
@@ -776,7 +914,7 @@ impl Rule<Ready> {
     /// useful for making intentions clear that this rule is to match
     /// anything.
     pub fn match_any(priority: u16, action: Action) -> Rule<Finalized> {
-        Rule { state: Finalized { preds: None }, action, priority }
+        Rule { state: Finalized { preds: None }, action, priority, log: false }
     }
 
     /// Add a single [`Predicate`] to the end of the list.
@@ -822,8 +960,58 @@ impl Rule<Ready> {
             state: Finalized { preds },
             priority: self.priority,
             action: self.action,
+            log: self.log,
+        }
+    }
+}
+
+impl Rule<Finalized> {
+    /// Determine if this rule may match packets of the given flow.
+    ///
+    /// This is conservative: only the predicates which can be decided
+    /// by the flow ID alone are considered, so `true` means the rule
+    /// may match, while `false` means it certainly does not.
+    pub(crate) fn may_match_flow(&self, flow: &InnerFlowId) -> bool {
+        match &self.state.preds {
+            None => true,
+
+            Some(preds) => preds
+                .hdr_preds
+                .iter()
+                .all(|p| p.flow_match(flow).unwrap_or(true)),
         }
     }
+
+    /// Determine if this rule is the same as `other`: it has the same
+    /// predicates, priority, action, and logging.
+    ///
+    /// Actions holding state are only considered the same if they
+    /// are the very same action, not merely an equivalent one.
+    pub(crate) fn is_same(&self, other: &Self) -> bool {
+        // Compare only the data pointers, as the vtable pointers of
+        // the same object may differ between codegen units.
+        fn same<T: ?Sized>(a: &Arc<T>, b: &Arc<T>) -> bool {
+            Arc::as_ptr(a) as *const u8 == Arc::as_ptr(b) as *const u8
+        }
+
+        let same_action = match (&self.action, &other.action) {
+            (Action::Allow, Action::Allow)
+            | (Action::HandlePacket, Action::HandlePacket)
+            | (Action::StatefulAllow, Action::StatefulAllow)
+            | (Action::Deny, Action::Deny) => true,
+            (Action::Meta(a), Action::Meta(b)) => same(a, b),
+            (Action::Static(a), Action::Static(b)) => same(a, b),
+            (Action::Stateful(a), Action::Stateful(b)) => same(a, b),
+            (Action::Hairpin(a), Action::Hairpin(b)) => same(a, b),
+            (Action::Mirror(a), Action::Mirror(b)) => same(a, b),
+            _ => false,
+        };
+
+        same_action
+            && self.priority == other.priority
+            && self.log == other.log
+            && self == other
+    }
 }
 
 impl<'a> Rule<Finalized> {
@@ -873,6 +1061,122 @@ impl<'a> Rule<Finalized> {
     pub fn priority(&self) -> u16 {
         self.priority
     }
+
+    /// Return the header predicates of this rule.
+    pub fn hdr_preds(&self) -> &[Predicate] {
+        match &self.state.preds {
+            Some(rp) => &rp.hdr_preds,
+            None => &[],
+        }
+    }
+}
+
+/// Print a rule in the textual form accepted by [`FromStr`].
+impl Display for Rule<Finalized> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.state.preds {
+            None => write!(f, "any")?,
+
+            Some(rp) => {
+                let preds = rp
+                    .hdr_preds
+                    .iter()
+                    .map(ToString::to_string)
+                    .chain(rp.data_preds.iter().map(ToString::to_string))
+                    .collect::<Vec<String>>()
+                    .join(" && ");
+                write!(f, "{}", preds)?;
+            }
+        }
+
+        write!(f, " => {} prio {}", self.action, self.priority)?;
+
+        if self.log {
+            write!(f, " log")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse a rule from its textual form.
+///
+/// ```text
+/// <predicates> => <action> prio <priority> [log]
+/// ```
+///
+/// The predicates are a list of [`Predicate`] and [`DataPredicate`]
+/// joined by `&&`, all of which must match, or `any` to match all
+/// packets. See their [`FromStr`] implementations for the syntax of
+/// each predicate, and [`Action`]'s for the actions which may be
+/// written as text. For example:
+///
+/// ```text
+/// ip.dst in 10.0.0.0/8 && tcp.dport in 80,443 => allow prio 100
+/// ```
+#[cfg(any(feature = "std", test))]
+impl FromStr for Rule<Finalized> {
+    type Err = ParseErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (preds_s, rest) = match s.split_once("=>") {
+            Some(v) => v,
+            None => {
+                return Err(ParseErr::Other(format!(
+                    "expected '<predicates> => <action> prio <priority>': {}",
+                    s.trim()
+                )));
+            }
+        };
+
+        let (action_s, prio_s) = match rest.rsplit_once("prio") {
+            Some(v) => v,
+            None => {
+                return Err(ParseErr::Other(format!(
+                    "missing 'prio <priority>': {}",
+                    rest.trim()
+                )));
+            }
+        };
+
+        let (prio_s, log) = match prio_s.trim().strip_suffix("log") {
+            Some(prio_s) => (prio_s.trim(), true),
+            None => (prio_s.trim(), false),
+        };
+
+        let priority = prio_s.parse::<u16>().map_err(|e| {
+            ParseErr::BadToken(format!("prio: '{}': {}", prio_s, e))
+        })?;
+        let action = action_s.parse::<Action>()?;
+        let preds_s = preds_s.trim();
+
+        if preds_s == "any" {
+            let mut rule = Rule::match_any(priority, action);
+            rule.set_log(log);
+            return Ok(rule);
+        }
+
+        let mut rule = Rule::new(priority, action);
+        rule.set_log(log);
+
+        for pred_s in preds_s.split("&&") {
+            let pred_s = pred_s.trim();
+            if pred_s.is_empty() {
+                return Err(ParseErr::Other(format!(
+                    "empty predicate: {}",
+                    preds_s
+                )));
+            }
+
+            if is_data_predicate(pred_s) {
+                rule.add_data_predicate(pred_s.parse()?);
+            } else {
+                rule.add_predicate(pred_s.parse()?);
+            }
+        }
+
+        Ok(rule.finalize())
+    }
 }
 
 impl From<&Rule<Finalized>> for super::ioctl::RuleDump {
@@ -982,3 +1286,83 @@ fn rule_matching() {
 
     assert!(!r1.is_match(&meta, &ameta, &mut rdr));
 }
+
+#[test]
+fn rule_parse() {
+    use crate::engine::predicate::Ipv4AddrMatch;
+    use crate::engine::predicate::PortMatch;
+
+    let r1: Rule<Finalized> =
+        "ip.dst in 10.0.0.0/8 && tcp.dport in 80,443 => allow prio 100"
+            .parse()
+            .unwrap();
+    assert_eq!(r1.priority(), 100);
+    assert!(matches!(r1.action(), Action::Allow));
+
+    let mut r2 = Rule::new(100, Action::Allow);
+    r2.add_predicate(Predicate::InnerDstIp4(vec![Ipv4AddrMatch::Prefix(
+        "10.0.0.0/8".parse().unwrap(),
+    )]));
+    r2.add_predicate(Predicate::InnerDstPort(vec![
+        PortMatch::Exact(80),
+        PortMatch::Exact(443),
+    ]));
+    assert_eq!(r1, r2.finalize());
+
+    let any: Rule<Finalized> =
+        "any => stateful allow prio 65535".parse().unwrap();
+    assert_eq!(any.priority(), 65535);
+    assert!(matches!(any.action(), Action::StatefulAllow));
+    assert_eq!(any, Rule::match_any(65535, Action::StatefulAllow));
+
+    assert!("ip.dst=10.0.0.0/8 => allow".parse::<Rule<Finalized>>().is_err());
+    assert!("ip.dst=10.0.0.0/8 prio 1".parse::<Rule<Finalized>>().is_err());
+    assert!("ip.dst=10.0.0.0/8 => nat prio 1"
+        .parse::<Rule<Finalized>>()
+        .is_err());
+    assert!("ip.dst=10.0.0.0/33 => deny prio 1"
+        .parse::<Rule<Finalized>>()
+        .is_err());
+    assert!("ip.bogus=1 => deny prio 1".parse::<Rule<Finalized>>().is_err());
+    assert!("tcp.dport=20-10 => deny prio 1"
+        .parse::<Rule<Finalized>>()
+        .is_err());
+    assert!("ip.dst=10.0.0.1 && => deny prio 1"
+        .parse::<Rule<Finalized>>()
+        .is_err());
+}
+
+#[test]
+fn rule_parse_round_trip() {
+    let rules = [
+        "inner.ether.ether_type=0x800 && inner.ether.dst=A8:40:25:FF:77:77 \
+         => Deny prio 10",
+        "inner.ip.proto=TCP,UDP,132 && inner.ip.src=10.0.0.1,172.30.0.0/22 \
+         && inner.ulp.src=22 => Stateful Allow prio 1",
+        "inner.ip6.dst=fd00::/8 && !inner.ulp.dst=53 => Allow prio 2",
+        "meta: router-target=ig && icmp.msg_type=echo request => Deny prio 3",
+        "!dhcp.msg_type=Discover && icmpv6.msg_type=128 => Allow prio 4",
+        "any => Deny prio 5",
+        "inner.ulp.dst=22,30000-32767 && icmp.code=3 => Allow prio 6",
+        "inner.ip.proto=TCP => Deny prio 7 log",
+    ];
+
+    for s in rules {
+        let rule: Rule<Finalized> = s.parse().unwrap();
+        let again: Rule<Finalized> = rule.to_string().parse().unwrap();
+        assert_eq!(rule, again);
+        assert_eq!(rule.to_string(), again.to_string());
+        assert_eq!(rule.priority(), again.priority());
+        assert_eq!(rule.log(), again.log());
+    }
+
+    assert!(rules[7].parse::<Rule<Finalized>>().unwrap().log());
+
+    // The predicate text used by `print_rule()` parses back into the
+    // same predicate.
+    let rule: Rule<Finalized> = rules[1].parse().unwrap();
+    let dump = super::ioctl::RuleDump::from(&rule);
+    for p in &dump.predicates {
+        assert_eq!(&p.parse::<Predicate>().unwrap().to_string(), p);
+    }
+}
@@ -0,0 +1,183 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! Rule logging.
+//!
+//! A rule may be marked to have its matches logged, see
+//! [`Rule::set_log()`]. The first packet of each flow matching such a
+//! rule is recorded in the layer's [`RuleLog`], along with the ID of
+//! the rule and its verdict. The log is a bounded ring: once full, the
+//! oldest entry makes room for the newest.
+//!
+//! A flow is only logged once for as long as its packets keep coming;
+//! this is what keeps a denied flow, which has no flow table entry to
+//! speak of, from filling the log with its retransmissions. The log
+//! forgets about a flow once it has been idle for the usual flow TTL,
+//! or when the layer's rules change.
+//!
+//! [`Rule::set_log()`]: super::rule::Rule::set_log
+use super::flow_record::FlowVerdict;
+use super::flow_table::Dump;
+use super::flow_table::FlowTable;
+use super::ioctl::DumpRuleLogResp;
+use super::layer::RuleId;
+use super::packet::InnerFlowId;
+use super::rule::Action;
+use crate::ddi::time::Moment;
+use core::fmt;
+use core::fmt::Display;
+use core::num::NonZeroU32;
+use opte_api::Direction;
+use serde::Deserialize;
+use serde::Serialize;
+
+cfg_if! {
+    if #[cfg(all(not(feature = "std"), not(test)))] {
+        use alloc::collections::VecDeque;
+        use alloc::format;
+    } else {
+        use std::collections::VecDeque;
+        use std::format;
+    }
+}
+
+/// The maximum number of entries a layer's log holds.
+pub const RULE_LOG_MAX: usize = 1024;
+
+/// A logged rule match.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RuleLogEntry {
+    /// The sequence number of the entry, starting at 1 and unique for
+    /// the life of the layer.
+    pub seq: u64,
+    /// The time of the match, as nanoseconds since the layer was
+    /// created.
+    pub time_ns: u64,
+    /// The ID of the matching rule.
+    pub rule_id: RuleId,
+    pub dir: Direction,
+    /// The flow of the packet, as seen by the layer.
+    pub flow: InnerFlowId,
+    pub verdict: FlowVerdict,
+}
+
+impl Display for RuleLogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} rule={} {} {}",
+            self.seq, self.dir, self.rule_id, self.flow, self.verdict,
+        )
+    }
+}
+
+// A flow which has been logged.
+#[derive(Clone, Debug)]
+struct Logged;
+
+impl Dump for Logged {
+    type DumpVal = ();
+
+    fn dump(&self, _hits: u64) {}
+}
+
+/// The log of a layer's rule matches.
+pub struct RuleLog {
+    start: Moment,
+    entries: VecDeque<RuleLogEntry>,
+    logged_in: FlowTable<Logged>,
+    logged_out: FlowTable<Logged>,
+    next_seq: u64,
+    lost: u64,
+}
+
+impl RuleLog {
+    pub(crate) fn new(port: &str, layer: &str, limit: NonZeroU32) -> Self {
+        Self {
+            start: Moment::now(),
+            entries: VecDeque::new(),
+            logged_in: FlowTable::new(
+                port,
+                &format!("{}_log_in", layer),
+                limit,
+                None,
+            ),
+            logged_out: FlowTable::new(
+                port,
+                &format!("{}_log_out", layer),
+                limit,
+                None,
+            ),
+            next_seq: 1,
+            lost: 0,
+        }
+    }
+
+    /// Log the match of rule `rule_id`, whose action is `action`, by
+    /// a packet of `flow`, unless the flow has already been logged.
+    pub(crate) fn record(
+        &mut self,
+        dir: Direction,
+        rule_id: RuleId,
+        action: &Action,
+        flow: &InnerFlowId,
+    ) {
+        let logged = match dir {
+            Direction::In => &mut self.logged_in,
+            Direction::Out => &mut self.logged_out,
+        };
+
+        if let Some(entry) = logged.get_mut(flow) {
+            entry.hit();
+            return;
+        }
+
+        // If the table is full the flow is logged regardless; better
+        // to log a flow twice than not at all.
+        let _ = logged.add(*flow, Logged);
+
+        if self.entries.len() >= RULE_LOG_MAX {
+            self.entries.pop_front();
+            self.lost += 1;
+        }
+
+        self.entries.push_back(RuleLogEntry {
+            seq: self.next_seq,
+            time_ns: Moment::now().delta_as_nanos(self.start),
+            rule_id,
+            dir,
+            flow: *flow,
+            verdict: match action {
+                Action::Deny => FlowVerdict::Deny,
+                _ => FlowVerdict::Allow,
+            },
+        });
+        self.next_seq += 1;
+    }
+
+    /// Forget about the flows logged so far, so that the next packet
+    /// of each is logged again.
+    pub(crate) fn clear_logged(&mut self) {
+        self.logged_in.clear();
+        self.logged_out.clear();
+    }
+
+    /// Return the entries of the log, oldest first.
+    pub(crate) fn dump(&self) -> DumpRuleLogResp {
+        DumpRuleLogResp {
+            elapsed_ns: Moment::now().delta_as_nanos(self.start),
+            lost: self.lost,
+            entries: self.entries.iter().cloned().collect(),
+        }
+    }
+
+    /// Forget about the logged flows which have been idle for longer
+    /// than the TTL.
+    pub(crate) fn expire(&mut self, now: Moment) {
+        let _ = self.logged_in.expire_flows(now, |_| ());
+        let _ = self.logged_out.expire_flows(now, |_| ());
+    }
+}
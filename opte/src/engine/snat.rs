@@ -6,7 +6,6 @@
 
 //! Types for working with IP Source NAT, both IPv4 and IPv6.
 
-use super::ether::EtherMod;
 use super::headers::HeaderAction;
 use super::headers::IpMod;
 use super::headers::UlpGenericModify;
@@ -34,31 +33,44 @@ use super::rule::Resource;
 use super::rule::ResourceEntry;
 use super::rule::ResourceError;
 use super::rule::StatefulAction;
+use crate::ddi::kstat;
+use crate::ddi::kstat::KStatNamed;
+use crate::ddi::kstat::KStatProvider;
+use crate::ddi::kstat::KStatU64;
 use crate::ddi::sync::KMutex;
 use crate::ddi::sync::KMutexType;
+use core::any::Any;
 use core::fmt;
 use core::fmt::Display;
 use core::marker::PhantomData;
 use core::ops::RangeInclusive;
+use kstat_macro::KStatProvider;
 use opte_api::Direction;
 use opte_api::IpAddr;
 use opte_api::Ipv4Addr;
 use opte_api::Ipv6Addr;
-use opte_api::MacAddr;
+use opte_api::NatMapping;
 use opte_api::Protocol;
+use serde::Deserialize;
+use serde::Serialize;
 use smoltcp::wire::Icmpv4Message;
 use smoltcp::wire::Icmpv4Packet;
+use smoltcp::wire::Icmpv6Message;
+use smoltcp::wire::Icmpv6Packet;
+use smoltcp::wire::IpAddress;
 
 cfg_if! {
     if #[cfg(all(not(feature = "std"), not(test)))] {
         use alloc::boxed::Box;
         use alloc::collections::btree_map::BTreeMap;
+        use alloc::collections::btree_set::BTreeSet;
         use alloc::string::ToString;
         use alloc::sync::Arc;
         use alloc::vec::Vec;
     } else {
         use std::boxed::Box;
         use std::collections::btree_map::BTreeMap;
+        use std::collections::btree_set::BTreeSet;
         use std::string::ToString;
         use std::sync::Arc;
         use std::vec::Vec;
@@ -73,25 +85,349 @@ pub struct NatPoolEntry<T: ConcreteIpAddr> {
     port: u16,
 }
 
-// A public IP and port range for NAT. Includes the list of all possible ports
-// and those that are free.
-#[derive(Debug, Clone)]
-struct PortList<T: ConcreteIpAddr> {
-    // The public IP address to which a private IP is mapped
+impl<T: ConcreteIpAddr> NatPoolEntry<T> {
+    /// The public IP of this entry.
+    pub fn ip(&self) -> T {
+        self.ip
+    }
+
+    /// The public port of this entry.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl<T: ConcreteIpAddr> ResourceEntry for NatPoolEntry<T> {}
+
+/// The flow for which an entry is obtained from, or released to, a
+/// [`NatPool`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct NatFlow<T: ConcreteIpAddr> {
+    pub priv_ip: T,
+    pub proto: Protocol,
+    pub priv_port: u16,
+    pub remote_ip: IpAddr,
+    pub remote_port: u16,
+}
+
+impl<T: ConcreteIpAddr> NatFlow<T> {
+    /// The NAT flow of the outbound flow `flow_id` from `priv_ip`.
+    pub fn new(priv_ip: T, flow_id: &InnerFlowId) -> Self {
+        Self {
+            priv_ip,
+            proto: flow_id.proto,
+            priv_port: flow_id.src_port,
+            remote_ip: flow_id.dst_ip,
+            remote_port: flow_id.dst_port,
+        }
+    }
+
+    // Two flows may share a port only if they differ in their remote
+    // endpoint, as the replies are told apart by it.
+    fn remote(&self) -> (Protocol, IpAddr, u16) {
+        (self.proto, self.remote_ip, self.remote_port)
+    }
+}
+
+impl<T: ConcreteIpAddr> From<&NatFlow<T>> for InnerFlowId {
+    fn from(flow: &NatFlow<T>) -> Self {
+        InnerFlowId {
+            proto: flow.proto,
+            src_ip: flow.priv_ip.into(),
+            src_port: flow.priv_port,
+            dst_ip: flow.remote_ip,
+            dst_port: flow.remote_port,
+        }
+    }
+}
+
+/// The counters of a private IP's mapping in a [`NatPool`].
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize,
+)]
+pub struct NatPoolStats {
+    /// The number of times a flow was refused a port because the
+    /// mapping's ports were exhausted.
+    pub exhausted: u64,
+
+    /// The number of times a flow was refused a port because the
+    /// private IP holds as many ports as its limit allows.
+    pub limited: u64,
+
+    /// The number of times a flow was given a port already in use by
+    /// other flows, as per the mapping's [`NatMapping`].
+    pub reused: u64,
+}
+
+/// The kstats of a [`NatPool`], summed over all of its mappings.
+#[derive(KStatProvider)]
+pub struct NatPoolKStats {
+    /// The number of ports in use.
+    ports_used: KStatU64,
+
+    /// The number of free ports, including those also free for the
+    /// pools of other ports sharing them.
+    ports_free: KStatU64,
+
+    /// The number of flows using the ports.
+    flows: KStatU64,
+
+    /// The number of flows refused a port because the ports were
+    /// exhausted.
+    alloc_fail_exhausted: KStatU64,
+
+    /// The number of flows refused a port because their private IP
+    /// reached its limit of ports.
+    alloc_fail_limit: KStatU64,
+
+    /// The number of flows given a port already in use.
+    ports_reused: KStatU64,
+}
+
+/// The state of a private IP's mapping in a [`NatPool`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NatMappingDump {
+    pub priv_ip: IpAddr,
+    pub external_ip: IpAddr,
+    pub ports: RangeInclusive<u16>,
+    pub mapping: NatMapping,
+    pub max_ports: Option<u16>,
+    pub ports_free: u32,
+    pub stats: NatPoolStats,
+
+    /// The ports in use, along with the outbound flows using each.
+    pub ports_used: Vec<(u16, Vec<InnerFlowId>)>,
+}
+
+/// The ports of a public IP and port range, along with those that are
+/// free.
+///
+/// The range may be shared by the [`NatPool`]s of several ports, all
+/// taking their ports from the same free list. How many of them a
+/// private IP may hold at once is then capped by the `max_ports` of
+/// its mapping, see [`NatPool::add_shared()`].
+pub struct NatPorts<T: ConcreteIpAddr> {
     ip: T,
-    // The list of all possible ports available in the NAT pool
     ports: RangeInclusive<u16>,
-    // The list of unused / free ports in the pool
-    free_ports: Vec<u16>,
+    free: KMutex<Vec<u16>>,
 }
 
-impl<T: ConcreteIpAddr> ResourceEntry for NatPoolEntry<T> {}
+impl<T: ConcreteIpAddr> NatPorts<T> {
+    /// The public IP of these ports.
+    pub fn ip(&self) -> T {
+        self.ip
+    }
+
+    /// The range of these ports.
+    pub fn ports(&self) -> &RangeInclusive<u16> {
+        &self.ports
+    }
+
+    /// Return the number of free ports.
+    pub fn num_free(&self) -> usize {
+        self.free.lock().len()
+    }
+
+    /// Do these ports overlap `ports` of the public IP `ip`?
+    pub fn overlaps(&self, ip: T, ports: &RangeInclusive<u16>) -> bool {
+        self.ip == ip
+            && self.ports.start() <= ports.end()
+            && ports.start() <= self.ports.end()
+    }
+
+    /// Create the ports `ports` of the public IP `ip`, all free.
+    pub fn new(ip: T, ports: RangeInclusive<u16>) -> Self {
+        let free = ports.clone().collect();
+        Self { ip, ports, free: KMutex::new(free, KMutexType::Driver) }
+    }
+
+    fn take(&self) -> Option<u16> {
+        self.free.lock().pop()
+    }
+
+    fn put(&self, port: u16) {
+        self.free.lock().push(port);
+    }
+}
+
+// A private IP's mapping to the ports of a public IP, along with the
+// ports it holds.
+struct PortList<T: ConcreteIpAddr> {
+    // The public IP and ports to which a private IP is mapped, which
+    // may be shared with other mappings
+    ports: Arc<NatPorts<T>>,
+    // How flows are mapped to ports
+    mapping: NatMapping,
+    // The maximum number of ports in use at once, if less than all of
+    // `ports`
+    max_ports: Option<u16>,
+    // The ports in use, along with the flows using each
+    used_ports: BTreeMap<u16, Vec<NatFlow<T>>>,
+    // For `EndpointIndependent`: the port of each private protocol
+    // and port in use
+    eim_ports: BTreeMap<(Protocol, u16), u16>,
+    // For `PortReuse`: the ports in use with each remote endpoint
+    remote_ports: BTreeMap<(Protocol, IpAddr, u16), BTreeSet<u16>>,
+    stats: NatPoolStats,
+}
+
+impl<T: ConcreteIpAddr> PortList<T> {
+    // Return the port to use for `flow`, and whether it is a reused
+    // one.
+    fn obtain(
+        &mut self,
+        flow: &NatFlow<T>,
+    ) -> Result<(u16, bool), ResourceError> {
+        if self.mapping == NatMapping::EndpointIndependent {
+            let key = (flow.proto, flow.priv_port);
+            if let Some(port) = self.eim_ports.get(&key).copied() {
+                return Ok(self.reuse(port, flow));
+            }
+        }
+
+        let limited = match self.max_ports {
+            Some(max) => self.used_ports.len() >= usize::from(max),
+            None => false,
+        };
+
+        let free = if limited { None } else { self.ports.take() };
+        let port = match free {
+            Some(port) => port,
+
+            // Reuse a port only once the free ports are exhausted, or
+            // the private IP holds as many ports as its limit allows.
+            None => {
+                if self.mapping == NatMapping::PortReuse {
+                    if let Some(port) = self.reusable(flow) {
+                        return Ok(self.reuse(port, flow));
+                    }
+                }
+
+                if limited {
+                    self.stats.limited += 1;
+                    return Err(ResourceError::LimitReached);
+                }
+
+                self.stats.exhausted += 1;
+                return Err(ResourceError::Exhausted);
+            }
+        };
+
+        self.used_ports.insert(port, vec![*flow]);
+        match self.mapping {
+            NatMapping::EndpointIndependent => {
+                self.eim_ports.insert((flow.proto, flow.priv_port), port);
+            }
+
+            NatMapping::PortReuse => {
+                self.remote_ports
+                    .entry(flow.remote())
+                    .or_default()
+                    .insert(port);
+            }
+
+            NatMapping::PerFlow => (),
+        }
+        Ok((port, false))
+    }
+
+    // Return a port in use which `flow` may share.
+    //
+    // The ports in use are walked in order, skipping those already in
+    // use with the flow's remote endpoint; so this costs one step per
+    // such port, not one per port in use.
+    fn reusable(&self, flow: &NatFlow<T>) -> Option<u16> {
+        let taken = self.remote_ports.get(&flow.remote());
+        self.used_ports
+            .keys()
+            .find(|port| match taken {
+                Some(taken) => !taken.contains(port),
+                None => true,
+            })
+            .copied()
+    }
+
+    // Add `flow` to the flows using `port`.
+    fn reuse(&mut self, port: u16, flow: &NatFlow<T>) -> (u16, bool) {
+        // Unwrap: The caller found the port in use.
+        self.used_ports.get_mut(&port).unwrap().push(*flow);
+        if self.mapping == NatMapping::PortReuse {
+            self.remote_ports.entry(flow.remote()).or_default().insert(port);
+        }
+        self.stats.reused += 1;
+        (port, true)
+    }
+
+    // Release `port` from `flow`, returning whether the port is now
+    // free, or `None` if `flow` does not hold `port`.
+    //
+    // A port unknown to this list was obtained from a mapping since
+    // replaced by `NatPool::add()`, which gave it back on drop.
+    fn release(&mut self, flow: &NatFlow<T>, port: u16) -> Option<bool> {
+        let flows = self.used_ports.get_mut(&port)?;
+        let idx = flows.iter().position(|f| f == flow)?;
+        flows.swap_remove(idx);
+
+        if self.mapping == NatMapping::PortReuse {
+            let remote = flow.remote();
+            if let Some(taken) = self.remote_ports.get_mut(&remote) {
+                taken.remove(&port);
+                if taken.is_empty() {
+                    self.remote_ports.remove(&remote);
+                }
+            }
+        }
+
+        if !flows.is_empty() {
+            return Some(false);
+        }
+
+        self.used_ports.remove(&port);
+        if self.mapping == NatMapping::EndpointIndependent {
+            self.eim_ports.remove(&(flow.proto, flow.priv_port));
+        }
+        self.ports.put(port);
+        Some(true)
+    }
+
+    fn dump(&self, priv_ip: T) -> NatMappingDump {
+        NatMappingDump {
+            priv_ip: priv_ip.into(),
+            external_ip: self.ports.ip().into(),
+            ports: self.ports.ports().clone(),
+            mapping: self.mapping,
+            max_ports: self.max_ports,
+            ports_free: self.ports.num_free() as u32,
+            stats: self.stats,
+            ports_used: self
+                .used_ports
+                .iter()
+                .map(|(port, flows)| {
+                    (*port, flows.iter().map(InnerFlowId::from).collect())
+                })
+                .collect(),
+        }
+    }
+}
+
+// The ports still in use when a mapping goes away, because it was
+// replaced or its pool was dropped along with its port, are given
+// back for the other mappings sharing them.
+impl<T: ConcreteIpAddr> Drop for PortList<T> {
+    fn drop(&mut self) {
+        for port in self.used_ports.keys() {
+            self.ports.put(*port);
+        }
+    }
+}
 
 /// A mapping from private IP addresses to a public IP and a port range used for
 /// NAT-ing connections.
 pub struct NatPool<T: ConcreteIpAddr> {
     // Map private IP to public IP + free list of ports
     free_list: KMutex<BTreeMap<T, PortList<T>>>,
+    // The kstats of all mappings, taken while holding `free_list`
+    stats: KMutex<KStatNamed<NatPoolKStats>>,
 }
 
 mod private {
@@ -107,17 +443,67 @@ pub trait ConcreteIpAddr: private::Ip + Copy + Clone + Display + Ord {}
 impl<T: Copy + Clone + Display + Ord> ConcreteIpAddr for T where T: private::Ip {}
 
 impl<T: ConcreteIpAddr> NatPool<T> {
-    /// Add a new mapping from private IP to public IP and ports.
-    pub fn add(&self, priv_ip: T, pub_ip: T, pub_ports: RangeInclusive<u16>) {
-        let free_ports = pub_ports.clone().collect();
-        let entry = PortList { ip: pub_ip, ports: pub_ports, free_ports };
-        self.free_list.lock().insert(priv_ip, entry);
+    /// Add a new mapping from private IP to public IP and ports, with
+    /// flows mapped to ports as per `mapping`. The ports are the
+    /// mapping's own; see [`NatPool::add_shared()`] for the meaning
+    /// of `max_ports`.
+    pub fn add(
+        &self,
+        priv_ip: T,
+        pub_ip: T,
+        pub_ports: RangeInclusive<u16>,
+        mapping: NatMapping,
+        max_ports: Option<u16>,
+    ) {
+        let ports = Arc::new(NatPorts::new(pub_ip, pub_ports));
+        self.add_shared(priv_ip, ports, mapping, max_ports);
+    }
+
+    /// Add a new mapping from private IP to `ports`, which may be
+    /// shared with the mappings of other pools, with flows mapped to
+    /// ports as per `mapping`.
+    ///
+    /// The private IP may hold at most `max_ports` of the ports at
+    /// once, leaving the rest to the others sharing them; past that,
+    /// a flow is refused a port unless `mapping` is
+    /// [`NatMapping::PortReuse`], which reuses one already held.
+    pub fn add_shared(
+        &self,
+        priv_ip: T,
+        ports: Arc<NatPorts<T>>,
+        mapping: NatMapping,
+        max_ports: Option<u16>,
+    ) {
+        let entry = PortList {
+            ports,
+            mapping,
+            max_ports,
+            used_ports: BTreeMap::new(),
+            eim_ports: BTreeMap::new(),
+            remote_ports: BTreeMap::new(),
+            stats: NatPoolStats::default(),
+        };
+
+        let mut lock = self.free_list.lock();
+        let mut stats = self.stats.lock();
+        if let Some(old) = lock.insert(priv_ip, entry) {
+            stats.vals.ports_used -= old.used_ports.len() as u64;
+            stats.vals.flows -=
+                old.used_ports.values().map(|f| f.len() as u64).sum::<u64>();
+        }
+        stats.vals.ports_free.set(Self::ports_free(&lock));
+    }
+
+    // The number of free ports of all mappings, including those free
+    // for the other pools sharing them.
+    fn ports_free(lists: &BTreeMap<T, PortList<T>>) -> u64 {
+        lists.values().map(|list| list.ports.num_free() as u64).sum()
     }
 
     /// Return the number of available ports for a given private IP address.
     pub fn num_avail(&self, priv_ip: T) -> Result<usize, ResourceError> {
         match self.free_list.lock().get(&priv_ip) {
-            Some(PortList { free_ports, .. }) => Ok(free_ports.len()),
+            Some(list) => Ok(list.ports.num_free()),
             _ => Err(ResourceError::NoMatch(priv_ip.to_string())),
         }
     }
@@ -127,23 +513,52 @@ impl<T: ConcreteIpAddr> NatPool<T> {
         self.free_list
             .lock()
             .get(&priv_ip)
-            .map(|PortList { ip, ports, .. }| (ip.clone(), ports.clone()))
+            .map(|list| (list.ports.ip(), list.ports.ports().clone()))
+    }
+
+    /// Return the counters of the mapping of a private IP address.
+    pub fn stats(&self, priv_ip: T) -> Result<NatPoolStats, ResourceError> {
+        match self.free_list.lock().get(&priv_ip) {
+            Some(PortList { stats, .. }) => Ok(*stats),
+            _ => Err(ResourceError::NoMatch(priv_ip.to_string())),
+        }
+    }
+
+    /// Return a snapshot of the pool's kstats.
+    pub fn kstats_snap(&self) -> NatPoolKStatsSnap {
+        self.stats.lock().vals.snapshot()
+    }
+
+    /// Dump the state of all mappings.
+    pub fn dump(&self) -> Vec<NatMappingDump> {
+        self.free_list
+            .lock()
+            .iter()
+            .map(|(priv_ip, list)| list.dump(*priv_ip))
+            .collect()
     }
 
-    /// Create a new NAT pool, with no entries.
-    pub fn new() -> Self {
-        NatPool { free_list: KMutex::new(BTreeMap::new(), KMutexType::Driver) }
+    /// Create a new NAT pool, with no entries, whose kstats are
+    /// registered under `name`.
+    pub fn new(name: &str) -> Self {
+        // Unwrap: We know this is fine because the stat names are
+        // generated from the NatPoolKStats structure.
+        let stats = KStatNamed::new("xde", name, NatPoolKStats::new()).unwrap();
+        NatPool {
+            free_list: KMutex::new(BTreeMap::new(), KMutexType::Driver),
+            stats: KMutex::new(stats, KMutexType::Driver),
+        }
     }
 
     // A helper function to verify correct operation during testing.
     #[cfg(test)]
     fn verify_available(&self, priv_ip: T, pub_ip: T, pub_port: u16) -> bool {
         match self.free_list.lock().get(&priv_ip) {
-            Some(PortList { ip, free_ports, .. }) => {
-                if pub_ip != *ip {
+            Some(list) => {
+                if pub_ip != list.ports.ip() {
                     return false;
                 }
-                free_ports.contains(&pub_port)
+                list.ports.free.lock().contains(&pub_port)
             }
             None => false,
         }
@@ -153,31 +568,67 @@ impl<T: ConcreteIpAddr> NatPool<T> {
 impl<T: ConcreteIpAddr> Resource for NatPool<T> {}
 
 impl<T: ConcreteIpAddr> FiniteResource for NatPool<T> {
-    type Key = T;
+    type Key = NatFlow<T>;
     type Entry = NatPoolEntry<T>;
 
-    fn obtain(&self, priv_ip: &T) -> Result<Self::Entry, ResourceError> {
-        match self.free_list.lock().get_mut(&priv_ip) {
-            Some(PortList { ip, free_ports, .. }) => {
-                if let Some(port) = free_ports.pop() {
-                    Ok(Self::Entry { ip: *ip, port })
+    fn obtain(&self, flow: &NatFlow<T>) -> Result<Self::Entry, ResourceError> {
+        let mut lock = self.free_list.lock();
+        let list = match lock.get_mut(&flow.priv_ip) {
+            Some(list) => list,
+            None => {
+                return Err(ResourceError::NoMatch(flow.priv_ip.to_string()))
+            }
+        };
+
+        let ip = list.ports.ip();
+        let res = list.obtain(flow);
+        let mut stats = self.stats.lock();
+        stats.vals.ports_free.set(Self::ports_free(&lock));
+        match res {
+            Ok((port, reused)) => {
+                stats.vals.flows += 1;
+                if reused {
+                    stats.vals.ports_reused += 1;
                 } else {
-                    Err(ResourceError::Exhausted)
+                    stats.vals.ports_used += 1;
                 }
+                Ok(Self::Entry { ip, port })
             }
 
-            None => Err(ResourceError::NoMatch(priv_ip.to_string())),
+            Err(e) => {
+                match e {
+                    ResourceError::LimitReached => {
+                        stats.vals.alloc_fail_limit += 1
+                    }
+                    _ => stats.vals.alloc_fail_exhausted += 1,
+                }
+                Err(e)
+            }
         }
     }
 
-    fn release(&self, priv_ip: &T, entry: Self::Entry) {
-        match self.free_list.lock().get_mut(&priv_ip) {
-            Some(PortList { free_ports, .. }) => {
-                free_ports.push(entry.port);
+    fn release(&self, flow: &NatFlow<T>, entry: Self::Entry) {
+        let mut lock = self.free_list.lock();
+        match lock.get_mut(&flow.priv_ip) {
+            Some(list) => {
+                let freed = match list.release(flow, entry.port) {
+                    Some(freed) => freed,
+                    None => return,
+                };
+
+                let mut stats = self.stats.lock();
+                stats.vals.flows -= 1;
+                if freed {
+                    stats.vals.ports_used -= 1;
+                }
+                stats.vals.ports_free.set(Self::ports_free(&lock));
             }
 
             None => {
-                panic!("cannot release port to unknown mapping: {}", priv_ip);
+                panic!(
+                    "cannot release port to unknown mapping: {}",
+                    flow.priv_ip
+                );
             }
         }
     }
@@ -188,23 +639,23 @@ impl<T: ConcreteIpAddr> FiniteResource for NatPool<T> {
 pub struct SNat {
     priv_ip: Ipv4Addr,
     ip_pool: Arc<NatPool<Ipv4Addr>>,
-    // XXX-EXT-IP
-    phys_gw_mac: Option<MacAddr>,
 }
 
 impl SNat {
-    pub fn new(
-        addr: Ipv4Addr,
-        ip_pool: Arc<NatPool<Ipv4Addr>>,
-        phys_gw_mac: Option<MacAddr>,
-    ) -> Self {
-        SNat { priv_ip: addr, ip_pool, phys_gw_mac }
+    pub fn new(addr: Ipv4Addr, ip_pool: Arc<NatPool<Ipv4Addr>>) -> Self {
+        SNat { priv_ip: addr, ip_pool }
+    }
+
+    /// The pool from which this action obtains its ports.
+    pub fn pool(&self) -> &Arc<NatPool<Ipv4Addr>> {
+        &self.ip_pool
     }
 
     // A helper method for generating an SNAT + ICMP action descriptor.
     fn gen_icmp_desc(
         &self,
         nat: NatPoolEntry<Ipv4Addr>,
+        flow: NatFlow<Ipv4Addr>,
         pkt: &Packet<Parsed>,
     ) -> GenDescResult {
         if let Some(body_segs) = pkt.body_segs() {
@@ -228,12 +679,11 @@ impl SNat {
 
             let desc = SNatIcmpEchoDesc {
                 pool: self.ip_pool.clone(),
-                priv_ip: self.priv_ip,
+                flow,
                 nat,
                 // Panic: We know this is safe because we make it here
                 // only if this ICMP message is an Echo Request.
                 echo_ident: icmp.echo_ident(),
-                phys_gw_mac: self.phys_gw_mac,
             };
 
             Ok(AllowOrDeny::Allow(Arc::new(desc)))
@@ -258,19 +708,21 @@ impl StatefulAction for SNat {
         _meta: &mut ActionMeta,
     ) -> GenDescResult {
         let pool = &self.ip_pool;
-        let priv_port = flow_id.src_port;
-        match pool.obtain(&self.priv_ip.into()) {
+        let flow = NatFlow::new(self.priv_ip, flow_id);
+        match pool.obtain(&flow) {
             Ok(nat) => match flow_id.proto {
-                Protocol::ICMP => self.gen_icmp_desc(nat, pkt),
+                Protocol::ICMP => {
+                    // The port goes back to the pool if no descriptor
+                    // takes ownership of it.
+                    let res = self.gen_icmp_desc(nat, flow, pkt);
+                    if res.is_err() {
+                        pool.release(&flow, nat);
+                    }
+                    res
+                }
 
                 _ => {
-                    let desc = SNatDesc {
-                        pool: pool.clone(),
-                        priv_ip: self.priv_ip.into(),
-                        priv_port: priv_port,
-                        phys_gw_mac: self.phys_gw_mac,
-                        nat,
-                    };
+                    let desc = SNatDesc { pool: pool.clone(), flow, nat };
 
                     Ok(AllowOrDeny::Allow(Arc::new(desc)))
                 }
@@ -282,6 +734,12 @@ impl StatefulAction for SNat {
                 });
             }
 
+            Err(ResourceError::LimitReached) => {
+                return Err(GenDescError::ResourceExhausted {
+                    name: "SNAT Pool (port limit)".to_string(),
+                });
+            }
+
             Err(ResourceError::NoMatch(ip)) => {
                 return Err(GenDescError::Unexpected {
                     msg: format!("SNAT pool (no match: {})", ip),
@@ -296,23 +754,69 @@ impl StatefulAction for SNat {
     fn implicit_preds(&self) -> (Vec<Predicate>, Vec<DataPredicate>) {
         (vec![], vec![])
     }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        Some(self)
+    }
 }
 
 #[derive(Clone)]
 pub struct SNat6 {
     priv_ip: Ipv6Addr,
     ip_pool: Arc<NatPool<Ipv6Addr>>,
-    // XXX-EXT-IP
-    phys_gw_mac: Option<MacAddr>,
 }
 
 impl SNat6 {
-    pub fn new(
-        addr: Ipv6Addr,
-        ip_pool: Arc<NatPool<Ipv6Addr>>,
-        phys_gw_mac: Option<MacAddr>,
-    ) -> Self {
-        SNat6 { priv_ip: addr, ip_pool, phys_gw_mac }
+    pub fn new(addr: Ipv6Addr, ip_pool: Arc<NatPool<Ipv6Addr>>) -> Self {
+        SNat6 { priv_ip: addr, ip_pool }
+    }
+
+    /// The pool from which this action obtains its ports.
+    pub fn pool(&self) -> &Arc<NatPool<Ipv6Addr>> {
+        &self.ip_pool
+    }
+
+    // A helper method for generating an SNAT + ICMPv6 action descriptor.
+    fn gen_icmp6_desc(
+        &self,
+        nat: NatPoolEntry<Ipv6Addr>,
+        flow: NatFlow<Ipv6Addr>,
+        pkt: &Packet<Parsed>,
+    ) -> GenDescResult {
+        if let Some(body_segs) = pkt.body_segs() {
+            let icmp = match Icmpv6Packet::new_checked(body_segs[0]) {
+                Ok(icmp) => icmp,
+                Err(e) => {
+                    return Err(GenDescError::Unexpected {
+                        msg: format!("Failed to parse ICMPv6: {}", e),
+                    });
+                }
+            };
+
+            if icmp.msg_type() != Icmpv6Message::EchoRequest {
+                return Err(GenDescError::Unexpected {
+                    msg: format!(
+                        "Expected ICMPv6 Echo Request, found: {}",
+                        icmp.msg_type()
+                    ),
+                });
+            }
+
+            let desc = SNatIcmpEchoDesc {
+                pool: self.ip_pool.clone(),
+                flow,
+                nat,
+                // Panic: We know this is safe because we make it here
+                // only if this ICMPv6 message is an Echo Request.
+                echo_ident: icmp.echo_ident(),
+            };
+
+            Ok(AllowOrDeny::Allow(Arc::new(desc)))
+        } else {
+            Err(GenDescError::Unexpected {
+                msg: format!("No ICMPv6 body found"),
+            })
+        }
     }
 }
 
@@ -320,23 +824,29 @@ impl StatefulAction for SNat6 {
     fn gen_desc(
         &self,
         flow_id: &InnerFlowId,
-        _pkt: &Packet<Parsed>,
+        pkt: &Packet<Parsed>,
         _meta: &mut ActionMeta,
     ) -> GenDescResult {
         let pool = &self.ip_pool;
-        let priv_port = flow_id.src_port;
-        match pool.obtain(&self.priv_ip) {
-            Ok(nat) => {
-                let desc = SNatDesc {
-                    pool: pool.clone(),
-                    priv_ip: self.priv_ip.into(),
-                    priv_port: priv_port,
-                    phys_gw_mac: self.phys_gw_mac,
-                    nat,
-                };
+        let flow = NatFlow::new(self.priv_ip, flow_id);
+        match pool.obtain(&flow) {
+            Ok(nat) => match flow_id.proto {
+                Protocol::ICMPv6 => {
+                    // The port goes back to the pool if no descriptor
+                    // takes ownership of it.
+                    let res = self.gen_icmp6_desc(nat, flow, pkt);
+                    if res.is_err() {
+                        pool.release(&flow, nat);
+                    }
+                    res
+                }
 
-                Ok(AllowOrDeny::Allow(Arc::new(desc)))
-            }
+                _ => {
+                    let desc = SNatDesc { pool: pool.clone(), flow, nat };
+
+                    Ok(AllowOrDeny::Allow(Arc::new(desc)))
+                }
+            },
 
             Err(ResourceError::Exhausted) => {
                 return Err(GenDescError::ResourceExhausted {
@@ -344,6 +854,12 @@ impl StatefulAction for SNat6 {
                 });
             }
 
+            Err(ResourceError::LimitReached) => {
+                return Err(GenDescError::ResourceExhausted {
+                    name: "SNAT Pool (port limit)".to_string(),
+                });
+            }
+
             Err(ResourceError::NoMatch(ip)) => {
                 return Err(GenDescError::Unexpected {
                     msg: format!("SNAT pool (no match: {})", ip),
@@ -358,6 +874,10 @@ impl StatefulAction for SNat6 {
     fn implicit_preds(&self) -> (Vec<Predicate>, Vec<DataPredicate>) {
         (vec![], vec![])
     }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        Some(self)
+    }
 }
 
 impl Display for SNat6 {
@@ -371,10 +891,7 @@ impl Display for SNat6 {
 pub struct SNatDesc<T: ConcreteIpAddr> {
     pool: Arc<NatPool<T>>,
     nat: NatPoolEntry<T>,
-    priv_ip: T,
-    priv_port: u16,
-    // XXX-EXT-IP
-    phys_gw_mac: Option<MacAddr>,
+    flow: NatFlow<T>,
 }
 
 pub const SNAT_NAME: &'static str = "SNAT";
@@ -389,7 +906,7 @@ impl ActionDesc for SNatDesc<Ipv4Addr> {
                     ..Default::default()
                 });
 
-                let mut ht = HdrTransform {
+                HdrTransform {
                     name: SNAT_NAME.to_string(),
                     inner_ip: HeaderAction::Modify(ip, PhantomData),
                     inner_ulp: UlpHeaderAction::Modify(UlpMetaModify {
@@ -400,22 +917,7 @@ impl ActionDesc for SNatDesc<Ipv4Addr> {
                         ..Default::default()
                     }),
                     ..Default::default()
-                };
-
-                // XXX-EXT-IP hack to rewrite destination MAC adress
-                // from virtual gateway addr to the real gateway addr
-                // on the same subnet as the external IP.
-                if self.phys_gw_mac.is_some() {
-                    ht.inner_ether = HeaderAction::Modify(
-                        EtherMod {
-                            dst: Some(self.phys_gw_mac.unwrap()),
-                            ..Default::default()
-                        },
-                        PhantomData,
-                    );
                 }
-
-                ht
             }
 
             // Inbound traffic needs its destination IP and
@@ -423,7 +925,7 @@ impl ActionDesc for SNatDesc<Ipv4Addr> {
             // the guest expects to see.
             Direction::In => {
                 let ip = IpMod::from(Ipv4Mod {
-                    dst: Some(self.priv_ip),
+                    dst: Some(self.flow.priv_ip),
                     ..Default::default()
                 });
                 HdrTransform {
@@ -431,7 +933,7 @@ impl ActionDesc for SNatDesc<Ipv4Addr> {
                     inner_ip: HeaderAction::Modify(ip, PhantomData),
                     inner_ulp: UlpHeaderAction::Modify(UlpMetaModify {
                         generic: UlpGenericModify {
-                            dst_port: Some(self.priv_port),
+                            dst_port: Some(self.flow.priv_port),
                             ..Default::default()
                         },
                         ..Default::default()
@@ -456,7 +958,7 @@ impl ActionDesc for SNatDesc<Ipv6Addr> {
                     src: Some(self.nat.ip),
                     ..Default::default()
                 });
-                let mut ht = HdrTransform {
+                HdrTransform {
                     name: SNAT_NAME.to_string(),
                     inner_ip: HeaderAction::Modify(ip, PhantomData),
                     inner_ulp: UlpHeaderAction::Modify(UlpMetaModify {
@@ -467,22 +969,7 @@ impl ActionDesc for SNatDesc<Ipv6Addr> {
                         ..Default::default()
                     }),
                     ..Default::default()
-                };
-
-                // XXX-EXT-IP hack to rewrite destination MAC adress
-                // from virtual gateway addr to the real gateway addr
-                // on the same subnet as the external IP.
-                if self.phys_gw_mac.is_some() {
-                    ht.inner_ether = HeaderAction::Modify(
-                        EtherMod {
-                            dst: Some(self.phys_gw_mac.unwrap()),
-                            ..Default::default()
-                        },
-                        PhantomData,
-                    );
                 }
-
-                ht
             }
 
             // Inbound traffic needs its destination IP and
@@ -490,7 +977,7 @@ impl ActionDesc for SNatDesc<Ipv6Addr> {
             // the guest expects to see.
             Direction::In => {
                 let ip = IpMod::from(Ipv6Mod {
-                    dst: Some(self.priv_ip),
+                    dst: Some(self.flow.priv_ip),
                     ..Default::default()
                 });
                 HdrTransform {
@@ -498,7 +985,7 @@ impl ActionDesc for SNatDesc<Ipv6Addr> {
                     inner_ip: HeaderAction::Modify(ip, PhantomData),
                     inner_ulp: UlpHeaderAction::Modify(UlpMetaModify {
                         generic: UlpGenericModify {
-                            dst_port: Some(self.priv_port),
+                            dst_port: Some(self.flow.priv_port),
                             ..Default::default()
                         },
                         ..Default::default()
@@ -516,23 +1003,21 @@ impl ActionDesc for SNatDesc<Ipv6Addr> {
 
 impl<T: ConcreteIpAddr> Drop for SNatDesc<T> {
     fn drop(&mut self) {
-        self.pool.release(&self.priv_ip, self.nat);
+        self.pool.release(&self.flow, self.nat);
     }
 }
 
 #[derive(Clone)]
-pub struct SNatIcmpEchoDesc {
-    pool: Arc<NatPool<Ipv4Addr>>,
-    nat: NatPoolEntry<Ipv4Addr>,
-    priv_ip: Ipv4Addr,
+pub struct SNatIcmpEchoDesc<T: ConcreteIpAddr> {
+    pool: Arc<NatPool<T>>,
+    nat: NatPoolEntry<T>,
+    flow: NatFlow<T>,
     echo_ident: u16,
-    // XXX-EXT-IP
-    phys_gw_mac: Option<MacAddr>,
 }
 
 pub const SNAT_ICMP_ECHO_NAME: &'static str = "SNAT_ICMP_ECHO";
 
-impl ActionDesc for SNatIcmpEchoDesc {
+impl ActionDesc for SNatIcmpEchoDesc<Ipv4Addr> {
     fn gen_ht(&self, dir: Direction) -> HdrTransform {
         match dir {
             // Outbound traffic needs its source IP and source port
@@ -541,26 +1026,11 @@ impl ActionDesc for SNatIcmpEchoDesc {
                     src: Some(self.nat.ip),
                     ..Default::default()
                 });
-                let mut ht = HdrTransform {
+                HdrTransform {
                     name: SNAT_NAME.to_string(),
                     inner_ip: HeaderAction::Modify(ip, PhantomData),
                     ..Default::default()
-                };
-
-                // XXX-EXT-IP hack to rewrite destination MAC adress
-                // from virtual gateway addr to the real gateway addr
-                // on the same subnet as the external IP.
-                if self.phys_gw_mac.is_some() {
-                    ht.inner_ether = HeaderAction::Modify(
-                        EtherMod {
-                            dst: Some(self.phys_gw_mac.unwrap()),
-                            ..Default::default()
-                        },
-                        PhantomData,
-                    );
                 }
-
-                ht
             }
 
             // Inbound traffic needs its destination IP and
@@ -568,7 +1038,7 @@ impl ActionDesc for SNatIcmpEchoDesc {
             // the guest expects to see.
             Direction::In => {
                 let ip = IpMod::from(Ipv4Mod {
-                    dst: Some(self.priv_ip),
+                    dst: Some(self.flow.priv_ip),
                     ..Default::default()
                 });
                 HdrTransform {
@@ -596,9 +1066,74 @@ impl ActionDesc for SNatIcmpEchoDesc {
     }
 }
 
-impl Drop for SNatIcmpEchoDesc {
+impl ActionDesc for SNatIcmpEchoDesc<Ipv6Addr> {
+    fn gen_ht(&self, dir: Direction) -> HdrTransform {
+        match dir {
+            // Outbound traffic needs its source IP and source port
+            Direction::Out => {
+                let ip = IpMod::from(Ipv6Mod {
+                    src: Some(self.nat.ip),
+                    ..Default::default()
+                });
+                HdrTransform {
+                    name: SNAT_NAME.to_string(),
+                    inner_ip: HeaderAction::Modify(ip, PhantomData),
+                    ..Default::default()
+                }
+            }
+
+            // Inbound traffic needs its destination IP and
+            // destination port mapped back to the private values that
+            // the guest expects to see.
+            Direction::In => {
+                let ip = IpMod::from(Ipv6Mod {
+                    dst: Some(self.flow.priv_ip),
+                    ..Default::default()
+                });
+                HdrTransform {
+                    name: SNAT_NAME.to_string(),
+                    inner_ip: HeaderAction::Modify(ip, PhantomData),
+                    ..Default::default()
+                }
+            }
+        }
+    }
+
+    // As with ICMP, the Echo Identifier is treated as a pseudo ULP
+    // port. The ICMPv6 checksum also covers the IPv6 pseudo-header,
+    // so the transform must know the addresses it ends up with.
+    fn gen_bt(
+        &self,
+        _dir: Direction,
+        _meta: &PacketMeta,
+        _payload_segs: &[&[u8]],
+    ) -> Result<Option<Box<dyn BodyTransform>>, GenBtError> {
+        let remote_ip = match self.flow.remote_ip {
+            IpAddr::Ip6(ip6) => ip6,
+            IpAddr::Ip4(_) => {
+                return Err(GenBtError::ParseBody(format!(
+                    "expected IPv6 remote for ICMPv6 flow: {}",
+                    self.flow.remote_ip
+                )));
+            }
+        };
+
+        Ok(Some(Box::new(SNatIcmp6EchoBt {
+            ident: self.echo_ident,
+            nat: self.nat,
+            priv_ip: self.flow.priv_ip,
+            remote_ip,
+        })))
+    }
+
+    fn name(&self) -> &str {
+        SNAT_ICMP_ECHO_NAME
+    }
+}
+
+impl<T: ConcreteIpAddr> Drop for SNatIcmpEchoDesc<T> {
     fn drop(&mut self) {
-        self.pool.release(&self.priv_ip.into(), self.nat);
+        self.pool.release(&self.flow, self.nat);
     }
 }
 
@@ -659,14 +1194,74 @@ impl Display for SNatIcmpEchoBt {
     }
 }
 
+/// Perform SNAT for ICMPv6 Echo/Reply messages, treating the
+/// Identifier as a source port.
+#[derive(Clone)]
+pub struct SNatIcmp6EchoBt {
+    ident: u16,
+    nat: NatPoolEntry<Ipv6Addr>,
+    priv_ip: Ipv6Addr,
+    remote_ip: Ipv6Addr,
+}
+
+impl BodyTransform for SNatIcmp6EchoBt {
+    fn run(
+        &self,
+        dir: Direction,
+        body: &mut [&mut [u8]],
+    ) -> Result<(), BodyTransformError> {
+        use Icmpv6Message::EchoReply;
+        use Icmpv6Message::EchoRequest;
+
+        let mut icmp = Icmpv6Packet::new_checked(&mut *body[0])?;
+        let remote_ip = IpAddress::Ipv6(self.remote_ip.into());
+
+        // The checksum is computed over the addresses the packet has
+        // once all header transformations are done.
+        let (src, dst) = match (icmp.msg_type(), dir) {
+            (EchoReply | EchoRequest, Direction::Out) => {
+                // Panic: We know this is safe because we make it here
+                // only if this ICMPv6 message is an Echo/Reply.
+                icmp.set_echo_ident(self.nat.port);
+                let nat_ip = IpAddress::Ipv6(self.nat.ip.into());
+                (nat_ip, remote_ip)
+            }
+
+            (EchoReply | EchoRequest, Direction::In) => {
+                // Panic: We know this is safe because we make it here
+                // only if this ICMPv6 message is an Echo/Reply.
+                icmp.set_echo_ident(self.ident);
+                let priv_ip = IpAddress::Ipv6(self.priv_ip.into());
+                (remote_ip, priv_ip)
+            }
+
+            (_, _) => {
+                return Err(BodyTransformError::UnexpectedBody(format!(
+                    "Expected ICMPv6 Echo/Reply, found: {}",
+                    icmp.msg_type()
+                )));
+            }
+        };
+
+        icmp.fill_checksum(&src, &dst);
+        Ok(())
+    }
+}
+
+impl Display for SNatIcmp6EchoBt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ICMPv6 Echo Ident/SNAT {} <=> {}", self.ident, self.nat.port)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
     #[test]
     fn test_nat_pool_different_ip_types() {
-        let pool4 = NatPool::new();
-        let pool6 = NatPool::new();
+        let pool4 = NatPool::new("test");
+        let pool6 = NatPool::new("test");
 
         let ipv4: Ipv4Addr = "172.30.0.1".parse().unwrap();
         let pub_ipv4 = "76.76.21.21".parse().unwrap();
@@ -676,10 +1271,10 @@ mod test {
         assert!(pool4.mapping(ipv4).is_none());
         assert!(pool6.mapping(ipv6).is_none());
 
-        pool4.add(ipv4, pub_ipv4, 0..=4096);
+        pool4.add(ipv4, pub_ipv4, 0..=4096, NatMapping::PerFlow, None);
         assert!(pool4.mapping(ipv4).is_some());
 
-        pool6.add(ipv6, pub_ipv6, 0..=4096);
+        pool6.add(ipv6, pub_ipv6, 0..=4096, NatMapping::PerFlow, None);
         assert!(pool6.mapping(ipv6).is_some());
     }
 
@@ -707,9 +1302,9 @@ mod test {
         let outside_ip: Ipv4Addr = "76.76.21.21".parse().unwrap();
         let outside_port = 80;
 
-        let pool = Arc::new(NatPool::new());
-        pool.add(priv_ip, pub_ip, 8765..=8765);
-        let snat = SNat::new(priv_ip, pool.clone(), None);
+        let pool = Arc::new(NatPool::new("test"));
+        pool.add(priv_ip, pub_ip, 8765..=8765, NatMapping::PerFlow, None);
+        let snat = SNat::new(priv_ip, pool.clone());
         let mut action_meta = ActionMeta::new();
         assert!(pool.verify_available(priv_ip, pub_ip, pub_port));
 
@@ -842,18 +1437,34 @@ mod test {
         assert!(pool.verify_available(priv_ip, pub_ip, pub_port));
     }
 
+    fn tcp_flow(
+        priv_ip: Ipv4Addr,
+        priv_port: u16,
+        remote_ip: &str,
+        remote_port: u16,
+    ) -> NatFlow<Ipv4Addr> {
+        NatFlow {
+            priv_ip,
+            proto: Protocol::TCP,
+            priv_port,
+            remote_ip: IpAddr::Ip4(remote_ip.parse().unwrap()),
+            remote_port,
+        }
+    }
+
     #[test]
     fn nat_mappings() {
-        let pool = NatPool::new();
+        let pool = NatPool::new("test");
         let priv1 = "192.168.2.8".parse::<Ipv4Addr>().unwrap();
         let priv2 = "192.168.2.33".parse::<Ipv4Addr>().unwrap();
         let external_ip = "52.10.128.69".parse().unwrap();
 
-        pool.add(priv1, external_ip, 1025..=4096);
-        pool.add(priv2, external_ip, 4097..=8192);
+        pool.add(priv1, external_ip, 1025..=4096, NatMapping::PerFlow, None);
+        pool.add(priv2, external_ip, 4097..=8192, NatMapping::PerFlow, None);
 
+        let flow1 = tcp_flow(priv1, 4999, "76.76.21.21", 80);
         assert_eq!(pool.num_avail(priv1).unwrap(), 3072);
-        let npe1 = match pool.obtain(&priv1) {
+        let npe1 = match pool.obtain(&flow1) {
             Ok(npe) => npe,
             _ => panic!("failed to obtain mapping"),
         };
@@ -862,8 +1473,9 @@ mod test {
         assert!(npe1.port >= 1025);
         assert!(npe1.port <= 4096);
 
+        let flow2 = tcp_flow(priv2, 4999, "76.76.21.21", 80);
         assert_eq!(pool.num_avail(priv2).unwrap(), 4096);
-        let npe2 = match pool.obtain(&priv2) {
+        let npe2 = match pool.obtain(&flow2) {
             Ok(npe) => npe,
             _ => panic!("failed to obtain mapping"),
         };
@@ -872,9 +1484,287 @@ mod test {
         assert!(npe2.port >= 4097);
         assert!(npe2.port <= 8192);
 
-        pool.release(&priv1, npe1);
+        pool.release(&flow1, npe1);
         assert_eq!(pool.num_avail(priv1).unwrap(), 3072);
-        pool.release(&priv2, npe2);
+        pool.release(&flow2, npe2);
         assert_eq!(pool.num_avail(priv2).unwrap(), 4096);
     }
+
+    // Verify that all flows from a private port share one external
+    // port, which is freed only once they are all gone.
+    #[test]
+    fn nat_mapping_endpoint_independent() {
+        let pool = NatPool::new("test");
+        let priv_ip = "192.168.2.8".parse::<Ipv4Addr>().unwrap();
+        let external_ip = "52.10.128.69".parse().unwrap();
+        pool.add(
+            priv_ip,
+            external_ip,
+            1025..=1026,
+            NatMapping::EndpointIndependent,
+            None,
+        );
+
+        let flow1 = tcp_flow(priv_ip, 4999, "76.76.21.21", 80);
+        let flow2 = tcp_flow(priv_ip, 4999, "76.76.21.22", 443);
+        let flow3 = tcp_flow(priv_ip, 5000, "76.76.21.21", 80);
+        let npe1 = pool.obtain(&flow1).unwrap();
+        let npe2 = pool.obtain(&flow2).unwrap();
+        let npe3 = pool.obtain(&flow3).unwrap();
+        assert_eq!(npe1.port, npe2.port);
+        assert_ne!(npe1.port, npe3.port);
+        assert_eq!(pool.num_avail(priv_ip).unwrap(), 0);
+
+        // A new private port finds the ports exhausted.
+        let flow4 = tcp_flow(priv_ip, 5001, "76.76.21.21", 80);
+        assert!(matches!(pool.obtain(&flow4), Err(ResourceError::Exhausted)));
+        assert_eq!(
+            pool.stats(priv_ip).unwrap(),
+            NatPoolStats { exhausted: 1, limited: 0, reused: 1 }
+        );
+
+        pool.release(&flow1, npe1);
+        assert_eq!(pool.num_avail(priv_ip).unwrap(), 0);
+        pool.release(&flow2, npe2);
+        assert_eq!(pool.num_avail(priv_ip).unwrap(), 1);
+        pool.release(&flow3, npe3);
+        assert_eq!(pool.num_avail(priv_ip).unwrap(), 2);
+    }
+
+    // Verify that once the free ports are exhausted, a port is reused
+    // only for flows to distinct remote endpoints.
+    #[test]
+    fn nat_mapping_port_reuse() {
+        let pool = NatPool::new("test");
+        let priv_ip = "192.168.2.8".parse::<Ipv4Addr>().unwrap();
+        let external_ip = "52.10.128.69".parse().unwrap();
+        pool.add(
+            priv_ip,
+            external_ip,
+            1025..=1025,
+            NatMapping::PortReuse,
+            None,
+        );
+
+        let flow1 = tcp_flow(priv_ip, 4999, "76.76.21.21", 80);
+        let flow2 = tcp_flow(priv_ip, 5000, "76.76.21.22", 80);
+        let flow3 = tcp_flow(priv_ip, 5001, "76.76.21.21", 80);
+        let npe1 = pool.obtain(&flow1).unwrap();
+        let npe2 = pool.obtain(&flow2).unwrap();
+        assert_eq!(npe1.port, 1025);
+        assert_eq!(npe2.port, 1025);
+
+        // The port is already used with the remote endpoint of flow3.
+        assert!(matches!(pool.obtain(&flow3), Err(ResourceError::Exhausted)));
+        assert_eq!(
+            pool.stats(priv_ip).unwrap(),
+            NatPoolStats { exhausted: 1, limited: 0, reused: 1 }
+        );
+
+        pool.release(&flow1, npe1);
+        let npe3 = pool.obtain(&flow3).unwrap();
+        assert_eq!(npe3.port, 1025);
+        pool.release(&flow2, npe2);
+        pool.release(&flow3, npe3);
+        assert_eq!(pool.num_avail(priv_ip).unwrap(), 1);
+    }
+
+    // Verify that a port is reused only by the flows to remote
+    // endpoints which are not already using it, as the ports in use
+    // with each remote endpoint are tracked.
+    #[test]
+    fn nat_mapping_port_reuse_remotes() {
+        let pool = NatPool::new("test");
+        let priv_ip = "192.168.2.8".parse::<Ipv4Addr>().unwrap();
+        let external_ip = "52.10.128.69".parse().unwrap();
+        pool.add(
+            priv_ip,
+            external_ip,
+            1025..=1026,
+            NatMapping::PortReuse,
+            None,
+        );
+
+        let flow1 = tcp_flow(priv_ip, 4999, "76.76.21.21", 80);
+        let flow2 = tcp_flow(priv_ip, 5000, "76.76.21.22", 80);
+        let npe1 = pool.obtain(&flow1).unwrap();
+        let npe2 = pool.obtain(&flow2).unwrap();
+        assert_ne!(npe1.port, npe2.port);
+
+        // The second flow to the first remote endpoint takes the port
+        // not yet used with it; a third finds none.
+        let flow3 = tcp_flow(priv_ip, 5001, "76.76.21.21", 80);
+        let flow4 = tcp_flow(priv_ip, 5002, "76.76.21.21", 80);
+        let npe3 = pool.obtain(&flow3).unwrap();
+        assert_eq!(npe3.port, npe2.port);
+        assert!(matches!(pool.obtain(&flow4), Err(ResourceError::Exhausted)));
+
+        // Releasing a flow makes its port usable with its remote
+        // endpoint again.
+        pool.release(&flow3, npe3);
+        let npe4 = pool.obtain(&flow4).unwrap();
+        assert_eq!(npe4.port, npe2.port);
+        assert_eq!(
+            pool.stats(priv_ip).unwrap(),
+            NatPoolStats { exhausted: 1, limited: 0, reused: 2 }
+        );
+
+        pool.release(&flow1, npe1);
+        pool.release(&flow2, npe2);
+        pool.release(&flow4, npe4);
+        assert_eq!(pool.num_avail(priv_ip).unwrap(), 2);
+    }
+
+    // Verify that once a private IP holds as many ports as its limit
+    // allows, a port is reused for flows to distinct remote endpoints,
+    // even though the pool has more to give.
+    #[test]
+    fn nat_mapping_port_reuse_limit() {
+        let pool = NatPool::new("test");
+        let priv_ip = "192.168.2.8".parse::<Ipv4Addr>().unwrap();
+        let external_ip = "52.10.128.69".parse().unwrap();
+        pool.add(
+            priv_ip,
+            external_ip,
+            1025..=1034,
+            NatMapping::PortReuse,
+            Some(1),
+        );
+
+        let flow1 = tcp_flow(priv_ip, 4999, "76.76.21.21", 80);
+        let flow2 = tcp_flow(priv_ip, 5000, "76.76.21.22", 80);
+        let flow3 = tcp_flow(priv_ip, 5001, "76.76.21.21", 80);
+        let npe1 = pool.obtain(&flow1).unwrap();
+        let npe2 = pool.obtain(&flow2).unwrap();
+        assert_eq!(npe1.port, npe2.port);
+        assert_eq!(pool.num_avail(priv_ip).unwrap(), 9);
+
+        // The one port is already used with the remote endpoint of
+        // flow3, and the limit forbids another.
+        assert!(matches!(
+            pool.obtain(&flow3),
+            Err(ResourceError::LimitReached)
+        ));
+        assert_eq!(
+            pool.stats(priv_ip).unwrap(),
+            NatPoolStats { exhausted: 0, limited: 1, reused: 1 }
+        );
+
+        pool.release(&flow1, npe1);
+        pool.release(&flow2, npe2);
+        assert_eq!(pool.num_avail(priv_ip).unwrap(), 10);
+    }
+
+    // Verify that a private IP may hold no more than its limit of
+    // ports, even though the pool has more to give.
+    #[test]
+    fn nat_mapping_port_limit() {
+        let pool = NatPool::new("test");
+        let priv_ip = "192.168.2.8".parse::<Ipv4Addr>().unwrap();
+        let external_ip = "52.10.128.69".parse().unwrap();
+        pool.add(
+            priv_ip,
+            external_ip,
+            1025..=1034,
+            NatMapping::EndpointIndependent,
+            Some(2),
+        );
+
+        let flow1 = tcp_flow(priv_ip, 4999, "76.76.21.21", 80);
+        let flow2 = tcp_flow(priv_ip, 4999, "76.76.21.22", 443);
+        let flow3 = tcp_flow(priv_ip, 5000, "76.76.21.21", 80);
+        let flow4 = tcp_flow(priv_ip, 5001, "76.76.21.21", 80);
+        let npe1 = pool.obtain(&flow1).unwrap();
+        let npe2 = pool.obtain(&flow2).unwrap();
+        let npe3 = pool.obtain(&flow3).unwrap();
+        assert!(matches!(
+            pool.obtain(&flow4),
+            Err(ResourceError::LimitReached)
+        ));
+        assert_eq!(pool.num_avail(priv_ip).unwrap(), 8);
+        assert_eq!(
+            pool.stats(priv_ip).unwrap(),
+            NatPoolStats { exhausted: 0, limited: 1, reused: 1 }
+        );
+
+        let snap = pool.kstats_snap();
+        assert_eq!(snap.ports_used, 2);
+        assert_eq!(snap.ports_free, 8);
+        assert_eq!(snap.flows, 3);
+        assert_eq!(snap.alloc_fail_limit, 1);
+        assert_eq!(snap.ports_reused, 1);
+
+        let dump = pool.dump();
+        assert_eq!(dump.len(), 1);
+        assert_eq!(dump[0].max_ports, Some(2));
+        assert_eq!(dump[0].ports_free, 8);
+        assert_eq!(
+            dump[0].ports_used,
+            vec![
+                (
+                    npe1.port,
+                    vec![InnerFlowId::from(&flow1), InnerFlowId::from(&flow2)]
+                ),
+                (npe3.port, vec![InnerFlowId::from(&flow3)]),
+            ]
+        );
+
+        // Freeing a port makes room for a new one.
+        pool.release(&flow3, npe3);
+        let npe4 = pool.obtain(&flow4).unwrap();
+        pool.release(&flow1, npe1);
+        pool.release(&flow2, npe2);
+        pool.release(&flow4, npe4);
+        assert_eq!(pool.num_avail(priv_ip).unwrap(), 10);
+
+        let snap = pool.kstats_snap();
+        assert_eq!(snap.ports_used, 0);
+        assert_eq!(snap.ports_free, 10);
+        assert_eq!(snap.flows, 0);
+    }
+
+    // Verify that the pools of two ports sharing the same ports take
+    // them from one free list, each private IP held to its limit
+    // within them, and that dropping a mapping gives back its ports.
+    #[test]
+    fn nat_mapping_shared_ports() {
+        let pool1 = NatPool::new("test1");
+        let pool2 = NatPool::new("test2");
+        let priv_ip1 = "192.168.2.8".parse::<Ipv4Addr>().unwrap();
+        let priv_ip2 = "192.168.2.9".parse::<Ipv4Addr>().unwrap();
+        let external_ip = "52.10.128.69".parse().unwrap();
+        let ports = Arc::new(NatPorts::new(external_ip, 1025..=1027));
+        pool1.add_shared(priv_ip1, ports.clone(), NatMapping::PerFlow, Some(2));
+        pool2.add_shared(priv_ip2, ports.clone(), NatMapping::PerFlow, Some(2));
+
+        let flow1 = tcp_flow(priv_ip1, 4999, "76.76.21.21", 80);
+        let flow2 = tcp_flow(priv_ip1, 5000, "76.76.21.21", 80);
+        let flow3 = tcp_flow(priv_ip1, 5001, "76.76.21.21", 80);
+        let npe1 = pool1.obtain(&flow1).unwrap();
+        let npe2 = pool1.obtain(&flow2).unwrap();
+        assert!(matches!(
+            pool1.obtain(&flow3),
+            Err(ResourceError::LimitReached)
+        ));
+        assert_eq!(ports.num_free(), 1);
+        assert_eq!(pool2.kstats_snap().ports_free, 1);
+
+        // The second private IP is under its limit, but the shared
+        // ports run out.
+        let flow4 = tcp_flow(priv_ip2, 4999, "76.76.21.21", 80);
+        let flow5 = tcp_flow(priv_ip2, 5000, "76.76.21.21", 80);
+        let npe4 = pool2.obtain(&flow4).unwrap();
+        assert!(![npe1.port, npe2.port].contains(&npe4.port));
+        assert!(matches!(pool2.obtain(&flow5), Err(ResourceError::Exhausted)));
+
+        // A port released by one pool is free for the other, and
+        // replacing a mapping gives back the ports it held.
+        pool1.release(&flow1, npe1);
+        let npe5 = pool2.obtain(&flow5).unwrap();
+        assert_eq!(npe5.port, npe1.port);
+        pool1.add_shared(priv_ip1, ports.clone(), NatMapping::PerFlow, Some(2));
+        assert_eq!(ports.num_free(), 1);
+        drop(pool2);
+        assert_eq!(ports.num_free(), 3);
+    }
 }
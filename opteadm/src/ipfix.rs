@@ -0,0 +1,346 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! A minimal IPFIX writer for port flow records.
+//!
+//! Each message carries a Template Set describing the IPv4, IPv6, and
+//! NAT64 records, followed by a Data Set for each kind present. A
+//! NAT64 record has an IPv6 guest side and an IPv4 network side.
+//! The guest side of the flow is reported in the regular address and
+//! port fields, the network side in the post-NAT fields. The guest
+//! is considered the initiator of the flow for the purposes of the
+//! octet and packet counts, i.e. the initiator counts are those of
+//! the packets sent by the guest.
+//!
+//! See RFC 7011 for the format and the IANA IPFIX registry for the
+//! Information Elements.
+use std::io;
+use std::io::Write;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use opte::api::IpAddr;
+use opte::engine::flow_record::FlowEndReason;
+use opte::engine::flow_record::FlowRecord;
+use opte::engine::flow_record::FlowVerdict;
+use opte::engine::ioctl::DumpFlowRecordsResp;
+
+const IPFIX_VERSION: u16 = 10;
+const SET_ID_TEMPLATE: u16 = 2;
+const TEMPLATE_ID_V4: u16 = 256;
+const TEMPLATE_ID_V6: u16 = 257;
+const TEMPLATE_ID_NAT64: u16 = 258;
+
+// The number of records per message, keeping each message well under
+// the 64KiB limit.
+const RECORDS_PER_MSG: usize = 256;
+
+// Information Elements.
+const IE_OCTET_DELTA_COUNT: u16 = 1;
+const IE_PACKET_DELTA_COUNT: u16 = 2;
+const IE_PROTOCOL_IDENTIFIER: u16 = 4;
+const IE_TCP_CONTROL_BITS: u16 = 6;
+const IE_SOURCE_TRANSPORT_PORT: u16 = 7;
+const IE_SOURCE_IPV4_ADDRESS: u16 = 8;
+const IE_DESTINATION_TRANSPORT_PORT: u16 = 11;
+const IE_DESTINATION_IPV4_ADDRESS: u16 = 12;
+const IE_SOURCE_IPV6_ADDRESS: u16 = 27;
+const IE_DESTINATION_IPV6_ADDRESS: u16 = 28;
+const IE_FLOW_END_REASON: u16 = 136;
+const IE_FLOW_START_MILLISECONDS: u16 = 152;
+const IE_FLOW_END_MILLISECONDS: u16 = 153;
+const IE_POST_NAT_SOURCE_IPV4_ADDRESS: u16 = 225;
+const IE_POST_NAT_DESTINATION_IPV4_ADDRESS: u16 = 226;
+const IE_POST_NAPT_SOURCE_TRANSPORT_PORT: u16 = 227;
+const IE_POST_NAPT_DESTINATION_TRANSPORT_PORT: u16 = 228;
+const IE_RESPONDER_OCTETS: u16 = 232;
+const IE_FIREWALL_EVENT: u16 = 233;
+const IE_POST_NAT_SOURCE_IPV6_ADDRESS: u16 = 281;
+const IE_POST_NAT_DESTINATION_IPV6_ADDRESS: u16 = 282;
+const IE_RESPONDER_PACKETS: u16 = 299;
+
+// The flowEndReason values.
+const END_REASON_IDLE_TIMEOUT: u8 = 1;
+const END_REASON_ACTIVE_TIMEOUT: u8 = 2;
+
+// The firewallEvent values.
+const FW_EVENT_DELETED: u8 = 2;
+const FW_EVENT_DENIED: u8 = 3;
+const FW_EVENT_UPDATE: u8 = 5;
+
+// The fields of a template, given the length of the addresses on its
+// guest and network sides.
+fn template_fields(guest_len: u16, net_len: u16) -> Vec<(u16, u16)> {
+    let (src, dst) = match guest_len {
+        4 => (IE_SOURCE_IPV4_ADDRESS, IE_DESTINATION_IPV4_ADDRESS),
+        _ => (IE_SOURCE_IPV6_ADDRESS, IE_DESTINATION_IPV6_ADDRESS),
+    };
+
+    let (nat_src, nat_dst) = match net_len {
+        4 => (
+            IE_POST_NAT_SOURCE_IPV4_ADDRESS,
+            IE_POST_NAT_DESTINATION_IPV4_ADDRESS,
+        ),
+
+        _ => (
+            IE_POST_NAT_SOURCE_IPV6_ADDRESS,
+            IE_POST_NAT_DESTINATION_IPV6_ADDRESS,
+        ),
+    };
+
+    vec![
+        (src, guest_len),
+        (dst, guest_len),
+        (IE_SOURCE_TRANSPORT_PORT, 2),
+        (IE_DESTINATION_TRANSPORT_PORT, 2),
+        (IE_PROTOCOL_IDENTIFIER, 1),
+        (nat_src, net_len),
+        (nat_dst, net_len),
+        (IE_POST_NAPT_SOURCE_TRANSPORT_PORT, 2),
+        (IE_POST_NAPT_DESTINATION_TRANSPORT_PORT, 2),
+        (IE_OCTET_DELTA_COUNT, 8),
+        (IE_PACKET_DELTA_COUNT, 8),
+        (IE_RESPONDER_OCTETS, 8),
+        (IE_RESPONDER_PACKETS, 8),
+        (IE_FLOW_START_MILLISECONDS, 8),
+        (IE_FLOW_END_MILLISECONDS, 8),
+        (IE_TCP_CONTROL_BITS, 2),
+        (IE_FIREWALL_EVENT, 1),
+        (IE_FLOW_END_REASON, 1),
+    ]
+}
+
+fn push_template(buf: &mut Vec<u8>, id: u16, guest_len: u16, net_len: u16) {
+    let fields = template_fields(guest_len, net_len);
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.extend_from_slice(&(fields.len() as u16).to_be_bytes());
+    for (ie, len) in fields {
+        buf.extend_from_slice(&ie.to_be_bytes());
+        buf.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+fn push_addr(buf: &mut Vec<u8>, addr: IpAddr) {
+    match addr {
+        IpAddr::Ip4(ip4) => buf.extend_from_slice(&ip4.bytes()),
+        IpAddr::Ip6(ip6) => buf.extend_from_slice(&ip6.bytes()),
+    }
+}
+
+// Push the data record of `rec`; `start_ms` is the wall-clock time of
+// the port's creation, in milliseconds.
+fn push_record(buf: &mut Vec<u8>, start_ms: u64, rec: &FlowRecord) {
+    let guest = &rec.guest_flow;
+    let net = &rec.net_flow;
+    push_addr(buf, guest.src_ip);
+    push_addr(buf, guest.dst_ip);
+    buf.extend_from_slice(&guest.src_port.to_be_bytes());
+    buf.extend_from_slice(&guest.dst_port.to_be_bytes());
+    buf.push(u8::from(guest.proto));
+    push_addr(buf, net.src_ip);
+    push_addr(buf, net.dst_ip);
+    buf.extend_from_slice(&net.src_port.to_be_bytes());
+    buf.extend_from_slice(&net.dst_port.to_be_bytes());
+    buf.extend_from_slice(&rec.counters.bytes_out.to_be_bytes());
+    buf.extend_from_slice(&rec.counters.pkts_out.to_be_bytes());
+    buf.extend_from_slice(&rec.counters.bytes_in.to_be_bytes());
+    buf.extend_from_slice(&rec.counters.pkts_in.to_be_bytes());
+    buf.extend_from_slice(&(start_ms + rec.start_ns / 1_000_000).to_be_bytes());
+    buf.extend_from_slice(&(start_ms + rec.end_ns / 1_000_000).to_be_bytes());
+    buf.extend_from_slice(&(rec.counters.tcp_flags as u16).to_be_bytes());
+
+    let fw_event = match (rec.verdict, rec.end_reason) {
+        (FlowVerdict::Deny, _) => FW_EVENT_DENIED,
+        (FlowVerdict::Allow, FlowEndReason::IdleTimeout) => FW_EVENT_DELETED,
+        (FlowVerdict::Allow, FlowEndReason::ActiveTimeout) => FW_EVENT_UPDATE,
+    };
+    buf.push(fw_event);
+
+    let end_reason = match rec.end_reason {
+        FlowEndReason::IdleTimeout => END_REASON_IDLE_TIMEOUT,
+        FlowEndReason::ActiveTimeout => END_REASON_ACTIVE_TIMEOUT,
+    };
+    buf.push(end_reason);
+}
+
+// Return the template of the record, or `None` if it has an IPv4
+// guest side and an IPv6 network side, which no port produces.
+fn record_template(rec: &FlowRecord) -> Option<u16> {
+    match (rec.guest_flow.src_ip, rec.net_flow.src_ip) {
+        (IpAddr::Ip4(_), IpAddr::Ip4(_)) => Some(TEMPLATE_ID_V4),
+        (IpAddr::Ip6(_), IpAddr::Ip6(_)) => Some(TEMPLATE_ID_V6),
+        (IpAddr::Ip6(_), IpAddr::Ip4(_)) => Some(TEMPLATE_ID_NAT64),
+        (IpAddr::Ip4(_), IpAddr::Ip6(_)) => None,
+    }
+}
+
+// Push a set, wrapping the body with its ID and length.
+fn push_set(buf: &mut Vec<u8>, id: u16, body: &[u8]) {
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.extend_from_slice(&((4 + body.len()) as u16).to_be_bytes());
+    buf.extend_from_slice(body);
+}
+
+fn write_msg<W: Write>(
+    w: &mut W,
+    export_secs: u32,
+    domain_id: u32,
+    start_ms: u64,
+    recs: &[FlowRecord],
+) -> io::Result<()> {
+    let mut tmpl = vec![];
+    push_template(&mut tmpl, TEMPLATE_ID_V4, 4, 4);
+    push_template(&mut tmpl, TEMPLATE_ID_V6, 16, 16);
+    push_template(&mut tmpl, TEMPLATE_ID_NAT64, 16, 4);
+
+    let mut v4 = vec![];
+    let mut v6 = vec![];
+    let mut nat64 = vec![];
+    for rec in recs {
+        match record_template(rec) {
+            Some(TEMPLATE_ID_V4) => push_record(&mut v4, start_ms, rec),
+            Some(TEMPLATE_ID_V6) => push_record(&mut v6, start_ms, rec),
+            Some(_) => push_record(&mut nat64, start_ms, rec),
+            None => (),
+        }
+    }
+
+    let mut sets = vec![];
+    push_set(&mut sets, SET_ID_TEMPLATE, &tmpl);
+    for (id, data) in
+        [(TEMPLATE_ID_V4, v4), (TEMPLATE_ID_V6, v6), (TEMPLATE_ID_NAT64, nat64)]
+    {
+        if !data.is_empty() {
+            push_set(&mut sets, id, &data);
+        }
+    }
+
+    // The sequence number is the number of data records sent prior
+    // to this message, which is one less than the first record's
+    // sequence number.
+    let seq = recs.first().map(|r| r.seq - 1).unwrap_or(0) as u32;
+
+    w.write_all(&IPFIX_VERSION.to_be_bytes())?;
+    w.write_all(&((16 + sets.len()) as u16).to_be_bytes())?;
+    w.write_all(&export_secs.to_be_bytes())?;
+    w.write_all(&seq.to_be_bytes())?;
+    w.write_all(&domain_id.to_be_bytes())?;
+    w.write_all(&sets)
+}
+
+/// Write the flow records of the dump as a series of IPFIX messages,
+/// using `domain_id` as the Observation Domain ID.
+///
+/// The record timestamps are converted to wall-clock time using the
+/// dump's `elapsed_ns` relative to the current system time. Records
+/// with an IPv4 guest side and an IPv6 network side are skipped.
+pub fn write_flow_records<W: Write>(
+    w: &mut W,
+    domain_id: u32,
+    dump: &DumpFlowRecordsResp,
+) -> io::Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let now_ms = now.as_millis() as u64;
+    let start_ms = now_ms.saturating_sub(dump.elapsed_ns / 1_000_000);
+    let export_secs = now.as_secs() as u32;
+
+    for recs in dump.records.chunks(RECORDS_PER_MSG) {
+        write_msg(w, export_secs, domain_id, start_ms, recs)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use opte::api::Ipv4Addr;
+    use opte::api::Ipv6Addr;
+    use opte::api::Protocol;
+    use opte::engine::flow_record::FlowCounters;
+    use opte::engine::packet::InnerFlowId;
+
+    // Return the ID and body of each set of the message.
+    fn parse_sets(msg: &[u8]) -> Vec<(u16, &[u8])> {
+        let msg_len = u16::from_be_bytes([msg[2], msg[3]]) as usize;
+        assert_eq!(msg_len, msg.len());
+        let mut sets = vec![];
+        let mut off = 16;
+        while off < msg_len {
+            let id = u16::from_be_bytes([msg[off], msg[off + 1]]);
+            let len = u16::from_be_bytes([msg[off + 2], msg[off + 3]]);
+            sets.push((id, &msg[off + 4..off + len as usize]));
+            off += len as usize;
+        }
+        sets
+    }
+
+    #[test]
+    fn nat64_record() {
+        let priv_ip: Ipv6Addr = "fd00::5".parse().unwrap();
+        let remote: Ipv4Addr = "198.51.100.7".parse().unwrap();
+        let nat_ip: Ipv4Addr = "10.0.0.99".parse().unwrap();
+        let guest_flow = InnerFlowId {
+            proto: Protocol::TCP,
+            src_ip: IpAddr::Ip6(priv_ip),
+            src_port: 44444,
+            dst_ip: IpAddr::Ip6(Ipv6Addr::from_nat64(remote)),
+            dst_port: 443,
+        };
+        let net_flow = InnerFlowId {
+            proto: Protocol::TCP,
+            src_ip: IpAddr::Ip4(nat_ip),
+            src_port: 8765,
+            dst_ip: IpAddr::Ip4(remote),
+            dst_port: 443,
+        };
+        let rec = FlowRecord {
+            seq: 1,
+            guest_flow,
+            net_flow,
+            start_ns: 0,
+            end_ns: 5_000_000,
+            counters: FlowCounters {
+                pkts_out: 3,
+                bytes_out: 300,
+                pkts_in: 2,
+                bytes_in: 200,
+                tcp_flags: 0x12,
+            },
+            verdict: FlowVerdict::Allow,
+            end_reason: FlowEndReason::IdleTimeout,
+        };
+        let dump = DumpFlowRecordsResp {
+            elapsed_ns: 0,
+            dropped: 0,
+            records: vec![rec],
+        };
+
+        let mut msg = vec![];
+        write_flow_records(&mut msg, 7, &dump).unwrap();
+        let sets = parse_sets(&msg);
+        assert_eq!(sets.len(), 2);
+        assert_eq!(sets[0].0, SET_ID_TEMPLATE);
+
+        // The NAT64 data set holds the single record, laid out per
+        // its template.
+        let (id, data) = sets[1];
+        assert_eq!(id, TEMPLATE_ID_NAT64);
+        let rec_len: u16 =
+            template_fields(16, 4).iter().map(|(_, len)| len).sum();
+        assert_eq!(data.len(), rec_len as usize);
+        assert_eq!(&data[0..16], &priv_ip.bytes());
+        assert_eq!(&data[16..32], &Ipv6Addr::from_nat64(remote).bytes());
+        assert_eq!(&data[32..34], &44444u16.to_be_bytes());
+        assert_eq!(&data[34..36], &443u16.to_be_bytes());
+        assert_eq!(data[36], u8::from(Protocol::TCP));
+        assert_eq!(&data[37..41], &nat_ip.bytes());
+        assert_eq!(&data[41..45], &remote.bytes());
+        assert_eq!(&data[45..47], &8765u16.to_be_bytes());
+        assert_eq!(&data[47..49], &443u16.to_be_bytes());
+        assert_eq!(&data[49..57], &300u64.to_be_bytes());
+    }
+}
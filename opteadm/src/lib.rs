@@ -7,26 +7,66 @@
 //! OPTE driver administration library
 // Copyright 2021 Oxide Computer Company
 
+pub mod ipfix;
+pub mod pcapng;
+
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::os::unix::io::AsRawFd;
 
+use opte::api::Direction;
+use opte::api::IpAddr;
+use opte::api::Ipv6Addr;
 use opte::api::NoResp;
 use opte::api::OpteCmd;
 use opte::api::SetXdeUnderlayReq;
+use opte::engine::capture::CaptureConfig;
+use opte::engine::capture::CAPTURE_DUMP_MAX;
 use opte::engine::ioctl::{self as api};
+use opte::engine::layer::DefaultAction;
 use opte_ioctl::run_cmd_ioctl;
 use opte_ioctl::Error;
 use oxide_vpc::api::AddFwRuleReq;
+use oxide_vpc::api::AddPortForwardReq;
 use oxide_vpc::api::AddRouterEntryReq;
+use oxide_vpc::api::AttachFloatingIpReq;
 use oxide_vpc::api::CreateXdeReq;
+use oxide_vpc::api::DelAddrSetReq;
+use oxide_vpc::api::DelAddrSetResp;
+use oxide_vpc::api::DelPortForwardReq;
+use oxide_vpc::api::DelPortForwardResp;
+use oxide_vpc::api::DelRouterEntryReq;
+use oxide_vpc::api::DelRouterEntryResp;
+use oxide_vpc::api::DelVirt2PhysReq;
+use oxide_vpc::api::DelVirt2PhysResp;
 use oxide_vpc::api::DeleteXdeReq;
+use oxide_vpc::api::DetachFloatingIpReq;
+use oxide_vpc::api::DetachFloatingIpResp;
+use oxide_vpc::api::DumpAddrSetsReq;
+use oxide_vpc::api::DumpAddrSetsResp;
+use oxide_vpc::api::DumpBsvcStateReq;
+use oxide_vpc::api::DumpBsvcStateResp;
 use oxide_vpc::api::FirewallRule;
+use oxide_vpc::api::ListPortForwardsReq;
+use oxide_vpc::api::ListPortForwardsResp;
 use oxide_vpc::api::ListPortsResp;
+use oxide_vpc::api::ListRouterEntriesReq;
+use oxide_vpc::api::ListRouterEntriesResp;
+use oxide_vpc::api::MirrorCfg;
+use oxide_vpc::api::PortForward;
+use oxide_vpc::api::ReadV2pMissesReq;
+use oxide_vpc::api::ReadV2pMissesResp;
 use oxide_vpc::api::RemFwRuleReq;
+use oxide_vpc::api::SetAddrSetReq;
+use oxide_vpc::api::SetBsvcStateReq;
 use oxide_vpc::api::SetFwRulesReq;
+use oxide_vpc::api::SetFwRulesResp;
+use oxide_vpc::api::SetMirrorsReq;
 use oxide_vpc::api::SetVirt2PhysReq;
+use oxide_vpc::api::SyncVirt2PhysReq;
 use oxide_vpc::api::VpcCfg;
+use oxide_vpc::engine::nat::DumpSnatReq;
+use oxide_vpc::engine::nat::DumpSnatResp;
 use oxide_vpc::engine::overlay;
 
 /// The handle used to send administration commands to the OPTE
@@ -105,13 +145,134 @@ impl OpteAdm {
         &self,
         port_name: &str,
         rules: Vec<FirewallRule>,
-    ) -> Result<NoResp, Error> {
+    ) -> Result<SetFwRulesResp, Error> {
         let cmd = OpteCmd::SetFwRules;
         let req =
             SetFwRulesReq { port_name: port_name.to_string(), rules: rules };
         run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
     }
 
+    /// Create or replace a VPC address set.
+    pub fn set_addr_set(&self, req: &SetAddrSetReq) -> Result<NoResp, Error> {
+        let cmd = OpteCmd::SetAddrSet;
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
+
+    /// Delete a VPC address set.
+    pub fn del_addr_set(
+        &self,
+        req: &DelAddrSetReq,
+    ) -> Result<DelAddrSetResp, Error> {
+        let cmd = OpteCmd::DelAddrSet;
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
+
+    /// Dump the address sets of all VPCs.
+    pub fn dump_addr_sets(&self) -> Result<DumpAddrSetsResp, Error> {
+        let cmd = OpteCmd::DumpAddrSets;
+        run_cmd_ioctl(
+            self.device.as_raw_fd(),
+            cmd,
+            Some(&DumpAddrSetsReq { unused: 99 }),
+        )
+    }
+
+    /// Set/replace all mirrors of a port.
+    pub fn set_mirrors(
+        &self,
+        port_name: &str,
+        mirrors: Vec<MirrorCfg>,
+    ) -> Result<NoResp, Error> {
+        let cmd = OpteCmd::SetMirrors;
+        let req = SetMirrorsReq { port_name: port_name.to_string(), mirrors };
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
+
+    /// Mark a Boundary Services endpoint as up or down.
+    pub fn set_bsvc_state(
+        &self,
+        ip: Ipv6Addr,
+        up: bool,
+    ) -> Result<NoResp, Error> {
+        let cmd = OpteCmd::SetBsvcState;
+        let req = SetBsvcStateReq { ip, up };
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
+
+    /// Dump the state of the Boundary Services endpoints.
+    pub fn dump_bsvc_state(&self) -> Result<DumpBsvcStateResp, Error> {
+        let cmd = OpteCmd::DumpBsvcState;
+        run_cmd_ioctl(
+            self.device.as_raw_fd(),
+            cmd,
+            Some(&DumpBsvcStateReq { unused: 99 }),
+        )
+    }
+
+    /// Attach a floating IP to a port.
+    pub fn attach_floating_ip(
+        &self,
+        port_name: &str,
+        ip: IpAddr,
+    ) -> Result<NoResp, Error> {
+        let cmd = OpteCmd::AttachFloatingIp;
+        let req = AttachFloatingIpReq { port_name: port_name.to_string(), ip };
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
+
+    /// Detach a floating IP from a port.
+    pub fn detach_floating_ip(
+        &self,
+        port_name: &str,
+        ip: IpAddr,
+    ) -> Result<DetachFloatingIpResp, Error> {
+        let cmd = OpteCmd::DetachFloatingIp;
+        let req = DetachFloatingIpReq { port_name: port_name.to_string(), ip };
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
+
+    /// Add a port forwarding rule to a port.
+    pub fn add_port_forward(
+        &self,
+        port_name: &str,
+        forward: PortForward,
+    ) -> Result<NoResp, Error> {
+        let cmd = OpteCmd::AddPortForward;
+        let req =
+            AddPortForwardReq { port_name: port_name.to_string(), forward };
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
+
+    /// Delete a port forwarding rule from a port.
+    pub fn del_port_forward(
+        &self,
+        port_name: &str,
+        forward: PortForward,
+    ) -> Result<DelPortForwardResp, Error> {
+        let cmd = OpteCmd::DelPortForward;
+        let req =
+            DelPortForwardReq { port_name: port_name.to_string(), forward };
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
+
+    /// List the port forwarding rules of a port.
+    pub fn list_port_forwards(
+        &self,
+        port_name: &str,
+    ) -> Result<ListPortForwardsResp, Error> {
+        let cmd = OpteCmd::ListPortForwards;
+        let req = ListPortForwardsReq { port_name: port_name.to_string() };
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
+
+    /// Dump the SNAT mappings of a port, along with the flows using
+    /// their ports.
+    pub fn dump_snat(&self, port_name: &str) -> Result<DumpSnatResp, Error> {
+        let cmd = OpteCmd::DumpSnat;
+        let req = DumpSnatReq { port_name: port_name.to_string() };
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
+
     /// Return the contents of an OPTE layer.
     pub fn get_layer_by_name(
         &self,
@@ -172,6 +333,24 @@ impl OpteAdm {
         )
     }
 
+    /// Set the default action of a layer for the given direction.
+    pub fn set_default_action(
+        &self,
+        port_name: &str,
+        layer_name: &str,
+        dir: Direction,
+        action: DefaultAction,
+    ) -> Result<NoResp, Error> {
+        let cmd = OpteCmd::SetDefaultAction;
+        let req = api::SetDefaultActionReq {
+            port_name: port_name.to_string(),
+            layer_name: layer_name.to_string(),
+            dir,
+            action,
+        };
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
+
     /// Clear all entries from the Unified Flow Table (UFT).
     pub fn clear_uft(&self, port_name: &str) -> Result<NoResp, Error> {
         let cmd = OpteCmd::ClearUft;
@@ -197,14 +376,38 @@ impl OpteAdm {
         run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
     }
 
-    /// Dump the Virtual-to-Physical mappings.
-    pub fn dump_v2p(&self) -> Result<overlay::DumpVirt2PhysResp, Error> {
+    pub fn del_v2p(
+        &self,
+        req: &DelVirt2PhysReq,
+    ) -> Result<DelVirt2PhysResp, Error> {
+        let cmd = OpteCmd::DelVirt2Phys;
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
+
+    /// Replace all Virtual-to-Physical mappings of a VPC.
+    pub fn sync_v2p(&self, req: &SyncVirt2PhysReq) -> Result<NoResp, Error> {
+        let cmd = OpteCmd::SyncVirt2Phys;
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
+
+    /// Read the queued V2P misses, waiting for one if `block` is set
+    /// and the queue is empty.
+    pub fn read_v2p_misses(
+        &self,
+        block: bool,
+    ) -> Result<ReadV2pMissesResp, Error> {
+        let cmd = OpteCmd::ReadV2pMisses;
+        let req = ReadV2pMissesReq { block };
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
+
+    /// Dump a page of the Virtual-to-Physical mappings.
+    pub fn dump_v2p(
+        &self,
+        req: &overlay::DumpVirt2PhysReq,
+    ) -> Result<overlay::DumpVirt2PhysResp, Error> {
         let cmd = OpteCmd::DumpVirt2Phys;
-        run_cmd_ioctl(
-            self.device.as_raw_fd(),
-            cmd,
-            Some(&overlay::DumpVirt2PhysReq { unused: 99 }),
-        )
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(req))
     }
 
     pub fn add_router_entry(
@@ -214,4 +417,108 @@ impl OpteAdm {
         let cmd = OpteCmd::AddRouterEntry;
         run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
     }
+
+    pub fn del_router_entry(
+        &self,
+        req: &DelRouterEntryReq,
+    ) -> Result<DelRouterEntryResp, Error> {
+        let cmd = OpteCmd::DelRouterEntry;
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
+
+    /// List the router entries of a port.
+    pub fn list_router_entries(
+        &self,
+        port_name: &str,
+    ) -> Result<ListRouterEntriesResp, Error> {
+        let cmd = OpteCmd::ListRouterEntries;
+        let req = ListRouterEntriesReq { port_name: port_name.to_string() };
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
+
+    /// Enable packet capture on a port with the given configuration,
+    /// or disable it when `config` is `None`.
+    pub fn set_capture(
+        &self,
+        port_name: &str,
+        config: Option<CaptureConfig>,
+    ) -> Result<NoResp, Error> {
+        let cmd = OpteCmd::SetCapture;
+        let req =
+            api::SetCaptureReq { port_name: port_name.to_string(), config };
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
+
+    /// Return the contents of a port's capture ring.
+    ///
+    /// The ring is read one page at a time, up to the packets it held
+    /// when the first page was read.
+    pub fn dump_capture(
+        &self,
+        port_name: &str,
+    ) -> Result<api::DumpCaptureResp, Error> {
+        let cmd = OpteCmd::DumpCapture;
+        let mut req = api::DumpCaptureReq {
+            port_name: port_name.to_string(),
+            start: 0,
+            max_pkts: CAPTURE_DUMP_MAX,
+        };
+        let mut dump = run_cmd_ioctl::<api::DumpCaptureResp, _>(
+            self.device.as_raw_fd(),
+            cmd,
+            Some(&req),
+        )?;
+        let end = dump.next + dump.remaining;
+
+        while dump.next < end {
+            req.start = dump.next;
+            let page = run_cmd_ioctl::<api::DumpCaptureResp, _>(
+                self.device.as_raw_fd(),
+                cmd,
+                Some(&req),
+            )?;
+
+            if page.pkts.is_empty() {
+                break;
+            }
+
+            // Leave out the packets recorded after the first page.
+            let past_end = page.next.saturating_sub(end) as usize;
+            let keep = page.pkts.len().saturating_sub(past_end);
+            dump.pkts.extend(page.pkts.into_iter().take(keep));
+            dump.elapsed_ns = page.elapsed_ns;
+            dump.evicted = page.evicted;
+            dump.next = page.next;
+            dump.remaining = page.remaining;
+        }
+
+        Ok(dump)
+    }
+
+    /// Return a port's queued flow records, first acknowledging (and
+    /// thus removing) all records up to and including `ack`.
+    pub fn dump_flow_records(
+        &self,
+        port_name: &str,
+        ack: Option<u64>,
+    ) -> Result<api::DumpFlowRecordsResp, Error> {
+        let cmd = OpteCmd::DumpFlowRecords;
+        let req =
+            api::DumpFlowRecordsReq { port_name: port_name.to_string(), ack };
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
+
+    /// Return the rule log of a port's layer.
+    pub fn dump_rule_log(
+        &self,
+        port_name: &str,
+        layer_name: &str,
+    ) -> Result<api::DumpRuleLogResp, Error> {
+        let cmd = OpteCmd::DumpRuleLog;
+        let req = api::DumpRuleLogReq {
+            port_name: port_name.to_string(),
+            layer_name: layer_name.to_string(),
+        };
+        run_cmd_ioctl(self.device.as_raw_fd(), cmd, Some(&req))
+    }
 }
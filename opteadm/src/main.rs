@@ -6,40 +6,79 @@
 
 #![feature(extern_types)]
 
+use std::fs::File;
 use std::io;
+use std::io::Write;
+use std::path::PathBuf;
 use std::str::FromStr;
 
 use structopt::StructOpt;
 
 use opte::api::Direction;
+use opte::api::IpAddr;
 use opte::api::IpCidr;
 use opte::api::Ipv4Addr;
 use opte::api::Ipv4Cidr;
+use opte::api::Ipv6Addr;
+use opte::api::Ipv6Cidr;
 use opte::api::MacAddr;
 use opte::api::Vni;
+use opte::engine::capture::CaptureConfig;
+use opte::engine::capture::CapturePoint;
+use opte::engine::layer::DefaultAction;
+use opte::engine::predicate::Predicate;
 use opte::engine::print::print_layer;
 use opte::engine::print::print_list_layers;
 use opte::engine::print::print_tcp_flows;
 use opte::engine::print::print_uft;
+use opteadm::ipfix::write_flow_records;
+use opteadm::pcapng::write_capture;
 use opteadm::OpteAdm;
 use oxide_vpc::api::AddRouterEntryReq;
 use oxide_vpc::api::Address;
 use oxide_vpc::api::BoundaryServices;
+use oxide_vpc::api::DelAddrSetReq;
+use oxide_vpc::api::DelAddrSetResp;
+use oxide_vpc::api::DelPortForwardResp;
+use oxide_vpc::api::DelRouterEntryReq;
+use oxide_vpc::api::DelRouterEntryResp;
+use oxide_vpc::api::DelVirt2PhysReq;
+use oxide_vpc::api::DelVirt2PhysResp;
+use oxide_vpc::api::DetachFloatingIpResp;
 use oxide_vpc::api::Filters as FirewallFilters;
 use oxide_vpc::api::FirewallAction;
+use oxide_vpc::api::FirewallMode;
 use oxide_vpc::api::FirewallRule;
+use oxide_vpc::api::GuestPhysAddr;
 use oxide_vpc::api::IpCfg;
 use oxide_vpc::api::Ipv4Cfg;
+use oxide_vpc::api::Ipv6Cfg;
+use oxide_vpc::api::MirrorCfg;
+use oxide_vpc::api::Nat64Cfg;
+use oxide_vpc::api::NatMapping;
 use oxide_vpc::api::PhysNet;
+use oxide_vpc::api::PortForward;
 use oxide_vpc::api::PortInfo;
 use oxide_vpc::api::Ports;
 use oxide_vpc::api::ProtoFilter;
 use oxide_vpc::api::RemFwRuleReq;
+use oxide_vpc::api::RouteFilters;
 use oxide_vpc::api::RouterTarget;
 use oxide_vpc::api::SNat4Cfg;
+use oxide_vpc::api::SNat6Cfg;
+use oxide_vpc::api::SetAddrSetReq;
 use oxide_vpc::api::SetVirt2PhysReq;
+use oxide_vpc::api::SyncVirt2PhysReq;
 use oxide_vpc::api::VpcCfg;
+use oxide_vpc::engine::firewall::FW_LAYER_NAME;
+use oxide_vpc::engine::overlay::DumpVirt2PhysReq;
+use oxide_vpc::engine::print::print_addr_sets;
+use oxide_vpc::engine::print::print_bsvc_state;
+use oxide_vpc::engine::print::print_port_forwards;
+use oxide_vpc::engine::print::print_router_entries;
+use oxide_vpc::engine::print::print_snat;
 use oxide_vpc::engine::print::print_v2p;
+use oxide_vpc::engine::print::print_v2p_misses;
 
 /// Administer the Oxide Packet Transformation Engine (OPTE)
 #[derive(Debug, StructOpt)]
@@ -60,6 +99,87 @@ enum Command {
         name: String,
     },
 
+    /// Set the default action of a layer
+    SetDefaultAction {
+        #[structopt(short)]
+        port: String,
+
+        /// The name of the layer
+        layer: String,
+
+        #[structopt(long = "dir")]
+        direction: Direction,
+
+        /// One of "allow", "stateful-allow", "deny", or "action=<name>"
+        /// to use one of the layer's named actions
+        action: DefaultAction,
+    },
+
+    /// Enable (or disable) packet capture on a port
+    SetCapture {
+        #[structopt(short)]
+        port: String,
+
+        /// Disable capture and discard the capture ring
+        #[structopt(long)]
+        disable: bool,
+
+        /// The points at which to capture: guest, underlay, drop
+        #[structopt(
+            long,
+            use_delimiter = true,
+            default_value = "guest,underlay,drop"
+        )]
+        points: Vec<CapturePoint>,
+
+        /// The maximum number of packets held by the capture ring, at
+        /// most 4096 and lowered so the ring holds at most 8 MiB of
+        /// packet bytes
+        #[structopt(long, default_value = "1024")]
+        capacity: u32,
+
+        /// The maximum number of bytes captured from each packet
+        #[structopt(long, default_value = "256")]
+        snaplen: u32,
+
+        /// Only capture packets matching these predicates, e.g.
+        /// "ip.proto=TCP && ulp.dport=80"
+        #[structopt(long)]
+        filter: Option<String>,
+    },
+
+    /// Write the contents of a port's capture ring to a pcapng file
+    Capture {
+        #[structopt(short)]
+        port: String,
+
+        /// The pcapng file to write
+        #[structopt(short, long)]
+        output: PathBuf,
+    },
+
+    /// Retrieve and release the port's queued flow records, printing
+    /// them or writing them to an IPFIX file
+    FlowRecords {
+        #[structopt(short)]
+        port: String,
+
+        /// The IPFIX file to write
+        #[structopt(short, long)]
+        output: Option<PathBuf>,
+
+        /// The IPFIX Observation Domain ID
+        #[structopt(long, default_value = "0")]
+        domain_id: u32,
+    },
+
+    /// Print the firewall log of a port: the first packet of each
+    /// flow which matched a rule with a logging action
+    FwLog {
+        #[structopt(short)]
+        port: String,
+    },
+
     /// Clear all entries from the Unified Flow Table
     ClearUft {
         #[structopt(short)]
@@ -79,7 +199,11 @@ enum Command {
     },
 
     /// Dump virtual to physical address mapping
-    DumpV2P,
+    DumpV2P {
+        /// Dump only the mappings of this VNI
+        #[structopt(long)]
+        vni: Option<Vni>,
+    },
 
     /// Add a firewall rule
     AddFwRule {
@@ -92,11 +216,17 @@ enum Command {
         #[structopt(flatten)]
         filters: Filters,
 
+        /// One of "allow", "deny", "allow-log", or "deny-log"
         #[structopt(long)]
         action: FirewallAction,
 
         #[structopt(long)]
         priority: u16,
+
+        /// Either "stateful" or "stateless"; a stateless rule does not
+        /// track the flows it allows
+        #[structopt(long, default_value = "stateful")]
+        mode: FirewallMode,
     },
 
     /// Remove a firewall rule
@@ -111,11 +241,46 @@ enum Command {
     },
 
     /// Set/replace all firewall rules atomically
+    ///
+    /// Rules found in both the current and new sets are left in
+    /// place, as are the flows not affected by the changes.
     SetFwRules {
         #[structopt(short)]
         port: String,
     },
 
+    /// Create or replace a VPC address set, which firewall rules may
+    /// reference as "hosts=set=<name>"
+    SetAddrSet {
+        #[structopt(long)]
+        vni: Vni,
+
+        name: String,
+
+        /// The addresses of the set, either IPv4 or IPv6
+        addrs: Vec<opte::api::IpAddr>,
+    },
+
+    /// Delete a VPC address set
+    DelAddrSet {
+        #[structopt(long)]
+        vni: Vni,
+
+        name: String,
+    },
+
+    /// Dump the address sets of all VPCs
+    DumpAddrSets,
+
+    /// Set/replace all port mirrors atomically, one per line of
+    /// stdin, e.g. "name=ids dir=out dest=port:xde1 trunc=128 rate=10
+    /// priority=10 protocol=TCP"; where mirrors overlap, only the
+    /// one with the lowest priority value copies a packet
+    SetMirrors {
+        #[structopt(short)]
+        port: String,
+    },
+
     /// Create an xde device
     CreateXde {
         name: String,
@@ -123,20 +288,28 @@ enum Command {
         #[structopt(long)]
         guest_mac: MacAddr,
 
-        #[structopt(long)]
-        private_ip: std::net::Ipv4Addr,
+        /// The guest's private IPv4 address; if omitted, the port is
+        /// IPv6-only and --private-ipv6 is required
+        #[structopt(
+            long,
+            required_unless("private-ipv6"),
+            requires_all(&["vpc-subnet", "gateway-ip"])
+        )]
+        private_ip: Option<std::net::Ipv4Addr>,
 
         #[structopt(long)]
-        vpc_subnet: Ipv4Cidr,
+        vpc_subnet: Option<Ipv4Cidr>,
 
         #[structopt(long)]
         gateway_mac: MacAddr,
 
         #[structopt(long)]
-        gateway_ip: std::net::Ipv4Addr,
+        gateway_ip: Option<std::net::Ipv4Addr>,
 
-        #[structopt(long)]
-        bsvc_addr: std::net::Ipv6Addr,
+        /// An endpoint of Boundary Services, which may be given more
+        /// than once
+        #[structopt(long, required = true)]
+        bsvc_addr: Vec<std::net::Ipv6Addr>,
 
         #[structopt(long)]
         bsvc_vni: Vni,
@@ -150,7 +323,10 @@ enum Command {
         #[structopt(long)]
         src_underlay_addr: std::net::Ipv6Addr,
 
-        #[structopt(long, requires_all(&["snat-start", "snat-end"]))]
+        #[structopt(
+            long,
+            requires_all(&["private-ip", "snat-start", "snat-end"])
+        )]
         snat_ip: Option<std::net::Ipv4Addr>,
 
         #[structopt(long)]
@@ -159,11 +335,66 @@ enum Command {
         #[structopt(long)]
         snat_end: Option<u16>,
 
+        /// How flows are mapped to SNAT ports: "per-flow",
+        /// "endpoint-independent" (or "eim"), or "port-reuse"
+        #[structopt(long, default_value = "per-flow")]
+        snat_mapping: NatMapping,
+
+        /// The most SNAT ports the guest may hold at once, out of the
+        /// range it shares with the ports configured with the same one;
+        /// past that, new flows are refused a port unless the mapping
+        /// is "port-reuse"
+        #[structopt(long)]
+        snat_max_ports: Option<u16>,
+
+        /// A floating IP to attach, which may be given more than once;
+        /// the first one is used for outbound traffic
+        #[structopt(long, requires("private-ip"))]
+        external_ipv4: Vec<Ipv4Addr>,
+
+        /// The guest's private IPv6 address, making the port
+        /// dual-stack, or IPv6-only if --private-ip is omitted
+        #[structopt(long, requires_all(&["vpc-subnet6", "gateway-ipv6"]))]
+        private_ipv6: Option<std::net::Ipv6Addr>,
+
+        #[structopt(long)]
+        vpc_subnet6: Option<Ipv6Cidr>,
+
         #[structopt(long)]
-        phys_gw_mac: Option<MacAddr>,
+        gateway_ipv6: Option<std::net::Ipv6Addr>,
+
+        /// The IPv6 SNAT address; --snat-mapping and --snat-max-ports
+        /// apply to it as well
+        #[structopt(
+            long,
+            requires_all(&["private-ipv6", "snat6-start", "snat6-end"])
+        )]
+        snat6_ip: Option<std::net::Ipv6Addr>,
 
         #[structopt(long)]
-        external_ipv4: Option<Ipv4Addr>,
+        snat6_start: Option<u16>,
+
+        #[structopt(long)]
+        snat6_end: Option<u16>,
+
+        /// An IPv6 floating IP to attach, which may be given more than
+        /// once; the first one is used for outbound traffic
+        #[structopt(long, requires("private-ipv6"))]
+        external_ipv6: Vec<Ipv6Addr>,
+
+        /// The IPv4 address of NAT64 for the IPv6 guest;
+        /// --snat-mapping and --snat-max-ports apply to it as well
+        #[structopt(
+            long,
+            requires_all(&["private-ipv6", "nat64-start", "nat64-end"])
+        )]
+        nat64_ip: Option<std::net::Ipv4Addr>,
+
+        #[structopt(long)]
+        nat64_start: Option<u16>,
+
+        #[structopt(long)]
+        nat64_end: Option<u16>,
 
         #[structopt(long)]
         passthrough: bool,
@@ -183,6 +414,54 @@ enum Command {
         vni: Vni,
     },
 
+    /// Delete a virtual-to-physical mapping
+    DelV2P { vpc_ip: IpAddr, vni: Vni },
+
+    /// Replace all virtual-to-physical mappings of a VPC with those
+    /// read from stdin, one "<vpc ip> <vpc mac> <underlay ip>" per
+    /// line
+    SyncV2P {
+        vni: Vni,
+        /// The generation of the mappings; a sync older than the last
+        /// one applied is rejected
+        gen: u64,
+    },
+
+    /// Read the VPC IPs for which the overlay found no
+    /// virtual-to-physical mapping
+    ReadV2PMisses {
+        /// Wait for a miss if none are queued
+        #[structopt(long)]
+        wait: bool,
+    },
+
+    /// Mark a Boundary Services endpoint as down, moving the flows
+    /// using it to the other endpoints, or back up
+    SetBsvcState {
+        ip: std::net::Ipv6Addr,
+
+        /// Mark the endpoint back up, rather than down
+        #[structopt(long)]
+        up: bool,
+    },
+
+    /// Dump the state of the Boundary Services endpoints
+    DumpBsvcState,
+
+    /// Attach a floating IP to a port
+    AttachFloatingIp {
+        #[structopt(short)]
+        port: String,
+        ip: IpAddr,
+    },
+
+    /// Detach a floating IP from a port
+    DetachFloatingIp {
+        #[structopt(short)]
+        port: String,
+        ip: IpAddr,
+    },
+
     /// Add a new router entry, either IPv4 or IPv6.
     AddRouterEntry {
         /// The OPTE port to which the route is added
@@ -190,22 +469,87 @@ enum Command {
         port: String,
         /// The network destination to which the route applies.
         dest: IpCidr,
+        /// The location to which traffic matching the destination is sent:
+        /// drop, ig, ip4=<ip>, ip6=<ip>, sub4=<cidr>, sub6=<cidr>, or
+        /// ecmp=<ip>,<ip>,... to spread flows across several next hops.
+        target: RouterTarget,
+        #[structopt(flatten)]
+        filters: RouteFilterOpts,
+        /// The priority of the entry, where a lower value takes
+        /// precedence; required when filtering on more than the
+        /// destination. By default, entries are ordered by longest
+        /// prefix match.
+        #[structopt(long)]
+        priority: Option<u16>,
+    },
+
+    /// Delete a router entry, either IPv4 or IPv6.
+    DelRouterEntry {
+        /// The OPTE port from which the route is deleted
+        #[structopt(short)]
+        port: String,
+        /// The network destination to which the route applies.
+        dest: IpCidr,
         /// The location to which traffic matching the destination is sent.
         target: RouterTarget,
+        #[structopt(flatten)]
+        filters: RouteFilterOpts,
+        /// The priority of the entry.
+        #[structopt(long)]
+        priority: Option<u16>,
+    },
+
+    /// List the router entries of a port.
+    ListRouterEntries {
+        #[structopt(short)]
+        port: String,
+    },
+
+    /// Forward ports of an external IP to a port's private IP
+    AddPortForward {
+        #[structopt(short)]
+        port: String,
+        #[structopt(flatten)]
+        forward: PortForwardOpts,
+    },
+
+    /// Delete a port forwarding rule
+    DelPortForward {
+        #[structopt(short)]
+        port: String,
+        #[structopt(flatten)]
+        forward: PortForwardOpts,
+    },
+
+    /// List the port forwarding rules of a port
+    ListPortForwards {
+        #[structopt(short)]
+        port: String,
+    },
+
+    /// Dump the SNAT mappings of a port, along with the flows using
+    /// their ports
+    DumpSnat {
+        #[structopt(short)]
+        port: String,
     },
 }
 
 #[derive(Debug, StructOpt)]
 struct Filters {
-    /// The host address or subnet to which the rule applies
+    /// The host address or subnet to which the rule applies, either
+    /// IPv4 or IPv6, e.g. "ip=fd00::1" or "subnet=10.0.0.0/24", or
+    /// the name of an address set, e.g. "set=web-servers"
     #[structopt(long)]
     hosts: Address,
 
-    /// The protocol to which the rule applies
+    /// The protocol to which the rule applies; an ICMP or ICMPv6 type
+    /// and optional code may be given, e.g. "icmp:3/4" or "icmpv6:128"
     #[structopt(long)]
     protocol: ProtoFilter,
 
-    /// The port(s) to which the rule applies
+    /// The port(s) to which the rule applies, as a list of ports and
+    /// port ranges, e.g. "22,30000-32767"
     #[structopt(long)]
     ports: Ports,
 }
@@ -220,6 +564,79 @@ impl From<Filters> for FirewallFilters {
     }
 }
 
+#[derive(Debug, StructOpt)]
+struct RouteFilterOpts {
+    /// Only route traffic from this subnet, e.g. "10.0.0.0/24"
+    #[structopt(long)]
+    src: Option<IpCidr>,
+
+    /// Only route traffic of this protocol, e.g. "tcp" or "udp"
+    #[structopt(long)]
+    protocol: Option<ProtoFilter>,
+
+    /// Only route traffic to these port(s), as a list of ports and
+    /// port ranges, e.g. "22,30000-32767"
+    #[structopt(long, default_value = "any")]
+    ports: Ports,
+}
+
+impl TryFrom<RouteFilterOpts> for RouteFilters {
+    type Error = anyhow::Error;
+
+    fn try_from(f: RouteFilterOpts) -> Result<Self, Self::Error> {
+        let protocol = match f.protocol {
+            None | Some(ProtoFilter::Any) => None,
+            Some(ProtoFilter::Proto(proto)) => Some(proto),
+            Some(p) => anyhow::bail!("cannot route on protocol: {}", p),
+        };
+
+        Ok(Self { src: f.src, protocol, ports: f.ports })
+    }
+}
+
+#[derive(Debug, StructOpt)]
+struct PortForwardOpts {
+    /// The external IP address to forward from
+    external_ip: IpAddr,
+
+    /// The protocol to forward: "tcp" or "udp"
+    #[structopt(long)]
+    protocol: ProtoFilter,
+
+    /// The external port, or port range, to forward, e.g. "8080" or
+    /// "30000-30099"
+    #[structopt(long)]
+    ports: Ports,
+
+    /// The private port to which the first external port is forwarded
+    #[structopt(long)]
+    private_port: u16,
+}
+
+impl TryFrom<PortForwardOpts> for PortForward {
+    type Error = anyhow::Error;
+
+    fn try_from(f: PortForwardOpts) -> Result<Self, Self::Error> {
+        let proto = match f.protocol {
+            ProtoFilter::Proto(proto) => proto,
+            p => anyhow::bail!("cannot forward protocol: {}", p),
+        };
+
+        let external_ports = match f.ports {
+            Ports::PortList(list) if list.len() == 1 => list[0]..=list[0],
+            Ports::Range(range) => range,
+            _ => anyhow::bail!("expected a single port or port range"),
+        };
+
+        Ok(Self {
+            external_ip: f.external_ip,
+            proto,
+            external_ports,
+            private_port: f.private_port,
+        })
+    }
+}
+
 fn print_port_header() {
     println!(
         "{:<32} {:<24} {:<16} {:<16} {:<40} {:<40} {:<8}",
@@ -233,6 +650,14 @@ fn print_port_header() {
     );
 }
 
+fn join_ips<T: std::fmt::Display>(ips: &[T]) -> String {
+    if ips.is_empty() {
+        return String::from("None");
+    }
+
+    ips.iter().map(|ip| ip.to_string()).collect::<Vec<_>>().join(",")
+}
+
 fn print_port(pi: PortInfo) {
     let none = String::from("None");
     println!(
@@ -240,13 +665,9 @@ fn print_port(pi: PortInfo) {
         pi.name,
         pi.mac_addr.to_string(),
         pi.ip4_addr.map(|x| x.to_string()).unwrap_or_else(|| none.clone()),
-        pi.external_ip4_addr
-            .map(|x| x.to_string())
-            .unwrap_or_else(|| none.clone()),
+        join_ips(&pi.external_ip4_addrs),
         pi.ip6_addr.map(|x| x.to_string()).unwrap_or_else(|| none.clone()),
-        pi.external_ip6_addr
-            .map(|x| x.to_string())
-            .unwrap_or_else(|| none.clone()),
+        join_ips(&pi.external_ip6_addrs),
         pi.state,
     );
 }
@@ -272,6 +693,92 @@ fn main() -> anyhow::Result<()> {
             print_layer(&hdl.get_layer_by_name(&port, &name)?);
         }
 
+        Command::SetDefaultAction { port, layer, direction, action } => {
+            let hdl = opteadm::OpteAdm::open(OpteAdm::DLD_CTL)?;
+            hdl.set_default_action(&port, &layer, direction, action)?;
+        }
+
+        Command::SetCapture {
+            port,
+            disable,
+            points,
+            capacity,
+            snaplen,
+            filter,
+        } => {
+            let hdl = opteadm::OpteAdm::open(OpteAdm::DLD_CTL)?;
+            let config = if disable {
+                None
+            } else {
+                let mut preds = vec![];
+                for clause in filter.iter().flat_map(|f| f.split("&&")) {
+                    let pred =
+                        Predicate::from_str(clause.trim()).map_err(|e| {
+                            anyhow::anyhow!("Invalid filter: {e:?}")
+                        })?;
+                    preds.push(pred);
+                }
+                Some(CaptureConfig { capacity, snaplen, points, preds })
+            };
+            hdl.set_capture(&port, config)?;
+        }
+
+        Command::Capture { port, output } => {
+            let hdl = opteadm::OpteAdm::open(OpteAdm::DLD_CTL)?;
+            let dump = hdl.dump_capture(&port)?;
+            let mut file = io::BufWriter::new(File::create(&output)?);
+            write_capture(&mut file, &port, &dump)?;
+            file.flush()?;
+            println!(
+                "wrote {} packets to {} ({} evicted)",
+                dump.pkts.len(),
+                output.display(),
+                dump.evicted,
+            );
+        }
+
+        Command::FlowRecords { port, output, domain_id } => {
+            let hdl = opteadm::OpteAdm::open(OpteAdm::DLD_CTL)?;
+            let dump = hdl.dump_flow_records(&port, None)?;
+            match &output {
+                Some(output) => {
+                    let mut file = io::BufWriter::new(File::create(output)?);
+                    write_flow_records(&mut file, domain_id, &dump)?;
+                    file.flush()?;
+                    println!(
+                        "wrote {} records to {} ({} dropped)",
+                        dump.records.len(),
+                        output.display(),
+                        dump.dropped,
+                    );
+                }
+
+                None => {
+                    for rec in &dump.records {
+                        println!("{}", rec);
+                    }
+                }
+            }
+
+            // Only release the records once they have been written.
+            if let Some(last) = dump.records.last() {
+                hdl.dump_flow_records(&port, Some(last.seq))?;
+            }
+        }
+
+        Command::FwLog { port } => {
+            let hdl = opteadm::OpteAdm::open(OpteAdm::DLD_CTL)?;
+            let dump = hdl.dump_rule_log(&port, FW_LAYER_NAME)?;
+            if dump.lost > 0 {
+                println!("({} older entries lost)", dump.lost);
+            }
+
+            for entry in &dump.entries {
+                let age_ms = (dump.elapsed_ns - entry.time_ns) / 1_000_000;
+                println!("{} ({} ms ago)", entry, age_ms);
+            }
+        }
+
         Command::ClearUft { port } => {
             let hdl = opteadm::OpteAdm::open(OpteAdm::DLD_CTL)?;
             hdl.clear_uft(&port)?;
@@ -287,18 +794,49 @@ fn main() -> anyhow::Result<()> {
             print_tcp_flows(&hdl.dump_tcp_flows(&port)?);
         }
 
-        Command::DumpV2P => {
+        Command::DumpV2P { vni } => {
             let hdl = opteadm::OpteAdm::open(OpteAdm::DLD_CTL)?;
-            print_v2p(&hdl.dump_v2p()?);
+            let req = DumpVirt2PhysReq { vni, ..Default::default() };
+            let mut resp = hdl.dump_v2p(&req)?;
+
+            // Each VPC is dumped a page at a time, fetch the remaining
+            // pages of any VPC not dumped in full.
+            for vpc in &mut resp.mappings {
+                while let Some(after) = vpc.next {
+                    let req = DumpVirt2PhysReq {
+                        vni: Some(vpc.vni),
+                        after: Some(after),
+                        limit: 0,
+                    };
+                    let mut page = hdl.dump_v2p(&req)?;
+                    let page = match page.mappings.pop() {
+                        Some(page) => page,
+                        None => break,
+                    };
+                    vpc.ip4.extend(page.ip4);
+                    vpc.ip6.extend(page.ip6);
+                    vpc.next = page.next;
+                }
+            }
+
+            print_v2p(&resp);
         }
 
-        Command::AddFwRule { port, direction, filters, action, priority } => {
+        Command::AddFwRule {
+            port,
+            direction,
+            filters,
+            action,
+            priority,
+            mode,
+        } => {
             let hdl = opteadm::OpteAdm::open(OpteAdm::DLD_CTL)?;
             let rule = FirewallRule {
                 direction,
                 filters: filters.into(),
                 action,
                 priority,
+                mode,
             };
             hdl.add_firewall_rule(&port, &rule)?;
         }
@@ -313,7 +851,46 @@ fn main() -> anyhow::Result<()> {
             }
 
             let hdl = opteadm::OpteAdm::open(OpteAdm::DLD_CTL)?;
-            hdl.set_firewall_rules(&port, rules)?;
+            let resp = hdl.set_firewall_rules(&port, rules)?;
+            println!(
+                "added: {}, removed: {}, unchanged: {}, flows invalidated: {}",
+                resp.added,
+                resp.removed,
+                resp.unchanged,
+                resp.flows_invalidated,
+            );
+        }
+
+        Command::SetAddrSet { vni, name, addrs } => {
+            let hdl = opteadm::OpteAdm::open(OpteAdm::DLD_CTL)?;
+            let req = SetAddrSetReq { vni, name, addrs };
+            hdl.set_addr_set(&req)?;
+        }
+
+        Command::DelAddrSet { vni, name } => {
+            let hdl = opteadm::OpteAdm::open(OpteAdm::DLD_CTL)?;
+            let req = DelAddrSetReq { vni, name: name.clone() };
+            if let DelAddrSetResp::NotFound = hdl.del_addr_set(&req)? {
+                anyhow::bail!("address set not found: {}", name);
+            }
+        }
+
+        Command::DumpAddrSets => {
+            let hdl = opteadm::OpteAdm::open(OpteAdm::DLD_CTL)?;
+            print_addr_sets(&hdl.dump_addr_sets()?);
+        }
+
+        Command::SetMirrors { port } => {
+            let mut mirrors = vec![];
+            for line in io::stdin().lines() {
+                let mirror_str = line?;
+                let m = MirrorCfg::from_str(&mirror_str)
+                    .map_err(|e| anyhow::anyhow!("Invalid mirror: {e}"))?;
+                mirrors.push(m);
+            }
+
+            let hdl = opteadm::OpteAdm::open(OpteAdm::DLD_CTL)?;
+            hdl.set_mirrors(&port, mirrors)?;
         }
 
         Command::CreateXde {
@@ -331,8 +908,19 @@ fn main() -> anyhow::Result<()> {
             snat_ip,
             snat_start,
             snat_end,
-            phys_gw_mac,
+            snat_mapping,
+            snat_max_ports,
             external_ipv4,
+            private_ipv6,
+            vpc_subnet6,
+            gateway_ipv6,
+            snat6_ip,
+            snat6_start,
+            snat6_end,
+            external_ipv6,
+            nat64_ip,
+            nat64_start,
+            nat64_end,
             passthrough,
         } => {
             let hdl = opteadm::OpteAdm::open(OpteAdm::DLD_CTL)?;
@@ -343,33 +931,78 @@ fn main() -> anyhow::Result<()> {
                         snat_start.unwrap(),
                         snat_end.unwrap(),
                     ),
+                    mapping: snat_mapping,
+                    max_ports: snat_max_ports,
                 }),
 
                 None => None,
             };
 
+            let ipv4 = private_ip.map(|ip| Ipv4Cfg {
+                vpc_subnet: vpc_subnet.unwrap(),
+                private_ip: ip.into(),
+                gateway_ip: gateway_ip.unwrap().into(),
+                snat,
+                external_ips: external_ipv4,
+            });
+
+            let ipv6 = match private_ipv6 {
+                Some(ip) => {
+                    let snat = snat6_ip.map(|ip| SNat6Cfg {
+                        external_ip: ip.into(),
+                        ports: core::ops::RangeInclusive::new(
+                            snat6_start.unwrap(),
+                            snat6_end.unwrap(),
+                        ),
+                        mapping: snat_mapping,
+                        max_ports: snat_max_ports,
+                    });
+
+                    let nat64 = nat64_ip.map(|ip| Nat64Cfg {
+                        external_ip: ip.into(),
+                        ports: core::ops::RangeInclusive::new(
+                            nat64_start.unwrap(),
+                            nat64_end.unwrap(),
+                        ),
+                        mapping: snat_mapping,
+                        max_ports: snat_max_ports,
+                    });
+
+                    Some(Ipv6Cfg {
+                        vpc_subnet: vpc_subnet6.unwrap(),
+                        private_ip: ip.into(),
+                        gateway_ip: gateway_ipv6.unwrap().into(),
+                        snat,
+                        external_ips: external_ipv6,
+                        nat64,
+                    })
+                }
+
+                None => None,
+            };
+
+            // Unwrap: One of --private-ip or --private-ipv6 is
+            // required.
+            let ip_cfg = match (ipv4, ipv6) {
+                (Some(ipv4), Some(ipv6)) => IpCfg::DualStack { ipv4, ipv6 },
+                (Some(ipv4), None) => IpCfg::Ipv4(ipv4),
+                (None, ipv6) => IpCfg::Ipv6(ipv6.unwrap()),
+            };
+
             let cfg = VpcCfg {
-                ip_cfg: IpCfg::Ipv4(Ipv4Cfg {
-                    vpc_subnet,
-                    private_ip: private_ip.into(),
-                    gateway_ip: gateway_ip.into(),
-                    snat,
-                    external_ips: external_ipv4,
-                }),
+                ip_cfg,
                 guest_mac,
                 gateway_mac,
                 vni: vpc_vni,
                 phys_ip: src_underlay_addr.into(),
-                boundary_services: BoundaryServices {
-                    ip: bsvc_addr.into(),
-                    vni: bsvc_vni,
-                    mac: bsvc_mac,
-                },
-                // XXX-EXT-IP: This is part of the external IP hack. We're
-                // removing this shortly, and won't be supporting creating OPTE
-                // ports through `opteadm` that use the hack.
-                proxy_arp_enable: false,
-                phys_gw_mac,
+                boundary_services: bsvc_addr
+                    .into_iter()
+                    .map(|ip| BoundaryServices {
+                        ip: ip.into(),
+                        vni: bsvc_vni,
+                        mac: bsvc_mac,
+                    })
+                    .collect(),
             };
 
             hdl.create_xde(&name, cfg, passthrough)?;
@@ -399,11 +1032,127 @@ fn main() -> anyhow::Result<()> {
             hdl.set_v2p(&req)?;
         }
 
-        Command::AddRouterEntry { port, dest, target } => {
+        Command::DelV2P { vpc_ip, vni } => {
+            let hdl = opteadm::OpteAdm::open(OpteAdm::DLD_CTL)?;
+            let req = DelVirt2PhysReq { vip: vpc_ip, vni };
+            if let DelVirt2PhysResp::NotFound = hdl.del_v2p(&req)? {
+                anyhow::bail!("mapping not found: {} in VNI {}", vpc_ip, vni);
+            }
+        }
+
+        Command::SyncV2P { vni, gen } => {
+            let mut mappings = vec![];
+            for line in io::stdin().lines() {
+                let line = line?;
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                let (vip, ether, ip) = match fields.as_slice() {
+                    [] => continue,
+                    [vip, ether, ip] => (vip, ether, ip),
+                    _ => anyhow::bail!("Invalid mapping: {line}"),
+                };
+                let vip = vip
+                    .parse::<IpAddr>()
+                    .map_err(|e| anyhow::anyhow!("Invalid VPC IP: {e}"))?;
+                let ether = ether
+                    .parse::<MacAddr>()
+                    .map_err(|e| anyhow::anyhow!("Invalid VPC MAC: {e}"))?;
+                let ip = ip.parse::<std::net::Ipv6Addr>()?.into();
+                mappings.push((vip, GuestPhysAddr { ether, ip }));
+            }
+
+            let hdl = opteadm::OpteAdm::open(OpteAdm::DLD_CTL)?;
+            let req = SyncVirt2PhysReq { vni, gen, mappings };
+            hdl.sync_v2p(&req)?;
+        }
+
+        Command::ReadV2PMisses { wait } => {
+            let hdl = opteadm::OpteAdm::open(OpteAdm::DLD_CTL)?;
+            print_v2p_misses(&hdl.read_v2p_misses(wait)?);
+        }
+
+        Command::SetBsvcState { ip, up } => {
+            let hdl = opteadm::OpteAdm::open(OpteAdm::DLD_CTL)?;
+            hdl.set_bsvc_state(ip.into(), up)?;
+        }
+
+        Command::DumpBsvcState => {
+            let hdl = opteadm::OpteAdm::open(OpteAdm::DLD_CTL)?;
+            print_bsvc_state(&hdl.dump_bsvc_state()?);
+        }
+
+        Command::AttachFloatingIp { port, ip } => {
             let hdl = opteadm::OpteAdm::open(OpteAdm::DLD_CTL)?;
-            let req = AddRouterEntryReq { port_name: port, dest, target };
+            hdl.attach_floating_ip(&port, ip)?;
+        }
+
+        Command::DetachFloatingIp { port, ip } => {
+            let hdl = opteadm::OpteAdm::open(OpteAdm::DLD_CTL)?;
+            if let DetachFloatingIpResp::NotFound =
+                hdl.detach_floating_ip(&port, ip)?
+            {
+                anyhow::bail!("floating IP not found: {}", ip);
+            }
+        }
+
+        Command::AddRouterEntry { port, dest, target, filters, priority } => {
+            let hdl = opteadm::OpteAdm::open(OpteAdm::DLD_CTL)?;
+            let req = AddRouterEntryReq {
+                port_name: port,
+                dest,
+                target,
+                filters: filters.try_into()?,
+                priority,
+            };
             hdl.add_router_entry(&req)?;
         }
+
+        Command::DelRouterEntry { port, dest, target, filters, priority } => {
+            let hdl = opteadm::OpteAdm::open(OpteAdm::DLD_CTL)?;
+            let req = DelRouterEntryReq {
+                port_name: port,
+                dest,
+                target,
+                filters: filters.try_into()?,
+                priority,
+            };
+            if let DelRouterEntryResp::NotFound = hdl.del_router_entry(&req)? {
+                anyhow::bail!(
+                    "router entry not found: {} {}",
+                    req.dest,
+                    req.target
+                );
+            }
+        }
+
+        Command::ListRouterEntries { port } => {
+            let hdl = opteadm::OpteAdm::open(OpteAdm::DLD_CTL)?;
+            print_router_entries(&hdl.list_router_entries(&port)?);
+        }
+
+        Command::AddPortForward { port, forward } => {
+            let hdl = opteadm::OpteAdm::open(OpteAdm::DLD_CTL)?;
+            hdl.add_port_forward(&port, forward.try_into()?)?;
+        }
+
+        Command::DelPortForward { port, forward } => {
+            let hdl = opteadm::OpteAdm::open(OpteAdm::DLD_CTL)?;
+            let forward: PortForward = forward.try_into()?;
+            if let DelPortForwardResp::NotFound =
+                hdl.del_port_forward(&port, forward.clone())?
+            {
+                anyhow::bail!("port forward not found: {}", forward);
+            }
+        }
+
+        Command::ListPortForwards { port } => {
+            let hdl = opteadm::OpteAdm::open(OpteAdm::DLD_CTL)?;
+            print_port_forwards(&hdl.list_port_forwards(&port)?);
+        }
+
+        Command::DumpSnat { port } => {
+            let hdl = opteadm::OpteAdm::open(OpteAdm::DLD_CTL)?;
+            print_snat(&hdl.dump_snat(&port)?);
+        }
     }
 
     Ok(())
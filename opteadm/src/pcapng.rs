@@ -0,0 +1,145 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! A minimal pcapng writer for port captures.
+//!
+//! The file consists of a single section with a single Ethernet
+//! interface, named after the port. Each captured packet is written
+//! as an Enhanced Packet Block carrying its direction in the
+//! `epb_flags` option and its capture point (and drop reason, if
+//! any) in an `opt_comment` option.
+//!
+//! See draft-ietf-opsawg-pcapng for the format.
+use std::io;
+use std::io::Write;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use opte::api::Direction;
+use opte::engine::capture::CapturedPacket;
+use opte::engine::ioctl::DumpCaptureResp;
+
+const BT_SHB: u32 = 0x0A0D_0D0A;
+const BT_IDB: u32 = 0x0000_0001;
+const BT_EPB: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const LINKTYPE_ETHERNET: u16 = 1;
+
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const SHB_USERAPPL: u16 = 4;
+const IF_NAME: u16 = 2;
+const IF_TSRESOL: u16 = 9;
+const EPB_FLAGS: u16 = 2;
+
+// The epb_flags inbound/outbound direction bits.
+const EPB_FLAG_INBOUND: u32 = 0b01;
+const EPB_FLAG_OUTBOUND: u32 = 0b10;
+
+fn pad4(len: usize) -> usize {
+    (4 - (len % 4)) % 4
+}
+
+fn push_opt(buf: &mut Vec<u8>, code: u16, val: &[u8]) {
+    buf.extend_from_slice(&code.to_le_bytes());
+    buf.extend_from_slice(&(val.len() as u16).to_le_bytes());
+    buf.extend_from_slice(val);
+    buf.extend(std::iter::repeat(0).take(pad4(val.len())));
+}
+
+fn push_end_opt(buf: &mut Vec<u8>) {
+    push_opt(buf, OPT_ENDOFOPT, &[]);
+}
+
+// Write a block of the given type, wrapping the body with the type
+// and the (repeated) total length.
+fn write_block<W: Write>(w: &mut W, btype: u32, body: &[u8]) -> io::Result<()> {
+    let total_len = (12 + body.len()) as u32;
+    w.write_all(&btype.to_le_bytes())?;
+    w.write_all(&total_len.to_le_bytes())?;
+    w.write_all(body)?;
+    w.write_all(&total_len.to_le_bytes())
+}
+
+fn write_shb<W: Write>(w: &mut W) -> io::Result<()> {
+    let mut body = vec![];
+    body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+    // Major and minor version.
+    body.extend_from_slice(&1u16.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    // The section length is unspecified.
+    body.extend_from_slice(&(-1i64).to_le_bytes());
+    push_opt(&mut body, SHB_USERAPPL, b"opteadm");
+    push_end_opt(&mut body);
+    write_block(w, BT_SHB, &body)
+}
+
+fn write_idb<W: Write>(w: &mut W, name: &str, snaplen: u32) -> io::Result<()> {
+    let mut body = vec![];
+    body.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+    // Reserved.
+    body.extend_from_slice(&0u16.to_le_bytes());
+    body.extend_from_slice(&snaplen.to_le_bytes());
+    push_opt(&mut body, IF_NAME, name.as_bytes());
+    // Timestamps are in nanoseconds (10^-9).
+    push_opt(&mut body, IF_TSRESOL, &[9]);
+    push_end_opt(&mut body);
+    write_block(w, BT_IDB, &body)
+}
+
+fn write_epb<W: Write>(
+    w: &mut W,
+    ts_ns: u64,
+    pkt: &CapturedPacket,
+) -> io::Result<()> {
+    let mut body = vec![];
+    // Interface ID.
+    body.extend_from_slice(&0u32.to_le_bytes());
+    body.extend_from_slice(&((ts_ns >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(ts_ns as u32).to_le_bytes());
+    body.extend_from_slice(&(pkt.bytes.len() as u32).to_le_bytes());
+    body.extend_from_slice(&pkt.orig_len.to_le_bytes());
+    body.extend_from_slice(&pkt.bytes);
+    body.extend(std::iter::repeat(0).take(pad4(pkt.bytes.len())));
+
+    let mut comment = format!("point={} dir={}", pkt.point, pkt.dir);
+    if let Some(reason) = &pkt.drop_reason {
+        comment.push_str(&format!(" reason={}", reason));
+    }
+    push_opt(&mut body, OPT_COMMENT, comment.as_bytes());
+
+    let flags = match pkt.dir {
+        Direction::In => EPB_FLAG_INBOUND,
+        Direction::Out => EPB_FLAG_OUTBOUND,
+    };
+    push_opt(&mut body, EPB_FLAGS, &flags.to_le_bytes());
+    push_end_opt(&mut body);
+    write_block(w, BT_EPB, &body)
+}
+
+/// Write the capture dump of the port `name` as a pcapng section.
+///
+/// The packet timestamps are converted to wall-clock time using the
+/// dump's `elapsed_ns` relative to the current system time.
+pub fn write_capture<W: Write>(
+    w: &mut W,
+    name: &str,
+    dump: &DumpCaptureResp,
+) -> io::Result<()> {
+    let now_ns = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    let start_ns = now_ns.saturating_sub(dump.elapsed_ns);
+
+    write_shb(w)?;
+    write_idb(w, name, dump.config.snaplen)?;
+    for pkt in &dump.pkts {
+        write_epb(w, start_ns + pkt.ts_ns, pkt)?;
+    }
+
+    Ok(())
+}
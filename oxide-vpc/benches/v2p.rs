@@ -0,0 +1,103 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! Benchmarks of the V2P store at one million mappings.
+//!
+//! cargo +nightly bench -p oxide-vpc --bench v2p
+#![feature(test)]
+
+extern crate test;
+
+use opte::api::IpAddr;
+use opte::api::Ipv4Addr;
+use opte::api::Ipv6Addr;
+use opte::api::MacAddr;
+use opte::api::Vni;
+use opte::engine::rule::MappingResource;
+use oxide_vpc::api::GuestPhysAddr;
+use oxide_vpc::engine::overlay::Virt2Phys;
+use oxide_vpc::engine::overlay::VpcMappings;
+use std::sync::Arc;
+use test::black_box;
+use test::Bencher;
+
+const MAPPINGS: u32 = 1_000_000;
+const SLEDS: u32 = 512;
+
+fn mapping(i: u32) -> (IpAddr, GuestPhysAddr) {
+    let [a, b, c, d] = i.to_be_bytes();
+    let sled = (i % SLEDS) as u16;
+    let [s0, s1] = sled.to_be_bytes();
+    let vip = IpAddr::Ip4(Ipv4Addr::from(0x0A00_0000 + i));
+    let phys = GuestPhysAddr {
+        ether: MacAddr::from([0xA8, 0x40, a, b, c, d]),
+        ip: Ipv6Addr::from([
+            0xFD, 0x00, 0x11, 0x22, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, s0, s1,
+        ]),
+    };
+    (vip, phys)
+}
+
+fn mappings() -> Vec<(IpAddr, GuestPhysAddr)> {
+    (0..MAPPINGS).map(mapping).collect()
+}
+
+fn vni() -> Vni {
+    Vni::new(7777u32).unwrap()
+}
+
+fn full_v2p() -> Arc<Virt2Phys> {
+    let v2p = VpcMappings::new().sync(vni(), 1, &mappings()).unwrap();
+    eprintln!(
+        "{} mappings in {} bytes ({} bytes per mapping)",
+        v2p.len(),
+        v2p.mem_bytes(),
+        v2p.mem_bytes() / v2p.len()
+    );
+    v2p
+}
+
+#[bench]
+fn lookup_hit(b: &mut Bencher) {
+    let v2p = full_v2p();
+    let mut i = 0;
+    b.iter(|| {
+        i = (i + 7919) % MAPPINGS;
+        black_box(v2p.get(&mapping(i).0))
+    });
+}
+
+#[bench]
+fn lookup_miss(b: &mut Bencher) {
+    let v2p = full_v2p();
+    let mut i = 0;
+    b.iter(|| {
+        i = (i + 7919) % MAPPINGS;
+        black_box(v2p.get(&mapping(MAPPINGS + i).0))
+    });
+}
+
+#[bench]
+fn set(b: &mut Bencher) {
+    let v2p = full_v2p();
+    let mut i = 0;
+    b.iter(|| {
+        i = (i + 7919) % (2 * MAPPINGS);
+        let (vip, phys) = mapping(i);
+        black_box(v2p.set(vip, phys))
+    });
+}
+
+#[bench]
+fn sync(b: &mut Bencher) {
+    let vpc_map = VpcMappings::new();
+    let mappings = mappings();
+    let mut gen = 0;
+    b.iter(|| {
+        gen += 1;
+        vpc_map.sync(vni(), gen, &mappings).unwrap()
+    });
+}
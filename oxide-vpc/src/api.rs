@@ -6,6 +6,8 @@
 
 use core::fmt;
 use core::fmt::Display;
+use core::num::NonZeroU32;
+use core::ops::RangeInclusive;
 use core::result;
 use core::str::FromStr;
 use illumos_sys_hdrs::datalink_id_t;
@@ -23,8 +25,11 @@ cfg_if! {
     }
 }
 
-/// Description of Boundary Services, the endpoint used to route traffic
-/// to external networks.
+/// Description of an endpoint of Boundary Services, used to route
+/// traffic to external networks.
+///
+/// A rack runs Boundary Services on each of its switches, and a port
+/// is configured with all of them, see [`VpcCfg::boundary_services`].
 //
 // NOTE: This is identical to the `PhysNet` type below, but serves a different
 // purpose, to identify Boundary Services itself, not a generic physical network
@@ -77,14 +82,13 @@ pub struct Ipv4Cfg {
     // implemented in Omicron.
     pub snat: Option<SNat4Cfg>,
 
-    /// Optional external IP addresses for this port.
+    /// The floating IPs initially attached to this port.
     ///
     /// This allows hosts on the external network to make inbound connections to
-    /// the guest. When present, it is also used as 1:1 NAT for outbound
-    /// connections from the guest to an external network.
-    //
-    // XXX For now we only allow one external IP.
-    pub external_ips: Option<Ipv4Addr>,
+    /// the guest. The first floating IP is also used as 1:1 NAT for outbound
+    /// connections from the guest to an external network. Floating IPs may be
+    /// attached and detached at runtime, see [`AttachFloatingIpReq`].
+    pub external_ips: Vec<Ipv4Addr>,
 }
 
 /// The IPv6 configuration of a VPC guest.
@@ -119,14 +123,21 @@ pub struct Ipv6Cfg {
     // implemented in Omicron.
     pub snat: Option<SNat6Cfg>,
 
-    /// Optional external IP addresses for this port.
+    /// The floating IPs initially attached to this port.
     ///
     /// This allows hosts on the external network to make inbound connections to
-    /// the guest. When present, it is also used as 1:1 NAT for outbound
-    /// connections from the guest to an external network.
-    //
-    // XXX For now we only allow one external IP.
-    pub external_ips: Option<Ipv6Addr>,
+    /// the guest. The first floating IP is also used as 1:1 NAT for outbound
+    /// connections from the guest to an external network. Floating IPs may be
+    /// attached and detached at runtime, see [`AttachFloatingIpReq`].
+    pub external_ips: Vec<Ipv6Addr>,
+
+    /// The NAT64 configuration for reaching IPv4 hosts on an external
+    /// network.
+    ///
+    /// This allows the guest to make outbound connections to an IPv4 host
+    /// by its address under the NAT64 Well-Known Prefix, `64:ff9b::/96`,
+    /// without an IPv4 configuration of its own.
+    pub nat64: Option<Nat64Cfg>,
 }
 
 /// The IP configuration of a VPC guest.
@@ -142,7 +153,7 @@ impl IpCfg {
     pub fn ext_ipv4(&self) -> Ipv4Addr {
         match self {
             Self::Ipv4(ipv4) | Self::DualStack { ipv4, .. } => {
-                ipv4.external_ips.unwrap()
+                ipv4.external_ips[0]
             }
 
             _ => panic!("set IPv4 external IP on IPv6-only config"),
@@ -156,7 +167,7 @@ impl IpCfg {
                 if let Some(snat) = &ipv4.snat {
                     assert_ne!(snat.external_ip, ip);
                 }
-                ipv4.external_ips = Some(ip);
+                ipv4.external_ips = vec![ip];
             }
 
             _ => panic!("set IPv4 external IP on IPv6-only config"),
@@ -189,16 +200,25 @@ pub struct VpcCfg {
     /// sourced to a single IPv6 address.
     pub phys_ip: Ipv6Addr,
 
-    /// Information for reaching Boundary Services, for traffic destined
-    /// for external networks.
-    pub boundary_services: BoundaryServices,
-
-    // XXX-EXT-IP the following two fields are for the external IP hack.
-    pub proxy_arp_enable: bool,
-    pub phys_gw_mac: Option<MacAddr>,
+    /// The endpoints of Boundary Services, for traffic destined for
+    /// external networks.
+    ///
+    /// Flows are spread across the endpoints by flow hash; an endpoint
+    /// marked down, see [`SetBsvcStateReq`], has its flows moved to the
+    /// others. There must be at least one endpoint, and all endpoints
+    /// must use the same VNI.
+    pub boundary_services: Vec<BoundaryServices>,
 }
 
 impl VpcCfg {
+    /// Return the VNI dedicated to Boundary Services traffic.
+    ///
+    /// Port creation verifies that there is at least one endpoint and
+    /// that they all agree on the VNI.
+    pub fn bsvc_vni(&self) -> Vni {
+        self.boundary_services[0].vni
+    }
+
     /// Return the IPv4 configuration, if it exists, or None.
     pub fn ipv4_cfg(&self) -> Option<&Ipv4Cfg> {
         match self.ip_cfg {
@@ -218,6 +238,15 @@ impl VpcCfg {
         }
     }
 
+    #[cfg(any(feature = "test-help", test))]
+    pub fn ipv6(&self) -> &Ipv6Cfg {
+        match &self.ip_cfg {
+            IpCfg::Ipv6(ipv6) | IpCfg::DualStack { ipv6, .. } => ipv6,
+
+            _ => panic!("expected an IPv6 configuration"),
+        }
+    }
+
     #[cfg(any(feature = "test-help", test))]
     pub fn ext_ipv4(&self) -> Ipv4Addr {
         self.ip_cfg.ext_ipv4()
@@ -266,6 +295,29 @@ impl VpcCfg {
             _ => panic!("expected an IPv4 SNAT configuration"),
         }
     }
+
+    #[cfg(not(any(feature = "test-help", test)))]
+    /// Return the IPv6 SNAT config, if it exists.
+    pub fn snat6(&self) -> Option<&SNat6Cfg> {
+        match &self.ip_cfg {
+            IpCfg::Ipv6(ipv6) | IpCfg::DualStack { ipv6, .. } => {
+                ipv6.snat.as_ref()
+            }
+
+            _ => None,
+        }
+    }
+
+    #[cfg(any(feature = "test-help", test))]
+    pub fn snat6(&self) -> &SNat6Cfg {
+        match &self.ip_cfg {
+            IpCfg::Ipv6(ipv6) | IpCfg::DualStack { ipv6, .. } => {
+                ipv6.snat.as_ref().unwrap()
+            }
+
+            _ => panic!("expected an IPv6 SNAT configuration"),
+        }
+    }
 }
 
 /// A network destination on the Oxide Rack's physical network.
@@ -280,7 +332,7 @@ pub struct PhysNet {
 ///
 /// We save space in the VPC mappings by grouping guest
 /// Virtual-to-Physical mappings by VNI.
-#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct GuestPhysAddr {
     pub ether: MacAddr,
     pub ip: Ipv6Addr,
@@ -311,12 +363,18 @@ impl From<PhysNet> for GuestPhysAddr {
 /// abstraction, it's simply allowing one subnet to talk to another.
 /// There is no separate VPC router process, the real routing is done
 /// by the underlay.
-#[derive(Clone, Debug, Copy, Deserialize, Serialize)]
+///
+/// * Ecmp: Packets matching this entry are forwarded to one of the
+/// specified IPs, chosen by hashing the flow's 5-tuple. All packets
+/// of a flow go to the same IP, and removing an IP from the list only
+/// moves the flows which were going to it.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub enum RouterTarget {
     Drop,
     InternetGateway,
     Ip(IpAddr),
     VpcSubnet(IpCidr),
+    Ecmp(Vec<IpAddr>),
 }
 
 #[cfg(any(feature = "std", test))]
@@ -348,6 +406,14 @@ impl FromStr for RouterTarget {
                     cidr6s.parse().map(|x| Self::VpcSubnet(IpCidr::Ip6(x)))
                 }
 
+                Some(("ecmp", ips)) => {
+                    let members = ips
+                        .split(",")
+                        .map(|ip| ip.parse::<IpAddr>())
+                        .collect::<Result<Vec<_>, _>>()?;
+                    Ok(Self::Ecmp(members))
+                }
+
                 _ => Err(format!("malformed router target: {}", lower)),
             },
         }
@@ -363,10 +429,44 @@ impl Display for RouterTarget {
             Self::Ip(IpAddr::Ip6(ip6)) => write!(f, "ip6={}", ip6),
             Self::VpcSubnet(IpCidr::Ip4(sub4)) => write!(f, "sub4={}", sub4),
             Self::VpcSubnet(IpCidr::Ip6(sub6)) => write!(f, "sub6={}", sub6),
+            Self::Ecmp(members) => {
+                write!(f, "ecmp=")?;
+                for (i, ip) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", ip)?;
+                }
+                Ok(())
+            }
         }
     }
 }
 
+#[test]
+fn parse_router_target_ecmp() {
+    let target = "ecmp=172.30.0.20,172.30.0.21".parse::<RouterTarget>();
+    assert_eq!(
+        target,
+        Ok(RouterTarget::Ecmp(vec![
+            IpAddr::Ip4("172.30.0.20".parse().unwrap()),
+            IpAddr::Ip4("172.30.0.21".parse().unwrap()),
+        ]))
+    );
+    assert_eq!(target.unwrap().to_string(), "ecmp=172.30.0.20,172.30.0.21");
+
+    let target = "ecmp=fd00::20,fd00::21".parse::<RouterTarget>();
+    assert_eq!(
+        target,
+        Ok(RouterTarget::Ecmp(vec![
+            IpAddr::Ip6("fd00::20".parse().unwrap()),
+            IpAddr::Ip6("fd00::21".parse().unwrap()),
+        ]))
+    );
+
+    assert!("ecmp=172.30.0.20,bogus".parse::<RouterTarget>().is_err());
+}
+
 /// Xde create ioctl parameter data.
 ///
 /// The bulk of the information is provided via [`VpcCfg`].
@@ -395,6 +495,13 @@ pub struct CreateXdeReq {
 pub struct SNat4Cfg {
     pub external_ip: Ipv4Addr,
     pub ports: core::ops::RangeInclusive<u16>,
+
+    /// How the guest's flows are mapped to `ports`.
+    pub mapping: NatMapping,
+
+    /// The most of `ports` the guest may hold at once, see
+    /// [`NatPool::add_shared()`](opte::engine::snat::NatPool::add_shared).
+    pub max_ports: Option<u16>,
 }
 
 /// Configuration of source NAT for a port, describing how a private IP
@@ -403,6 +510,29 @@ pub struct SNat4Cfg {
 pub struct SNat6Cfg {
     pub external_ip: Ipv6Addr,
     pub ports: core::ops::RangeInclusive<u16>,
+
+    /// How the guest's flows are mapped to `ports`.
+    pub mapping: NatMapping,
+
+    /// The most of `ports` the guest may hold at once, see
+    /// [`NatPool::add_shared()`](opte::engine::snat::NatPool::add_shared).
+    pub max_ports: Option<u16>,
+}
+
+/// Configuration of NAT64 for a port, describing the external IPv4
+/// address and port range to which the guest's outbound connections to
+/// IPv4 hosts are mapped.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Nat64Cfg {
+    pub external_ip: Ipv4Addr,
+    pub ports: core::ops::RangeInclusive<u16>,
+
+    /// How the guest's flows are mapped to `ports`.
+    pub mapping: NatMapping,
+
+    /// The most of `ports` the guest may hold at once, see
+    /// [`NatPool::add_shared()`](opte::engine::snat::NatPool::add_shared).
+    pub max_ports: Option<u16>,
 }
 
 /// Xde delete ioctl parameter data.
@@ -417,9 +547,9 @@ pub struct PortInfo {
     pub name: String,
     pub mac_addr: MacAddr,
     pub ip4_addr: Option<Ipv4Addr>,
-    pub external_ip4_addr: Option<Ipv4Addr>,
+    pub external_ip4_addrs: Vec<Ipv4Addr>,
     pub ip6_addr: Option<Ipv6Addr>,
-    pub external_ip6_addr: Option<Ipv6Addr>,
+    pub external_ip6_addrs: Vec<Ipv6Addr>,
     pub state: String,
 }
 
@@ -437,13 +567,81 @@ pub struct SetVirt2PhysReq {
     pub phys: PhysNet,
 }
 
+/// Delete the mapping for a VPC IP.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DelVirt2PhysReq {
+    pub vip: IpAddr,
+    pub vni: Vni,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum DelVirt2PhysResp {
+    Ok,
+    NotFound,
+}
+
+impl opte::api::cmd::CmdOk for DelVirt2PhysResp {}
+
+/// Replace all mappings of a VPC with `mappings`.
+///
+/// The control plane numbers each version of a VPC's mappings with
+/// an increasing generation. A sync carrying a generation older than
+/// the last one applied to the VPC is rejected with
+/// [`OpteError::StaleGeneration`], so that a delayed request cannot
+/// roll the mappings back. Repeating a sync of the current generation
+/// is allowed, which makes it safe to retry.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SyncVirt2PhysReq {
+    pub vni: Vni,
+    pub gen: u64,
+    pub mappings: Vec<(IpAddr, GuestPhysAddr)>,
+}
+
+/// A VPC IP for which the overlay found no mapping.
+///
+/// Misses are queued for a resolver to read with
+/// [`ReadV2pMissesReq`], which then answers with a
+/// [`SetVirt2PhysReq`] for each mapping it finds.
+#[derive(
+    Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize,
+)]
+pub struct V2pMiss {
+    pub vni: Vni,
+    pub vip: IpAddr,
+}
+
+/// Read the queued V2P misses.
+///
+/// When `block` is set and no miss is queued, the request waits for
+/// one to arrive. Otherwise it returns immediately, which allows the
+/// resolver to poll the queue instead.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ReadV2pMissesReq {
+    pub block: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ReadV2pMissesResp {
+    pub misses: Vec<V2pMiss>,
+
+    /// The total number of misses dropped because the queue was
+    /// full.
+    pub dropped: u64,
+}
+
+impl opte::api::cmd::CmdOk for ReadV2pMissesResp {}
+
 /// Add an entry to the router. Addresses may be either IPv4 or IPv6, though the
 /// destination and target must match in protocol version.
+///
+/// See [`RouterEntry`] for the meaning of `filters` and `priority`.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct AddRouterEntryReq {
     pub port_name: String,
     pub dest: IpCidr,
     pub target: RouterTarget,
+    pub filters: RouteFilters,
+    pub priority: Option<u16>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -451,6 +649,8 @@ pub struct DelRouterEntryReq {
     pub port_name: String,
     pub dest: IpCidr,
     pub target: RouterTarget,
+    pub filters: RouteFilters,
+    pub priority: Option<u16>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -459,6 +659,93 @@ pub enum DelRouterEntryResp {
     NotFound,
 }
 
+impl opte::api::cmd::CmdOk for DelRouterEntryResp {}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ListRouterEntriesReq {
+    pub port_name: String,
+}
+
+/// A router entry: traffic to `dest` is sent to `target`.
+///
+/// A plain entry matches on the destination alone, and entries are
+/// chosen by longest prefix match. A policy entry additionally
+/// matches on the `filters`, and must be given an explicit
+/// `priority`, where a lower value takes precedence. The plain
+/// entries occupy priorities 10 through 138, so a policy entry with a
+/// priority below 10 is chosen over any plain entry it overlaps. A
+/// plain entry may also be given an explicit priority, overriding the
+/// one derived from its prefix length.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct RouterEntry {
+    pub dest: IpCidr,
+    pub target: RouterTarget,
+    pub filters: RouteFilters,
+    pub priority: Option<u16>,
+}
+
+impl RouterEntry {
+    /// Create a plain entry, routing `dest` to `target`.
+    pub fn new(dest: IpCidr, target: RouterTarget) -> Self {
+        Self { dest, target, filters: RouteFilters::default(), priority: None }
+    }
+}
+
+/// The criteria, beyond the destination, which a policy router entry
+/// matches on. All criteria must match.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct RouteFilters {
+    /// Match traffic from this subnet.
+    pub src: Option<IpCidr>,
+
+    /// Match traffic of this protocol.
+    pub protocol: Option<Protocol>,
+
+    /// Match traffic to these ports.
+    pub ports: Ports,
+}
+
+impl RouteFilters {
+    /// Return `true` if these filters match all traffic.
+    pub fn is_empty(&self) -> bool {
+        self.src.is_none()
+            && self.protocol.is_none()
+            && self.ports == Ports::Any
+    }
+}
+
+impl Display for RouteFilters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "any");
+        }
+
+        let mut sep = "";
+        if let Some(src) = &self.src {
+            write!(f, "src={}", src)?;
+            sep = " ";
+        }
+
+        if let Some(proto) = &self.protocol {
+            write!(f, "{}proto={}", sep, proto)?;
+            sep = " ";
+        }
+
+        if self.ports != Ports::Any {
+            write!(f, "{}ports={}", sep, self.ports)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ListRouterEntriesResp {
+    pub entries: Vec<RouterEntry>,
+}
+
+impl opte::api::cmd::CmdOk for ListRouterEntriesResp {}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct AddFwRuleReq {
     pub port_name: String,
@@ -471,6 +758,27 @@ pub struct SetFwRulesReq {
     pub rules: Vec<FirewallRule>,
 }
 
+/// A summary of the changes made by [`SetFwRulesReq`].
+///
+/// The rules are synced against the current set: the rules found in
+/// both sets are left in place, along with any flows which are not
+/// affected by the added or removed rules.
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize,
+)]
+pub struct SetFwRulesResp {
+    /// The number of rules added.
+    pub added: u32,
+    /// The number of rules removed.
+    pub removed: u32,
+    /// The number of rules left in place.
+    pub unchanged: u32,
+    /// The number of established flows invalidated.
+    pub flows_invalidated: u32,
+}
+
+impl opte::api::cmd::CmdOk for SetFwRulesResp {}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct RemFwRuleReq {
     pub port_name: String,
@@ -478,12 +786,398 @@ pub struct RemFwRuleReq {
     pub id: u64,
 }
 
+/// Create or replace the named address set of a VPC.
+///
+/// A set is referenced from firewall rules by name, see
+/// [`Address::Set`]. The set is shared by all ports of the VPC.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SetAddrSetReq {
+    pub vni: Vni,
+    pub name: String,
+    pub addrs: Vec<IpAddr>,
+}
+
+/// Delete the named address set of a VPC.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DelAddrSetReq {
+    pub vni: Vni,
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum DelAddrSetResp {
+    Ok,
+    NotFound,
+}
+
+impl opte::api::cmd::CmdOk for DelAddrSetResp {}
+
+#[repr(C)]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DumpAddrSetsReq {
+    pub unused: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AddrSetDump {
+    pub vni: Vni,
+    pub name: String,
+    pub addrs: Vec<IpAddr>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DumpAddrSetsResp {
+    pub sets: Vec<AddrSetDump>,
+}
+
+impl opte::api::cmd::CmdOk for DumpAddrSetsResp {}
+
+/// The destination of a port mirror.
+///
+/// * Port: Deliver the mirrored packets to the guest attached to the
+/// named port on this sled.
+///
+/// * Underlay: Encapsulate the mirrored packets in Geneve and send
+/// them to the physical destination over the underlay.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum MirrorDest {
+    Port(String),
+    Underlay(PhysNet),
+}
+
+#[cfg(any(feature = "std", test))]
+impl FromStr for MirrorDest {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(":") {
+            Some(("port", name)) if !name.is_empty() => {
+                Ok(Self::Port(name.to_string()))
+            }
+
+            Some(("underlay", phys)) => {
+                let fields: Vec<&str> = phys.split(",").collect();
+                if fields.len() != 3 {
+                    return Err(format!(
+                        "malformed underlay dest: {} (<mac>,<ip6>,<vni>)",
+                        phys
+                    ));
+                }
+
+                Ok(Self::Underlay(PhysNet {
+                    ether: fields[0].parse()?,
+                    ip: fields[1].parse()?,
+                    vni: fields[2].parse()?,
+                }))
+            }
+
+            _ => Err(format!(
+                "invalid mirror dest: {} ('port:<name>' or \
+                 'underlay:<mac>,<ip6>,<vni>')",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for MirrorDest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Port(name) => write!(f, "port:{}", name),
+            Self::Underlay(phys) => {
+                write!(f, "underlay:{},{},{}", phys.ether, phys.ip, phys.vni)
+            }
+        }
+    }
+}
+
+/// The configuration of a single port mirror.
+///
+/// Packets traveling in `direction` which match the `filters` are
+/// copied to `dest`. At most `trunc_len` bytes of the inner frame are
+/// copied, where zero means the entire frame; the headers are always
+/// copied in full. Only one out of every `sample_rate` matching
+/// packets is copied.
+///
+/// Mirrors do not stack: when the filters of more than one mirror
+/// match a packet, only the mirror with the lowest `priority` value
+/// copies it. A port target is resolved when the mirrors are set, and
+/// must exist at that time.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MirrorCfg {
+    pub name: String,
+    pub direction: Direction,
+    pub filters: Filters,
+    pub dest: MirrorDest,
+    pub trunc_len: u32,
+    pub sample_rate: NonZeroU32,
+    pub priority: u16,
+}
+
+#[cfg(any(feature = "std", test))]
+impl FromStr for MirrorCfg {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut name = None;
+        let mut direction = None;
+        let mut dest = None;
+        let mut trunc_len = 0;
+        let mut sample_rate = NonZeroU32::new(1).unwrap();
+        let mut priority = None;
+        let mut hosts = None;
+        let mut protocol = None;
+        let mut ports = None;
+
+        for token in s.split(" ").filter(|t| !t.is_empty()) {
+            match token.split_once("=") {
+                None => {
+                    return Err(format!("bad token: {}", token));
+                }
+
+                Some(("name", val)) => {
+                    name = Some(val.to_string());
+                }
+
+                Some(("dir", val)) => {
+                    direction = Some(val.parse::<Direction>()?);
+                }
+
+                Some(("dest", val)) => {
+                    dest = Some(val.parse::<MirrorDest>()?);
+                }
+
+                Some(("trunc", val)) => {
+                    trunc_len = val.parse::<u32>().map_err(|e| {
+                        format!("bad trunc: '{}' {}", val, e.to_string())
+                    })?;
+                }
+
+                Some(("rate", val)) => {
+                    sample_rate = val.parse::<NonZeroU32>().map_err(|e| {
+                        format!("bad rate: '{}' {}", val, e.to_string())
+                    })?;
+                }
+
+                Some(("priority", val)) => {
+                    priority = Some(val.parse::<u16>().map_err(|e| {
+                        format!("bad priority: '{}' {}", val, e.to_string())
+                    })?);
+                }
+
+                // Parse the filters.
+                Some(("hosts", val)) => {
+                    hosts = Some(val.parse::<Address>()?);
+                }
+
+                Some(("protocol", val)) => {
+                    protocol = Some(val.parse::<ProtoFilter>()?);
+                }
+
+                Some(("port", val)) => {
+                    ports = Some(val.parse::<Ports>()?);
+                }
+
+                Some((_, _)) => {
+                    return Err(format!("invalid key: {}", token));
+                }
+            }
+        }
+
+        let mut filters = Filters::new();
+        filters
+            .set_hosts(hosts.unwrap_or(Address::Any))
+            .set_protocol(protocol.unwrap_or(ProtoFilter::Any))
+            .set_ports(ports.unwrap_or(Ports::Any));
+
+        Ok(MirrorCfg {
+            name: name.ok_or("missing 'name' key".to_string())?,
+            direction: direction
+                .ok_or("missing direction ('dir') key".to_string())?,
+            filters,
+            dest: dest.ok_or("missing 'dest' key".to_string())?,
+            trunc_len,
+            sample_rate,
+            priority: priority.ok_or("missing 'priority' key".to_string())?,
+        })
+    }
+}
+
+impl Display for MirrorCfg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "name={} dir={} dest={} trunc={} rate={} priority={} {}",
+            self.name,
+            self.direction,
+            self.dest,
+            self.trunc_len,
+            self.sample_rate,
+            self.priority,
+            self.filters,
+        )
+    }
+}
+
+/// Mark the Boundary Services endpoint `ip` as down, or back up.
+///
+/// The state of an endpoint is shared by all ports. Marking an
+/// endpoint down moves only the flows which were using it; marking it
+/// back up makes it available to new flows, while the flows moved off
+/// of it stay where they are.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SetBsvcStateReq {
+    pub ip: Ipv6Addr,
+    pub up: bool,
+}
+
+#[repr(C)]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DumpBsvcStateReq {
+    pub unused: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DumpBsvcStateResp {
+    /// The generation of the endpoint state, which moves forward on
+    /// every change.
+    pub gen: u64,
+
+    /// The endpoints currently marked down.
+    pub down: Vec<Ipv6Addr>,
+}
+
+impl opte::api::cmd::CmdOk for DumpBsvcStateResp {}
+
+/// Attach the floating IP `ip` to a port.
+///
+/// Inbound traffic for `ip` is translated to the port's private IP of
+/// the same family. If this is the first floating IP of its family on
+/// the port, it is also used as 1:1 NAT for the port's outbound
+/// traffic to external networks.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AttachFloatingIpReq {
+    pub port_name: String,
+    pub ip: IpAddr,
+}
+
+/// Detach the floating IP `ip` from a port.
+///
+/// Only the flows using `ip` are affected. If `ip` was used for
+/// outbound traffic, the next floating IP of the same family, if any,
+/// takes its place.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DetachFloatingIpReq {
+    pub port_name: String,
+    pub ip: IpAddr,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum DetachFloatingIpResp {
+    Ok,
+    NotFound,
+}
+
+impl opte::api::cmd::CmdOk for DetachFloatingIpResp {}
+
+/// Forward inbound connections to a range of ports on an external IP
+/// address to the port's private IP of the same family.
+///
+/// The ports are mapped in order: the first external port maps to
+/// `private_port`, the next one to `private_port + 1`, and so on.
+/// This allows a guest to expose a service on an external IP shared
+/// with other guests, without attaching a whole floating IP.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct PortForward {
+    pub external_ip: IpAddr,
+
+    /// The protocol to forward, either TCP or UDP.
+    pub proto: Protocol,
+
+    pub external_ports: RangeInclusive<u16>,
+    pub private_port: u16,
+}
+
+impl Display for PortForward {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (start, end) =
+            (*self.external_ports.start(), *self.external_ports.end());
+        let last = self.private_port as u32 + end.saturating_sub(start) as u32;
+
+        if start == end {
+            write!(
+                f,
+                "{} {}:{} => {}",
+                self.proto, self.external_ip, start, self.private_port
+            )
+        } else {
+            write!(
+                f,
+                "{} {}:{}-{} => {}-{}",
+                self.proto,
+                self.external_ip,
+                start,
+                end,
+                self.private_port,
+                last
+            )
+        }
+    }
+}
+
+/// Add a port forwarding rule to a port.
+///
+/// The external ports of the rule may not overlap with those of any
+/// other rule for the same external IP and protocol.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AddPortForwardReq {
+    pub port_name: String,
+    pub forward: PortForward,
+}
+
+/// Delete a port forwarding rule from a port.
+///
+/// The established connections of the rule are dropped along with it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DelPortForwardReq {
+    pub port_name: String,
+    pub forward: PortForward,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum DelPortForwardResp {
+    Ok,
+    NotFound,
+}
+
+impl opte::api::cmd::CmdOk for DelPortForwardResp {}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ListPortForwardsReq {
+    pub port_name: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ListPortForwardsResp {
+    pub forwards: Vec<PortForward>,
+}
+
+impl opte::api::cmd::CmdOk for ListPortForwardsResp {}
+
+/// Set (replace) all mirrors of a port.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SetMirrorsReq {
+    pub port_name: String,
+    pub mirrors: Vec<MirrorCfg>,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct FirewallRule {
     pub direction: Direction,
     pub filters: Filters,
     pub action: FirewallAction,
     pub priority: u16,
+    pub mode: FirewallMode,
 }
 
 impl FromStr for FirewallRule {
@@ -496,6 +1190,7 @@ impl FromStr for FirewallRule {
         let mut hosts = None;
         let mut protocol = None;
         let mut ports = None;
+        let mut mode = None;
 
         for token in s.to_ascii_lowercase().split(" ") {
             match token.split_once("=") {
@@ -517,6 +1212,10 @@ impl FromStr for FirewallRule {
                     })?);
                 }
 
+                Some(("mode", val)) => {
+                    mode = Some(val.parse::<FirewallMode>()?);
+                }
+
                 // Parse the filters.
                 Some(("hosts", val)) => {
                     hosts = Some(val.parse::<Address>()?);
@@ -526,7 +1225,6 @@ impl FromStr for FirewallRule {
                     protocol = Some(val.parse::<ProtoFilter>()?);
                 }
 
-                // TODO For now just allow single port.
                 Some(("port", val)) => {
                     ports = Some(val.parse::<Ports>()?);
                 }
@@ -561,14 +1259,91 @@ impl FromStr for FirewallRule {
             filters,
             action: action.unwrap(),
             priority: priority.unwrap(),
+            mode: mode.unwrap_or_default(),
         })
     }
 }
 
+#[test]
+fn parse_firewall_mode() {
+    let rule = "dir=in action=allow priority=10 protocol=UDP"
+        .parse::<FirewallRule>()
+        .unwrap();
+    assert_eq!(rule.mode, FirewallMode::Stateful);
+
+    let rule = "dir=in action=allow priority=10 protocol=UDP mode=stateless"
+        .parse::<FirewallRule>()
+        .unwrap();
+    assert_eq!(rule.mode, FirewallMode::Stateless);
+
+    assert!("dir=in action=allow priority=10 mode=tracked"
+        .parse::<FirewallRule>()
+        .is_err());
+}
+
+/// Whether the flows allowed by a firewall rule are tracked.
+///
+/// A stateful rule creates a flow table entry for each flow it
+/// allows, which in turn allows the flow's traffic in the opposite
+/// direction. A stateless rule is evaluated anew for each packet and
+/// uses none of the firewall's flow table; the traffic in the
+/// opposite direction must be allowed by a rule of its own.
+///
+/// The mode has no bearing on a rule which denies traffic.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum FirewallMode {
+    Stateful,
+    Stateless,
+}
+
+impl Default for FirewallMode {
+    fn default() -> Self {
+        Self::Stateful
+    }
+}
+
+impl FromStr for FirewallMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "stateful" => Ok(FirewallMode::Stateful),
+            "stateless" => Ok(FirewallMode::Stateless),
+            _ => {
+                Err(format!("invalid mode: {} ('stateful' or 'stateless')", s))
+            }
+        }
+    }
+}
+
+impl Display for FirewallMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            FirewallMode::Stateful => "stateful",
+            FirewallMode::Stateless => "stateless",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// The action of a firewall rule.
+///
+/// The `AllowLog` and `DenyLog` actions behave like their plain
+/// counterparts, and also log the first packet of each flow matching
+/// the rule to the port's firewall log.
 #[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub enum FirewallAction {
     Allow,
     Deny,
+    AllowLog,
+    DenyLog,
+}
+
+impl FirewallAction {
+    /// Return whether the rule's matches are logged.
+    pub fn is_log(&self) -> bool {
+        matches!(self, Self::AllowLog | Self::DenyLog)
+    }
 }
 
 impl FromStr for FirewallAction {
@@ -578,7 +1353,13 @@ impl FromStr for FirewallAction {
         match s.to_ascii_lowercase().as_str() {
             "allow" => Ok(FirewallAction::Allow),
             "deny" => Ok(FirewallAction::Deny),
-            _ => Err(format!("invalid action: {} ('allow' or 'deny')", s)),
+            "allow-log" => Ok(FirewallAction::AllowLog),
+            "deny-log" => Ok(FirewallAction::DenyLog),
+            _ => Err(format!(
+                "invalid action: {} ('allow', 'deny', 'allow-log' or \
+                 'deny-log')",
+                s
+            )),
         }
     }
 }
@@ -610,7 +1391,7 @@ impl Filters {
     }
 
     pub fn hosts(&self) -> Address {
-        self.hosts
+        self.hosts.clone()
     }
 
     pub fn new_hosts(hosts: Address) -> Self {
@@ -650,21 +1431,41 @@ impl Filters {
 }
 
 /// Filter traffic by address.
-#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub enum Address {
     /// Match traffic from any address.
     Any,
 
-    /// Match traffic from the given subnet CIDR.
-    Subnet(Ipv4Cidr),
+    /// Match traffic from the given IPv4 or IPv6 subnet CIDR.
+    Subnet(IpCidr),
 
-    /// Match traffic from the given IP address.
-    Ip(Ipv4Addr),
+    /// Match traffic from the given IPv4 or IPv6 address.
+    Ip(IpAddr),
 
     /// Match traffic from the given VNI.
     Vni(Vni),
+
+    /// Match traffic from any address in the named address set, see
+    /// [`SetAddrSetReq`]. Membership is checked when the rule is
+    /// evaluated, so the set may change without touching the rule.
+    Set(String),
+}
+
+/// Is `name` a valid address set name?
+///
+/// A name is made up of ASCII alphanumerics, `-`, `_` and `.`, and is
+/// case-insensitive.
+pub fn valid_addr_set_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= ADDR_SET_NAME_MAX
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
 }
 
+/// The maximum length of an address set name.
+pub const ADDR_SET_NAME_MAX: usize = 64;
+
 impl FromStr for Address {
     type Err = String;
 
@@ -680,6 +1481,12 @@ impl FromStr for Address {
                 Some(("ip", val)) => Ok(Address::Ip(val.parse()?)),
                 Some(("subnet", val)) => Ok(Address::Subnet(val.parse()?)),
                 Some(("vni", val)) => Ok(Address::Vni(val.parse()?)),
+                Some(("set", val)) if valid_addr_set_name(val) => {
+                    Ok(Address::Set(val.to_string()))
+                }
+                Some(("set", val)) => {
+                    Err(format!("invalid address set name: {}", val))
+                }
                 Some((key, _)) => Err(format!("invalid address type: {}", key)),
             },
         }
@@ -693,10 +1500,26 @@ fn parse_good_address() {
         "ip=192.168.2.1".parse::<Address>(),
         Ok(Address::Ip("192.168.2.1".parse().unwrap()))
     );
+    assert_eq!(
+        "ip=fd00::1".parse::<Address>(),
+        Ok(Address::Ip("fd00::1".parse().unwrap()))
+    );
+    assert_eq!(
+        "subnet=192.168.2.0/24".parse::<Address>(),
+        Ok(Address::Subnet("192.168.2.0/24".parse().unwrap()))
+    );
+    assert_eq!(
+        "subnet=fd00:1234::/64".parse::<Address>(),
+        Ok(Address::Subnet("fd00:1234::/64".parse().unwrap()))
+    );
     assert_eq!(
         "vni=7777".parse(),
         Ok(Address::Vni(Vni::new(7777u32).unwrap()))
     );
+    assert_eq!(
+        "set=Web-Servers".parse::<Address>(),
+        Ok(Address::Set("web-servers".to_string()))
+    );
 }
 
 #[test]
@@ -704,7 +1527,12 @@ fn parse_bad_address() {
     assert!("ip:192.168.2.1".parse::<Address>().is_err());
     assert!("ip=192.168.2".parse::<Address>().is_err());
     assert!("ip=192.168.O.1".parse::<Address>().is_err());
+    assert!("ip=fd00::g".parse::<Address>().is_err());
+    assert!("subnet=fd00::/129".parse::<Address>().is_err());
     assert!("addr=192.168.2.1".parse::<Address>().is_err());
+    assert!("set=".parse::<Address>().is_err());
+    assert!("set=web servers".parse::<Address>().is_err());
+    assert!("set=web/servers".parse::<Address>().is_err());
 }
 
 impl Display for Address {
@@ -714,6 +1542,7 @@ impl Display for Address {
             Address::Ip(val) => write!(f, "{},", val),
             Address::Subnet(val) => write!(f, "{},", val),
             Address::Vni(val) => write!(f, "{}", val),
+            Address::Set(val) => write!(f, "set={}", val),
         }
     }
 }
@@ -722,6 +1551,12 @@ impl Display for Address {
 pub enum ProtoFilter {
     Any,
     Proto(Protocol),
+
+    /// Match ICMP messages of the given type, and optionally code.
+    Icmp(IcmpFilter),
+
+    /// Match ICMPv6 messages of the given type, and optionally code.
+    Icmpv6(IcmpFilter),
 }
 
 impl FromStr for ProtoFilter {
@@ -731,9 +1566,52 @@ impl FromStr for ProtoFilter {
         match s.to_ascii_lowercase().as_str() {
             "any" => Ok(ProtoFilter::Any),
             "icmp" => Ok(ProtoFilter::Proto(Protocol::ICMP)),
+            "icmpv6" => Ok(ProtoFilter::Proto(Protocol::ICMPv6)),
             "tcp" => Ok(ProtoFilter::Proto(Protocol::TCP)),
             "udp" => Ok(ProtoFilter::Proto(Protocol::UDP)),
-            _ => Err(format!("unknown protocol: {}", s)),
+            lower => match lower.split_once(":") {
+                Some(("icmp", val)) => Ok(ProtoFilter::Icmp(val.parse()?)),
+                Some(("icmpv6", val)) => Ok(ProtoFilter::Icmpv6(val.parse()?)),
+                _ => Err(format!("unknown protocol: {}", s)),
+            },
+        }
+    }
+}
+
+/// Filter ICMP or ICMPv6 messages by type and, optionally, code.
+///
+/// This is written as `<type>` or `<type>/<code>`, e.g. `3/4` for
+/// "fragmentation needed".
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct IcmpFilter {
+    pub msg_type: u8,
+    pub code: Option<u8>,
+}
+
+impl FromStr for IcmpFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |v: &str, what| {
+            v.parse::<u8>().map_err(|e| format!("bad ICMP {}: {}", what, e))
+        };
+
+        match s.split_once("/") {
+            Some((ty, code)) => Ok(IcmpFilter {
+                msg_type: parse(ty, "type")?,
+                code: Some(parse(code, "code")?),
+            }),
+
+            None => Ok(IcmpFilter { msg_type: parse(s, "type")?, code: None }),
+        }
+    }
+}
+
+impl Display for IcmpFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.code {
+            Some(code) => write!(f, "{}/{}", self.msg_type, code),
+            None => write!(f, "{}", self.msg_type),
         }
     }
 }
@@ -745,6 +1623,14 @@ fn parse_good_proto_filter() {
         "TCp".parse::<ProtoFilter>().unwrap(),
         ProtoFilter::Proto(Protocol::TCP)
     );
+    assert_eq!(
+        "icmp:8".parse::<ProtoFilter>().unwrap(),
+        ProtoFilter::Icmp(IcmpFilter { msg_type: 8, code: None })
+    );
+    assert_eq!(
+        "ICMPv6:1/4".parse::<ProtoFilter>().unwrap(),
+        ProtoFilter::Icmpv6(IcmpFilter { msg_type: 1, code: Some(4) })
+    );
 }
 
 #[test]
@@ -752,6 +1638,10 @@ fn parse_bad_proto_filter() {
     assert!("foo".parse::<ProtoFilter>().is_err());
     assert!("TCP,".parse::<ProtoFilter>().is_err());
     assert!("6".parse::<ProtoFilter>().is_err());
+    assert!("icmp:".parse::<ProtoFilter>().is_err());
+    assert!("icmp:256".parse::<ProtoFilter>().is_err());
+    assert!("icmp:3/".parse::<ProtoFilter>().is_err());
+    assert!("tcp:3".parse::<ProtoFilter>().is_err());
 }
 
 impl Display for ProtoFilter {
@@ -759,6 +1649,8 @@ impl Display for ProtoFilter {
         match self {
             ProtoFilter::Any => write!(f, "ANY"),
             ProtoFilter::Proto(proto) => write!(f, "{},", proto),
+            ProtoFilter::Icmp(icmp) => write!(f, "ICMP:{}", icmp),
+            ProtoFilter::Icmpv6(icmp) => write!(f, "ICMPv6:{}", icmp),
         }
     }
 }
@@ -767,6 +1659,19 @@ impl Display for ProtoFilter {
 pub enum Ports {
     Any,
     PortList(Vec<u16>),
+
+    /// A single inclusive range of ports.
+    Range(RangeInclusive<u16>),
+
+    /// A list of inclusive port ranges, where a single port is a range
+    /// of one.
+    RangeList(Vec<RangeInclusive<u16>>),
+}
+
+impl Default for Ports {
+    fn default() -> Self {
+        Ports::Any
+    }
 }
 
 impl FromStr for Ports {
@@ -778,21 +1683,41 @@ impl FromStr for Ports {
             "any," => Ok(Ports::Any),
 
             _ => {
-                let ports: Vec<u16> = s
+                let parse =
+                    |ps: &str| ps.parse::<u16>().map_err(|e| e.to_string());
+
+                let ranges = s
                     .split(",")
-                    .map(|ps| ps.parse::<u16>().map_err(|e| e.to_string()))
-                    .collect::<result::Result<Vec<u16>, _>>()?;
+                    .map(|ps| match ps.split_once("-") {
+                        Some((lo, hi)) => Ok(parse(lo)?..=parse(hi)?),
+                        None => parse(ps).map(|p| p..=p),
+                    })
+                    .collect::<result::Result<Vec<_>, _>>()?;
 
-                if ports.len() == 0 {
+                if ranges.len() == 0 {
                     return Err(format!("malformed ports spec: {}", s));
                 }
 
-                for p in ports.iter() {
-                    if *p == DYNAMIC_PORT {
-                        return Err(format!("invalid port: {}", p));
+                for r in ranges.iter() {
+                    if r.is_empty() {
+                        return Err(format!("invalid port range: {}", s));
+                    }
+
+                    if *r.start() == DYNAMIC_PORT {
+                        return Err(format!("invalid port: {}", r.start()));
                     }
                 }
-                Ok(Ports::PortList(ports))
+
+                // Use the simplest form which describes the ports.
+                if ranges.iter().all(|r| r.start() == r.end()) {
+                    Ok(Ports::PortList(
+                        ranges.iter().map(|r| *r.start()).collect(),
+                    ))
+                } else if ranges.len() == 1 {
+                    Ok(Ports::Range(ranges[0].clone()))
+                } else {
+                    Ok(Ports::RangeList(ranges))
+                }
             }
         }
     }
@@ -807,6 +1732,14 @@ fn ports_from_str_good() {
         "22,443".parse::<Ports>().unwrap(),
         Ports::PortList(vec![22, 443])
     );
+    assert_eq!(
+        "30000-32767".parse::<Ports>().unwrap(),
+        Ports::Range(30000..=32767)
+    );
+    assert_eq!(
+        "22,30000-32767".parse::<Ports>().unwrap(),
+        Ports::RangeList(vec![22..=22, 30000..=32767])
+    );
 }
 
 #[test]
@@ -818,6 +1751,10 @@ fn ports_from_str_bad() {
     assert!("rpz,22".parse::<Ports>().is_err());
     assert!("22,rpz".parse::<Ports>().is_err());
     assert!("any,rpz".parse::<Ports>().is_err());
+    assert!("0-22".parse::<Ports>().is_err());
+    assert!("443-22".parse::<Ports>().is_err());
+    assert!("22-".parse::<Ports>().is_err());
+    assert!("22,80-rpz".parse::<Ports>().is_err());
 }
 
 impl Display for Ports {
@@ -825,8 +1762,31 @@ impl Display for Ports {
         match self {
             Ports::Any => write!(f, "ANY"),
             Ports::PortList(plist) => {
-                // TODO For now we just assume one port.
-                write!(f, "{}", plist[0])
+                let s = plist
+                    .iter()
+                    .map(|p| p.to_string())
+                    .collect::<Vec<String>>()
+                    .join(",");
+                write!(f, "{}", s)
+            }
+
+            Ports::Range(range) => {
+                write!(f, "{}-{}", range.start(), range.end())
+            }
+
+            Ports::RangeList(ranges) => {
+                let s = ranges
+                    .iter()
+                    .map(|r| {
+                        if r.start() == r.end() {
+                            r.start().to_string()
+                        } else {
+                            format!("{}-{}", r.start(), r.end())
+                        }
+                    })
+                    .collect::<Vec<String>>()
+                    .join(",");
+                write!(f, "{}", s)
             }
         }
     }
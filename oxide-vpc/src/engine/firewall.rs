@@ -8,28 +8,61 @@
 //!
 //! This layer is responsible for implementing the VPC firewall as
 //! described in RFD 21 §2.8.
+//!
+//! # Address Sets
+//!
+//! A rule may filter on a named set of addresses, see
+//! [`Address::Set`]. The sets belong to the VPC and live in its
+//! [`VpcMappings`], not in the rules. Rather than matching on the
+//! addresses themselves, the rule matches on a piece of action
+//! metadata: the gateway layer tags outbound flows with the sets
+//! containing the destination, and the overlay layer tags inbound
+//! flows with the sets containing the source. Membership is thus
+//! checked when a flow's first packet is processed, and changing a
+//! set never rewrites a rule. Instead, the firewall flows of the
+//! addresses which joined or left the set are removed, see
+//! [`addr_set_changed()`], so that their next packet is checked
+//! against the new membership.
+//!
+//! [`VpcMappings`]: crate::engine::overlay::VpcMappings
 
 use super::VpcNetwork;
+use crate::api::valid_addr_set_name;
 use crate::api::AddFwRuleReq;
+use crate::api::AddrSetDump;
 use crate::api::Address;
+use crate::api::Filters;
 use crate::api::FirewallAction;
+use crate::api::FirewallMode;
 use crate::api::FirewallRule;
 use crate::api::Ports;
 pub use crate::api::ProtoFilter;
 use crate::api::RemFwRuleReq;
 use crate::api::SetFwRulesReq;
+use crate::api::SetFwRulesResp;
 use crate::engine::overlay::ACTION_META_VNI;
 use core::num::NonZeroU32;
 use opte::api::Direction;
+use opte::api::IpAddr;
+use opte::api::IpCidr;
 use opte::api::OpteError;
+use opte::api::Protocol;
+use opte::api::Vni;
+use opte::ddi::sync::KMutex;
+use opte::ddi::sync::KMutexType;
+use opte::engine::icmp::MessageType as IcmpMessageType;
+use opte::engine::icmpv6::MessageType as Icmpv6MessageType;
 use opte::engine::layer::DefaultAction;
 use opte::engine::layer::Layer;
 use opte::engine::layer::LayerActions;
+use opte::engine::port::meta::ActionMeta;
 use opte::engine::port::Port;
 use opte::engine::port::PortBuilder;
 use opte::engine::port::Pos;
+use opte::engine::predicate::DataPredicate;
 use opte::engine::predicate::IpProtoMatch;
 use opte::engine::predicate::Ipv4AddrMatch;
+use opte::engine::predicate::Ipv6AddrMatch;
 use opte::engine::predicate::PortMatch;
 use opte::engine::predicate::Predicate;
 use opte::engine::rule::Action;
@@ -38,19 +71,39 @@ use opte::engine::rule::Rule;
 
 cfg_if! {
     if #[cfg(all(not(feature = "std"), not(test)))] {
+        use alloc::collections::btree_map::BTreeMap;
+        use alloc::collections::btree_set::BTreeSet;
+        use alloc::string::String;
         use alloc::string::ToString;
+        use alloc::vec::Vec;
     } else {
+        use std::collections::btree_map::BTreeMap;
+        use std::collections::btree_set::BTreeSet;
+        use std::string::String;
         use std::string::ToString;
+        use std::vec::Vec;
     }
 }
 
 pub const FW_LAYER_NAME: &'static str = "firewall";
 
+/// The prefix of the action metadata key which marks a flow's remote
+/// address as a member of an address set. The key is followed by the
+/// name of the set.
+pub const ACTION_META_ADDR_SET_PREFIX: &str = "addr-set:";
+
+/// The value of an address set's action metadata key.
+const ACTION_META_ADDR_SET_MEMBER: &str = "true";
+
+fn addr_set_meta_key(name: &str) -> String {
+    format!("{}{}", ACTION_META_ADDR_SET_PREFIX, name)
+}
+
 pub fn setup(
     pb: &mut PortBuilder,
     ft_limit: NonZeroU32,
 ) -> Result<(), OpteError> {
-    let fw_layer = Firewall::create_layer(pb.name(), ft_limit);
+    let fw_layer = Firewall::create_layer(pb.name(), ft_limit)?;
     pb.add_layer(fw_layer, Pos::First)
 }
 
@@ -58,15 +111,24 @@ pub fn add_fw_rule(
     port: &Port<VpcNetwork>,
     req: &AddFwRuleReq,
 ) -> Result<(), OpteError> {
-    let action = match req.rule.action {
-        FirewallAction::Allow => Action::StatefulAllow,
-        FirewallAction::Deny => Action::Deny,
-    };
-
+    let action = fw_action(&req.rule);
     let rule = from_fw_rule(req.rule.clone(), action);
     port.add_rule(FW_LAYER_NAME, req.rule.direction, rule)
 }
 
+/// Return the layer action which implements the firewall rule's
+/// action.
+///
+/// A stateless allow rule uses [`Action::Allow`], a static action
+/// which never touches the layer's flow table.
+fn fw_action(rule: &FirewallRule) -> Action {
+    match (rule.action, rule.mode) {
+        (FirewallAction::Deny | FirewallAction::DenyLog, _) => Action::Deny,
+        (_, FirewallMode::Stateful) => Action::StatefulAllow,
+        (_, FirewallMode::Stateless) => Action::Allow,
+    }
+}
+
 pub fn rem_fw_rule(
     port: &Port<VpcNetwork>,
     req: &RemFwRuleReq,
@@ -77,16 +139,12 @@ pub fn rem_fw_rule(
 pub fn set_fw_rules(
     port: &Port<VpcNetwork>,
     req: &SetFwRulesReq,
-) -> Result<(), OpteError> {
+) -> Result<SetFwRulesResp, OpteError> {
     let mut in_rules = vec![];
     let mut out_rules = vec![];
 
     for fwr in &req.rules {
-        let action = match fwr.action {
-            FirewallAction::Allow => Action::StatefulAllow,
-            FirewallAction::Deny => Action::Deny,
-        };
-
+        let action = fw_action(fwr);
         let rule = from_fw_rule(fwr.clone(), action);
         if fwr.direction == Direction::In {
             in_rules.push(rule);
@@ -95,21 +153,131 @@ pub fn set_fw_rules(
         }
     }
 
-    port.set_rules(FW_LAYER_NAME, in_rules, out_rules)
+    let res = port.sync_rules(FW_LAYER_NAME, in_rules, out_rules)?;
+    Ok(SetFwRulesResp {
+        added: res.added,
+        removed: res.removed,
+        unchanged: res.unchanged,
+        flows_invalidated: res.flows_invalidated,
+    })
+}
+
+/// Invalidate the flows of `port` which may have been given their
+/// verdict based on the previous membership of `addrs` in an address
+/// set. These are the firewall flows whose remote address is one of
+/// `addrs`.
+pub fn addr_set_changed(
+    port: &Port<VpcNetwork>,
+    addrs: &BTreeSet<IpAddr>,
+) -> Result<(), OpteError> {
+    if addrs.is_empty() {
+        return Ok(());
+    }
+
+    // The outbound side of a flow always has the remote address as
+    // its destination, whichever side initiated it.
+    port.remove_flows(FW_LAYER_NAME, Direction::Out, |flow| {
+        addrs.contains(&flow.dst_ip)
+    })?;
+    Ok(())
+}
+
+/// The named address sets of a VPC.
+pub struct AddrSets {
+    inner: KMutex<BTreeMap<String, BTreeSet<IpAddr>>>,
+}
+
+impl AddrSets {
+    /// Remove the set `name`, returning its addresses if it existed.
+    pub fn del(&self, name: &str) -> Option<BTreeSet<IpAddr>> {
+        self.inner.lock().remove(&name.to_ascii_lowercase())
+    }
+
+    /// Produce an [`AddrSetDump`] for each of the sets.
+    pub fn dump(&self, vni: Vni) -> Vec<AddrSetDump> {
+        self.inner
+            .lock()
+            .iter()
+            .map(|(name, addrs)| AddrSetDump {
+                vni,
+                name: name.clone(),
+                addrs: addrs.iter().copied().collect(),
+            })
+            .collect()
+    }
+
+    pub fn new() -> Self {
+        AddrSets { inner: KMutex::new(BTreeMap::new(), KMutexType::Driver) }
+    }
+
+    /// Create the set `name`, replacing any existing set of the same
+    /// name. Return the addresses which joined or left the set.
+    pub fn set(
+        &self,
+        name: &str,
+        addrs: &[IpAddr],
+    ) -> Result<BTreeSet<IpAddr>, OpteError> {
+        if !valid_addr_set_name(name) {
+            return Err(OpteError::BadName);
+        }
+
+        let new: BTreeSet<IpAddr> = addrs.iter().copied().collect();
+        let old = self
+            .inner
+            .lock()
+            .insert(name.to_ascii_lowercase(), new.clone())
+            .unwrap_or_default();
+        Ok(old.symmetric_difference(&new).copied().collect())
+    }
+
+    /// Mark `ip` as a member of each set containing it.
+    pub fn tag(&self, ip: &IpAddr, action_meta: &mut ActionMeta) {
+        for (name, addrs) in self.inner.lock().iter() {
+            if addrs.contains(ip) {
+                action_meta.insert(
+                    addr_set_meta_key(name),
+                    ACTION_META_ADDR_SET_MEMBER.to_string(),
+                );
+            }
+        }
+    }
 }
 
 pub struct Firewall {}
 
 pub fn from_fw_rule(fw_rule: FirewallRule, action: Action) -> Rule<Finalized> {
-    let addr_pred = fw_rule.filters.hosts().into_predicate(fw_rule.direction);
-    let proto_pred = fw_rule.filters.protocol().into_predicate();
-    let port_pred = fw_rule.filters.ports().into_predicate();
+    let mut rule = filters_rule(
+        &fw_rule.filters,
+        fw_rule.direction,
+        fw_rule.priority,
+        action,
+    );
+    rule.set_log(fw_rule.action.is_log());
+    rule
+}
+
+/// Build a rule which runs `action` for all packets traveling in
+/// direction `dir` which match the `filters`.
+pub fn filters_rule(
+    filters: &Filters,
+    dir: Direction,
+    priority: u16,
+    action: Action,
+) -> Rule<Finalized> {
+    let addr_pred = filters.hosts().into_predicate(dir);
+    let proto_pred = filters.protocol().into_predicate();
+    let port_pred = filters.ports().into_predicate();
+    let data_preds = filters.protocol().into_data_predicates();
 
     if addr_pred.is_none() && proto_pred.is_none() && port_pred.is_none() {
-        return Rule::match_any(fw_rule.priority, action);
+        return Rule::match_any(priority, action);
     }
 
-    let mut rule = Rule::new(fw_rule.priority, action);
+    let mut rule = Rule::new(priority, action);
+
+    for pred in data_preds {
+        rule.add_data_predicate(pred);
+    }
 
     if proto_pred.is_some() {
         rule.add_predicate(proto_pred.unwrap());
@@ -127,7 +295,10 @@ pub fn from_fw_rule(fw_rule: FirewallRule, action: Action) -> Rule<Finalized> {
 }
 
 impl Firewall {
-    pub fn create_layer(port_name: &str, ft_limit: NonZeroU32) -> Layer {
+    pub fn create_layer(
+        port_name: &str,
+        ft_limit: NonZeroU32,
+    ) -> Result<Layer, OpteError> {
         // The inbound side of the firewall is a filtering layer, only
         // traffic explicitly allowed should pass. By setting the
         // default inbound action to deny we effectively implement the
@@ -140,6 +311,7 @@ impl Firewall {
         // allow.
         let actions = LayerActions {
             actions: vec![],
+            named: vec![],
             default_in: DefaultAction::Deny,
             default_out: DefaultAction::StatefulAllow,
         };
@@ -150,12 +322,43 @@ impl Firewall {
 
 impl ProtoFilter {
     pub fn into_predicate(self) -> Option<Predicate> {
+        let proto = match self {
+            ProtoFilter::Any => return None,
+            ProtoFilter::Proto(p) => p,
+            ProtoFilter::Icmp(_) => Protocol::ICMP,
+            ProtoFilter::Icmpv6(_) => Protocol::ICMPv6,
+        };
+
+        Some(Predicate::InnerIpProto(vec![IpProtoMatch::Exact(proto)]))
+    }
+
+    /// Return the predicates on the ICMP message, if any. These must
+    /// always be paired with the protocol predicate, as the body of a
+    /// non-ICMP packet could otherwise be mistaken for an ICMP
+    /// message.
+    pub fn into_data_predicates(self) -> Vec<DataPredicate> {
         match self {
-            ProtoFilter::Any => None,
+            ProtoFilter::Icmp(icmp) => {
+                let mut preds = vec![DataPredicate::IcmpMsgType(
+                    IcmpMessageType::from(icmp.msg_type),
+                )];
+                if let Some(code) = icmp.code {
+                    preds.push(DataPredicate::IcmpMsgCode(code));
+                }
+                preds
+            }
 
-            ProtoFilter::Proto(p) => {
-                Some(Predicate::InnerIpProto(vec![IpProtoMatch::Exact(p)]))
+            ProtoFilter::Icmpv6(icmp) => {
+                let mut preds = vec![DataPredicate::Icmpv6MsgType(
+                    Icmpv6MessageType::from(icmp.msg_type),
+                )];
+                if let Some(code) = icmp.code {
+                    preds.push(DataPredicate::Icmpv6MsgCode(code));
+                }
+                preds
             }
+
+            _ => vec![],
         }
     }
 }
@@ -165,26 +368,47 @@ impl Address {
         match (dir, self) {
             (_, Address::Any) => None,
 
-            (Direction::Out, Address::Ip(ip4)) => {
+            (Direction::Out, Address::Ip(IpAddr::Ip4(ip4))) => {
                 Some(Predicate::InnerDstIp4(vec![Ipv4AddrMatch::Exact(ip4)]))
             }
 
-            (Direction::In, Address::Ip(ip4)) => {
+            (Direction::In, Address::Ip(IpAddr::Ip4(ip4))) => {
                 Some(Predicate::InnerSrcIp4(vec![Ipv4AddrMatch::Exact(ip4)]))
             }
 
-            (Direction::Out, Address::Subnet(ip4_sub)) => Some(
+            (Direction::Out, Address::Ip(IpAddr::Ip6(ip6))) => {
+                Some(Predicate::InnerDstIp6(vec![Ipv6AddrMatch::Exact(ip6)]))
+            }
+
+            (Direction::In, Address::Ip(IpAddr::Ip6(ip6))) => {
+                Some(Predicate::InnerSrcIp6(vec![Ipv6AddrMatch::Exact(ip6)]))
+            }
+
+            (Direction::Out, Address::Subnet(IpCidr::Ip4(ip4_sub))) => Some(
                 Predicate::InnerDstIp4(vec![Ipv4AddrMatch::Prefix(ip4_sub)]),
             ),
 
-            (Direction::In, Address::Subnet(ip4_sub)) => Some(
+            (Direction::In, Address::Subnet(IpCidr::Ip4(ip4_sub))) => Some(
                 Predicate::InnerSrcIp4(vec![Ipv4AddrMatch::Prefix(ip4_sub)]),
             ),
 
+            (Direction::Out, Address::Subnet(IpCidr::Ip6(ip6_sub))) => Some(
+                Predicate::InnerDstIp6(vec![Ipv6AddrMatch::Prefix(ip6_sub)]),
+            ),
+
+            (Direction::In, Address::Subnet(IpCidr::Ip6(ip6_sub))) => Some(
+                Predicate::InnerSrcIp6(vec![Ipv6AddrMatch::Prefix(ip6_sub)]),
+            ),
+
             (_, Address::Vni(vni)) => Some(Predicate::Meta(
                 ACTION_META_VNI.to_string(),
                 vni.to_string(),
             )),
+
+            (_, Address::Set(name)) => Some(Predicate::Meta(
+                addr_set_meta_key(&name.to_ascii_lowercase()),
+                ACTION_META_ADDR_SET_MEMBER.to_string(),
+            )),
         }
     }
 }
@@ -199,6 +423,26 @@ impl Ports {
                     ports.iter().map(|p| PortMatch::Exact(*p)).collect();
                 Some(Predicate::InnerDstPort(mlist))
             }
+
+            Ports::Range(range) => {
+                Some(Predicate::InnerDstPort(vec![PortMatch::Range(
+                    range.clone(),
+                )]))
+            }
+
+            Ports::RangeList(ranges) => {
+                let mlist = ranges
+                    .iter()
+                    .map(|r| {
+                        if r.start() == r.end() {
+                            PortMatch::Exact(*r.start())
+                        } else {
+                            PortMatch::Range(r.clone())
+                        }
+                    })
+                    .collect();
+                Some(Predicate::InnerDstPort(mlist))
+            }
         }
     }
 }
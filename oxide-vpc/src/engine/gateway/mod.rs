@@ -21,7 +21,9 @@
 //!
 //! We use the outbound no spoof check as a convenient place to insert
 //! the VNI of the destination into the action metadata. This can be
-//! used by the firewall to filter traffic by VNI (VPC).
+//! used by the firewall to filter traffic by VNI (VPC). The same goes
+//! for the VPC's address sets containing the destination, which the
+//! firewall uses to filter traffic by address set.
 //!
 //! # L3 Unicast Inbound
 //!
@@ -45,6 +47,7 @@ use crate::api::Ipv6Cfg;
 use crate::api::MacAddr;
 use crate::api::Vni;
 use crate::api::VpcCfg;
+use crate::engine::firewall::AddrSets;
 use crate::engine::overlay::VpcMappings;
 use crate::engine::overlay::ACTION_META_VNI;
 use core::fmt;
@@ -112,11 +115,12 @@ pub fn setup(
     // for inbound traffic to be that of the gateway.
     let actions = LayerActions {
         actions: vec![],
+        named: vec![],
         default_in: DefaultAction::Deny,
         default_out: DefaultAction::Deny,
     };
 
-    let mut layer = Layer::new(NAME, pb.name(), actions, ft_limit);
+    let mut layer = Layer::new(NAME, pb.name(), actions, ft_limit)?;
 
     if let Some(ipv4_cfg) = cfg.ipv4_cfg() {
         setup_ipv4(&mut layer, cfg, ipv4_cfg, vpc_mappings.clone())?;
@@ -167,12 +171,13 @@ fn setup_ipv4(
     ip_cfg: &Ipv4Cfg,
     vpc_mappings: Arc<VpcMappings>,
 ) -> Result<(), OpteError> {
-    arp::setup(layer, cfg, ip_cfg)?;
+    arp::setup(layer, cfg)?;
     dhcp::setup(layer, cfg, ip_cfg)?;
     icmp::setup(layer, cfg, ip_cfg)?;
 
+    let addr_sets = vpc_mappings.addr_sets(cfg.vni);
     let vpc_meta =
-        Arc::new(VpcMeta::new(vpc_mappings, cfg.boundary_services.vni));
+        Arc::new(VpcMeta::new(vpc_mappings, addr_sets, cfg.bsvc_vni()));
 
     let mut nospoof_out = Rule::new(1000, Action::Meta(vpc_meta));
     nospoof_out.add_predicate(Predicate::InnerSrcIp4(vec![
@@ -208,8 +213,9 @@ fn setup_ipv6(
 ) -> Result<(), OpteError> {
     icmpv6::setup(layer, cfg, ip_cfg)?;
     dhcpv6::setup(layer, cfg)?;
+    let addr_sets = vpc_mappings.addr_sets(cfg.vni);
     let vpc_meta =
-        Arc::new(VpcMeta::new(vpc_mappings, cfg.boundary_services.vni));
+        Arc::new(VpcMeta::new(vpc_mappings, addr_sets, cfg.bsvc_vni()));
     let mut nospoof_out = Rule::new(1000, Action::Meta(vpc_meta));
     nospoof_out.add_predicate(Predicate::InnerSrcIp6(vec![
         Ipv6AddrMatch::Exact(ip_cfg.private_ip),
@@ -242,12 +248,17 @@ fn setup_ipv6(
 /// VPC.
 struct VpcMeta {
     vpc_mappings: Arc<VpcMappings>,
+    addr_sets: Arc<AddrSets>,
     bsvc_vni: Vni,
 }
 
 impl VpcMeta {
-    fn new(vpc_mappings: Arc<VpcMappings>, bsvc_vni: Vni) -> Self {
-        Self { vpc_mappings, bsvc_vni }
+    fn new(
+        vpc_mappings: Arc<VpcMappings>,
+        addr_sets: Arc<AddrSets>,
+        bsvc_vni: Vni,
+    ) -> Self {
+        Self { vpc_mappings, addr_sets, bsvc_vni }
     }
 }
 
@@ -257,6 +268,8 @@ impl MetaAction for VpcMeta {
         flow: &InnerFlowId,
         action_meta: &mut ActionMeta,
     ) -> ModMetaResult {
+        self.addr_sets.tag(&flow.dst_ip, action_meta);
+
         match self.vpc_mappings.ip_to_vni(&flow.dst_ip) {
             Some(vni) => {
                 action_meta
@@ -0,0 +1,109 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! The Oxide VPC port mirror layer.
+//!
+//! This layer holds one [`Action::Mirror`] rule per configured
+//! mirror. It sits closest to the guest: outbound packets are
+//! matched as sent by the guest, inbound packets are matched as they
+//! will be delivered to the guest. Packets not matching any mirror
+//! pass through untouched.
+//!
+//! Only the first matching mirror (by priority) is applied to a
+//! given packet.
+use super::firewall::filters_rule;
+use super::overlay::encap_ht;
+use super::VpcNetwork;
+use crate::api::MirrorDest;
+use crate::api::SetMirrorsReq;
+use crate::api::VpcCfg;
+use core::num::NonZeroU32;
+use opte::api::Direction;
+use opte::api::OpteError;
+use opte::engine::headers::HeaderAction;
+use opte::engine::layer::DefaultAction;
+use opte::engine::layer::Layer;
+use opte::engine::layer::LayerActions;
+use opte::engine::mirror::Mirror;
+use opte::engine::mirror::MirrorTarget;
+use opte::engine::port::Port;
+use opte::engine::port::PortBuilder;
+use opte::engine::port::Pos;
+use opte::engine::rule::Action;
+use opte::engine::rule::HdrTransform;
+
+cfg_if! {
+    if #[cfg(all(not(feature = "std"), not(test)))] {
+        use alloc::sync::Arc;
+    } else {
+        use std::sync::Arc;
+    }
+}
+
+pub const MIRROR_LAYER_NAME: &'static str = "mirror";
+
+pub fn setup(
+    pb: &mut PortBuilder,
+    ft_limit: NonZeroU32,
+) -> Result<(), OpteError> {
+    let actions = LayerActions {
+        actions: vec![],
+        named: vec![],
+        default_in: DefaultAction::Allow,
+        default_out: DefaultAction::Allow,
+    };
+
+    let layer = Layer::new(MIRROR_LAYER_NAME, pb.name(), actions, ft_limit)?;
+    pb.add_layer(layer, Pos::First)
+}
+
+pub fn set_mirrors(
+    port: &Port<VpcNetwork>,
+    cfg: &VpcCfg,
+    req: &SetMirrorsReq,
+) -> Result<(), OpteError> {
+    let mut in_rules = vec![];
+    let mut out_rules = vec![];
+
+    for mcfg in &req.mirrors {
+        let target = match &mcfg.dest {
+            MirrorDest::Port(name) => MirrorTarget::Port(name.clone()),
+
+            // The mirrored frame is left as is, only the outer
+            // headers are pushed. Rewriting the inner destination
+            // MAC, like the overlay does, would hide the original
+            // destination from the collector.
+            MirrorDest::Underlay(phys) => {
+                MirrorTarget::Underlay(HdrTransform {
+                    inner_ether: HeaderAction::Ignore,
+                    ..encap_ht(cfg.phys_ip, *phys)
+                })
+            }
+        };
+
+        let mirror = Arc::new(Mirror::new(
+            &mcfg.name,
+            target,
+            mcfg.trunc_len,
+            mcfg.sample_rate,
+        ));
+
+        let rule = filters_rule(
+            &mcfg.filters,
+            mcfg.direction,
+            mcfg.priority,
+            Action::Mirror(mirror),
+        );
+
+        if mcfg.direction == Direction::In {
+            in_rules.push(rule);
+        } else {
+            out_rules.push(rule);
+        }
+    }
+
+    port.set_rules(MIRROR_LAYER_NAME, in_rules, out_rules)
+}
@@ -4,52 +4,191 @@
 
 // Copyright 2022 Oxide Computer Company
 
+//! The NAT layer of the Oxide Network VPC.
+//!
+//! A port may have any number of floating IPs attached, each one
+//! translated 1:1 to the port's private IP of the same family. The
+//! first floating IP of a family is also used for the outbound traffic
+//! to external networks; without one, the port falls back to SNAT.
+//!
+//! Traffic from external networks reaches the port the same way all
+//! other traffic does: Boundary Services encapsulates it on the VPC's
+//! VNI, addressed to the guest's MAC, as per the NAT mapping the
+//! switch keeps for each floating IP. The overlay decapsulates it and
+//! this layer translates the floating IP to the private IP.
+//!
+//! A port may also forward ports of an external IP it otherwise does
+//! not own to its private IP, see [`add_port_forward`]. These rules
+//! take precedence over the floating IPs.
+//!
+//! The usage of the SNAT ports, down to the flows holding each port,
+//! is reported by [`dump_snat`], and summed in the port's
+//! `<port>_snat4` and `<port>_snat6` kstats.
+//!
+//! A port with an IPv6 configuration may also reach IPv4 hosts through
+//! NAT64, see [`Nat64`]. The guest addresses such a host under the
+//! NAT64 Well-Known Prefix, which its router must send to the
+//! Internet Gateway, as the default route does. The ports of NAT64 are
+//! reported alongside those of SNAT, and summed in the port's
+//! `<port>_nat64` kstats.
+
 cfg_if! {
     if #[cfg(all(not(feature = "std"), not(test)))] {
+        use alloc::boxed::Box;
+        use alloc::string::String;
         use alloc::string::ToString;
         use alloc::sync::Arc;
+        use alloc::sync::Weak;
+        use alloc::vec::Vec;
     } else {
+        use std::boxed::Box;
+        use std::string::String;
         use std::string::ToString;
         use std::sync::Arc;
+        use std::sync::Weak;
+        use std::vec::Vec;
     }
 }
 
 use super::router::RouterTargetInternal;
 use super::router::ROUTER_LAYER_NAME;
+use super::VpcNetwork;
+use crate::api::DelPortForwardResp;
+use crate::api::DetachFloatingIpResp;
 use crate::api::Ipv4Cfg;
 use crate::api::Ipv6Cfg;
-use crate::api::MacAddr;
+use crate::api::PortForward;
 use crate::api::VpcCfg;
 use core::num::NonZeroU32;
+use core::ops::RangeInclusive;
 use core::result::Result;
+use opte::api::CmdOk;
 use opte::api::Direction;
+use opte::api::IpAddr;
+use opte::api::Ipv4Addr;
+use opte::api::Ipv6Addr;
+use opte::api::Ipv6Cidr;
+use opte::api::NoResp;
 use opte::api::OpteError;
+use opte::ddi::sync::KMutex;
+use opte::ddi::sync::KMutexType;
 use opte::engine::ether::ETHER_TYPE_IPV4;
 use opte::engine::ether::ETHER_TYPE_IPV6;
+use opte::engine::ip4::Protocol;
 use opte::engine::layer::DefaultAction;
 use opte::engine::layer::Layer;
 use opte::engine::layer::LayerActions;
+use opte::engine::nat::DNat;
 use opte::engine::nat::Nat;
+use opte::engine::nat64::Nat64;
+use opte::engine::packet::InnerFlowId;
 use opte::engine::port::meta::ActionMetaValue;
+use opte::engine::port::Port;
 use opte::engine::port::PortBuilder;
 use opte::engine::port::Pos;
+use opte::engine::port::RuleUpdate;
 use opte::engine::predicate::EtherTypeMatch;
+use opte::engine::predicate::IpProtoMatch;
 use opte::engine::predicate::Ipv4AddrMatch;
 use opte::engine::predicate::Ipv6AddrMatch;
+use opte::engine::predicate::PortMatch;
 use opte::engine::predicate::Predicate;
 use opte::engine::rule::Action;
+use opte::engine::rule::Finalized;
 use opte::engine::rule::Rule;
+use opte::engine::rule::StatefulAction;
+use opte::engine::snat::ConcreteIpAddr;
+use opte::engine::snat::NatMappingDump;
 use opte::engine::snat::NatPool;
+use opte::engine::snat::NatPorts;
 use opte::engine::snat::SNat;
 use opte::engine::snat::SNat6;
+use serde::Deserialize;
+use serde::Serialize;
 
 pub const NAT_LAYER_NAME: &'static str = "nat";
+const PORT_FORWARD_PRIORITY: u16 = 5;
+const NAT64_PRIORITY: u16 = 7;
 const ONE_TO_ONE_NAT_PRIORITY: u16 = 10;
 const SNAT_PRIORITY: u16 = 100;
 
+/// The SNAT and NAT64 ports in use by all ports, by external IP and
+/// port range.
+///
+/// The ports configured with the same external IP and port range
+/// take them from the same [`NatPorts`], each guest held to the
+/// `max_ports` of its configuration within them. A range which
+/// overlaps, but differs from, one in use is refused, as both would
+/// hand out the same ports.
+pub struct SharedNatPorts {
+    ip4: KMutex<Vec<Weak<NatPorts<Ipv4Addr>>>>,
+    ip6: KMutex<Vec<Weak<NatPorts<Ipv6Addr>>>>,
+}
+
+impl SharedNatPorts {
+    /// Return the ports `ports` of the IPv4 address `ip`.
+    pub fn ip4(
+        &self,
+        ip: Ipv4Addr,
+        ports: &RangeInclusive<u16>,
+    ) -> Result<Arc<NatPorts<Ipv4Addr>>, OpteError> {
+        find_or_add_ports(&self.ip4, ip, ports)
+    }
+
+    /// Return the ports `ports` of the IPv6 address `ip`.
+    pub fn ip6(
+        &self,
+        ip: Ipv6Addr,
+        ports: &RangeInclusive<u16>,
+    ) -> Result<Arc<NatPorts<Ipv6Addr>>, OpteError> {
+        find_or_add_ports(&self.ip6, ip, ports)
+    }
+
+    pub fn new() -> Self {
+        Self {
+            ip4: KMutex::new(Vec::new(), KMutexType::Driver),
+            ip6: KMutex::new(Vec::new(), KMutexType::Driver),
+        }
+    }
+}
+
+// Return the ports in use matching `ip` and `ports`, or new ones if
+// there are none. The ports are only held by the pools using them,
+// so those no longer in use are pruned along the way.
+fn find_or_add_ports<T: ConcreteIpAddr>(
+    shared: &KMutex<Vec<Weak<NatPorts<T>>>>,
+    ip: T,
+    ports: &RangeInclusive<u16>,
+) -> Result<Arc<NatPorts<T>>, OpteError> {
+    let mut lock = shared.lock();
+    lock.retain(|used| used.strong_count() > 0);
+
+    for used in lock.iter().filter_map(Weak::upgrade) {
+        if used.ip() == ip && used.ports() == ports {
+            return Ok(used);
+        }
+
+        if used.overlaps(ip, ports) {
+            return Err(OpteError::InvalidNatPorts(format!(
+                "{} {}-{}: overlaps ports {}-{} in use",
+                ip,
+                ports.start(),
+                ports.end(),
+                used.ports().start(),
+                used.ports().end(),
+            )));
+        }
+    }
+
+    let new = Arc::new(NatPorts::new(ip, ports.clone()));
+    lock.push(Arc::downgrade(&new));
+    Ok(new)
+}
+
 pub fn setup(
     pb: &mut PortBuilder,
     cfg: &VpcCfg,
+    nat_ports: &SharedNatPorts,
     ft_limit: NonZeroU32,
 ) -> Result<(), OpteError> {
     // The NAT layer is rewrite layer and not a filtering one. Any
@@ -57,60 +196,50 @@ pub fn setup(
     // the next layer.
     let actions = LayerActions {
         actions: vec![],
+        named: vec![],
         default_in: DefaultAction::Allow,
         default_out: DefaultAction::Allow,
     };
-    let mut layer = Layer::new(NAT_LAYER_NAME, pb.name(), actions, ft_limit);
+    let mut layer = Layer::new(NAT_LAYER_NAME, pb.name(), actions, ft_limit)?;
     if let Some(ipv4_cfg) = cfg.ipv4_cfg() {
-        setup_ipv4_nat(&mut layer, ipv4_cfg, cfg.phys_gw_mac)?;
+        setup_ipv4_nat(&mut layer, pb.name(), ipv4_cfg, nat_ports)?;
     }
     if let Some(ipv6_cfg) = cfg.ipv6_cfg() {
-        setup_ipv6_nat(&mut layer, ipv6_cfg, cfg.phys_gw_mac)?;
+        setup_ipv6_nat(&mut layer, pb.name(), ipv6_cfg, nat_ports)?;
     }
     pb.add_layer(layer, Pos::After(ROUTER_LAYER_NAME))
 }
 
 fn setup_ipv4_nat(
     layer: &mut Layer,
+    port_name: &str,
     ip_cfg: &Ipv4Cfg,
-    // XXX-EXT-IP Remove
-    phys_gw_mac: Option<MacAddr>,
+    nat_ports: &SharedNatPorts,
 ) -> Result<(), OpteError> {
     // When it comes to NAT we always prefer using 1:1 NAT of external
     // IP to SNAT. To achieve this we place the NAT rules at a lower
     // priority than SNAT.
-    if let Some(ip4) = ip_cfg.external_ips {
-        let nat = Arc::new(Nat::new(ip_cfg.private_ip, ip4, phys_gw_mac));
+    for (i, ip4) in ip_cfg.external_ips.iter().enumerate() {
+        let ip = IpAddr::from(*ip4);
+        let nat: Arc<dyn StatefulAction> =
+            Arc::new(Nat::new(ip_cfg.private_ip, *ip4));
 
-        // 1:1 NAT outbound packets destined for internet gateway.
-        let mut out_nat =
-            Rule::new(ONE_TO_ONE_NAT_PRIORITY, Action::Stateful(nat.clone()));
-        out_nat.add_predicate(Predicate::InnerEtherType(vec![
-            EtherTypeMatch::Exact(ETHER_TYPE_IPV4),
-        ]));
-        out_nat.add_predicate(Predicate::Meta(
-            RouterTargetInternal::KEY.to_string(),
-            RouterTargetInternal::InternetGateway.as_meta(),
-        ));
-        layer.add_rule(Direction::Out, out_nat.finalize());
-
-        // 1:1 NAT inbound packets destined for external IP.
-        let mut in_nat =
-            Rule::new(ONE_TO_ONE_NAT_PRIORITY, Action::Stateful(nat));
-        in_nat.add_predicate(Predicate::InnerDstIp4(vec![
-            Ipv4AddrMatch::Exact(ip4),
-        ]));
-        layer.add_rule(Direction::In, in_nat.finalize());
+        if i == 0 {
+            layer.add_rule(Direction::Out, out_nat_rule(ip, nat.clone()));
+        }
+        layer.add_rule(Direction::In, in_nat_rule(ip, nat));
     }
 
     if let Some(snat_cfg) = &ip_cfg.snat {
-        let pool = NatPool::new();
-        pool.add(
+        let ports = nat_ports.ip4(snat_cfg.external_ip, &snat_cfg.ports)?;
+        let pool = NatPool::new(&format!("{}_snat4", port_name));
+        pool.add_shared(
             ip_cfg.private_ip,
-            snat_cfg.external_ip,
-            snat_cfg.ports.clone(),
+            ports,
+            snat_cfg.mapping,
+            snat_cfg.max_ports,
         );
-        let snat = SNat::new(ip_cfg.private_ip, Arc::new(pool), phys_gw_mac);
+        let snat = SNat::new(ip_cfg.private_ip, Arc::new(pool));
         let mut rule =
             Rule::new(SNAT_PRIORITY, Action::Stateful(Arc::new(snat)));
 
@@ -128,50 +257,68 @@ fn setup_ipv4_nat(
 
 fn setup_ipv6_nat(
     layer: &mut Layer,
+    port_name: &str,
     ip_cfg: &Ipv6Cfg,
-    // XXX-EXT-IP Remove
-    phys_gw_mac: Option<MacAddr>,
+    nat_ports: &SharedNatPorts,
 ) -> Result<(), OpteError> {
     // When it comes to NAT we always prefer using 1:1 NAT of external
     // IP to SNAT. To achieve this we place the NAT rules at a lower
     // priority than SNAT.
-    if let Some(ip6) = ip_cfg.external_ips {
-        let nat = Arc::new(Nat::new(ip_cfg.private_ip, ip6, phys_gw_mac));
+    for (i, ip6) in ip_cfg.external_ips.iter().enumerate() {
+        let ip = IpAddr::from(*ip6);
+        let nat: Arc<dyn StatefulAction> =
+            Arc::new(Nat::new(ip_cfg.private_ip, *ip6));
+
+        if i == 0 {
+            layer.add_rule(Direction::Out, out_nat_rule(ip, nat.clone()));
+        }
+        layer.add_rule(Direction::In, in_nat_rule(ip, nat));
+    }
 
-        // 1:1 NAT outbound packets destined for internet gateway.
-        let mut out_nat =
-            Rule::new(ONE_TO_ONE_NAT_PRIORITY, Action::Stateful(nat.clone()));
-        out_nat.add_predicate(Predicate::InnerEtherType(vec![
+    if let Some(ref snat_cfg) = ip_cfg.snat {
+        let ports = nat_ports.ip6(snat_cfg.external_ip, &snat_cfg.ports)?;
+        let pool = NatPool::new(&format!("{}_snat6", port_name));
+        pool.add_shared(
+            ip_cfg.private_ip,
+            ports,
+            snat_cfg.mapping,
+            snat_cfg.max_ports,
+        );
+        let snat = SNat6::new(ip_cfg.private_ip, Arc::new(pool));
+        let mut rule =
+            Rule::new(SNAT_PRIORITY, Action::Stateful(Arc::new(snat)));
+
+        rule.add_predicate(Predicate::InnerEtherType(vec![
             EtherTypeMatch::Exact(ETHER_TYPE_IPV6),
         ]));
-        out_nat.add_predicate(Predicate::Meta(
+        rule.add_predicate(Predicate::Meta(
             RouterTargetInternal::KEY.to_string(),
             RouterTargetInternal::InternetGateway.as_meta(),
         ));
-        layer.add_rule(Direction::Out, out_nat.finalize());
-
-        // 1:1 NAT inbound packets destined for external IP.
-        let mut in_nat =
-            Rule::new(ONE_TO_ONE_NAT_PRIORITY, Action::Stateful(nat));
-        in_nat.add_predicate(Predicate::InnerDstIp6(vec![
-            Ipv6AddrMatch::Exact(ip6),
-        ]));
-        layer.add_rule(Direction::In, in_nat.finalize());
+        layer.add_rule(Direction::Out, rule.finalize());
     }
 
-    if let Some(ref snat_cfg) = ip_cfg.snat {
-        let pool = NatPool::new();
-        pool.add(
+    // NAT64 takes precedence over 1:1 NAT and SNAT, as neither can
+    // deliver traffic to the NAT64 prefix.
+    if let Some(ref nat64_cfg) = ip_cfg.nat64 {
+        let ports = nat_ports.ip4(nat64_cfg.external_ip, &nat64_cfg.ports)?;
+        let pool = NatPool::new(&format!("{}_nat64", port_name));
+        pool.add_shared(
+            nat64_cfg.external_ip,
+            ports,
+            nat64_cfg.mapping,
+            nat64_cfg.max_ports,
+        );
+        let nat64 = Nat64::new(
             ip_cfg.private_ip,
-            snat_cfg.external_ip,
-            snat_cfg.ports.clone(),
+            nat64_cfg.external_ip,
+            Arc::new(pool),
         );
-        let snat = SNat6::new(ip_cfg.private_ip, Arc::new(pool), phys_gw_mac);
         let mut rule =
-            Rule::new(SNAT_PRIORITY, Action::Stateful(Arc::new(snat)));
+            Rule::new(NAT64_PRIORITY, Action::Stateful(Arc::new(nat64)));
 
-        rule.add_predicate(Predicate::InnerEtherType(vec![
-            EtherTypeMatch::Exact(ETHER_TYPE_IPV6),
+        rule.add_predicate(Predicate::InnerDstIp6(vec![
+            Ipv6AddrMatch::Prefix(Ipv6Cidr::NAT64_WKP),
         ]));
         rule.add_predicate(Predicate::Meta(
             RouterTargetInternal::KEY.to_string(),
@@ -181,3 +328,415 @@ fn setup_ipv6_nat(
     }
     Ok(())
 }
+
+// 1:1 NAT outbound packets destined for internet gateway, using the
+// floating IP `ip`.
+fn out_nat_rule(ip: IpAddr, nat: Arc<dyn StatefulAction>) -> Rule<Finalized> {
+    let ether_type = match ip {
+        IpAddr::Ip4(_) => ETHER_TYPE_IPV4,
+        IpAddr::Ip6(_) => ETHER_TYPE_IPV6,
+    };
+
+    let mut rule = Rule::new(ONE_TO_ONE_NAT_PRIORITY, Action::Stateful(nat));
+    rule.add_predicate(Predicate::InnerEtherType(vec![EtherTypeMatch::Exact(
+        ether_type,
+    )]));
+    rule.add_predicate(Predicate::Meta(
+        RouterTargetInternal::KEY.to_string(),
+        RouterTargetInternal::InternetGateway.as_meta(),
+    ));
+    rule.finalize()
+}
+
+// 1:1 NAT inbound packets destined for the floating IP `ip`.
+fn in_nat_rule(ip: IpAddr, nat: Arc<dyn StatefulAction>) -> Rule<Finalized> {
+    let pred = match ip {
+        IpAddr::Ip4(ip4) => {
+            Predicate::InnerDstIp4(vec![Ipv4AddrMatch::Exact(ip4)])
+        }
+
+        IpAddr::Ip6(ip6) => {
+            Predicate::InnerDstIp6(vec![Ipv6AddrMatch::Exact(ip6)])
+        }
+    };
+
+    let mut rule = Rule::new(ONE_TO_ONE_NAT_PRIORITY, Action::Stateful(nat));
+    rule.add_predicate(pred);
+    rule.finalize()
+}
+
+// Return the floating IP of a 1:1 NAT rule, or `None` for any other
+// rule.
+fn rule_nat_ip(rule: &Rule<Finalized>) -> Option<IpAddr> {
+    match rule.action() {
+        Action::Stateful(action) => {
+            let nat = action.as_any()?.downcast_ref::<Nat>()?;
+            Some(nat.external_ip())
+        }
+
+        _ => None,
+    }
+}
+
+fn same_family(a: IpAddr, b: IpAddr) -> bool {
+    matches!(
+        (a, b),
+        (IpAddr::Ip4(_), IpAddr::Ip4(_)) | (IpAddr::Ip6(_), IpAddr::Ip6(_))
+    )
+}
+
+/// Return the floating IPs attached to the port.
+pub fn floating_ips(port: &Port<VpcNetwork>) -> Result<Vec<IpAddr>, OpteError> {
+    let rules = port.layer_rules(NAT_LAYER_NAME, Direction::In)?;
+    Ok(rules.iter().filter_map(|(_, rule)| rule_nat_ip(rule)).collect())
+}
+
+/// Attach the floating IP `ip` to the port.
+///
+/// The port must have an IP configuration of the same family, and
+/// `ip` must not be its SNAT address. Attaching an IP which is
+/// already attached is a no-op. Only the flows which may match the
+/// new rules are invalidated.
+pub fn attach_floating_ip(
+    port: &Port<VpcNetwork>,
+    cfg: &VpcCfg,
+    ip: IpAddr,
+) -> Result<NoResp, OpteError> {
+    let nat: Arc<dyn StatefulAction> = match ip {
+        IpAddr::Ip4(ip4) => {
+            let ip_cfg = cfg.ipv4_cfg().ok_or_else(|| {
+                OpteError::InvalidFloatingIp(format!(
+                    "{}: port has no IPv4 configuration",
+                    ip
+                ))
+            })?;
+
+            if ip_cfg.snat.as_ref().map(|snat| snat.external_ip) == Some(ip4) {
+                return Err(OpteError::InvalidFloatingIp(format!(
+                    "{}: in use for SNAT",
+                    ip
+                )));
+            }
+
+            Arc::new(Nat::new(ip_cfg.private_ip, ip4))
+        }
+
+        IpAddr::Ip6(ip6) => {
+            let ip_cfg = cfg.ipv6_cfg().ok_or_else(|| {
+                OpteError::InvalidFloatingIp(format!(
+                    "{}: port has no IPv6 configuration",
+                    ip
+                ))
+            })?;
+
+            if ip_cfg.snat.as_ref().map(|snat| snat.external_ip) == Some(ip6) {
+                return Err(OpteError::InvalidFloatingIp(format!(
+                    "{}: in use for SNAT",
+                    ip
+                )));
+            }
+
+            Arc::new(Nat::new(ip_cfg.private_ip, ip6))
+        }
+    };
+
+    port.update_rules(NAT_LAYER_NAME, |mut in_rules, mut out_rules| {
+        if in_rules.iter().any(|rule| rule_nat_ip(rule) == Some(ip)) {
+            return Ok(None);
+        }
+
+        // The first floating IP of a family takes over the outbound
+        // traffic from SNAT.
+        let has_out = out_rules.iter().any(|rule| {
+            rule_nat_ip(rule).map(|o| same_family(o, ip)).unwrap_or(false)
+        });
+        if !has_out {
+            out_rules.push(out_nat_rule(ip, nat.clone()));
+        }
+
+        in_rules.push(in_nat_rule(ip, nat));
+        Ok(Some(RuleUpdate { in_rules, out_rules, invalidate_in: None }))
+    })?;
+    Ok(NoResp::default())
+}
+
+/// Detach the floating IP `ip` from the port.
+///
+/// If `ip` was used for outbound traffic, the next floating IP of the
+/// same family takes its place, or SNAT if there is none. Only the
+/// flows of `ip` are invalidated: those of the connections made to
+/// `ip`, and of those made from it. The flows of the connections
+/// made through other floating IPs, or through SNAT, are kept.
+pub fn detach_floating_ip(
+    port: &Port<VpcNetwork>,
+    ip: IpAddr,
+) -> Result<DetachFloatingIpResp, OpteError> {
+    let res =
+        port.update_rules(NAT_LAYER_NAME, |mut in_rules, mut out_rules| {
+            let in_len = in_rules.len();
+            in_rules.retain(|rule| rule_nat_ip(rule) != Some(ip));
+            if in_rules.len() == in_len {
+                return Ok(None);
+            }
+
+            let out_len = out_rules.len();
+            out_rules.retain(|rule| rule_nat_ip(rule) != Some(ip));
+            if out_rules.len() != out_len {
+                // Share the action of the next IP's inbound rule, so that
+                // rule is kept as is.
+                let next = in_rules.iter().find_map(|rule| {
+                    match (rule_nat_ip(rule), rule.action()) {
+                        (Some(next_ip), Action::Stateful(nat))
+                            if same_family(next_ip, ip) =>
+                        {
+                            Some((next_ip, nat.clone()))
+                        }
+
+                        _ => None,
+                    }
+                });
+
+                if let Some((next_ip, nat)) = next {
+                    out_rules.push(out_nat_rule(next_ip, nat));
+                }
+            }
+
+            // A flow of `ip`, whether its connection was made to or
+            // from `ip`, has `ip` as the destination of its inbound
+            // side. The predicates of the outbound rules match all of
+            // the guest's flows, and would invalidate them all.
+            Ok(Some(RuleUpdate {
+                in_rules,
+                out_rules,
+                invalidate_in: Some(Box::new(move |flow: &InnerFlowId| {
+                    flow.dst_ip == ip
+                })),
+            }))
+        })?;
+
+    match res {
+        Some(_) => Ok(DetachFloatingIpResp::Ok),
+        None => Ok(DetachFloatingIpResp::NotFound),
+    }
+}
+
+fn in_port_forward_rule(fwd: &PortForward, dnat: DNat) -> Rule<Finalized> {
+    let ip_pred = match fwd.external_ip {
+        IpAddr::Ip4(ip4) => {
+            Predicate::InnerDstIp4(vec![Ipv4AddrMatch::Exact(ip4)])
+        }
+
+        IpAddr::Ip6(ip6) => {
+            Predicate::InnerDstIp6(vec![Ipv6AddrMatch::Exact(ip6)])
+        }
+    };
+
+    let ports = &fwd.external_ports;
+    let port_match = if ports.start() == ports.end() {
+        PortMatch::Exact(*ports.start())
+    } else {
+        PortMatch::Range(ports.clone())
+    };
+
+    let mut rule =
+        Rule::new(PORT_FORWARD_PRIORITY, Action::Stateful(Arc::new(dnat)));
+    rule.add_predicate(ip_pred);
+    rule.add_predicate(Predicate::InnerIpProto(vec![IpProtoMatch::Exact(
+        fwd.proto,
+    )]));
+    rule.add_predicate(Predicate::InnerDstPort(vec![port_match]));
+    rule.finalize()
+}
+
+// Return the port forward of a DNAT rule, or `None` for any other
+// rule.
+fn rule_port_forward(rule: &Rule<Finalized>) -> Option<PortForward> {
+    match rule.action() {
+        Action::Stateful(action) => {
+            let dnat = action.as_any()?.downcast_ref::<DNat>()?;
+            Some(PortForward {
+                external_ip: dnat.external_ip(),
+                proto: dnat.proto(),
+                external_ports: dnat.ext_ports(),
+                private_port: dnat.priv_port(),
+            })
+        }
+
+        _ => None,
+    }
+}
+
+fn ranges_overlap(a: &RangeInclusive<u16>, b: &RangeInclusive<u16>) -> bool {
+    a.start() <= b.end() && b.start() <= a.end()
+}
+
+/// Return the port forwarding rules of the port.
+pub fn port_forwards(
+    port: &Port<VpcNetwork>,
+) -> Result<Vec<PortForward>, OpteError> {
+    let rules = port.layer_rules(NAT_LAYER_NAME, Direction::In)?;
+    Ok(rules.iter().filter_map(|(_, rule)| rule_port_forward(rule)).collect())
+}
+
+/// Add the port forwarding rule `fwd` to the port.
+///
+/// The port must have an IP configuration of the same family as the
+/// external IP, and the external ports must neither overlap with
+/// another rule for the same IP and protocol, nor with the port's
+/// SNAT ports. Adding a rule which already exists is a no-op.
+pub fn add_port_forward(
+    port: &Port<VpcNetwork>,
+    cfg: &VpcCfg,
+    fwd: PortForward,
+) -> Result<NoResp, OpteError> {
+    let invalid = |msg: &str| {
+        Err(OpteError::InvalidPortForward(format!("{}: {}", fwd, msg)))
+    };
+
+    if fwd.proto != Protocol::TCP && fwd.proto != Protocol::UDP {
+        return invalid("only TCP and UDP may be forwarded");
+    }
+
+    let (start, end) = (*fwd.external_ports.start(), *fwd.external_ports.end());
+    if start == 0 || start > end {
+        return invalid("invalid external ports");
+    }
+
+    if fwd.private_port == 0
+        || fwd.private_port as u32 + (end - start) as u32 > u16::MAX as u32
+    {
+        return invalid("invalid private port");
+    }
+
+    let (dnat, snat) = match fwd.external_ip {
+        IpAddr::Ip4(ip4) => match cfg.ipv4_cfg() {
+            Some(ip_cfg) => (
+                DNat::new(
+                    ip4,
+                    fwd.proto,
+                    fwd.external_ports.clone(),
+                    ip_cfg.private_ip,
+                    fwd.private_port,
+                ),
+                ip_cfg.snat.as_ref().and_then(|snat| {
+                    (snat.external_ip == ip4).then(|| snat.ports.clone())
+                }),
+            ),
+
+            None => return invalid("port has no IPv4 configuration"),
+        },
+
+        IpAddr::Ip6(ip6) => match cfg.ipv6_cfg() {
+            Some(ip_cfg) => (
+                DNat::new(
+                    ip6,
+                    fwd.proto,
+                    fwd.external_ports.clone(),
+                    ip_cfg.private_ip,
+                    fwd.private_port,
+                ),
+                ip_cfg.snat.as_ref().and_then(|snat| {
+                    (snat.external_ip == ip6).then(|| snat.ports.clone())
+                }),
+            ),
+
+            None => return invalid("port has no IPv6 configuration"),
+        },
+    };
+
+    if let Some(snat_ports) = snat {
+        if ranges_overlap(&snat_ports, &fwd.external_ports) {
+            return invalid("overlaps with SNAT ports");
+        }
+    }
+
+    // The overlap check and the addition of the rule must be done
+    // under the same port lock, lest two overlapping rules both pass.
+    port.update_rules(NAT_LAYER_NAME, |mut in_rules, out_rules| {
+        for other in in_rules.iter().filter_map(rule_port_forward) {
+            if other == fwd {
+                return Ok(None);
+            }
+
+            if other.external_ip == fwd.external_ip
+                && other.proto == fwd.proto
+                && ranges_overlap(&other.external_ports, &fwd.external_ports)
+            {
+                return Err(OpteError::InvalidPortForward(format!(
+                    "{}: overlaps with {}",
+                    fwd, other
+                )));
+            }
+        }
+
+        in_rules.push(in_port_forward_rule(&fwd, dnat));
+        Ok(Some(RuleUpdate { in_rules, out_rules, invalidate_in: None }))
+    })?;
+    Ok(NoResp::default())
+}
+
+/// Delete the port forwarding rule `fwd` from the port, along with
+/// the flows it created.
+pub fn del_port_forward(
+    port: &Port<VpcNetwork>,
+    fwd: &PortForward,
+) -> Result<DelPortForwardResp, OpteError> {
+    let res =
+        port.update_rules(NAT_LAYER_NAME, |mut in_rules, out_rules| {
+            let in_len = in_rules.len();
+            in_rules
+                .retain(|rule| rule_port_forward(rule).as_ref() != Some(fwd));
+            if in_rules.len() == in_len {
+                return Ok(None);
+            }
+
+            Ok(Some(RuleUpdate { in_rules, out_rules, invalidate_in: None }))
+        })?;
+
+    match res {
+        Some(_) => Ok(DelPortForwardResp::Ok),
+        None => Ok(DelPortForwardResp::NotFound),
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DumpSnatReq {
+    pub port_name: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DumpSnatResp {
+    pub mappings: Vec<NatMappingDump>,
+}
+
+impl CmdOk for DumpSnatResp {}
+
+/// Dump the SNAT mappings of the port, along with the flows using
+/// their ports. This includes the NAT64 mapping, which is keyed by
+/// its external IP in place of the private IP.
+pub fn dump_snat(port: &Port<VpcNetwork>) -> Result<DumpSnatResp, OpteError> {
+    let rules = port.layer_rules(NAT_LAYER_NAME, Direction::Out)?;
+    let mut mappings = Vec::new();
+
+    for (_, rule) in rules.iter() {
+        let action = match rule.action() {
+            Action::Stateful(action) => action,
+            _ => continue,
+        };
+
+        let any = match action.as_any() {
+            Some(any) => any,
+            None => continue,
+        };
+
+        if let Some(snat) = any.downcast_ref::<SNat>() {
+            mappings.extend(snat.pool().dump());
+        } else if let Some(snat) = any.downcast_ref::<SNat6>() {
+            mappings.extend(snat.pool().dump());
+        } else if let Some(nat64) = any.downcast_ref::<Nat64>() {
+            mappings.extend(nat64.pool().dump());
+        }
+    }
+
+    Ok(DumpSnatResp { mappings })
+}
@@ -12,11 +12,15 @@ use core::fmt;
 cfg_if! {
     if #[cfg(all(not(feature = "std"), not(test)))] {
         use alloc::collections::btree_map::BTreeMap;
+        use alloc::collections::btree_set::BTreeSet;
+        use alloc::collections::VecDeque;
         use alloc::string::ToString;
         use alloc::sync::Arc;
         use alloc::vec::Vec;
     } else {
         use std::collections::btree_map::BTreeMap;
+        use std::collections::btree_set::BTreeSet;
+        use std::collections::VecDeque;
         use std::string::ToString;
         use std::sync::Arc;
         use std::vec::Vec;
@@ -26,19 +30,34 @@ cfg_if! {
 use serde::Deserialize;
 use serde::Serialize;
 
+use super::firewall::AddrSets;
+use super::nat::SharedNatPorts;
+use super::router::ecmp_select;
 use super::router::RouterTargetInternal;
+use super::v2p::V2pTable;
 use crate::api::BoundaryServices;
+use crate::api::DumpAddrSetsResp;
+use crate::api::DumpBsvcStateResp;
 use crate::api::GuestPhysAddr;
 use crate::api::PhysNet;
+use crate::api::ReadV2pMissesResp;
+use crate::api::V2pMiss;
 use crate::api::VpcCfg;
 use core::marker::PhantomData;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering::SeqCst;
+use illumos_sys_hdrs::EINTR;
 use opte::api::CmdOk;
 use opte::api::Direction;
 use opte::api::Ipv4Addr;
 use opte::api::MacAddr;
 use opte::api::OpteError;
+use opte::ddi::sync::KCondvar;
 use opte::ddi::sync::KMutex;
 use opte::ddi::sync::KMutexType;
+use opte::ddi::sync::KRwLock;
+use opte::ddi::sync::KRwLockType;
+use opte::ddi::time::Moment;
 use opte::engine::ether::EtherMeta;
 use opte::engine::ether::EtherMod;
 use opte::engine::ether::EtherType;
@@ -68,6 +87,7 @@ use opte::engine::rule::AllowOrDeny;
 use opte::engine::rule::GenHtError;
 use opte::engine::rule::GenHtResult;
 use opte::engine::rule::HdrTransform;
+use opte::engine::rule::HtCheck;
 use opte::engine::rule::MappingResource;
 use opte::engine::rule::Resource;
 use opte::engine::rule::ResourceEntry;
@@ -80,27 +100,33 @@ pub fn setup(
     pb: &PortBuilder,
     cfg: &VpcCfg,
     v2p: Arc<Virt2Phys>,
+    addr_sets: Arc<AddrSets>,
+    misses: Arc<V2pMisses>,
+    bsvc: Arc<BsvcState>,
     ft_limit: core::num::NonZeroU32,
 ) -> core::result::Result<(), OpteError> {
     // Action Index 0
     let encap = Action::Static(Arc::new(EncapAction::new(
-        cfg.boundary_services,
+        &cfg.boundary_services,
         cfg.phys_ip,
         cfg.vni,
         v2p,
+        misses,
+        bsvc,
     )));
 
     // Action Index 1
-    let decap = Action::Static(Arc::new(DecapAction::new()));
+    let decap = Action::Static(Arc::new(DecapAction::new(addr_sets)));
 
     let actions = LayerActions {
         actions: vec![encap, decap],
+        named: vec![],
         default_in: DefaultAction::Deny,
         default_out: DefaultAction::Deny,
     };
 
     let mut layer =
-        Layer::new(OVERLAY_LAYER_NAME, pb.name(), actions, ft_limit);
+        Layer::new(OVERLAY_LAYER_NAME, pb.name(), actions, ft_limit)?;
     let encap_rule = Rule::match_any(1, layer.action(0).unwrap().clone());
     layer.add_rule(Direction::Out, encap_rule);
     let decap_rule = Rule::match_any(1, layer.action(1).unwrap().clone());
@@ -113,6 +139,59 @@ pub fn setup(
 pub const DECAP_NAME: &'static str = "decap";
 pub const ENCAP_NAME: &'static str = "encap";
 
+/// Generate the [`HdrTransform`] which encapsulates a packet from the
+/// host `phys_ip_src` to the physical location `phys_target`.
+///
+/// The outer MAC addresses are left zero'd for the driver to fill
+/// in, see [`EncapAction`].
+pub fn encap_ht(phys_ip_src: Ipv6Addr, phys_target: PhysNet) -> HdrTransform {
+    HdrTransform {
+        name: ENCAP_NAME.to_string(),
+        // We leave the outer src/dst up to the driver.
+        outer_ether: HeaderAction::Push(
+            EtherMeta {
+                src: MacAddr::ZERO,
+                dst: MacAddr::ZERO,
+                ether_type: EtherType::Ipv6,
+            },
+            PhantomData,
+        ),
+        outer_ip: HeaderAction::Push(
+            IpPush::from(Ipv6Push {
+                src: phys_ip_src,
+                dst: phys_target.ip.into(),
+                proto: Protocol::UDP,
+            }),
+            PhantomData,
+        ),
+        // XXX Geneve uses the UDP source port as a flow label
+        // value for the purposes of ECMP -- a hash of the
+        // 5-tuple. However, when using Geneve in IPv6 one could
+        // also choose to use the IPv6 Flow Label field, which has
+        // 4 more bits of entropy and could be argued to be more
+        // fit for this purpose. As we know that our physical
+        // network is always IPv6, perhaps we should just use
+        // that? For now I defer the choice and leave this
+        // hard-coded.
+        outer_encap: HeaderAction::Push(
+            EncapPush::from(GenevePush {
+                vni: phys_target.vni.into(),
+                entropy: 7777,
+                ..Default::default()
+            }),
+            PhantomData,
+        ),
+        inner_ether: HeaderAction::Modify(
+            EtherMod {
+                dst: Some(phys_target.ether.into()),
+                ..Default::default()
+            },
+            PhantomData,
+        ),
+        ..Default::default()
+    }
+}
+
 /// A [`StaticAction`] to encapsulate a packet for the purpose of
 /// implementing the Oxide VPC overlay network.
 ///
@@ -174,33 +253,69 @@ pub const ENCAP_NAME: &'static str = "encap";
 /// destination to the physical location. These mappings are
 /// determined by Nexus and pushed down to individual OPTE instances.
 /// The mapping itself is available through the port metadata passes
-/// as argument to the [`StaticAction`] callback.
+/// as argument to the [`StaticAction`] callback. A destination
+/// without a mapping is reported to the resolver through
+/// [`V2pMisses`].
+///
+/// There may be several endpoints of Boundary Services, one per
+/// switch. A flow to the Internet Gateway is sent to one of the
+/// endpoints not marked down in the [`BsvcState`], chosen by
+/// rendezvous hashing of the flow, see [`ecmp_select()`]. When an
+/// endpoint is marked down, only the flows which chose it are
+/// re-resolved, and they are spread across the remaining endpoints.
 pub struct EncapAction {
-    boundary_services: PhysNet,
+    boundary_services: Vec<PhysNet>,
     // The physical IPv6 ULA of the server that hosts this guest
     // sending data.
     phys_ip_src: Ipv6Addr,
     vni: Vni,
     v2p: Arc<Virt2Phys>,
+    misses: Arc<V2pMisses>,
+    bsvc: Arc<BsvcState>,
 }
 
 impl EncapAction {
     pub fn new(
-        boundary_services: BoundaryServices,
+        boundary_services: &[BoundaryServices],
         phys_ip_src: Ipv6Addr,
         vni: Vni,
         v2p: Arc<Virt2Phys>,
+        misses: Arc<V2pMisses>,
+        bsvc: Arc<BsvcState>,
     ) -> Self {
-        Self {
-            boundary_services: PhysNet {
-                ether: boundary_services.mac,
-                ip: boundary_services.ip,
-                vni: boundary_services.vni,
-            },
-            phys_ip_src,
-            vni,
-            v2p,
-        }
+        let boundary_services = boundary_services
+            .iter()
+            .map(|bs| PhysNet { ether: bs.mac, ip: bs.ip, vni: bs.vni })
+            .collect();
+
+        Self { boundary_services, phys_ip_src, vni, v2p, misses, bsvc }
+    }
+
+    // Choose the Boundary Services endpoint for `flow_id`, among
+    // those not marked down. Should all of them be down, the choice
+    // is made among all endpoints, as there is nowhere better to
+    // send the flow.
+    fn select_bsvc(&self, flow_id: &InnerFlowId) -> Option<PhysNet> {
+        let all: Vec<IpAddr> = self
+            .boundary_services
+            .iter()
+            .map(|bs| IpAddr::Ip6(bs.ip))
+            .collect();
+        let up: Vec<IpAddr> = all
+            .iter()
+            .filter(|ip| match ip {
+                IpAddr::Ip6(ip6) => self.bsvc.is_up(ip6),
+                IpAddr::Ip4(_) => false,
+            })
+            .copied()
+            .collect();
+        let members = if up.is_empty() { &all } else { &up };
+
+        let chosen = ecmp_select(flow_id, members)?;
+        self.boundary_services
+            .iter()
+            .find(|bs| IpAddr::Ip6(bs.ip) == chosen)
+            .copied()
     }
 }
 
@@ -247,15 +362,42 @@ impl StaticAction for EncapAction {
             }
         };
 
-        let phys_target = match target {
-            RouterTargetInternal::InternetGateway => self.boundary_services,
+        // Read the generation before the lookup: should the mapping
+        // change after this point, the check attached to the
+        // transform will see a newer generation and look again.
+        let gen = self.v2p.gen();
+        let (phys_target, check) = match target {
+            RouterTargetInternal::InternetGateway => {
+                // Likewise, read the generation of the endpoint state
+                // before choosing an endpoint.
+                let bsvc_gen = self.bsvc.gen();
+                match self.select_bsvc(flow_id) {
+                    Some(bs) => {
+                        let check = BsvcCheck::new(&self.bsvc, bsvc_gen, bs.ip);
+                        (bs, Some(Arc::new(check) as Arc<dyn HtCheck>))
+                    }
+
+                    // Port creation requires at least one endpoint.
+                    None => {
+                        return Err(GenHtError::Unexpected {
+                            msg: format!("no Boundary Services endpoint"),
+                        });
+                    }
+                }
+            }
 
             RouterTargetInternal::Ip(virt_ip) => match self.v2p.get(&virt_ip) {
-                Some(phys) => PhysNet {
-                    ether: phys.ether.into(),
-                    ip: phys.ip,
-                    vni: self.vni,
-                },
+                Some(phys) => (
+                    PhysNet {
+                        ether: phys.ether.into(),
+                        ip: phys.ip,
+                        vni: self.vni,
+                    },
+                    Some(
+                        Arc::new(V2pCheck::new(&self.v2p, gen, virt_ip, phys))
+                            as Arc<dyn HtCheck>,
+                    ),
+                ),
 
                 // The router target has specified a VPC IP we do not
                 // currently know about; this could be for two
@@ -268,19 +410,31 @@ impl StaticAction for EncapAction {
                 //
                 // We cannot differentiate these cases from the point
                 // of view of this code without more information from
-                // the control plane; rather we drop the packet. If we
-                // are dealing with scenario (2), the control plane
-                // should eventually provide us with a mapping.
-                None => return Ok(AllowOrDeny::Deny),
+                // the control plane; rather we drop the packet and
+                // report the miss to the resolver. If we are dealing
+                // with scenario (2), the resolver answers with a
+                // mapping.
+                None => {
+                    self.misses.miss(self.vni, virt_ip);
+                    return Ok(AllowOrDeny::Deny);
+                }
             },
 
             RouterTargetInternal::VpcSubnet(_) => {
                 match self.v2p.get(&flow_id.dst_ip) {
-                    Some(phys) => PhysNet {
-                        ether: phys.ether.into(),
-                        ip: phys.ip,
-                        vni: self.vni,
-                    },
+                    Some(phys) => (
+                        PhysNet {
+                            ether: phys.ether.into(),
+                            ip: phys.ip,
+                            vni: self.vni,
+                        },
+                        Some(Arc::new(V2pCheck::new(
+                            &self.v2p,
+                            gen,
+                            flow_id.dst_ip,
+                            phys,
+                        )) as Arc<dyn HtCheck>),
+                    ),
 
                     // The guest is attempting to contact a VPC IP we
                     // do not currently know about; this could be for
@@ -295,59 +449,20 @@ impl StaticAction for EncapAction {
                     // We cannot differentiate these cases from the
                     // point of view of this code without more
                     // information from the control plane; rather we
-                    // drop the packet. If we are dealing with
-                    // scenario (2), the control plane should
-                    // eventually provide us with a mapping.
-                    None => return Ok(AllowOrDeny::Deny),
+                    // drop the packet and report the miss to the
+                    // resolver. If we are dealing with scenario (2),
+                    // the resolver answers with a mapping.
+                    None => {
+                        self.misses.miss(self.vni, flow_id.dst_ip);
+                        return Ok(AllowOrDeny::Deny);
+                    }
                 }
             }
         };
 
-        Ok(AllowOrDeny::Allow(HdrTransform {
-            name: ENCAP_NAME.to_string(),
-            // We leave the outer src/dst up to the driver.
-            outer_ether: HeaderAction::Push(
-                EtherMeta {
-                    src: MacAddr::ZERO,
-                    dst: MacAddr::ZERO,
-                    ether_type: EtherType::Ipv6,
-                },
-                PhantomData,
-            ),
-            outer_ip: HeaderAction::Push(
-                IpPush::from(Ipv6Push {
-                    src: self.phys_ip_src,
-                    dst: phys_target.ip.into(),
-                    proto: Protocol::UDP,
-                }),
-                PhantomData,
-            ),
-            // XXX Geneve uses the UDP source port as a flow label
-            // value for the purposes of ECMP -- a hash of the
-            // 5-tuple. However, when using Geneve in IPv6 one could
-            // also choose to use the IPv6 Flow Label field, which has
-            // 4 more bits of entropy and could be argued to be more
-            // fit for this purpose. As we know that our physical
-            // network is always IPv6, perhaps we should just use
-            // that? For now I defer the choice and leave this
-            // hard-coded.
-            outer_encap: HeaderAction::Push(
-                EncapPush::from(GenevePush {
-                    vni: phys_target.vni.into(),
-                    entropy: 7777,
-                    ..Default::default()
-                }),
-                PhantomData,
-            ),
-            inner_ether: HeaderAction::Modify(
-                EtherMod {
-                    dst: Some(phys_target.ether.into()),
-                    ..Default::default()
-                },
-                PhantomData,
-            ),
-            ..Default::default()
-        }))
+        let mut ht = encap_ht(self.phys_ip_src, phys_target);
+        ht.check = check;
+        Ok(AllowOrDeny::Allow(ht))
     }
 
     fn implicit_preds(&self) -> (Vec<Predicate>, Vec<DataPredicate>) {
@@ -355,13 +470,117 @@ impl StaticAction for EncapAction {
     }
 }
 
-pub struct DecapAction {}
+/// Check that the mapping an [`EncapAction`] transform was generated
+/// from is unchanged.
+///
+/// Any change to the [`Virt2Phys`] moves its generation forward. When
+/// the check sees a newer generation it looks the mapping up again:
+/// if the mapping is the same, the transform is still valid and the
+/// newer generation is remembered; otherwise the flow must be
+/// re-resolved. A change to one mapping thus only invalidates the
+/// flows which used it, such as those to an instance which has
+/// migrated to another sled.
+struct V2pCheck {
+    v2p: Arc<Virt2Phys>,
+    seen: AtomicU64,
+    vip: IpAddr,
+    phys: GuestPhysAddr,
+}
+
+impl V2pCheck {
+    fn new(
+        v2p: &Arc<Virt2Phys>,
+        gen: u64,
+        vip: IpAddr,
+        phys: GuestPhysAddr,
+    ) -> Self {
+        Self { v2p: v2p.clone(), seen: AtomicU64::new(gen), vip, phys }
+    }
+}
+
+impl fmt::Debug for V2pCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("V2pCheck")
+            .field("seen", &self.seen)
+            .field("vip", &self.vip)
+            .field("phys", &self.phys)
+            .finish()
+    }
+}
+
+impl HtCheck for V2pCheck {
+    fn is_current(&self) -> bool {
+        let gen = self.v2p.gen();
+        if gen == self.seen.load(SeqCst) {
+            return true;
+        }
+
+        match self.v2p.get(&self.vip) {
+            Some(phys) if phys == self.phys => {
+                self.seen.store(gen, SeqCst);
+                true
+            }
+
+            _ => false,
+        }
+    }
+}
+
+/// Check that the Boundary Services endpoint an [`EncapAction`]
+/// transform was generated for is not marked down.
+///
+/// Like [`V2pCheck`], this only looks at the endpoint's state when the
+/// generation of the [`BsvcState`] has moved forward. Only a flow
+/// whose own endpoint is down is re-resolved: a flow moved off of an
+/// endpoint is not moved back when the endpoint comes back up.
+struct BsvcCheck {
+    bsvc: Arc<BsvcState>,
+    seen: AtomicU64,
+    ip: Ipv6Addr,
+}
+
+impl BsvcCheck {
+    fn new(bsvc: &Arc<BsvcState>, gen: u64, ip: Ipv6Addr) -> Self {
+        Self { bsvc: bsvc.clone(), seen: AtomicU64::new(gen), ip }
+    }
+}
+
+impl fmt::Debug for BsvcCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BsvcCheck")
+            .field("seen", &self.seen)
+            .field("ip", &self.ip)
+            .finish()
+    }
+}
+
+impl HtCheck for BsvcCheck {
+    fn is_current(&self) -> bool {
+        let gen = self.bsvc.gen();
+        if gen == self.seen.load(SeqCst) {
+            return true;
+        }
+
+        if self.bsvc.is_up(&self.ip) {
+            self.seen.store(gen, SeqCst);
+            return true;
+        }
+
+        false
+    }
+}
+
+pub struct DecapAction {
+    // The address sets of the port's VPC, used to tag the source of
+    // inbound flows for the firewall.
+    addr_sets: Arc<AddrSets>,
+}
 
 /// A [`StaticAction`] representing the act of decapsulating a packet
 /// for the purpose of implementing an overlay network.
 impl DecapAction {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(addr_sets: Arc<AddrSets>) -> Self {
+        Self { addr_sets }
     }
 }
 
@@ -378,7 +597,7 @@ impl StaticAction for DecapAction {
         &self,
         // The decap action is only used for inbound.
         _dir: Direction,
-        _flow_id: &InnerFlowId,
+        flow_id: &InnerFlowId,
         pkt_meta: &PacketMeta,
         action_meta: &mut ActionMeta,
     ) -> GenHtResult {
@@ -388,6 +607,7 @@ impl StaticAction for DecapAction {
                     ACTION_META_VNI.to_string(),
                     geneve.vni.to_string(),
                 );
+                self.addr_sets.tag(&flow_id.src_ip, action_meta);
             }
 
             // This should be impossible. Non-encapsulated traffic
@@ -415,8 +635,217 @@ impl StaticAction for DecapAction {
     }
 }
 
+/// The maximum number of misses queued for the resolver.
+pub const V2P_MISS_QUEUE_MAX: usize = 256;
+
+/// The maximum number of outstanding misses, queued or read by the
+/// resolver but not yet answered.
+pub const V2P_MISS_OUTSTANDING_MAX: usize = 4 * V2P_MISS_QUEUE_MAX;
+
+/// The number of milliseconds an unanswered miss is outstanding,
+/// before a lookup of the same VPC IP queues it again.
+pub const V2P_MISS_RETRY_MILLIS: u64 = 1_000;
+
+/// The misses of the [`Virt2Phys`] lookups, queued for the resolver.
+///
+/// A miss is queued only once while it's outstanding: until the
+/// resolver sets a mapping for the VPC IP, or
+/// [`V2P_MISS_RETRY_MILLIS`] pass, further lookups of the same IP
+/// don't add to the queue. This keeps a guest sending to an IP
+/// without a mapping from flooding the resolver, while still retrying
+/// should the resolver have missed it. When the queue is full, new
+/// misses are counted and dropped; the next lookup after the queue
+/// drains reports them again.
+pub struct V2pMisses {
+    state: KMutex<MissState>,
+    cv: KCondvar,
+}
+
+struct MissState {
+    queue: VecDeque<V2pMiss>,
+    // The outstanding misses, with when and in what order each was
+    // last queued.
+    outstanding: BTreeMap<V2pMiss, (Moment, u64)>,
+    // The outstanding misses, oldest first. An entry whose order no
+    // longer matches the one in `outstanding` was since resolved or
+    // queued again, and is skipped.
+    order: VecDeque<(V2pMiss, u64)>,
+    next_order: u64,
+    dropped: u64,
+}
+
+impl MissState {
+    // Forget the oldest misses the resolver never answered, e.g.
+    // those of IPs which don't exist in the VPC, while the outstanding
+    // misses are at their limit. Each queued miss is popped from
+    // `order` only once, so this is O(1) amortized.
+    fn expire(&mut self, now: Moment) {
+        while let Some(&(miss, order)) = self.order.front() {
+            let queued = match self.outstanding.get(&miss) {
+                Some((queued, o)) if *o == order => *queued,
+
+                // Resolved or queued again since.
+                _ => {
+                    self.order.pop_front();
+                    continue;
+                }
+            };
+
+            // Keep the oldest outstanding miss, unless there is no room
+            // for another and it went unanswered, or `order` has grown
+            // long with skipped entries behind it.
+            let full = self.outstanding.len() >= V2P_MISS_OUTSTANDING_MAX;
+            let stale = now.delta_as_millis(queued) >= V2P_MISS_RETRY_MILLIS;
+            if !(full && stale)
+                && self.order.len() <= 2 * V2P_MISS_OUTSTANDING_MAX
+            {
+                break;
+            }
+
+            self.outstanding.remove(&miss);
+            self.order.pop_front();
+        }
+    }
+}
+
+impl V2pMisses {
+    /// Report a miss of `vip` in `vni`, waking any resolver waiting
+    /// on the queue.
+    pub fn miss(&self, vni: Vni, vip: IpAddr) {
+        let miss = V2pMiss { vni, vip };
+        let now = Moment::now();
+        let mut state = self.state.lock();
+
+        if let Some((queued, _)) = state.outstanding.get(&miss) {
+            if now.delta_as_millis(*queued) < V2P_MISS_RETRY_MILLIS {
+                return;
+            }
+        }
+
+        state.expire(now);
+
+        if state.queue.len() >= V2P_MISS_QUEUE_MAX
+            || state.outstanding.len() >= V2P_MISS_OUTSTANDING_MAX
+        {
+            state.dropped += 1;
+            return;
+        }
+
+        let order = state.next_order;
+        state.next_order += 1;
+        state.outstanding.insert(miss, (now, order));
+        state.order.push_back((miss, order));
+        state.queue.push_back(miss);
+        self.cv.broadcast();
+    }
+
+    pub fn new() -> Self {
+        Self {
+            state: KMutex::new(
+                MissState {
+                    queue: VecDeque::new(),
+                    outstanding: BTreeMap::new(),
+                    order: VecDeque::new(),
+                    next_order: 0,
+                    dropped: 0,
+                },
+                KMutexType::Driver,
+            ),
+            cv: KCondvar::new(),
+        }
+    }
+
+    /// Take all queued misses.
+    ///
+    /// If `block` is set, wait for a miss to be queued when there is
+    /// none. A wait interrupted by a signal returns `EINTR`.
+    pub fn read(&self, block: bool) -> Result<ReadV2pMissesResp, OpteError> {
+        let mut state = self.state.lock();
+
+        while block && state.queue.is_empty() {
+            state = match self.cv.wait_sig(state) {
+                Some(state) => state,
+                None => {
+                    return Err(OpteError::System {
+                        errno: EINTR,
+                        msg: "interrupted waiting for V2P misses".to_string(),
+                    });
+                }
+            };
+        }
+
+        Ok(ReadV2pMissesResp {
+            misses: state.queue.drain(..).collect(),
+            dropped: state.dropped,
+        })
+    }
+
+    /// Forget the outstanding miss of `vip` in `vni`, if any, as it
+    /// now has a mapping.
+    pub fn resolved(&self, vni: Vni, vip: IpAddr) {
+        self.state.lock().outstanding.remove(&V2pMiss { vni, vip });
+    }
+}
+
+/// The state of the Boundary Services endpoints, shared by all
+/// ports.
+///
+/// An endpoint is up unless marked down, such as when its switch has
+/// failed. Every change moves the generation forward, which the
+/// [`EncapAction`] transforms use to notice that their endpoint may
+/// have gone down.
+pub struct BsvcState {
+    gen: AtomicU64,
+    down: KMutex<BTreeSet<Ipv6Addr>>,
+}
+
+impl BsvcState {
+    pub fn dump(&self) -> DumpBsvcStateResp {
+        let down = self.down.lock();
+        DumpBsvcStateResp {
+            gen: self.gen(),
+            down: down.iter().copied().collect(),
+        }
+    }
+
+    /// Return the current generation of the endpoint state.
+    pub fn gen(&self) -> u64 {
+        self.gen.load(SeqCst)
+    }
+
+    /// Is the endpoint `ip` up?
+    pub fn is_up(&self, ip: &Ipv6Addr) -> bool {
+        !self.down.lock().contains(ip)
+    }
+
+    pub fn new() -> Self {
+        Self {
+            gen: AtomicU64::new(0),
+            down: KMutex::new(BTreeSet::new(), KMutexType::Driver),
+        }
+    }
+
+    /// Mark the endpoint `ip` as up or down. The generation only
+    /// moves forward if the state of the endpoint changes.
+    pub fn set(&self, ip: Ipv6Addr, up: bool) {
+        let mut down = self.down.lock();
+        let changed = match up {
+            true => down.remove(&ip),
+            false => down.insert(ip),
+        };
+
+        if changed {
+            self.gen.fetch_add(1, SeqCst);
+        }
+    }
+}
+
 pub struct VpcMappings {
     inner: KMutex<BTreeMap<Vni, Arc<Virt2Phys>>>,
+    addr_sets: KMutex<BTreeMap<Vni, Arc<AddrSets>>>,
+    misses: Arc<V2pMisses>,
+    bsvc: Arc<BsvcState>,
+    nat_ports: Arc<SharedNatPorts>,
 }
 
 impl VpcMappings {
@@ -426,33 +855,32 @@ impl VpcMappings {
         // We convert to GuestPhysAddr because it saves us from
         // redundant storage of the VNI.
         let guest_phys = GuestPhysAddr::from(phys);
-        let mut lock = self.inner.lock();
-
-        match lock.get(&phys.vni) {
-            Some(v2p) => {
-                v2p.set(vip, guest_phys);
-                v2p.clone()
-            }
 
-            None => {
-                let v2p = Arc::new(Virt2Phys::new());
-                v2p.set(vip, guest_phys);
-                lock.insert(phys.vni, v2p.clone());
-                v2p
-            }
-        }
+        // Only hold the lock of all VPCs to find the VPC's mappings;
+        // setting the mapping may merge them, and that should not
+        // hold up the other VPCs.
+        let v2p = self
+            .inner
+            .lock()
+            .entry(phys.vni)
+            .or_insert_with(|| Arc::new(Virt2Phys::new()))
+            .clone();
+        v2p.set(vip, guest_phys);
+        self.misses.resolved(phys.vni, vip);
+        v2p
     }
 
     /// Delete the mapping for the given VIP in the given VNI.
     ///
     /// Return the existing entry, if there is one.
-    pub fn del(&self, vip: &IpAddr, phys: &PhysNet) -> Option<PhysNet> {
-        match self.inner.lock().get(&phys.vni) {
+    pub fn del(&self, vip: &IpAddr, vni: Vni) -> Option<PhysNet> {
+        let v2p = self.inner.lock().get(&vni).cloned();
+        match v2p {
             Some(v2p) => match v2p.remove(vip) {
                 Some(guest_phys) => Some(PhysNet {
                     ether: guest_phys.ether,
                     ip: guest_phys.ip,
-                    vni: phys.vni,
+                    vni,
                 }),
 
                 None => None,
@@ -462,17 +890,103 @@ impl VpcMappings {
         }
     }
 
-    /// Iterate all VPC mappings and produce a [`DumpVirt2PhysResp`].
-    pub fn dump(&self) -> DumpVirt2PhysResp {
+    /// Replace all mappings of the given VNI with `mappings`, as of
+    /// the control plane's generation `gen`.
+    ///
+    /// Return [`OpteError::StaleGeneration`] if a newer generation has
+    /// already been applied; see [`SyncVirt2PhysReq`].
+    ///
+    /// [`SyncVirt2PhysReq`]: crate::api::SyncVirt2PhysReq
+    pub fn sync(
+        &self,
+        vni: Vni,
+        gen: u64,
+        mappings: &[(IpAddr, GuestPhysAddr)],
+    ) -> Result<Arc<Virt2Phys>, OpteError> {
+        // Hold the lock for the whole sync, so that two syncs for the
+        // same VNI can't interleave their generation check and
+        // replacement.
+        let mut lock = self.inner.lock();
+        let v2p = lock.entry(vni).or_insert_with(|| Arc::new(Virt2Phys::new()));
+        let mut sync_gen = v2p.sync_gen.lock();
+
+        if gen < *sync_gen {
+            return Err(OpteError::StaleGeneration {
+                current: *sync_gen,
+                given: gen,
+            });
+        }
+
+        v2p.replace(mappings);
+        *sync_gen = gen;
+
+        for (vip, _) in mappings {
+            self.misses.resolved(vni, *vip);
+        }
+
+        Ok(v2p.clone())
+    }
+
+    /// Return the queue of V2P misses shared by all VPCs.
+    pub fn misses(&self) -> Arc<V2pMisses> {
+        self.misses.clone()
+    }
+
+    /// Return the state of the Boundary Services endpoints, shared by
+    /// all VPCs.
+    pub fn bsvc(&self) -> Arc<BsvcState> {
+        self.bsvc.clone()
+    }
+
+    /// Return the SNAT and NAT64 ports in use, shared by all VPCs.
+    pub fn nat_ports(&self) -> Arc<SharedNatPorts> {
+        self.nat_ports.clone()
+    }
+
+    /// Return the address sets of the given VNI, if any were ever
+    /// created.
+    pub fn find_addr_sets(&self, vni: Vni) -> Option<Arc<AddrSets>> {
+        self.addr_sets.lock().get(&vni).cloned()
+    }
+
+    /// Return the address sets of the given VNI, creating them if
+    /// this is the first time they are asked for.
+    pub fn addr_sets(&self, vni: Vni) -> Arc<AddrSets> {
+        self.addr_sets
+            .lock()
+            .entry(vni)
+            .or_insert_with(|| Arc::new(AddrSets::new()))
+            .clone()
+    }
+
+    /// Iterate all VPC address sets and produce a
+    /// [`DumpAddrSetsResp`].
+    pub fn dump_addr_sets(&self) -> DumpAddrSetsResp {
+        let mut sets = Vec::new();
+
+        for (vni, vpc_sets) in self.addr_sets.lock().iter() {
+            sets.extend(vpc_sets.dump(*vni));
+        }
+
+        DumpAddrSetsResp { sets }
+    }
+
+    /// Produce a page of the VPC mappings selected by `req`, see
+    /// [`DumpVirt2PhysReq`].
+    pub fn dump(&self, req: &DumpVirt2PhysReq) -> DumpVirt2PhysResp {
+        let limit = match req.limit {
+            0 => V2P_DUMP_MAX,
+            limit => core::cmp::min(limit as usize, V2P_DUMP_MAX),
+        };
         let mut mappings = Vec::new();
         let lock = self.inner.lock();
 
         for (vni, v2p) in lock.iter() {
-            mappings.push(VpcMapResp {
-                vni: *vni,
-                ip4: v2p.dump_ip4(),
-                ip6: v2p.dump_ip6(),
-            });
+            if req.vni.map_or(false, |want| want != *vni) {
+                continue;
+            }
+
+            mappings.push(v2p.dump(*vni, req.after, limit));
         }
 
         DumpVirt2PhysResp { mappings }
@@ -495,51 +1009,177 @@ impl VpcMappings {
     }
 
     pub fn new() -> Self {
-        VpcMappings { inner: KMutex::new(BTreeMap::new(), KMutexType::Driver) }
+        VpcMappings {
+            inner: KMutex::new(BTreeMap::new(), KMutexType::Driver),
+            addr_sets: KMutex::new(BTreeMap::new(), KMutexType::Driver),
+            misses: Arc::new(V2pMisses::new()),
+            bsvc: Arc::new(BsvcState::new()),
+            nat_ports: Arc::new(SharedNatPorts::new()),
+        }
     }
 }
 
 /// A mapping from virtual IPs to physical location.
+///
+/// The mappings are kept in a [`V2pTable`] per IP version, behind a
+/// reader-writer lock: lookups only ever contend with the short
+/// writes to a table's delta, and with the swap of a merged table.
+/// The merge itself is done under the read lock, while the writers
+/// are held off by a separate lock.
+///
+/// Every write to the mappings moves the generation forward. The
+/// transforms generated from a mapping carry a check which uses the
+/// generation to notice that the mapping has changed, and that the
+/// flow must be re-resolved.
 pub struct Virt2Phys {
-    // XXX We need to implement some sort of invalidation mechanism
-    // for when a mapping is modified. The easiest way to do this is
-    // to bump the port's epoch whenever a write is made to the table,
-    // but that forces all ports to recompute all flows; it's the
-    // largest hammer we have in the toolbox. A better solution is to
-    // finally implement the "simulation" feature that VFP talks
-    // about; which they use both for flow pairing and for
-    // invalidation (they call it reconciliation).
-    //
-    // https://github.com/oxidecomputer/opte/issues/221
-    ip4: KMutex<BTreeMap<Ipv4Addr, GuestPhysAddr>>,
-    ip6: KMutex<BTreeMap<Ipv6Addr, GuestPhysAddr>>,
+    gen: AtomicU64,
+    ip4: KRwLock<V2pTable<Ipv4Addr>>,
+    ip6: KRwLock<V2pTable<Ipv6Addr>>,
+
+    // Serializes the writers, so that a table can't change between
+    // its merge and the swap of the merged table.
+    writer: KMutex<()>,
+
+    // The control plane generation of the last sync, see
+    // [`VpcMappings::sync()`].
+    sync_gen: KMutex<u64>,
 }
 
 pub const VIRT_2_PHYS_NAME: &'static str = "Virt2Phys";
 
 impl Virt2Phys {
-    pub fn dump_ip4(&self) -> Vec<(Ipv4Addr, GuestPhysAddr)> {
-        let mut ip4 = Vec::new();
-        for (vip, gaddr) in self.ip4.lock().iter() {
-            ip4.push((*vip, *gaddr));
+    /// Produce a page of at most `limit` mappings, starting after the
+    /// VPC IP `after`, as part of the [`DumpVirt2PhysResp`] for `vni`.
+    ///
+    /// The pages are in order of VPC IP, with the IPv4 mappings
+    /// coming before the IPv6 ones.
+    pub fn dump(
+        &self,
+        vni: Vni,
+        after: Option<IpAddr>,
+        limit: usize,
+    ) -> VpcMapResp {
+        let (skip_ip4, after4, after6) = match after {
+            None => (false, None, None),
+            Some(IpAddr::Ip4(ip4)) => (false, Some(ip4), None),
+            Some(IpAddr::Ip6(ip6)) => (true, None, Some(ip6)),
+        };
+
+        let mut next = None;
+        let (ip4, more) = match skip_ip4 {
+            false => self.ip4.read().page(after4, limit),
+            true => (Vec::new(), false),
+        };
+
+        let ip6 = match more {
+            false => {
+                let (ip6, more) =
+                    self.ip6.read().page(after6, limit - ip4.len());
+                if more {
+                    // The page may be full with IPv4 mappings alone.
+                    next = match ip6.last() {
+                        Some((ip6, _)) => Some(IpAddr::Ip6(*ip6)),
+                        None => ip4.last().map(|(ip4, _)| IpAddr::Ip4(*ip4)),
+                    };
+                }
+                ip6
+            }
+
+            true => {
+                next = ip4.last().map(|(ip4, _)| IpAddr::Ip4(*ip4));
+                Vec::new()
+            }
+        };
+
+        VpcMapResp {
+            vni,
+            gen: *self.sync_gen.lock(),
+            len: self.len() as u64,
+            mem_bytes: self.mem_bytes() as u64,
+            ip4,
+            ip6,
+            next,
         }
-        ip4
     }
 
-    pub fn dump_ip6(&self) -> Vec<(Ipv6Addr, GuestPhysAddr)> {
-        let mut ip6 = Vec::new();
-        for (vip, gaddr) in self.ip6.lock().iter() {
-            ip6.push((*vip, *gaddr));
-        }
-        ip6
+    /// Return the number of mappings.
+    pub fn len(&self) -> usize {
+        self.ip4.read().len() + self.ip6.read().len()
+    }
+
+    /// Return the number of bytes used to store the mappings, see
+    /// [`V2pTable::mem_bytes()`].
+    pub fn mem_bytes(&self) -> usize {
+        self.ip4.read().mem_bytes() + self.ip6.read().mem_bytes()
     }
 
     pub fn new() -> Self {
+        let mut ip4 = KRwLock::new(V2pTable::new());
+        ip4.init(KRwLockType::Driver);
+        let mut ip6 = KRwLock::new(V2pTable::new());
+        ip6.init(KRwLockType::Driver);
+
         Virt2Phys {
-            ip4: KMutex::new(BTreeMap::new(), KMutexType::Driver),
-            ip6: KMutex::new(BTreeMap::new(), KMutexType::Driver),
+            gen: AtomicU64::new(0),
+            ip4,
+            ip6,
+            writer: KMutex::new((), KMutexType::Driver),
+            sync_gen: KMutex::new(0, KMutexType::Driver),
         }
     }
+
+    /// Replace all mappings with `mappings`.
+    ///
+    /// The new tables are built before being swapped in, so that a
+    /// mapping present both before and after the replacement is never
+    /// missing from a lookup.
+    fn replace(&self, mappings: &[(IpAddr, GuestPhysAddr)]) {
+        let _writer = self.writer.lock();
+        let ip4 = V2pTable::from_mappings(mappings.iter().filter_map(
+            |(vip, phys)| match vip {
+                IpAddr::Ip4(vip4) => Some((*vip4, *phys)),
+                IpAddr::Ip6(_) => None,
+            },
+        ));
+        let ip6 = V2pTable::from_mappings(mappings.iter().filter_map(
+            |(vip, phys)| match vip {
+                IpAddr::Ip4(_) => None,
+                IpAddr::Ip6(vip6) => Some((*vip6, *phys)),
+            },
+        ));
+
+        // The old tables are dropped once the write locks are
+        // released.
+        let _old4 = core::mem::replace(&mut *self.ip4.write(), ip4);
+        let _old6 = core::mem::replace(&mut *self.ip6.write(), ip6);
+        self.gen.fetch_add(1, SeqCst);
+    }
+
+    /// Return the current generation of the mappings.
+    pub fn gen(&self) -> u64 {
+        self.gen.load(SeqCst)
+    }
+
+    // Apply the write `f` to `table`, merging the table's delta if it
+    // has grown too large. The caller must hold the `writer` lock.
+    fn update<K, F, R>(table: &KRwLock<V2pTable<K>>, f: F) -> R
+    where
+        K: Copy + Ord,
+        F: FnOnce(&mut V2pTable<K>) -> R,
+    {
+        let ret = f(&mut *table.write());
+
+        let merged = {
+            let lock = table.read();
+            if !lock.needs_merge() {
+                return ret;
+            }
+            lock.merged()
+        };
+
+        let _old = core::mem::replace(&mut *table.write(), merged);
+        ret
+    }
 }
 
 impl Resource for Virt2Phys {}
@@ -551,37 +1191,71 @@ impl MappingResource for Virt2Phys {
 
     fn get(&self, vip: &Self::Key) -> Option<Self::Entry> {
         match vip {
-            IpAddr::Ip4(ip4) => self.ip4.lock().get(ip4).cloned(),
-            IpAddr::Ip6(ip6) => self.ip6.lock().get(ip6).cloned(),
+            IpAddr::Ip4(ip4) => self.ip4.read().get(ip4),
+            IpAddr::Ip6(ip6) => self.ip6.read().get(ip6),
         }
     }
 
     fn remove(&self, vip: &Self::Key) -> Option<Self::Entry> {
-        match vip {
-            IpAddr::Ip4(ip4) => self.ip4.lock().remove(ip4),
-            IpAddr::Ip6(ip6) => self.ip6.lock().remove(ip6),
-        }
+        let _writer = self.writer.lock();
+        let old = match vip {
+            IpAddr::Ip4(ip4) => Self::update(&self.ip4, |t| t.remove(ip4)),
+            IpAddr::Ip6(ip6) => Self::update(&self.ip6, |t| t.remove(ip6)),
+        };
+        self.gen.fetch_add(1, SeqCst);
+        old
     }
 
     fn set(&self, vip: Self::Key, phys: GuestPhysAddr) -> Option<Self::Entry> {
-        match vip {
-            IpAddr::Ip4(ip4) => self.ip4.lock().insert(ip4, phys),
-            IpAddr::Ip6(ip6) => self.ip6.lock().insert(ip6, phys),
-        }
+        let _writer = self.writer.lock();
+        let old = match vip {
+            IpAddr::Ip4(ip4) => Self::update(&self.ip4, |t| t.set(ip4, phys)),
+            IpAddr::Ip6(ip6) => Self::update(&self.ip6, |t| t.set(ip6, phys)),
+        };
+        self.gen.fetch_add(1, SeqCst);
+        old
     }
 }
 
-#[repr(C)]
-#[derive(Debug, Deserialize, Serialize)]
+/// The maximum number of mappings of a VPC in one page of a
+/// [`DumpVirt2PhysResp`].
+pub const V2P_DUMP_MAX: usize = 8192;
+
+/// Dump the V2P mappings, a page at a time.
+///
+/// Each VPC in the response carries at most `limit` mappings, and the
+/// `next` VPC IP to resume from should more remain. A `limit` of zero,
+/// as well as any larger than [`V2P_DUMP_MAX`], is taken to mean
+/// [`V2P_DUMP_MAX`].
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct DumpVirt2PhysReq {
-    pub unused: u64,
+    /// Dump only the VPC of this VNI, rather than all of them.
+    pub vni: Option<Vni>,
+
+    /// Resume the dump after this VPC IP, as given by the `next` of
+    /// the previous page. This applies to every VPC dumped, and is
+    /// thus meant to be given along with `vni`.
+    pub after: Option<IpAddr>,
+
+    pub limit: u32,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct VpcMapResp {
     pub vni: Vni,
+    pub gen: u64,
+
+    /// The total number of mappings of the VPC.
+    pub len: u64,
+
+    /// The number of bytes used to store the mappings of the VPC.
+    pub mem_bytes: u64,
+
     pub ip4: Vec<(Ipv4Addr, GuestPhysAddr)>,
     pub ip6: Vec<(Ipv6Addr, GuestPhysAddr)>,
+
+    /// The VPC IP to resume the dump after, if more mappings remain.
+    pub next: Option<IpAddr>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -9,12 +9,111 @@
 //! This is mostly just a place to hang printing routines so that they
 //! can be used by both opteadm and integration tests.
 
+use crate::api::DumpAddrSetsResp;
+use crate::api::DumpBsvcStateResp;
 use crate::api::GuestPhysAddr;
 use crate::api::Ipv4Addr;
 use crate::api::Ipv6Addr;
+use crate::api::ListPortForwardsResp;
+use crate::api::ListRouterEntriesResp;
+use crate::api::ReadV2pMissesResp;
+use crate::engine::nat::DumpSnatResp;
 use crate::engine::overlay::DumpVirt2PhysResp;
 use opte::engine::print::*;
 
+/// Print a [`DumpAddrSetsResp`].
+pub fn print_addr_sets(resp: &DumpAddrSetsResp) {
+    println!("{:<10} {:<24} {}", "VNI", "NAME", "ADDRESSES");
+    print_hr();
+    for set in &resp.sets {
+        let addrs: Vec<String> =
+            set.addrs.iter().map(|a| a.to_string()).collect();
+        println!("{:<10} {:<24} {}", set.vni, set.name, addrs.join(","));
+    }
+}
+
+/// Print a [`ListRouterEntriesResp`].
+pub fn print_router_entries(resp: &ListRouterEntriesResp) {
+    println!(
+        "{:<43} {:<8} {:<40} {}",
+        "DESTINATION", "PRIORITY", "FILTERS", "TARGET"
+    );
+    print_hr();
+    for entry in &resp.entries {
+        let priority = match entry.priority {
+            Some(p) => p.to_string(),
+            None => "LPM".to_string(),
+        };
+        println!(
+            "{:<43} {:<8} {:<40} {}",
+            entry.dest.to_string(),
+            priority,
+            entry.filters.to_string(),
+            entry.target
+        );
+    }
+}
+
+/// Print a [`ListPortForwardsResp`].
+pub fn print_port_forwards(resp: &ListPortForwardsResp) {
+    println!(
+        "{:<8} {:<40} {:<12} {}",
+        "PROTO", "EXTERNAL IP", "EXT PORTS", "PRIVATE PORTS"
+    );
+    print_hr();
+    for fwd in &resp.forwards {
+        let (start, end) =
+            (*fwd.external_ports.start(), *fwd.external_ports.end());
+        let last = fwd.private_port as u32 + end.saturating_sub(start) as u32;
+        println!(
+            "{:<8} {:<40} {:<12} {}",
+            fwd.proto.to_string(),
+            fwd.external_ip.to_string(),
+            format!("{}-{}", start, end),
+            format!("{}-{}", fwd.private_port, last),
+        );
+    }
+}
+
+/// Print a [`DumpSnatResp`].
+pub fn print_snat(resp: &DumpSnatResp) {
+    println!("SNAT Mappings");
+    print_hrb();
+    for m in &resp.mappings {
+        let max_ports = match m.max_ports {
+            Some(max) => max.to_string(),
+            None => "none".to_string(),
+        };
+
+        println!("");
+        println!(
+            "{} => {}:{}-{} ({}, port limit {})",
+            m.priv_ip,
+            m.external_ip,
+            m.ports.start(),
+            m.ports.end(),
+            m.mapping,
+            max_ports,
+        );
+        println!(
+            "ports used: {}, ports free: {}, reused: {}, \
+             exhausted: {}, limited: {}",
+            m.ports_used.len(),
+            m.ports_free,
+            m.stats.reused,
+            m.stats.exhausted,
+            m.stats.limited,
+        );
+        print_hr();
+        println!("{:<8} {}", "PORT", "FLOW");
+        for (port, flows) in &m.ports_used {
+            for flow in flows {
+                println!("{:<8} {}", port, flow);
+            }
+        }
+    }
+}
+
 /// Print the header for the [`print_v2p()`] output.
 fn print_v2p_header() {
     println!("{:<24} {:<17} {}", "VPC IP", "VPC MAC ADDR", "UNDERLAY IP");
@@ -26,7 +125,10 @@ pub fn print_v2p(resp: &DumpVirt2PhysResp) {
     print_hrb();
     for vpc in &resp.mappings {
         println!("");
-        println!("VPC {}", vpc.vni);
+        println!(
+            "VPC {} (generation {}, {} mappings, {} bytes)",
+            vpc.vni, vpc.gen, vpc.len, vpc.mem_bytes
+        );
         print_hr();
         println!("");
         println!("IPv4 mappings");
@@ -46,6 +148,27 @@ pub fn print_v2p(resp: &DumpVirt2PhysResp) {
     }
 }
 
+/// Print a [`DumpBsvcStateResp`].
+pub fn print_bsvc_state(resp: &DumpBsvcStateResp) {
+    println!("Boundary Services endpoints marked down");
+    println!("generation: {}", resp.gen);
+    print_hr();
+    for ip in &resp.down {
+        println!("{}", ip);
+    }
+}
+
+/// Print a [`ReadV2pMissesResp`].
+pub fn print_v2p_misses(resp: &ReadV2pMissesResp) {
+    println!("{:<10} {}", "VNI", "VPC IP");
+    print_hr();
+    for miss in &resp.misses {
+        println!("{:<10} {}", miss.vni, miss.vip);
+    }
+    println!("");
+    println!("dropped: {}", resp.dropped);
+}
+
 fn print_v2p_ip4((src, phys): &(Ipv4Addr, GuestPhysAddr)) {
     let eth = format!("{}", phys.ether);
     println!(
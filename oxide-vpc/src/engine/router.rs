@@ -8,6 +8,7 @@
 //!
 //! This implements both the Oxide Network VPC "System Router" and
 //! "Custom Router" abstractions, as described in RFD 21 §2.3.
+use core::any::Any;
 use core::fmt;
 
 cfg_if! {
@@ -25,6 +26,10 @@ cfg_if! {
 use super::firewall as fw;
 use super::VpcNetwork;
 use crate::api::DelRouterEntryResp;
+use crate::api::ListRouterEntriesResp;
+use crate::api::Ports;
+use crate::api::RouteFilters;
+use crate::api::RouterEntry;
 use crate::api::RouterTarget;
 use crate::api::VpcCfg;
 use opte::api::Direction;
@@ -46,8 +51,10 @@ use opte::engine::port::Port;
 use opte::engine::port::PortBuilder;
 use opte::engine::port::Pos;
 use opte::engine::predicate::DataPredicate;
+use opte::engine::predicate::IpProtoMatch;
 use opte::engine::predicate::Ipv4AddrMatch;
 use opte::engine::predicate::Ipv6AddrMatch;
+use opte::engine::predicate::PortMatch;
 use opte::engine::predicate::Predicate;
 use opte::engine::rule::Action;
 use opte::engine::rule::AllowOrDeny;
@@ -188,11 +195,12 @@ pub fn setup(
     // make it no further.
     let actions = LayerActions {
         actions: vec![],
+        named: vec![],
         default_in: DefaultAction::Allow,
         default_out: DefaultAction::Deny,
     };
 
-    let layer = Layer::new(ROUTER_LAYER_NAME, pb.name(), actions, ft_limit);
+    let layer = Layer::new(ROUTER_LAYER_NAME, pb.name(), actions, ft_limit)?;
     pb.add_layer(layer, Pos::After(fw::FW_LAYER_NAME))
 }
 
@@ -212,87 +220,268 @@ fn valid_router_dest_target_pair(dest: &IpCidr, target: &RouterTarget) -> bool {
     ) ||
     // Only the default IP addresses are currently allowed to be directed to
     // the gateway
-    (matches!(target, RouterTarget::InternetGateway) && dest.is_default())
+    (matches!(target, RouterTarget::InternetGateway) && dest.is_default()) ||
+    // An ECMP group must have at least one member, and every member
+    // must be of the same IP version as the destination.
+    match target {
+        RouterTarget::Ecmp(members) => {
+            !members.is_empty()
+                && members.iter().all(|ip| {
+                    matches!(
+                        (dest, ip),
+                        (IpCidr::Ip4(_), IpAddr::Ip4(_))
+                            | (IpCidr::Ip6(_), IpAddr::Ip6(_))
+                    )
+                })
+        }
+
+        _ => false,
+    }
 }
 
-fn make_rule(
-    dest: IpCidr,
-    target: RouterTarget,
-) -> Result<Rule<Finalized>, OpteError> {
-    if !valid_router_dest_target_pair(&dest, &target) {
+// A policy entry must be given a priority, as there is no sensible
+// way to order it among the plain entries by prefix length alone, and
+// its source must be of the same IP version as its destination.
+fn valid_router_filters(entry: &RouterEntry) -> bool {
+    if entry.filters.is_empty() {
+        return true;
+    }
+
+    entry.priority.is_some()
+        && match &entry.filters.src {
+            None => true,
+            Some(src) => matches!(
+                (&entry.dest, src),
+                (IpCidr::Ip4(_), IpCidr::Ip4(_))
+                    | (IpCidr::Ip6(_), IpCidr::Ip6(_))
+            ),
+        }
+}
+
+// Put the members of an ECMP group in order and remove duplicates, so
+// that a group is the same entry whatever order its members are given
+// in.
+fn normalize_target(target: RouterTarget) -> RouterTarget {
+    match target {
+        RouterTarget::Ecmp(mut members) => {
+            members.sort();
+            members.dedup();
+            RouterTarget::Ecmp(members)
+        }
+
+        _ => target,
+    }
+}
+
+fn make_rule(entry: RouterEntry) -> Result<Rule<Finalized>, OpteError> {
+    if !valid_router_dest_target_pair(&entry.dest, &entry.target) {
         return Err(OpteError::InvalidRouterEntry {
-            dest,
-            target: target.to_string(),
+            dest: entry.dest,
+            target: entry.target.to_string(),
         });
     }
 
-    let (predicate, action) = match target {
-        RouterTarget::Drop => {
-            let predicate = match dest {
-                IpCidr::Ip4(ip4) => {
-                    Predicate::InnerDstIp4(vec![Ipv4AddrMatch::Prefix(ip4)])
-                }
+    if !valid_router_filters(&entry) {
+        return Err(OpteError::InvalidRouterEntry {
+            dest: entry.dest,
+            target: format!("{} filters={}", entry.target, entry.filters),
+        });
+    }
 
-                IpCidr::Ip6(ip6) => {
-                    Predicate::InnerDstIp6(vec![Ipv6AddrMatch::Prefix(ip6)])
-                }
-            };
-            (predicate, Action::Deny)
+    let RouterEntry { dest, target, filters, priority } = entry;
+    let target = normalize_target(target);
+
+    let mut preds = vec![match dest {
+        IpCidr::Ip4(ip4) => {
+            Predicate::InnerDstIp4(vec![Ipv4AddrMatch::Prefix(ip4)])
         }
 
-        RouterTarget::InternetGateway => {
-            let predicate = match dest {
-                IpCidr::Ip4(ip4) => {
-                    Predicate::InnerDstIp4(vec![Ipv4AddrMatch::Prefix(ip4)])
-                }
+        IpCidr::Ip6(ip6) => {
+            Predicate::InnerDstIp6(vec![Ipv6AddrMatch::Prefix(ip6)])
+        }
+    }];
 
-                IpCidr::Ip6(ip6) => {
-                    Predicate::InnerDstIp6(vec![Ipv6AddrMatch::Prefix(ip6)])
-                }
-            };
-            let action = Action::Meta(Arc::new(RouterAction::new(
-                RouterTargetInternal::InternetGateway,
-            )));
-            (predicate, action)
+    match filters.src {
+        Some(IpCidr::Ip4(ip4)) => {
+            preds.push(Predicate::InnerSrcIp4(vec![Ipv4AddrMatch::Prefix(ip4)]))
         }
 
-        RouterTarget::Ip(ip) => {
-            let predicate = match dest {
-                IpCidr::Ip4(ip4) => {
-                    Predicate::InnerDstIp4(vec![Ipv4AddrMatch::Prefix(ip4)])
-                }
+        Some(IpCidr::Ip6(ip6)) => {
+            preds.push(Predicate::InnerSrcIp6(vec![Ipv6AddrMatch::Prefix(ip6)]))
+        }
 
-                IpCidr::Ip6(ip6) => {
-                    Predicate::InnerDstIp6(vec![Ipv6AddrMatch::Prefix(ip6)])
-                }
-            };
-            let action = Action::Meta(Arc::new(RouterAction::new(
-                RouterTargetInternal::Ip(ip),
-            )));
-            (predicate, action)
+        None => (),
+    }
+
+    if let Some(proto) = filters.protocol {
+        preds.push(Predicate::InnerIpProto(vec![IpProtoMatch::Exact(proto)]));
+    }
+
+    if let Some(pred) = filters.ports.into_predicate() {
+        preds.push(pred);
+    }
+
+    let action = match target {
+        RouterTarget::Drop => Action::Deny,
+        _ => Action::Meta(Arc::new(RouterAction::new(target))),
+    };
+
+    let priority = priority.unwrap_or_else(|| prefix_len_to_priority(&dest));
+    let mut rule = Rule::new(priority, action);
+    rule.add_predicates(preds);
+    Ok(rule.finalize())
+}
+
+fn rule_target(rule: &Rule<Finalized>) -> Option<RouterTarget> {
+    match rule.action() {
+        Action::Deny => Some(RouterTarget::Drop),
+
+        Action::Meta(action) => {
+            let ra = action.as_any()?.downcast_ref::<RouterAction>()?;
+            Some(ra.target.clone())
         }
 
-        RouterTarget::VpcSubnet(vpc) => {
-            let predicate = match dest {
-                IpCidr::Ip4(ip4) => {
-                    Predicate::InnerDstIp4(vec![Ipv4AddrMatch::Prefix(ip4)])
+        _ => None,
+    }
+}
+
+// Recover the ports from the port predicate, in the simplest form
+// which describes them; the same form `Ports::from_str()` produces.
+fn matches_to_ports(list: &[PortMatch]) -> Ports {
+    if list.iter().all(|m| matches!(m, PortMatch::Exact(_))) {
+        return Ports::PortList(
+            list.iter()
+                .filter_map(|m| match m {
+                    PortMatch::Exact(p) => Some(*p),
+                    _ => None,
+                })
+                .collect(),
+        );
+    }
+
+    let ranges: Vec<_> = list
+        .iter()
+        .map(|m| match m {
+            PortMatch::Exact(p) => *p..=*p,
+            PortMatch::Range(r) => r.clone(),
+        })
+        .collect();
+
+    if ranges.len() == 1 {
+        Ports::Range(ranges[0].clone())
+    } else {
+        Ports::RangeList(ranges)
+    }
+}
+
+/// Recover the router entry a rule was made from, see
+/// [`make_rule()`].
+fn rule_to_entry(rule: &Rule<Finalized>) -> Option<RouterEntry> {
+    let mut dest = None;
+    let mut filters = RouteFilters::default();
+
+    for pred in rule.hdr_preds() {
+        match pred {
+            Predicate::InnerDstIp4(list) => match list.as_slice() {
+                [Ipv4AddrMatch::Prefix(ip4)] => dest = Some(IpCidr::Ip4(*ip4)),
+                _ => return None,
+            },
+
+            Predicate::InnerDstIp6(list) => match list.as_slice() {
+                [Ipv6AddrMatch::Prefix(ip6)] => dest = Some(IpCidr::Ip6(*ip6)),
+                _ => return None,
+            },
+
+            Predicate::InnerSrcIp4(list) => match list.as_slice() {
+                [Ipv4AddrMatch::Prefix(ip4)] => {
+                    filters.src = Some(IpCidr::Ip4(*ip4))
                 }
+                _ => return None,
+            },
 
-                IpCidr::Ip6(ip6) => {
-                    Predicate::InnerDstIp6(vec![Ipv6AddrMatch::Prefix(ip6)])
+            Predicate::InnerSrcIp6(list) => match list.as_slice() {
+                [Ipv6AddrMatch::Prefix(ip6)] => {
+                    filters.src = Some(IpCidr::Ip6(*ip6))
                 }
-            };
-            let action = Action::Meta(Arc::new(RouterAction::new(
-                RouterTargetInternal::VpcSubnet(vpc),
-            )));
-            (predicate, action)
+                _ => return None,
+            },
+
+            Predicate::InnerIpProto(list) => match list.as_slice() {
+                [IpProtoMatch::Exact(proto)] => filters.protocol = Some(*proto),
+                _ => return None,
+            },
+
+            Predicate::InnerDstPort(list) => {
+                filters.ports = matches_to_ports(list);
+            }
+
+            _ => return None,
         }
+    }
+
+    let dest = dest?;
+    let target = normalize_target(rule_target(rule)?);
+
+    // A plain entry at the priority derived from its prefix length
+    // is reported without an explicit priority.
+    let priority = if filters.is_empty()
+        && rule.priority() == prefix_len_to_priority(&dest)
+    {
+        None
+    } else {
+        Some(rule.priority())
     };
 
-    let priority = prefix_len_to_priority(&dest);
-    let mut rule = Rule::new(priority, action);
-    rule.add_predicate(predicate);
-    Ok(rule.finalize())
+    Some(RouterEntry { dest, target, filters, priority })
+}
+
+// Fold `bytes` into the FNV-1a hash `hash`.
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01B3);
+    }
+    hash
+}
+
+fn ip_bytes(hash: u64, ip: &IpAddr) -> u64 {
+    match ip {
+        IpAddr::Ip4(ip4) => fnv1a(hash, &ip4.bytes()),
+        IpAddr::Ip6(ip6) => fnv1a(hash, &ip6.bytes()),
+    }
+}
+
+/// Select the next hop for a flow from the members of an ECMP group.
+///
+/// This uses rendezvous (highest random weight) hashing: each member
+/// is scored by a hash of the flow's 5-tuple combined with the
+/// member's address, and the member with the highest score wins. The
+/// choice depends only on the flow and the member set, so it is
+/// stable for the life of the flow; and when a member is removed only
+/// the flows which had chosen it move, spread across the remaining
+/// members. Ties are broken by address so the choice doesn't depend
+/// on the order of the members.
+pub fn ecmp_select(flow: &InnerFlowId, members: &[IpAddr]) -> Option<IpAddr> {
+    let mut hash = 0xCBF2_9CE4_8422_2325;
+    hash = fnv1a(hash, &[u8::from(flow.proto)]);
+    hash = ip_bytes(hash, &flow.src_ip);
+    hash = fnv1a(hash, &flow.src_port.to_be_bytes());
+    hash = ip_bytes(hash, &flow.dst_ip);
+    hash = fnv1a(hash, &flow.dst_port.to_be_bytes());
+
+    members
+        .iter()
+        .map(|ip| {
+            // Finish with the splitmix64 finalizer so that members
+            // differing in a single byte still get well-spread
+            // scores.
+            let mut z = ip_bytes(hash, ip);
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            (z ^ (z >> 31), *ip)
+        })
+        .max()
+        .map(|(_, ip)| ip)
 }
 
 /// Delete a router entry.
@@ -304,8 +493,31 @@ pub fn del_entry(
     dest: IpCidr,
     target: RouterTarget,
 ) -> Result<DelRouterEntryResp, OpteError> {
-    let rule = make_rule(dest, target)?;
-    let maybe_id = port.find_rule(ROUTER_LAYER_NAME, Direction::Out, &rule)?;
+    del_route(port, RouterEntry::new(dest, target))
+}
+
+/// Delete a router entry.
+///
+/// For the entry to be deleted it must match exactly: in its
+/// destination, target, filters, and priority. The members of an ECMP
+/// target may be given in any order.
+pub fn del_route(
+    port: &Port<VpcNetwork>,
+    entry: RouterEntry,
+) -> Result<DelRouterEntryResp, OpteError> {
+    // Make sure the entry is valid, as a router entry.
+    let target = normalize_target(entry.target.clone());
+    let query = make_rule(entry)?;
+    let rules = port.layer_rules(ROUTER_LAYER_NAME, Direction::Out)?;
+    let maybe_id = rules
+        .iter()
+        .find(|(_, rule)| {
+            rule.priority() == query.priority()
+                && rule.hdr_preds() == query.hdr_preds()
+                && rule_target(rule).as_ref() == Some(&target)
+        })
+        .map(|(id, _)| *id);
+
     match maybe_id {
         Some(id) => {
             port.remove_rule(ROUTER_LAYER_NAME, Direction::Out, id)?;
@@ -316,6 +528,16 @@ pub fn del_entry(
     }
 }
 
+/// List the router entries, in order of priority.
+pub fn list_entries(
+    port: &Port<VpcNetwork>,
+) -> Result<ListRouterEntriesResp, OpteError> {
+    let rules = port.layer_rules(ROUTER_LAYER_NAME, Direction::Out)?;
+    let entries =
+        rules.iter().filter_map(|(_, rule)| rule_to_entry(rule)).collect();
+    Ok(ListRouterEntriesResp { entries })
+}
+
 /// Add a router entry.
 ///
 /// Route the [`IpCidr`] to the specified [`RouterTarget`].
@@ -324,7 +546,16 @@ pub fn add_entry(
     dest: IpCidr,
     target: RouterTarget,
 ) -> Result<NoResp, OpteError> {
-    let rule = make_rule(dest, target)?;
+    add_route(port, RouterEntry::new(dest, target))
+}
+
+/// Add a router entry, which may be a policy entry; see
+/// [`RouterEntry`].
+pub fn add_route(
+    port: &Port<VpcNetwork>,
+    entry: RouterEntry,
+) -> Result<NoResp, OpteError> {
+    let rule = make_rule(entry)?;
     port.add_rule(ROUTER_LAYER_NAME, Direction::Out, rule)?;
     Ok(NoResp::default())
 }
@@ -336,7 +567,7 @@ pub fn replace(
 ) -> Result<NoResp, OpteError> {
     let mut out_rules = Vec::with_capacity(entries.len());
     for (cidr, target) in entries {
-        out_rules.push(make_rule(cidr, target)?);
+        out_rules.push(make_rule(RouterEntry::new(cidr, target))?);
     }
 
     port.set_rules(ROUTER_LAYER_NAME, vec![], out_rules)?;
@@ -361,11 +592,11 @@ pub fn replace(
 pub struct RouterAction {
     // system_table: RouterTable,
     // subnet_table: Option<RouterTable>,
-    target: RouterTargetInternal,
+    target: RouterTarget,
 }
 
 impl RouterAction {
-    fn new(target: RouterTargetInternal) -> Self {
+    fn new(target: RouterTarget) -> Self {
         Self { target }
     }
 }
@@ -383,14 +614,39 @@ impl MetaAction for RouterAction {
 
     fn mod_meta(
         &self,
-        _flow_id: &InnerFlowId,
+        flow_id: &InnerFlowId,
         meta: &mut ActionMeta,
     ) -> ModMetaResult {
+        let target = match &self.target {
+            RouterTarget::InternetGateway => {
+                RouterTargetInternal::InternetGateway
+            }
+
+            RouterTarget::Ip(ip) => RouterTargetInternal::Ip(*ip),
+            RouterTarget::VpcSubnet(sub) => {
+                RouterTargetInternal::VpcSubnet(*sub)
+            }
+
+            RouterTarget::Ecmp(members) => {
+                match ecmp_select(flow_id, members) {
+                    Some(ip) => RouterTargetInternal::Ip(ip),
+                    None => return Err("empty ECMP group".to_string()),
+                }
+            }
+
+            // A drop entry is always made into `Action::Deny`.
+            RouterTarget::Drop => return Ok(AllowOrDeny::Deny),
+        };
+
         // No target entry should currently exist in the metadata; it
         // would be a bug. However, because of the dynamic nature of
         // metadata we don't have an easy way to enforce this
         // constraint in the type system.
-        meta.insert(self.target.key(), self.target.as_meta());
+        meta.insert(target.key(), target.as_meta());
         Ok(AllowOrDeny::Allow(()))
     }
+
+    fn as_any(&self) -> Option<&dyn Any> {
+        Some(self)
+    }
 }
@@ -0,0 +1,436 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+// Copyright 2022 Oxide Computer Company
+
+//! A compact store for the Virtual-to-Physical mappings.
+//!
+//! A sled may hold the mappings of VPCs with hundreds of thousands of
+//! instances, and the store is sized for millions of mappings. It
+//! keeps them in a sorted array rather than a tree, and stores the
+//! underlay IP of each mapping as an index into a table of the
+//! distinct underlay IPs: there are far fewer sleds than instances.
+//! Changes are first made to a small sorted delta, which is merged
+//! into the array once it grows past roughly the square root of the
+//! array's length, amortizing the cost of the merge.
+use core::cmp;
+use core::mem::size_of;
+
+cfg_if! {
+    if #[cfg(all(not(feature = "std"), not(test)))] {
+        use alloc::collections::btree_map::BTreeMap;
+        use alloc::vec::Vec;
+    } else {
+        use std::collections::btree_map::BTreeMap;
+        use std::vec::Vec;
+    }
+}
+
+use crate::api::GuestPhysAddr;
+use opte::api::Ipv6Addr;
+use opte::api::MacAddr;
+
+/// The delta of a [`V2pTable`] is merged once it holds more than the
+/// greater of this and the square root of the number of mappings.
+pub const V2P_DELTA_MIN: usize = 64;
+
+// The physical location of a mapping, with the underlay IP stored as
+// an index into `V2pTable::sleds`.
+#[derive(Clone, Copy, Debug)]
+struct Phys {
+    ether: MacAddr,
+    sled: u32,
+}
+
+/// The mappings of one IP version from VPC IP to [`GuestPhysAddr`].
+///
+/// The table is not synchronized: see
+/// [`Virt2Phys`](super::overlay::Virt2Phys), which takes care of
+/// merging the delta without blocking lookups.
+#[derive(Debug)]
+pub struct V2pTable<K> {
+    // The bulk of the mappings, sorted by VPC IP.
+    base: Vec<(K, Phys)>,
+
+    // The mappings set or removed since the last merge, sorted by VPC
+    // IP. A `None` marks the removal of a mapping in `base`.
+    delta: Vec<(K, Option<Phys>)>,
+
+    // The distinct underlay IPs, along with their index. An underlay
+    // IP is only dropped by a merge, once no mapping refers to it.
+    sleds: Vec<Ipv6Addr>,
+    sled_idx: BTreeMap<Ipv6Addr, u32>,
+
+    len: usize,
+}
+
+impl<K: Copy + Ord> V2pTable<K> {
+    /// Build a table from `mappings`, given in any order.
+    ///
+    /// Should a VPC IP appear more than once, its last mapping wins,
+    /// as it would had the mappings been set one by one.
+    pub fn from_mappings<I>(mappings: I) -> Self
+    where
+        I: IntoIterator<Item = (K, GuestPhysAddr)>,
+    {
+        let mut table = Self::new();
+        let mut all = Vec::new();
+        for (vip, phys) in mappings {
+            let phys = table.intern(phys);
+            all.push((vip, phys));
+        }
+
+        // The sort is stable, keeping the mappings of a VPC IP in the
+        // order given.
+        all.sort_by_key(|(vip, _)| *vip);
+        let mut base: Vec<(K, Phys)> = Vec::with_capacity(all.len());
+        for (vip, phys) in all {
+            match base.last_mut() {
+                Some(last) if last.0 == vip => last.1 = phys,
+                _ => base.push((vip, phys)),
+            }
+        }
+
+        base.shrink_to_fit();
+        table.len = base.len();
+        table.base = base;
+        table
+    }
+
+    /// Return the mapping of `vip`, if there is one.
+    pub fn get(&self, vip: &K) -> Option<GuestPhysAddr> {
+        let phys = match self.delta.binary_search_by_key(vip, |(k, _)| *k) {
+            Ok(i) => self.delta[i].1,
+            Err(_) => {
+                let i =
+                    self.base.binary_search_by_key(vip, |(k, _)| *k).ok()?;
+                Some(self.base[i].1)
+            }
+        };
+
+        phys.map(|phys| self.resolve(phys))
+    }
+
+    fn intern(&mut self, phys: GuestPhysAddr) -> Phys {
+        let next = self.sleds.len() as u32;
+        let sled = *self.sled_idx.entry(phys.ip).or_insert(next);
+        if sled == next {
+            self.sleds.push(phys.ip);
+        }
+        Phys { ether: phys.ether, sled }
+    }
+
+    /// Return the number of mappings.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Return the number of bytes used by the table.
+    ///
+    /// This counts the memory allocated for the mappings and the
+    /// underlay IPs, but not the overhead of the allocator or of the
+    /// underlay IP index's tree nodes.
+    pub fn mem_bytes(&self) -> usize {
+        size_of::<Self>()
+            + self.base.capacity() * size_of::<(K, Phys)>()
+            + self.delta.capacity() * size_of::<(K, Option<Phys>)>()
+            + self.sleds.capacity() * size_of::<Ipv6Addr>()
+            + self.sled_idx.len() * size_of::<(Ipv6Addr, u32)>()
+    }
+
+    /// Return true if the delta has grown enough to be merged, see
+    /// [`V2pTable::merged()`].
+    pub fn needs_merge(&self) -> bool {
+        // An approximation of the square root, good enough for the
+        // purpose of balancing the cost of the delta's inserts against
+        // that of the merges.
+        let bits = usize::BITS - self.base.len().leading_zeros();
+        self.delta.len() > cmp::max(V2P_DELTA_MIN, 1 << (bits / 2))
+    }
+
+    /// Return a copy of the table with the delta merged in, and with
+    /// the underlay IPs no longer used dropped.
+    pub fn merged(&self) -> Self {
+        let mut table = Self::new();
+        let mut base = Vec::with_capacity(self.len);
+        let mut remap = vec![u32::MAX; self.sleds.len()];
+
+        self.walk(None, |vip, phys| {
+            let sled = &mut remap[phys.sled as usize];
+            if *sled == u32::MAX {
+                *sled = table.intern_ip(self.sleds[phys.sled as usize]);
+            }
+            base.push((vip, Phys { ether: phys.ether, sled: *sled }));
+            true
+        });
+
+        table.len = base.len();
+        table.base = base;
+        table.sleds.shrink_to_fit();
+        table
+    }
+
+    fn intern_ip(&mut self, ip: Ipv6Addr) -> u32 {
+        let sled = self.sleds.len() as u32;
+        self.sleds.push(ip);
+        self.sled_idx.insert(ip, sled);
+        sled
+    }
+
+    pub fn new() -> Self {
+        Self {
+            base: Vec::new(),
+            delta: Vec::new(),
+            sleds: Vec::new(),
+            sled_idx: BTreeMap::new(),
+            len: 0,
+        }
+    }
+
+    /// Return up to `limit` mappings in order of VPC IP, starting
+    /// after `after`, along with whether more mappings follow.
+    pub fn page(
+        &self,
+        after: Option<K>,
+        limit: usize,
+    ) -> (Vec<(K, GuestPhysAddr)>, bool) {
+        let mut page = Vec::new();
+        let mut more = false;
+
+        self.walk(after, |vip, phys| {
+            if page.len() == limit {
+                more = true;
+                return false;
+            }
+
+            page.push((vip, self.resolve(phys)));
+            true
+        });
+
+        (page, more)
+    }
+
+    /// Remove the mapping of `vip`, returning it.
+    pub fn remove(&mut self, vip: &K) -> Option<GuestPhysAddr> {
+        let old = self.get(vip)?;
+        let in_base = self.base.binary_search_by_key(vip, |(k, _)| *k).is_ok();
+
+        match self.delta.binary_search_by_key(vip, |(k, _)| *k) {
+            Ok(i) if in_base => self.delta[i].1 = None,
+            Ok(i) => {
+                self.delta.remove(i);
+            }
+            // The mapping is in the base, as it exists.
+            Err(i) => self.delta.insert(i, (*vip, None)),
+        }
+
+        self.len -= 1;
+        Some(old)
+    }
+
+    fn resolve(&self, phys: Phys) -> GuestPhysAddr {
+        GuestPhysAddr { ether: phys.ether, ip: self.sleds[phys.sled as usize] }
+    }
+
+    /// Set the mapping of `vip` to `phys`, returning the previous
+    /// mapping, if any.
+    pub fn set(
+        &mut self,
+        vip: K,
+        phys: GuestPhysAddr,
+    ) -> Option<GuestPhysAddr> {
+        let old = self.get(&vip);
+        let phys = self.intern(phys);
+
+        match self.delta.binary_search_by_key(&vip, |(k, _)| *k) {
+            Ok(i) => self.delta[i].1 = Some(phys),
+            Err(i) => self.delta.insert(i, (vip, Some(phys))),
+        }
+
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    // Call `f` on each mapping in order of VPC IP, starting after
+    // `after`, until it returns false.
+    fn walk<F>(&self, after: Option<K>, mut f: F)
+    where
+        F: FnMut(K, Phys) -> bool,
+    {
+        let (mut b, mut d) = match after {
+            Some(after) => (
+                self.base.partition_point(|(k, _)| *k <= after),
+                self.delta.partition_point(|(k, _)| *k <= after),
+            ),
+
+            None => (0, 0),
+        };
+
+        loop {
+            let (vip, phys) = match (self.base.get(b), self.delta.get(d)) {
+                (None, None) => return,
+
+                (Some(&(bk, bp)), Some(&(dk, _))) if bk < dk => {
+                    b += 1;
+                    (bk, Some(bp))
+                }
+
+                // The delta overrides the base.
+                (Some(&(bk, _)), Some(&(dk, dp))) if bk == dk => {
+                    b += 1;
+                    d += 1;
+                    (dk, dp)
+                }
+
+                (_, Some(&(dk, dp))) => {
+                    d += 1;
+                    (dk, dp)
+                }
+
+                (Some(&(bk, bp)), None) => {
+                    b += 1;
+                    (bk, Some(bp))
+                }
+            };
+
+            if let Some(phys) = phys {
+                if !f(vip, phys) {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use opte::api::Ipv4Addr;
+
+    fn phys(host: u8, sled: u8) -> GuestPhysAddr {
+        GuestPhysAddr {
+            ether: MacAddr::from([0xA8, 0x40, 0x25, 0xF0, 0x00, host]),
+            ip: Ipv6Addr::from([0xFD00, 0, 0, 0, 0, 0, 0, sled as u16]),
+        }
+    }
+
+    fn vip(host: u8) -> Ipv4Addr {
+        Ipv4Addr::from([172, 30, 0, host])
+    }
+
+    // Check the table against the mappings it should hold, through
+    // both lookups and pages.
+    fn check(table: &V2pTable<Ipv4Addr>, want: &BTreeMap<u8, GuestPhysAddr>) {
+        assert_eq!(table.len(), want.len());
+
+        for host in 0..=u8::MAX {
+            assert_eq!(table.get(&vip(host)), want.get(&host).cloned());
+        }
+
+        let mut all = Vec::new();
+        let mut after = None;
+        loop {
+            let (page, more) = table.page(after, 7);
+            after = page.last().map(|(vip, _)| *vip);
+            all.extend(page);
+            if !more {
+                break;
+            }
+        }
+
+        let want: Vec<_> =
+            want.iter().map(|(host, phys)| (vip(*host), *phys)).collect();
+        assert_eq!(all, want);
+    }
+
+    #[test]
+    fn set_and_remove_across_merges() {
+        let mut table = V2pTable::from_mappings([
+            (vip(1), phys(1, 1)),
+            (vip(3), phys(3, 1)),
+            (vip(1), phys(1, 2)),
+        ]);
+        let mut want = BTreeMap::from([(1, phys(1, 2)), (3, phys(3, 1))]);
+        check(&table, &want);
+
+        // Changes in the delta shadow the base.
+        assert_eq!(table.set(vip(3), phys(3, 2)), Some(phys(3, 1)));
+        assert_eq!(table.set(vip(2), phys(2, 1)), None);
+        assert_eq!(table.remove(&vip(1)), Some(phys(1, 2)));
+        assert_eq!(table.remove(&vip(1)), None);
+        assert_eq!(table.remove(&vip(2)), Some(phys(2, 1)));
+        want.insert(3, phys(3, 2));
+        want.remove(&1);
+        check(&table, &want);
+
+        // Enough changes to go through a number of merges.
+        for host in 0..=u8::MAX {
+            let phys = phys(host, host % 3);
+            table.set(vip(host), phys);
+            want.insert(host, phys);
+
+            if host % 5 == 0 {
+                table.remove(&vip(host / 2));
+                want.remove(&(host / 2));
+            }
+
+            if table.needs_merge() {
+                table = table.merged();
+                assert!(table.delta.is_empty());
+            }
+        }
+
+        check(&table, &want);
+        check(&table.merged(), &want);
+    }
+
+    #[test]
+    fn merge_drops_unused_sleds() {
+        let mut table = V2pTable::from_mappings(
+            (0..100).map(|host| (vip(host), phys(host, host % 4))),
+        );
+        assert_eq!(table.sleds.len(), 4);
+
+        for host in (0..100).filter(|host| host % 4 == 3) {
+            table.remove(&vip(host));
+        }
+
+        let table = table.merged();
+        assert_eq!(table.len(), 75);
+        assert_eq!(table.sleds.len(), 3);
+        assert_eq!(table.sled_idx.len(), 3);
+        assert_eq!(table.get(&vip(2)), Some(phys(2, 2)));
+    }
+
+    // A mapping costs the VPC IP, the MAC address and the index of
+    // its underlay IP, plus padding.
+    #[test]
+    fn mem_bytes_per_mapping() {
+        let n: u32 = 100_000;
+        let table = V2pTable::from_mappings((0..n).map(|i| {
+            let host = i.to_be_bytes();
+            let phys = GuestPhysAddr {
+                ether: MacAddr::from([
+                    0xA8, 0x40, 0x25, host[1], host[2], host[3],
+                ]),
+                ip: Ipv6Addr::from([
+                    0xFD00,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0,
+                    (i % 512) as u16,
+                ]),
+            };
+            (Ipv4Addr::from(0x0A00_0000 + i), phys)
+        }));
+
+        assert_eq!(table.len(), n as usize);
+        assert!(table.mem_bytes() / n as usize <= 18);
+    }
+}
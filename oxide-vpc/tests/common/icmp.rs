@@ -118,7 +118,41 @@ pub fn gen_icmpv6_echo_req(
     seq_no: u16,
     data: &[u8],
 ) -> Packet<Parsed> {
-    let req = Icmpv6Repr::EchoRequest { ident, seq_no, data };
+    let etype = IcmpEchoType::Req;
+    gen_icmpv6_echo(
+        etype, eth_src, eth_dst, ip_src, ip_dst, ident, seq_no, data,
+    )
+}
+
+pub fn gen_icmpv6_echo_reply(
+    eth_src: MacAddr,
+    eth_dst: MacAddr,
+    ip_src: Ipv6Addr,
+    ip_dst: Ipv6Addr,
+    ident: u16,
+    seq_no: u16,
+    data: &[u8],
+) -> Packet<Parsed> {
+    let etype = IcmpEchoType::Reply;
+    gen_icmpv6_echo(
+        etype, eth_src, eth_dst, ip_src, ip_dst, ident, seq_no, data,
+    )
+}
+
+pub fn gen_icmpv6_echo(
+    etype: IcmpEchoType,
+    eth_src: MacAddr,
+    eth_dst: MacAddr,
+    ip_src: Ipv6Addr,
+    ip_dst: Ipv6Addr,
+    ident: u16,
+    seq_no: u16,
+    data: &[u8],
+) -> Packet<Parsed> {
+    let req = match etype {
+        IcmpEchoType::Req => Icmpv6Repr::EchoRequest { ident, seq_no, data },
+        IcmpEchoType::Reply => Icmpv6Repr::EchoReply { ident, seq_no, data },
+    };
     let mut body_bytes = vec![0u8; req.buffer_len()];
     let mut req_pkt = Icmpv6Packet::new_unchecked(&mut body_bytes);
     let _ = req.emit(
@@ -39,6 +39,7 @@ pub use opte::engine::ip4::UlpCsumOpt;
 pub use opte::engine::ip6::Ipv6Addr;
 pub use opte::engine::ip6::Ipv6Hdr;
 pub use opte::engine::ip6::Ipv6Meta;
+pub use opte::engine::layer::DefaultAction;
 pub use opte::engine::layer::DenyReason;
 pub use opte::engine::packet::BodyInfo;
 pub use opte::engine::packet::HdrOffset;
@@ -61,19 +62,32 @@ pub use opte::engine::GenericUlp;
 pub use opte::ExecCtx;
 pub use oxide_vpc::api::AddFwRuleReq;
 pub use oxide_vpc::api::BoundaryServices;
+pub use oxide_vpc::api::DelPortForwardResp;
+pub use oxide_vpc::api::DelRouterEntryResp;
+pub use oxide_vpc::api::DetachFloatingIpResp;
+pub use oxide_vpc::api::GuestPhysAddr;
 pub use oxide_vpc::api::IpCfg;
 pub use oxide_vpc::api::Ipv4Cfg;
 pub use oxide_vpc::api::Ipv6Cfg;
+pub use oxide_vpc::api::Nat64Cfg;
+pub use oxide_vpc::api::NatMapping;
 pub use oxide_vpc::api::PhysNet;
+pub use oxide_vpc::api::PortForward;
+pub use oxide_vpc::api::Ports;
+pub use oxide_vpc::api::RouteFilters;
+pub use oxide_vpc::api::RouterEntry;
 pub use oxide_vpc::api::RouterTarget;
 pub use oxide_vpc::api::SNat4Cfg;
 pub use oxide_vpc::api::SNat6Cfg;
 pub use oxide_vpc::api::SetFwRulesReq;
+pub use oxide_vpc::api::SetFwRulesResp;
 pub use oxide_vpc::api::VpcCfg;
 pub use oxide_vpc::engine::firewall;
 pub use oxide_vpc::engine::gateway;
+pub use oxide_vpc::engine::mirror;
 pub use oxide_vpc::engine::nat;
 pub use oxide_vpc::engine::overlay;
+pub use oxide_vpc::engine::overlay::DumpVirt2PhysReq;
 pub use oxide_vpc::engine::overlay::Virt2Phys;
 pub use oxide_vpc::engine::overlay::VpcMappings;
 pub use oxide_vpc::engine::router;
@@ -88,8 +102,8 @@ pub use std::sync::Arc;
 // It's imperative that this list stays in sync with the layers that
 // makeup the VPC implementation. We verify this in the `check_layers`
 // test.
-pub const VPC_LAYERS: [&str; 5] =
-    ["gateway", "firewall", "router", "nat", "overlay"];
+pub const VPC_LAYERS: [&str; 6] =
+    ["mirror", "gateway", "firewall", "router", "nat", "overlay"];
 
 // This is the MAC address that OPTE uses to act as the virtual gateway.
 pub const GW_MAC_ADDR: MacAddr =
@@ -111,8 +125,10 @@ pub fn g1_cfg() -> VpcCfg {
             snat: Some(SNat4Cfg {
                 external_ip: "10.77.77.13".parse().unwrap(),
                 ports: 1025..=4096,
+                mapping: NatMapping::PerFlow,
+                max_ports: None,
             }),
-            external_ips: None,
+            external_ips: vec![],
         },
         ipv6: Ipv6Cfg {
             vpc_subnet: "fd00::/64".parse().unwrap(),
@@ -121,13 +137,35 @@ pub fn g1_cfg() -> VpcCfg {
             snat: Some(SNat6Cfg {
                 external_ip: "2001:db8::1".parse().unwrap(),
                 ports: 1025..=4096,
+                mapping: NatMapping::PerFlow,
+                max_ports: None,
             }),
-            external_ips: None,
+            external_ips: vec![],
+            nat64: None,
         },
     };
     g1_cfg2(ip_cfg)
 }
 
+/// The configuration of an IPv6-only `g1`, reaching IPv4 hosts
+/// through NAT64.
+pub fn g1_nat64_cfg() -> VpcCfg {
+    let ip_cfg = IpCfg::Ipv6(Ipv6Cfg {
+        vpc_subnet: "fd00::/64".parse().unwrap(),
+        private_ip: "fd00::5".parse().unwrap(),
+        gateway_ip: "fd00::1".parse().unwrap(),
+        snat: None,
+        external_ips: vec![],
+        nat64: Some(Nat64Cfg {
+            external_ip: "10.77.77.13".parse().unwrap(),
+            ports: 1025..=4096,
+            mapping: NatMapping::PerFlow,
+            max_ports: None,
+        }),
+    });
+    g1_cfg2(ip_cfg)
+}
+
 pub fn g1_cfg2(ip_cfg: IpCfg) -> VpcCfg {
     VpcCfg {
         ip_cfg,
@@ -138,16 +176,14 @@ pub fn g1_cfg2(ip_cfg: IpCfg) -> VpcCfg {
         phys_ip: Ipv6Addr::from([
             0xFD00, 0x0000, 0x00F7, 0x0101, 0x0000, 0x0000, 0x0000, 0x0001,
         ]),
-        boundary_services: BoundaryServices {
+        boundary_services: vec![BoundaryServices {
             mac: MacAddr::from([0xA8, 0x40, 0x25, 0x77, 0x77, 0x77]),
             ip: Ipv6Addr::from([
                 0xFD, 0x00, 0x99, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
                 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
             ]),
             vni: Vni::new(99u32).unwrap(),
-        },
-        proxy_arp_enable: false,
-        phys_gw_mac: Some(MacAddr::from([0x78, 0x23, 0xae, 0x5d, 0x4f, 0x0d])),
+        }],
     }
 }
 
@@ -160,8 +196,10 @@ pub fn g2_cfg() -> VpcCfg {
             snat: Some(SNat4Cfg {
                 external_ip: "10.77.77.23".parse().unwrap(),
                 ports: 4096..=8192,
+                mapping: NatMapping::PerFlow,
+                max_ports: None,
             }),
-            external_ips: None,
+            external_ips: vec![],
         },
         ipv6: Ipv6Cfg {
             vpc_subnet: "fd00::/64".parse().unwrap(),
@@ -170,8 +208,11 @@ pub fn g2_cfg() -> VpcCfg {
             snat: Some(SNat6Cfg {
                 external_ip: "2001:db8::1".parse().unwrap(),
                 ports: 1025..=4096,
+                mapping: NatMapping::PerFlow,
+                max_ports: None,
             }),
-            external_ips: None,
+            external_ips: vec![],
+            nat64: None,
         },
     };
     VpcCfg {
@@ -183,16 +224,14 @@ pub fn g2_cfg() -> VpcCfg {
         phys_ip: Ipv6Addr::from([
             0xFD00, 0x0000, 0x00F7, 0x0116, 0x0000, 0x0000, 0x0000, 0x0001,
         ]),
-        boundary_services: BoundaryServices {
+        boundary_services: vec![BoundaryServices {
             mac: MacAddr::from([0xA8, 0x40, 0x25, 0x77, 0x77, 0x77]),
             ip: Ipv6Addr::from([
                 0xFD, 0x00, 0x11, 0x22, 0x33, 0x44, 0x01, 0xFF, 0x00, 0x00,
                 0x00, 0x00, 0x00, 0x00, 0x77, 0x77,
             ]),
             vni: Vni::new(99u32).unwrap(),
-        },
-        proxy_arp_enable: false,
-        phys_gw_mac: Some(MacAddr::from([0x78, 0x23, 0xae, 0x5d, 0x4f, 0x0d])),
+        }],
     }
 }
 
@@ -212,11 +251,17 @@ fn oxide_net_builder(
     let one_limit = NonZeroU32::new(1).unwrap();
 
     firewall::setup(&mut pb, fw_limit).expect("failed to add firewall layer");
+    mirror::setup(&mut pb, one_limit).expect("failed to add mirror layer");
+    let addr_sets = vpc_map.addr_sets(cfg.vni);
+    let misses = vpc_map.misses();
+    let bsvc = vpc_map.bsvc();
+    let nat_ports = vpc_map.nat_ports();
     gateway::setup(&mut pb, cfg, vpc_map, fw_limit)
         .expect("failed to setup gateway layer");
     router::setup(&mut pb, cfg, one_limit).expect("failed to add router layer");
-    nat::setup(&mut pb, cfg, snat_limit).expect("failed to add nat layer");
-    overlay::setup(&mut pb, cfg, v2p, one_limit)
+    nat::setup(&mut pb, cfg, &nat_ports, snat_limit)
+        .expect("failed to add nat layer");
+    overlay::setup(&mut pb, cfg, v2p, addr_sets, misses, bsvc, one_limit)
         .expect("failed to add overlay layer");
     pb
 }
@@ -304,9 +349,11 @@ pub fn oxide_net_setup2(
     let vps = VpcPortState::new();
     let mut pav = PortAndVps { port, vps, vpc_map };
 
-    let nat_rules = match cfg.ipv4().external_ips {
-        Some(_) => "incr:nat.rules.in, nat.rules.out",
-        _ => "",
+    // * Inbound 1:1 NAT for each IPv4 floating IP
+    // * Outbound 1:1 NAT for the first one
+    let nat_rules = match cfg.ipv4().external_ips.len() {
+        0 => String::new(),
+        n => format!("set:nat.rules.in={}, nat.rules.out=3", n),
     };
 
     let mut updates = vec![
@@ -339,7 +386,7 @@ pub fn oxide_net_setup2(
         // * Outbound IPv4 SNAT
         // * Outbound IPv6 SNAT
         "set:nat.rules.out=2",
-        nat_rules,
+        &nat_rules,
         // * Allow guest to route to own subnet
         "set:router.rules.out=1",
         // * Outbound encap
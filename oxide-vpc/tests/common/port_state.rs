@@ -20,7 +20,7 @@ pub fn print_port(port: &Port<VpcNetwork>, vpc_map: &VpcMappings) {
     // ================================================================
     // Print VPC mappings.
     // ================================================================
-    print_v2p(&vpc_map.dump());
+    print_v2p(&vpc_map.dump(&DumpVirt2PhysReq::default()));
     println!("");
 
     println!(
@@ -138,6 +138,9 @@ impl VpcPortState {
         counts.insert("stats.port.in_drop_layer".to_string(), 0);
         counts.insert("stats.port.in_uft_hit".to_string(), 0);
         counts.insert("stats.port.in_uft_miss".to_string(), 0);
+        counts.insert("stats.port.mirror".to_string(), 0);
+        counts.insert("stats.port.mirror_err".to_string(), 0);
+        counts.insert("stats.port.mirror_queue_full".to_string(), 0);
         counts.insert("stats.port.out_drop".to_string(), 0);
         counts.insert("stats.port.out_drop_layer".to_string(), 0);
         counts.insert("stats.port.out_modified".to_string(), 0);
@@ -173,6 +176,9 @@ pub fn port_stats_val(stats: PortStatsSnap, stat: &str) -> u64 {
         "in_modified" => stats.in_modified,
         "in_uft_hit" => stats.in_uft_hit,
         "in_uft_miss" => stats.in_uft_miss,
+        "mirror" => stats.mirror,
+        "mirror_err" => stats.mirror_err,
+        "mirror_queue_full" => stats.mirror_queue_full,
         "out_drop" => stats.out_drop,
         "out_drop_layer" => stats.out_drop_layer,
         "out_modified" => stats.out_modified,
@@ -1,6 +1,7 @@
 mod common;
 
 use common::*;
+use opte::engine::flow_record::FlowVerdict;
 
 #[test]
 fn firewall_replace_rules() {
@@ -314,3 +315,703 @@ fn firewall_vni_outbound() {
         ]
     );
 }
+
+// Verify that a layer's default action can be changed on a running
+// port, and that the default action hits are tracked.
+#[test]
+fn firewall_default_action() {
+    let g1_cfg = g1_cfg();
+    let g2_cfg = g2_cfg();
+    let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
+    g1.port.start();
+    set!(g1, "port_state=running");
+
+    let mut g2 = oxide_net_setup("g2_port", &g2_cfg, Some(g1.vpc_map.clone()));
+    g2.port.start();
+    set!(g2, "port_state=running");
+
+    // Remove all of g2's firewall rules so that inbound traffic is
+    // subject to the default action.
+    firewall::set_fw_rules(
+        &g2.port,
+        &SetFwRulesReq { port_name: g2.port.name().to_string(), rules: vec![] },
+    )
+    .unwrap();
+    update!(
+        g2,
+        ["incr:epoch", "set:firewall.rules.in=0, firewall.rules.out=0"]
+    );
+
+    // ================================================================
+    // Send a SYN from g1 to g2 and make two copies of the encap'd
+    // packet to deliver to g2.
+    // ================================================================
+    let mut pkt1 = http_syn(&g1_cfg, &g2_cfg);
+    let res = g1.port.process(Out, &mut pkt1, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(
+        g1,
+        [
+            "firewall.flows.out, firewall.flows.in",
+            "uft.out",
+            "stats.port.out_modified, stats.port.out_uft_miss"
+        ]
+    );
+
+    let mut pkt2 =
+        Packet::copy(&pkt1.all_bytes()).parse(In, VpcParser::new()).unwrap();
+    let mut pkt3 =
+        Packet::copy(&pkt1.all_bytes()).parse(In, VpcParser::new()).unwrap();
+
+    // ================================================================
+    // The default inbound action is deny.
+    // ================================================================
+    let res = g2.port.process(In, &mut pkt2, ActionMeta::new());
+    assert_drop!(
+        res,
+        DropReason::Layer { name: "firewall", reason: DenyReason::Default }
+    );
+    incr!(
+        g2,
+        [
+            "stats.port.in_drop, stats.port.in_drop_layer",
+            "stats.port.in_uft_miss"
+        ]
+    );
+    let stats = g2.port.layer_stats_snap("firewall").unwrap();
+    assert_eq!(stats.in_default_hits, 1);
+    assert_eq!(stats.out_default_hits, 0);
+
+    // ================================================================
+    // Switch the firewall to default-allow and verify the same packet
+    // is now allowed, creating a flow.
+    // ================================================================
+    g2.port
+        .set_default_action("firewall", In, DefaultAction::StatefulAllow)
+        .unwrap();
+    incr!(g2, ["epoch"]);
+    let stats = g2.port.layer_stats_snap("firewall").unwrap();
+    assert_eq!(stats.in_default_hits, 0);
+    assert_eq!(stats.set_default_action_called, 1);
+
+    let res = g2.port.process(In, &mut pkt3, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(
+        g2,
+        [
+            "firewall.flows.in, firewall.flows.out",
+            "uft.in",
+            "stats.port.in_modified, stats.port.in_uft_miss"
+        ]
+    );
+    let stats = g2.port.layer_stats_snap("firewall").unwrap();
+    assert_eq!(stats.in_default_hits, 1);
+    let dump = g2.port.dump_layer("firewall").unwrap();
+    assert_eq!(dump.default_in, "stateful allow");
+    assert_eq!(dump.default_in_hits, 1);
+
+    // ================================================================
+    // The firewall layer has no named actions to default to, and a
+    // layer must exist to have its default changed.
+    // ================================================================
+    assert!(g2
+        .port
+        .set_default_action(
+            "firewall",
+            In,
+            DefaultAction::Named("bogus".to_string())
+        )
+        .is_err());
+    assert!(g2
+        .port
+        .set_default_action("bogus", In, DefaultAction::Deny)
+        .is_err());
+    assert_port!(g2);
+}
+
+// Verify that setting the firewall rules only applies the delta
+// between the current and new rule sets, leaving the flows which are
+// not affected by the delta in place.
+#[test]
+fn firewall_sync_rules() {
+    let g1_cfg = g1_cfg();
+    let g2_cfg = g2_cfg();
+    let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
+    g1.port.start();
+    set!(g1, "port_state=running");
+
+    let mut g2 = oxide_net_setup("g2_port", &g2_cfg, Some(g1.vpc_map.clone()));
+    g2.port.start();
+    set!(g2, "port_state=running");
+
+    // The default rules, in the same form as set by oxide_net_setup().
+    let ssh_in = "dir=in action=allow priority=65534 protocol=TCP port=22";
+    let icmp_in = "dir=in action=allow priority=65534 protocol=ICMP";
+    let vpc_in =
+        format!("dir=in action=allow priority=65534 hosts=vni={}", g2_cfg.vni);
+    let udp_in = "dir=in action=deny priority=10 protocol=UDP port=53";
+
+    // ================================================================
+    // Establish an inbound HTTP flow on g2.
+    // ================================================================
+    let mut pkt1 = http_syn(&g1_cfg, &g2_cfg);
+    let res = g1.port.process(Out, &mut pkt1, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(
+        g1,
+        [
+            "firewall.flows.out, firewall.flows.in",
+            "uft.out",
+            "stats.port.out_modified, stats.port.out_uft_miss"
+        ]
+    );
+
+    let pkt1_bytes = pkt1.all_bytes();
+    let mut pkt2 =
+        Packet::copy(&pkt1_bytes).parse(In, VpcParser::new()).unwrap();
+    let res = g2.port.process(In, &mut pkt2, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(
+        g2,
+        [
+            "firewall.flows.in, firewall.flows.out",
+            "uft.in",
+            "stats.port.in_modified, stats.port.in_uft_miss"
+        ]
+    );
+
+    // ================================================================
+    // Setting the same rules is a no-op: the epoch stays put and the
+    // flow remains in the UFT.
+    // ================================================================
+    let res = firewall::set_fw_rules(
+        &g2.port,
+        &SetFwRulesReq {
+            port_name: g2.port.name().to_string(),
+            rules: vec![
+                icmp_in.parse().unwrap(),
+                vpc_in.parse().unwrap(),
+                ssh_in.parse().unwrap(),
+            ],
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        res,
+        SetFwRulesResp {
+            added: 0,
+            removed: 0,
+            unchanged: 3,
+            flows_invalidated: 0
+        }
+    );
+
+    let mut pkt3 =
+        Packet::copy(&pkt1_bytes).parse(In, VpcParser::new()).unwrap();
+    let res = g2.port.process(In, &mut pkt3, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(g2, ["stats.port.in_modified, stats.port.in_uft_hit"]);
+
+    // ================================================================
+    // Adding an unrelated rule moves the epoch forward, but keeps the
+    // firewall's flow. The UFT entry is rebuilt from it.
+    // ================================================================
+    let res = firewall::set_fw_rules(
+        &g2.port,
+        &SetFwRulesReq {
+            port_name: g2.port.name().to_string(),
+            rules: vec![
+                vpc_in.parse().unwrap(),
+                ssh_in.parse().unwrap(),
+                icmp_in.parse().unwrap(),
+                udp_in.parse().unwrap(),
+            ],
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        res,
+        SetFwRulesResp {
+            added: 1,
+            removed: 0,
+            unchanged: 3,
+            flows_invalidated: 0
+        }
+    );
+    update!(g2, ["incr:epoch", "set:firewall.rules.in=4"]);
+
+    let mut pkt4 =
+        Packet::copy(&pkt1_bytes).parse(In, VpcParser::new()).unwrap();
+    let res = g2.port.process(In, &mut pkt4, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(g2, ["stats.port.in_modified, stats.port.in_uft_miss"]);
+
+    // ================================================================
+    // Removing the SSH rule does not affect an HTTP flow.
+    // ================================================================
+    let res = firewall::set_fw_rules(
+        &g2.port,
+        &SetFwRulesReq {
+            port_name: g2.port.name().to_string(),
+            rules: vec![
+                vpc_in.parse().unwrap(),
+                icmp_in.parse().unwrap(),
+                udp_in.parse().unwrap(),
+            ],
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        res,
+        SetFwRulesResp {
+            added: 0,
+            removed: 1,
+            unchanged: 3,
+            flows_invalidated: 0
+        }
+    );
+    update!(g2, ["incr:epoch", "set:firewall.rules.in=3"]);
+
+    // ================================================================
+    // Removing the VPC rule, which allowed the flow, invalidates it.
+    // ================================================================
+    let res = firewall::set_fw_rules(
+        &g2.port,
+        &SetFwRulesReq {
+            port_name: g2.port.name().to_string(),
+            rules: vec![icmp_in.parse().unwrap(), udp_in.parse().unwrap()],
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        res,
+        SetFwRulesResp {
+            added: 0,
+            removed: 1,
+            unchanged: 2,
+            flows_invalidated: 1
+        }
+    );
+    update!(
+        g2,
+        [
+            "incr:epoch",
+            "set:firewall.rules.in=2",
+            "set:firewall.flows.in=0, firewall.flows.out=0",
+        ]
+    );
+
+    let mut pkt5 =
+        Packet::copy(&pkt1_bytes).parse(In, VpcParser::new()).unwrap();
+    let res = g2.port.process(In, &mut pkt5, ActionMeta::new());
+    assert_drop!(
+        res,
+        DropReason::Layer { name: "firewall", reason: DenyReason::Default }
+    );
+    update!(
+        g2,
+        [
+            "set:uft.in=0",
+            "incr:stats.port.in_drop, stats.port.in_drop_layer",
+            "incr:stats.port.in_uft_miss",
+        ]
+    );
+}
+
+// Verify that the logging actions record the first packet of each
+// flow matching their rule, and only that.
+#[test]
+fn firewall_log_actions() {
+    let g1_cfg = g1_cfg();
+    let g2_cfg = g2_cfg();
+    let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
+    g1.port.start();
+    set!(g1, "port_state=running");
+
+    let http_out = "dir=out action=deny-log priority=10 protocol=TCP port=80";
+    let telnet_out =
+        "dir=out action=allow-log priority=10 protocol=TCP port=23";
+    let udp_out = "dir=out action=deny priority=10 protocol=UDP";
+    firewall::set_fw_rules(
+        &g1.port,
+        &SetFwRulesReq {
+            port_name: g1.port.name().to_string(),
+            rules: vec![
+                http_out.parse().unwrap(),
+                telnet_out.parse().unwrap(),
+                udp_out.parse().unwrap(),
+            ],
+        },
+    )
+    .unwrap();
+    update!(g1, ["incr:epoch", "set:firewall.rules.out=3"]);
+
+    let dump = g1.port.dump_layer("firewall").unwrap();
+    let rule_id = |port: &str| {
+        dump.rules_out
+            .iter()
+            .find(|rte| rte.rule.predicates.iter().any(|p| p.ends_with(port)))
+            .unwrap()
+            .id
+    };
+    let http_id = rule_id("=80");
+    let telnet_id = rule_id("=23");
+
+    // ================================================================
+    // The denied HTTP flow is logged once, no matter how many of its
+    // packets are sent.
+    // ================================================================
+    for _ in 0..2 {
+        let mut pkt = http_syn(&g1_cfg, &g2_cfg);
+        let res = g1.port.process(Out, &mut pkt, ActionMeta::new());
+        assert_drop!(
+            res,
+            DropReason::Layer { name: "firewall", reason: DenyReason::Rule }
+        );
+        incr!(
+            g1,
+            [
+                "stats.port.out_drop, stats.port.out_drop_layer",
+                "stats.port.out_uft_miss",
+            ]
+        );
+    }
+
+    // ================================================================
+    // The allowed telnet flow is logged as well.
+    // ================================================================
+    let mut pkt = tcp_telnet_syn(&g1_cfg, &g2_cfg);
+    let telnet_flow = *pkt.flow();
+    let res = g1.port.process(Out, &mut pkt, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(
+        g1,
+        [
+            "firewall.flows.out, firewall.flows.in",
+            "uft.out",
+            "stats.port.out_modified, stats.port.out_uft_miss",
+        ]
+    );
+
+    let http_flow = *http_syn(&g1_cfg, &g2_cfg).flow();
+    let log = g1.port.dump_rule_log("firewall").unwrap();
+    assert_eq!(log.lost, 0);
+    assert_eq!(log.entries.len(), 2);
+
+    let entry = &log.entries[0];
+    assert_eq!(entry.seq, 1);
+    assert_eq!(entry.rule_id, http_id);
+    assert_eq!(entry.dir, Out);
+    assert_eq!(entry.flow, http_flow);
+    assert_eq!(entry.verdict, FlowVerdict::Deny);
+    assert!(entry.time_ns <= log.elapsed_ns);
+
+    let entry = &log.entries[1];
+    assert_eq!(entry.seq, 2);
+    assert_eq!(entry.rule_id, telnet_id);
+    assert_eq!(entry.flow, telnet_flow);
+    assert_eq!(entry.verdict, FlowVerdict::Allow);
+
+    // ================================================================
+    // A rule without logging leaves no trace in the log.
+    // ================================================================
+    let udp = UdpMeta {
+        src: 5353,
+        dst: 5353,
+        len: UdpHdr::SIZE as u16,
+        ..Default::default()
+    };
+    let ip = Ipv4Meta {
+        src: g1_cfg.ipv4_cfg().unwrap().private_ip,
+        dst: g2_cfg.ipv4_cfg().unwrap().private_ip,
+        proto: Protocol::UDP,
+        ttl: 64,
+        total_len: (Ipv4Hdr::BASE_SIZE + UdpHdr::SIZE) as u16,
+        ..Default::default()
+    };
+    let eth = EtherMeta {
+        ether_type: EtherType::Ipv4,
+        src: g1_cfg.guest_mac,
+        dst: g1_cfg.gateway_mac,
+    };
+    let mut pkt = ulp_pkt(eth, ip, udp, &[]);
+    let res = g1.port.process(Out, &mut pkt, ActionMeta::new());
+    assert_drop!(
+        res,
+        DropReason::Layer { name: "firewall", reason: DenyReason::Rule }
+    );
+    incr!(
+        g1,
+        [
+            "stats.port.out_drop, stats.port.out_drop_layer",
+            "stats.port.out_uft_miss",
+        ]
+    );
+
+    let log = g1.port.dump_rule_log("firewall").unwrap();
+    assert_eq!(log.entries.len(), 2);
+    assert!(g1.port.dump_rule_log("bogus").is_err());
+}
+
+// Verify that rules may filter on a VPC's address sets, and that the
+// sets may change without touching the rules, invalidating the flows
+// of the addresses whose membership changed.
+#[test]
+fn firewall_addr_sets() {
+    let g1_cfg = g1_cfg();
+    let g2_cfg = g2_cfg();
+    let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
+    g1.port.start();
+    set!(g1, "port_state=running");
+
+    let mut g2 = oxide_net_setup("g2_port", &g2_cfg, Some(g1.vpc_map.clone()));
+    g2.port.start();
+    set!(g2, "port_state=running");
+
+    let blocked_out = "dir=out action=deny priority=10 hosts=set=blocked";
+    firewall::set_fw_rules(
+        &g1.port,
+        &SetFwRulesReq {
+            port_name: g1.port.name().to_string(),
+            rules: vec![blocked_out.parse().unwrap()],
+        },
+    )
+    .unwrap();
+    update!(g1, ["incr:epoch", "set:firewall.rules.out=1"]);
+
+    // ================================================================
+    // The set does not exist yet, so the rule matches nothing.
+    // ================================================================
+    let mut pkt1 = http_syn(&g1_cfg, &g2_cfg);
+    let res = g1.port.process(Out, &mut pkt1, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(
+        g1,
+        [
+            "firewall.flows.out, firewall.flows.in",
+            "uft.out",
+            "stats.port.out_modified, stats.port.out_uft_miss"
+        ]
+    );
+    let pkt1_bytes = pkt1.all_bytes();
+
+    // ================================================================
+    // Add g2 to the set: new flows to g2 are denied, without the
+    // rules being touched.
+    // ================================================================
+    let addr_sets = g1.vpc_map.addr_sets(g1_cfg.vni);
+    let changed =
+        addr_sets.set("blocked", &[g2_cfg.ipv4().private_ip.into()]).unwrap();
+    assert_eq!(changed.len(), 1);
+    firewall::addr_set_changed(&g1.port, &changed).unwrap();
+    update!(g1, ["incr:epoch", "decr:firewall.flows.out, firewall.flows.in"]);
+
+    let mut pkt2 = tcp_telnet_syn(&g1_cfg, &g2_cfg);
+    let res = g1.port.process(Out, &mut pkt2, ActionMeta::new());
+    assert_drop!(
+        res,
+        DropReason::Layer { name: "firewall", reason: DenyReason::Rule }
+    );
+    incr!(
+        g1,
+        [
+            "stats.port.out_drop, stats.port.out_drop_layer",
+            "stats.port.out_uft_miss",
+        ]
+    );
+
+    // The established HTTP flow is checked against the new
+    // membership as well.
+    let mut pkt3 = http_syn(&g1_cfg, &g2_cfg);
+    let res = g1.port.process(Out, &mut pkt3, ActionMeta::new());
+    assert_drop!(
+        res,
+        DropReason::Layer { name: "firewall", reason: DenyReason::Rule }
+    );
+    update!(
+        g1,
+        [
+            "decr:uft.out",
+            "incr:stats.port.out_drop, stats.port.out_drop_layer",
+            "incr:stats.port.out_uft_miss",
+        ]
+    );
+
+    // Setting the same membership again changes nothing.
+    let changed =
+        addr_sets.set("blocked", &[g2_cfg.ipv4().private_ip.into()]).unwrap();
+    assert!(changed.is_empty());
+
+    // ================================================================
+    // Inbound, the set is matched against the source, as tagged by
+    // the overlay layer.
+    // ================================================================
+    let blocked_in = "dir=in action=deny priority=10 hosts=set=Blocked";
+    firewall::add_fw_rule(
+        &g2.port,
+        &AddFwRuleReq {
+            port_name: g2.port.name().to_string(),
+            rule: blocked_in.parse().unwrap(),
+        },
+    )
+    .unwrap();
+    incr!(g2, ["epoch", "firewall.rules.in"]);
+    let changed = addr_sets
+        .set(
+            "blocked",
+            &[g1_cfg.ipv4().private_ip.into(), g2_cfg.ipv4().private_ip.into()],
+        )
+        .unwrap();
+    assert_eq!(changed.len(), 1);
+    firewall::addr_set_changed(&g2.port, &changed).unwrap();
+    incr!(g2, ["epoch"]);
+
+    let mut pkt4 =
+        Packet::copy(&pkt1_bytes).parse(In, VpcParser::new()).unwrap();
+    let res = g2.port.process(In, &mut pkt4, ActionMeta::new());
+    assert_drop!(
+        res,
+        DropReason::Layer { name: "firewall", reason: DenyReason::Rule }
+    );
+    incr!(
+        g2,
+        [
+            "stats.port.in_drop, stats.port.in_drop_layer",
+            "stats.port.in_uft_miss",
+        ]
+    );
+
+    // ================================================================
+    // Once the set is deleted the traffic is allowed again.
+    // ================================================================
+    let removed = addr_sets.del("BLOCKED").unwrap();
+    assert_eq!(removed.len(), 2);
+    assert!(addr_sets.del("blocked").is_none());
+    firewall::addr_set_changed(&g2.port, &removed).unwrap();
+    incr!(g2, ["epoch"]);
+    assert!(g1.vpc_map.dump_addr_sets().sets.is_empty());
+
+    // Looking up the sets of another VPC does not create them.
+    let other_vni = Vni::new(1234u32).unwrap();
+    assert!(g1.vpc_map.find_addr_sets(other_vni).is_none());
+    assert!(g1.vpc_map.find_addr_sets(g1_cfg.vni).is_some());
+
+    let mut pkt5 =
+        Packet::copy(&pkt1_bytes).parse(In, VpcParser::new()).unwrap();
+    let res = g2.port.process(In, &mut pkt5, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(
+        g2,
+        [
+            "firewall.flows.in, firewall.flows.out",
+            "uft.in",
+            "stats.port.in_modified, stats.port.in_uft_miss"
+        ]
+    );
+
+    let mut pkt6 = tcp_telnet_syn(&g1_cfg, &g2_cfg);
+    let res = g1.port.process(Out, &mut pkt6, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(
+        g1,
+        [
+            "firewall.flows.out, firewall.flows.in",
+            "uft.out",
+            "stats.port.out_modified, stats.port.out_uft_miss"
+        ]
+    );
+}
+
+// Verify that a stateless rule allows traffic without creating any
+// flows in the firewall layer, and that the layer's stats count the
+// packets taking each path.
+#[test]
+fn firewall_stateless_rules() {
+    let g1_cfg = g1_cfg();
+    let g2_cfg = g2_cfg();
+    let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
+    g1.port.start();
+    set!(g1, "port_state=running");
+
+    let udp_out =
+        "dir=out action=allow priority=10 protocol=UDP mode=stateless";
+    firewall::set_fw_rules(
+        &g1.port,
+        &SetFwRulesReq {
+            port_name: g1.port.name().to_string(),
+            rules: vec![udp_out.parse().unwrap()],
+        },
+    )
+    .unwrap();
+    update!(g1, ["incr:epoch", "set:firewall.rules.out=1"]);
+
+    let udp_pkt = || {
+        let udp = UdpMeta {
+            src: 5353,
+            dst: 5353,
+            len: UdpHdr::SIZE as u16,
+            ..Default::default()
+        };
+        let ip = Ipv4Meta {
+            src: g1_cfg.ipv4_cfg().unwrap().private_ip,
+            dst: g2_cfg.ipv4_cfg().unwrap().private_ip,
+            proto: Protocol::UDP,
+            ttl: 64,
+            total_len: (Ipv4Hdr::BASE_SIZE + UdpHdr::SIZE) as u16,
+            ..Default::default()
+        };
+        let eth = EtherMeta {
+            ether_type: EtherType::Ipv4,
+            src: g1_cfg.guest_mac,
+            dst: g1_cfg.gateway_mac,
+        };
+        ulp_pkt(eth, ip, udp, &[])
+    };
+
+    // ================================================================
+    // The UDP traffic is allowed by the stateless rule: no firewall
+    // flows are created.
+    // ================================================================
+    let mut pkt1 = udp_pkt();
+    let res = g1.port.process(Out, &mut pkt1, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(g1, ["uft.out", "stats.port.out_modified, stats.port.out_uft_miss"]);
+    let stats = g1.port.layer_stats_snap("firewall").unwrap();
+    assert_eq!(stats.out_stateless, 1);
+    assert_eq!(stats.out_stateful, 0);
+
+    // The static transforms of the other layers are not counted.
+    let stats = g1.port.layer_stats_snap("gateway").unwrap();
+    assert_eq!(stats.out_stateless, 0);
+
+    // Each packet which misses the UFT is matched against the rules
+    // anew.
+    g1.port.clear_uft().unwrap();
+    update!(g1, ["set:uft.out=0"]);
+    let mut pkt2 = udp_pkt();
+    let res = g1.port.process(Out, &mut pkt2, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(g1, ["uft.out", "stats.port.out_modified, stats.port.out_uft_miss"]);
+    let stats = g1.port.layer_stats_snap("firewall").unwrap();
+    assert_eq!(stats.out_stateless, 2);
+    assert_eq!(stats.out_lft_miss, 2);
+
+    // ================================================================
+    // The TCP traffic is allowed by the stateful default action,
+    // creating a flow.
+    // ================================================================
+    let mut pkt3 = http_syn(&g1_cfg, &g2_cfg);
+    let res = g1.port.process(Out, &mut pkt3, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(
+        g1,
+        [
+            "firewall.flows.out, firewall.flows.in",
+            "uft.out",
+            "stats.port.out_modified, stats.port.out_uft_miss"
+        ]
+    );
+    let stats = g1.port.layer_stats_snap("firewall").unwrap();
+    assert_eq!(stats.out_stateless, 2);
+    assert_eq!(stats.out_stateful, 1);
+}
@@ -22,10 +22,15 @@ use opte::api::OpteError;
 use opte::ddi::time::Moment;
 use opte::engine::arp::ArpEthIpv4;
 use opte::engine::arp::ArpEthIpv4Raw;
+use opte::engine::capture::CaptureConfig;
+use opte::engine::capture::CapturePoint;
+use opte::engine::capture::CAPTURE_DUMP_MAX;
 use opte::engine::dhcpv6;
 use opte::engine::ether::EtherHdr;
 use opte::engine::ether::EtherHdrRaw;
 use opte::engine::ether::EtherMeta;
+use opte::engine::flow_record::FlowEndReason;
+use opte::engine::flow_record::FlowVerdict;
 use opte::engine::flow_table::FLOW_DEF_EXPIRE_SECS;
 use opte::engine::geneve::Vni;
 use opte::engine::headers::EncapMeta;
@@ -37,26 +42,42 @@ use opte::engine::ip4::Ipv4Meta;
 use opte::engine::ip4::Protocol;
 use opte::engine::ip6::Ipv6Hdr;
 use opte::engine::ip6::Ipv6Meta;
+use opte::engine::layer::LayerError;
+use opte::engine::mirror::MirrorTarget;
+use opte::engine::packet::InnerFlowId;
 use opte::engine::packet::Packet;
 use opte::engine::packet::PacketRead;
 use opte::engine::packet::ParseError;
 use opte::engine::packet::Parsed;
 use opte::engine::port::ProcessError;
+use opte::engine::rule::GenDescError;
+use opte::engine::rule::MappingResource;
+use opte::engine::tcp::TcpFlags;
 use opte::engine::tcp::TcpState;
 use opte::engine::udp::UdpMeta;
+use oxide_vpc::api::Filters;
 use oxide_vpc::api::FirewallRule;
+use oxide_vpc::api::MirrorCfg;
+use oxide_vpc::api::MirrorDest;
+use oxide_vpc::api::SetMirrorsReq;
+use oxide_vpc::api::V2pMiss;
 use oxide_vpc::api::VpcCfg;
 use smoltcp::phy::ChecksumCapabilities as CsumCapab;
+use smoltcp::wire::Icmpv4Message;
 use smoltcp::wire::Icmpv4Packet;
 use smoltcp::wire::Icmpv4Repr;
+use smoltcp::wire::Icmpv6Message;
 use smoltcp::wire::Icmpv6Packet;
 use smoltcp::wire::Icmpv6Repr;
 use smoltcp::wire::IpAddress;
 use smoltcp::wire::Ipv6Address;
+use smoltcp::wire::Ipv6Packet;
 use smoltcp::wire::NdiscNeighborFlags;
 use smoltcp::wire::NdiscRepr;
 use smoltcp::wire::NdiscRouterFlags;
 use smoltcp::wire::RawHardwareAddress;
+use smoltcp::wire::TcpPacket;
+use smoltcp::wire::UdpPacket;
 use std::prelude::v1::*;
 use std::time::Duration;
 use zerocopy::AsBytes;
@@ -83,8 +104,10 @@ fn lab_cfg() -> VpcCfg {
         snat: Some(SNat4Cfg {
             external_ip: "76.76.21.21".parse().unwrap(),
             ports: 1025..=4096,
+            mapping: NatMapping::PerFlow,
+            max_ports: None,
         }),
-        external_ips: None,
+        external_ips: vec![],
     });
     VpcCfg {
         ip_cfg,
@@ -101,16 +124,14 @@ fn lab_cfg() -> VpcCfg {
         phys_ip: Ipv6Addr::from([
             0xFD00, 0x0000, 0x00F7, 0x0101, 0x0000, 0x0000, 0x0000, 0x0001,
         ]),
-        boundary_services: BoundaryServices {
+        boundary_services: vec![BoundaryServices {
             mac: MacAddr::from([0xA8, 0x40, 0x25, 0x77, 0x77, 0x77]),
             ip: Ipv6Addr::from([
                 0xFD, 0x00, 0x11, 0x22, 0x33, 0x44, 0x01, 0xFF, 0x00, 0x00,
                 0x00, 0x00, 0x00, 0x00, 0x77, 0x77,
             ]),
             vni: Vni::new(99u32).unwrap(),
-        },
-        proxy_arp_enable: false,
-        phys_gw_mac: Some(MacAddr::from([0x78, 0x23, 0xae, 0x5d, 0x4f, 0x0d])),
+        }],
     }
 }
 
@@ -343,6 +364,193 @@ fn add_remove_fw_rule() {
     update!(g1, ["incr:epoch", "decr:firewall.rules.in"]);
 }
 
+// Verify that firewall rules may filter on both IPv4 and IPv6
+// hosts.
+#[test]
+fn fw_rule_ipv4_ipv6_hosts() {
+    let g1_cfg = g1_cfg();
+    let g2_cfg = g2_cfg();
+    let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
+    g1.vpc_map.add(g2_cfg.ipv4().private_ip.into(), g2_cfg.phys_addr());
+    g1.port.start();
+    set!(g1, "port_state=running");
+
+    let rules = [
+        "dir=out action=deny priority=10 protocol=TCP \
+         hosts=subnet=172.30.0.0/24",
+        "dir=out action=deny priority=10 protocol=TCP hosts=ip=fd00::6",
+    ];
+    for rule in rules {
+        firewall::add_fw_rule(
+            &g1.port,
+            &AddFwRuleReq {
+                port_name: g1.port.name().to_string(),
+                rule: rule.parse().unwrap(),
+            },
+        )
+        .unwrap();
+        incr!(g1, ["epoch", "firewall.rules.out"]);
+    }
+
+    // ================================================================
+    // An IPv4 packet to a host in the denied subnet.
+    // ================================================================
+    let mut pkt1 = http_syn(&g1_cfg, &g2_cfg);
+    let res = g1.port.process(Out, &mut pkt1, ActionMeta::new());
+    assert_drop!(
+        res,
+        DropReason::Layer { name: "firewall", reason: DenyReason::Rule }
+    );
+    incr!(
+        g1,
+        [
+            "stats.port.out_drop, stats.port.out_drop_layer",
+            "stats.port.out_uft_miss",
+        ]
+    );
+
+    // ================================================================
+    // An IPv6 packet to the denied host.
+    // ================================================================
+    let tcp = TcpMeta {
+        src: 44490,
+        dst: 80,
+        flags: TcpFlags::SYN,
+        seq: 2382112979,
+        ..Default::default()
+    };
+    let ip6 = Ipv6Meta {
+        src: g1_cfg.ipv6_cfg().unwrap().private_ip,
+        dst: "fd00::6".parse().unwrap(),
+        proto: Protocol::TCP,
+        next_hdr: IpProtocol::Tcp,
+        hop_limit: 64,
+        pay_len: tcp.hdr_len() as u16,
+        ..Default::default()
+    };
+    let eth = EtherMeta {
+        ether_type: EtherType::Ipv6,
+        src: g1_cfg.guest_mac,
+        dst: g1_cfg.gateway_mac,
+    };
+    let mut pkt2 = ulp_pkt(eth, ip6, tcp, &[]);
+    let res = g1.port.process(Out, &mut pkt2, ActionMeta::new());
+    assert_drop!(
+        res,
+        DropReason::Layer { name: "firewall", reason: DenyReason::Rule }
+    );
+    incr!(
+        g1,
+        [
+            "stats.port.out_drop, stats.port.out_drop_layer",
+            "stats.port.out_uft_miss",
+        ]
+    );
+}
+
+// Verify that firewall rules may filter on port ranges and ICMP
+// message types.
+#[test]
+fn fw_rule_port_range_icmp_type() {
+    let g1_cfg = g1_cfg();
+    let g2_cfg = g2_cfg();
+    let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
+    g1.vpc_map.add(g2_cfg.ipv4().private_ip.into(), g2_cfg.phys_addr());
+    g1.port.start();
+    set!(g1, "port_state=running");
+
+    let rules = [
+        "dir=out action=deny priority=10 protocol=TCP port=22,70-90",
+        "dir=out action=deny priority=10 protocol=icmp:8/0",
+    ];
+    for rule in rules {
+        firewall::add_fw_rule(
+            &g1.port,
+            &AddFwRuleReq {
+                port_name: g1.port.name().to_string(),
+                rule: rule.parse().unwrap(),
+            },
+        )
+        .unwrap();
+        incr!(g1, ["epoch", "firewall.rules.out"]);
+    }
+
+    // ================================================================
+    // Port 80 falls in the denied range, port 23 does not.
+    // ================================================================
+    let mut pkt1 = http_syn(&g1_cfg, &g2_cfg);
+    let res = g1.port.process(Out, &mut pkt1, ActionMeta::new());
+    assert_drop!(
+        res,
+        DropReason::Layer { name: "firewall", reason: DenyReason::Rule }
+    );
+    incr!(
+        g1,
+        [
+            "stats.port.out_drop, stats.port.out_drop_layer",
+            "stats.port.out_uft_miss",
+        ]
+    );
+
+    let mut pkt2 = tcp_telnet_syn(&g1_cfg, &g2_cfg);
+    let res = g1.port.process(Out, &mut pkt2, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(
+        g1,
+        [
+            "firewall.flows.out, firewall.flows.in",
+            "uft.out",
+            "stats.port.out_modified, stats.port.out_uft_miss",
+        ]
+    );
+
+    // ================================================================
+    // An Echo Request is denied, an Echo Reply is not.
+    // ================================================================
+    let data = b"reunion\0";
+    let mut pkt3 = gen_icmp_echo_req(
+        g1_cfg.guest_mac,
+        g1_cfg.gateway_mac,
+        g1_cfg.ipv4().private_ip.into(),
+        g2_cfg.ipv4().private_ip.into(),
+        7,
+        1,
+        &data[..],
+    );
+    let res = g1.port.process(Out, &mut pkt3, ActionMeta::new());
+    assert_drop!(
+        res,
+        DropReason::Layer { name: "firewall", reason: DenyReason::Rule }
+    );
+    incr!(
+        g1,
+        [
+            "stats.port.out_drop, stats.port.out_drop_layer",
+            "stats.port.out_uft_miss",
+        ]
+    );
+
+    let mut pkt4 = gen_icmp_echo_reply(
+        g1_cfg.guest_mac,
+        g1_cfg.gateway_mac,
+        g1_cfg.ipv4().private_ip,
+        g2_cfg.ipv4().private_ip,
+        7,
+        1,
+        &data[..],
+    );
+    let res = g1.port.process(Out, &mut pkt4, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(
+        g1,
+        [
+            "firewall.flows.out, firewall.flows.in",
+            "uft.out",
+            "stats.port.out_modified, stats.port.out_uft_miss",
+        ]
+    );
+}
+
 // Verify that the guest can ping the virtual gateway.
 #[test]
 fn gateway_icmp4_ping() {
@@ -428,95 +636,153 @@ fn gateway_icmp4_ping() {
     }
 }
 
-// Try to send a TCP packet from one guest to another; but in this
-// case the guest has not route to the other guest, resulting in the
-// packet being dropped.
+// Verify that router entries can be listed and deleted, and that a
+// deletion must match both the destination and the target.
 #[test]
-fn guest_to_guest_no_route() {
+fn router_list_and_del_entries() {
     let g1_cfg = g1_cfg();
-    let g2_cfg = g2_cfg();
     let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
-    g1.vpc_map.add(g2_cfg.ipv4().private_ip.into(), g2_cfg.phys_addr());
     g1.port.start();
     set!(g1, "port_state=running");
-    // Make sure the router is configured to drop all packets.
-    router::del_entry(
-        &g1.port,
-        IpCidr::Ip4(g1_cfg.ipv4().vpc_subnet),
-        RouterTarget::VpcSubnet(IpCidr::Ip4(g1_cfg.ipv4().vpc_subnet)),
-    )
-    .unwrap();
-    update!(g1, ["incr:epoch", "set:router.rules.out=0"]);
-    let mut pkt1 = http_syn(&g1_cfg, &g2_cfg);
-    let res = g1.port.process(Out, &mut pkt1, ActionMeta::new());
-    assert_drop!(
-        res,
-        DropReason::Layer { name: "router", reason: DenyReason::Default }
+
+    let subnet = IpCidr::Ip4(g1_cfg.ipv4().vpc_subnet);
+    let subnet_entry =
+        RouterEntry::new(subnet, RouterTarget::VpcSubnet(subnet));
+    let resp = router::list_entries(&g1.port).unwrap();
+    assert_eq!(resp.entries, vec![subnet_entry]);
+
+    let default = IpCidr::Ip4("0.0.0.0/0".parse().unwrap());
+    let ig_entry = RouterEntry::new(default, RouterTarget::InternetGateway);
+    let ip_dest = IpCidr::Ip4("192.168.77.0/24".parse().unwrap());
+    let ip_entry = RouterEntry::new(
+        ip_dest,
+        RouterTarget::Ip("172.30.0.1".parse().unwrap()),
     );
+    let drop_dest = IpCidr::Ip4("192.168.77.7/32".parse().unwrap());
+    let drop_entry = RouterEntry::new(drop_dest, RouterTarget::Drop);
 
-    // XXX The firewall layer comes before the router layer (in the
-    // outbound direction). The firewall layer allows this traffic;
-    // and a flow is created, regardless of the fact that a later
-    // layer decides to drop the packet. This means that a flow could
-    // take up space in some of the layers even though no traffic can
-    // actually flow through it. In the future it would be better to
-    // have a way to send "simulated" flow through the layer pipeline
-    // for the effect of removing it from any flow tables in which it
-    // exists.
-    incr!(
-        g1,
-        [
-            "firewall.flows.out, firewall.flows.in",
-            "stats.port.out_drop, stats.port.out_drop_layer",
-            "stats.port.out_uft_miss",
+    for entry in [ig_entry.clone(), ip_entry.clone(), drop_entry.clone()] {
+        router::add_entry(&g1.port, entry.dest, entry.target).unwrap();
+        incr!(g1, ["epoch", "router.rules.out"]);
+    }
+
+    // The entries are listed in order of priority: longest prefix
+    // first.
+    let resp = router::list_entries(&g1.port).unwrap();
+    assert_eq!(
+        resp.entries,
+        vec![
+            drop_entry.clone(),
+            ip_entry.clone(),
+            subnet_entry.clone(),
+            ig_entry.clone()
         ]
     );
+
+    // ================================================================
+    // An entry with the same destination but another target is not
+    // deleted.
+    // ================================================================
+    let res = router::del_entry(&g1.port, ip_dest, RouterTarget::Drop).unwrap();
+    assert!(matches!(res, DelRouterEntryResp::NotFound));
+    assert_eq!(router::list_entries(&g1.port).unwrap().entries.len(), 4);
+
+    let res =
+        router::del_entry(&g1.port, ip_entry.dest, ip_entry.target).unwrap();
+    assert!(matches!(res, DelRouterEntryResp::Ok));
+    update!(g1, ["incr:epoch", "decr:router.rules.out"]);
+
+    let resp = router::list_entries(&g1.port).unwrap();
+    assert_eq!(resp.entries, vec![drop_entry, subnet_entry, ig_entry]);
 }
 
-// Verify that two guests on the same VPC can communicate.
+// Verify that an ECMP route picks a next hop per flow, that the
+// choice is stable, and that removing a member only moves the flows
+// which were using it.
 #[test]
-fn guest_to_guest() {
+fn router_ecmp_next_hop() {
     let g1_cfg = g1_cfg();
     let g2_cfg = g2_cfg();
     let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
-    g1.vpc_map.add(g2_cfg.ipv4().private_ip.into(), g2_cfg.phys_addr());
     g1.port.start();
     set!(g1, "port_state=running");
-    let mut g2 = oxide_net_setup("g2_port", &g2_cfg, Some(g1.vpc_map.clone()));
-    g2.port.start();
-    set!(g2, "port_state=running");
 
-    // Allow incoming TCP connection from anyone.
-    let rule = "dir=in action=allow priority=10 protocol=TCP";
-    firewall::add_fw_rule(
-        &g2.port,
-        &AddFwRuleReq {
-            port_name: g2.port.name().to_string(),
-            rule: rule.parse().unwrap(),
-        },
-    )
-    .unwrap();
-    incr!(g2, ["epoch", "firewall.rules.in"]);
+    let members: Vec<IpAddr> = ["172.30.0.20", "172.30.0.21", "172.30.0.22"]
+        .iter()
+        .map(|ip| IpAddr::Ip4(ip.parse().unwrap()))
+        .collect();
+    let physs: Vec<PhysNet> = ["fd77::20", "fd77::21", "fd77::22"]
+        .iter()
+        .enumerate()
+        .map(|(i, ip)| PhysNet {
+            ether: ox_vpc_mac([0xF0, 0x00, 0x20 + i as u8]),
+            ip: ip.parse().unwrap(),
+            vni: g1_cfg.vni,
+        })
+        .collect();
+    for (ip, phys) in members.iter().zip(physs.iter()) {
+        g1.vpc_map.add(*ip, *phys);
+    }
 
-    let mut pcap_guest1 =
-        PcapBuilder::new("overlay_guest_to_guest-guest-1.pcap");
-    let mut pcap_phys1 = PcapBuilder::new("overlay_guest_to_guest-phys-1.pcap");
+    // ================================================================
+    // The next hop only depends on the flow and the set of members,
+    // not on the order of the members.
+    // ================================================================
+    let flows: Vec<InnerFlowId> = (0..64)
+        .map(|i| InnerFlowId {
+            proto: Protocol::TCP,
+            src_ip: g1_cfg.ipv4().private_ip.into(),
+            src_port: 44490 + i,
+            dst_ip: g2_cfg.ipv4().private_ip.into(),
+            dst_port: 80,
+        })
+        .collect();
+    let mut reversed = members.clone();
+    reversed.reverse();
+    let picks: Vec<IpAddr> = flows
+        .iter()
+        .map(|flow| router::ecmp_select(flow, &members).unwrap())
+        .collect();
+    for (flow, pick) in flows.iter().zip(picks.iter()) {
+        assert_eq!(router::ecmp_select(flow, &members), Some(*pick));
+        assert_eq!(router::ecmp_select(flow, &reversed), Some(*pick));
+    }
 
-    let mut pcap_guest2 =
-        PcapBuilder::new("overlay_guest_to_guest-guest-2.pcap");
-    let mut pcap_phys2 = PcapBuilder::new("overlay_guest_to_guest-phys-2.pcap");
+    // Every member gets some of the flows.
+    for member in &members {
+        assert!(picks.contains(member), "no flows for {}", member);
+    }
 
-    let mut pkt1 = http_syn(&g1_cfg, &g2_cfg);
-    pcap_guest1.add_pkt(&pkt1);
-    let ulp_csum_b4 = pkt1.meta().inner.ulp.unwrap().csum();
-    let ip_csum_b4 = pkt1.meta().inner.ip.unwrap().csum();
+    // ================================================================
+    // Removing a member only moves the flows which were using it.
+    // ================================================================
+    let remaining = vec![members[0], members[2]];
+    for (flow, pick) in flows.iter().zip(picks.iter()) {
+        let new_pick = router::ecmp_select(flow, &remaining).unwrap();
+        if *pick == members[1] {
+            assert!(remaining.contains(&new_pick));
+        } else {
+            assert_eq!(new_pick, *pick);
+        }
+    }
 
     // ================================================================
-    // Run the packet through g1's port in the outbound direction and
-    // verify the resulting packet meets expectations.
+    // Route g2 across the members, and verify the packet is
+    // encapsulated to the underlay address of the chosen member.
     // ================================================================
+    let dest = IpCidr::Ip4(
+        format!("{}/32", g2_cfg.ipv4().private_ip).parse().unwrap(),
+    );
+    let target = RouterTarget::Ecmp(members.clone());
+    router::add_entry(&g1.port, dest, target.clone()).unwrap();
+    incr!(g1, ["epoch", "router.rules.out"]);
+
+    let resp = router::list_entries(&g1.port).unwrap();
+    assert_eq!(resp.entries[0], RouterEntry::new(dest, target));
+
+    let mut pkt1 = http_syn(&g1_cfg, &g2_cfg);
+    let pick = router::ecmp_select(pkt1.flow(), &members).unwrap();
     let res = g1.port.process(Out, &mut pkt1, ActionMeta::new());
-    pcap_phys1.add_pkt(&pkt1);
     assert!(matches!(res, Ok(Modified)));
     incr!(
         g1,
@@ -527,175 +793,2057 @@ fn guest_to_guest() {
         ]
     );
 
-    assert_eq!(pkt1.body_offset(), VPC_ENCAP_SZ + TCP_SZ + HTTP_SYN_OPTS_LEN);
-    assert_eq!(pkt1.body_seg(), 1);
-    let ulp_csum_after = pkt1.meta().inner.ulp.unwrap().csum();
-    let ip_csum_after = pkt1.meta().inner.ip.unwrap().csum();
-    assert_eq!(ulp_csum_after, ulp_csum_b4);
-    assert_eq!(ip_csum_after, ip_csum_b4);
-
-    let meta = pkt1.meta();
-    match meta.outer.ether.as_ref() {
-        Some(eth) => {
-            assert_eq!(eth.src, MacAddr::ZERO);
-            assert_eq!(eth.dst, MacAddr::ZERO);
-        }
-
-        None => panic!("no outer ether header"),
-    }
-
-    match meta.outer.ip.as_ref().unwrap() {
+    let idx = members.iter().position(|ip| *ip == pick).unwrap();
+    match pkt1.meta().outer.ip.as_ref().unwrap() {
         IpMeta::Ip6(ip6) => {
             assert_eq!(ip6.src, g1_cfg.phys_ip);
-            assert_eq!(ip6.dst, g2_cfg.phys_ip);
+            assert_eq!(ip6.dst, physs[idx].ip);
         }
 
         val => panic!("expected outer IPv6, got: {:?}", val),
     }
 
-    match meta.outer.encap.as_ref() {
-        Some(EncapMeta::Geneve(geneve)) => {
-            assert_eq!(geneve.entropy, 7777);
-            assert_eq!(geneve.vni, Vni::new(g1_cfg.vni).unwrap());
-        }
-
-        None => panic!("expected outer Geneve metadata"),
-    }
+    // ================================================================
+    // The route is deleted by its set of members, whatever order
+    // they are given in.
+    // ================================================================
+    let res =
+        router::del_entry(&g1.port, dest, RouterTarget::Ecmp(reversed.clone()))
+            .unwrap();
+    assert!(matches!(res, DelRouterEntryResp::Ok));
+    update!(g1, ["incr:epoch", "decr:router.rules.out"]);
+    let res = router::del_entry(&g1.port, dest, RouterTarget::Ecmp(reversed))
+        .unwrap();
+    assert!(matches!(res, DelRouterEntryResp::NotFound));
+}
 
-    let eth = meta.inner.ether;
-    assert_eq!(eth.src, g1_cfg.guest_mac);
-    assert_eq!(eth.dst, g2_cfg.guest_mac);
+// Verify that a policy entry steers HTTP traffic from g1 through an
+// appliance, while the rest of its traffic to g2 still follows the
+// plain subnet entry.
+#[test]
+fn router_policy_entry() {
+    let g1_cfg = g1_cfg();
+    let g2_cfg = g2_cfg();
+    let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
+    g1.vpc_map.add(g2_cfg.ipv4().private_ip.into(), g2_cfg.phys_addr());
+    let appliance = IpAddr::Ip4("172.30.0.30".parse().unwrap());
+    let appliance_phys = PhysNet {
+        ether: ox_vpc_mac([0xF0, 0x00, 0x30]),
+        ip: "fd77::30".parse().unwrap(),
+        vni: g1_cfg.vni,
+    };
+    g1.vpc_map.add(appliance, appliance_phys);
+    g1.port.start();
+    set!(g1, "port_state=running");
+
+    let dest = IpCidr::Ip4(g2_cfg.ipv4().vpc_subnet);
+    let filters = RouteFilters {
+        src: Some(IpCidr::Ip4(
+            format!("{}/32", g1_cfg.ipv4().private_ip).parse().unwrap(),
+        )),
+        protocol: Some(Protocol::TCP),
+        ports: Ports::PortList(vec![80]),
+    };
+
+    // ================================================================
+    // A policy entry must be given a priority.
+    // ================================================================
+    let mut entry = RouterEntry::new(dest, RouterTarget::Ip(appliance));
+    entry.filters = filters.clone();
+    assert!(matches!(
+        router::add_route(&g1.port, entry.clone()),
+        Err(OpteError::InvalidRouterEntry { .. })
+    ));
+
+    entry.priority = Some(5);
+    router::add_route(&g1.port, entry.clone()).unwrap();
+    incr!(g1, ["epoch", "router.rules.out"]);
+
+    let resp = router::list_entries(&g1.port).unwrap();
+    assert_eq!(
+        resp.entries,
+        vec![
+            entry.clone(),
+            RouterEntry::new(dest, RouterTarget::VpcSubnet(dest)),
+        ]
+    );
+
+    // ================================================================
+    // HTTP from g1 goes through the appliance.
+    // ================================================================
+    let mut pkt1 = http_syn(&g1_cfg, &g2_cfg);
+    let res = g1.port.process(Out, &mut pkt1, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(
+        g1,
+        [
+            "firewall.flows.out, firewall.flows.in",
+            "uft.out",
+            "stats.port.out_modified, stats.port.out_uft_miss",
+        ]
+    );
+
+    match pkt1.meta().outer.ip.as_ref().unwrap() {
+        IpMeta::Ip6(ip6) => assert_eq!(ip6.dst, appliance_phys.ip),
+        val => panic!("expected outer IPv6, got: {:?}", val),
+    }
+
+    // ================================================================
+    // Telnet from g1 goes directly to g2.
+    // ================================================================
+    let mut pkt2 = tcp_telnet_syn(&g1_cfg, &g2_cfg);
+    let res = g1.port.process(Out, &mut pkt2, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(
+        g1,
+        [
+            "firewall.flows.out, firewall.flows.in",
+            "uft.out",
+            "stats.port.out_modified, stats.port.out_uft_miss",
+        ]
+    );
+
+    match pkt2.meta().outer.ip.as_ref().unwrap() {
+        IpMeta::Ip6(ip6) => assert_eq!(ip6.dst, g2_cfg.phys_ip),
+        val => panic!("expected outer IPv6, got: {:?}", val),
+    }
+
+    // ================================================================
+    // A policy entry is only deleted by an exact match, including
+    // its filters and priority.
+    // ================================================================
+    let mut query = entry.clone();
+    query.priority = Some(6);
+    let res = router::del_route(&g1.port, query).unwrap();
+    assert!(matches!(res, DelRouterEntryResp::NotFound));
+
+    let mut query = entry.clone();
+    query.filters.ports = Ports::PortList(vec![443]);
+    let res = router::del_route(&g1.port, query).unwrap();
+    assert!(matches!(res, DelRouterEntryResp::NotFound));
+
+    let res = router::del_route(&g1.port, entry).unwrap();
+    assert!(matches!(res, DelRouterEntryResp::Ok));
+    update!(g1, ["incr:epoch", "decr:router.rules.out"]);
+    assert_eq!(router::list_entries(&g1.port).unwrap().entries.len(), 1);
+}
+
+// Verify that a sync replaces all mappings of a VPC, that an older
+// generation is rejected, and that mappings may be deleted.
+#[test]
+fn v2p_sync_and_del() {
+    let g1_cfg = g1_cfg();
+    let g2_cfg = g2_cfg();
+    let vpc_map = VpcMappings::new();
+    let g1_ip = IpAddr::from(g1_cfg.ipv4().private_ip);
+    let g2_ip = IpAddr::from(g2_cfg.ipv4().private_ip);
+    let v2p = vpc_map.add(g1_ip, g1_cfg.phys_addr());
+    vpc_map.add(g2_ip, g2_cfg.phys_addr());
+
+    let appliance = IpAddr::Ip4("172.30.0.30".parse().unwrap());
+    let appliance_phys = GuestPhysAddr {
+        ether: ox_vpc_mac([0xF0, 0x00, 0x30]),
+        ip: "fd77::30".parse().unwrap(),
+    };
+    let mappings = vec![
+        (g1_ip, GuestPhysAddr::from(g1_cfg.phys_addr())),
+        (appliance, appliance_phys),
+    ];
+
+    // ================================================================
+    // The sync replaces g2's mapping, and is seen through the
+    // `Virt2Phys` the ports already hold.
+    // ================================================================
+    let synced = vpc_map.sync(g1_cfg.vni, 2, &mappings).unwrap();
+    assert!(Arc::ptr_eq(&synced, &v2p));
+    assert!(v2p.get(&g2_ip).is_none());
+    assert_eq!(v2p.get(&appliance).unwrap().ip, appliance_phys.ip);
+    assert_eq!(v2p.get(&g1_ip).unwrap().ip, g1_cfg.phys_ip);
+
+    let dump = vpc_map.dump(&DumpVirt2PhysReq::default());
+    assert_eq!(dump.mappings.len(), 1);
+    assert_eq!(dump.mappings[0].gen, 2);
+    assert_eq!(dump.mappings[0].ip4.len(), 2);
+
+    // ================================================================
+    // An older generation is rejected and changes nothing, while the
+    // current one may be repeated.
+    // ================================================================
+    let res = vpc_map.sync(g1_cfg.vni, 1, &[]);
+    assert!(matches!(
+        res,
+        Err(OpteError::StaleGeneration { current: 2, given: 1 })
+    ));
+    assert!(v2p.get(&appliance).is_some());
+
+    vpc_map.sync(g1_cfg.vni, 2, &mappings).unwrap();
+    let dump = vpc_map.dump(&DumpVirt2PhysReq::default());
+    assert_eq!(dump.mappings[0].ip4.len(), 2);
+
+    // ================================================================
+    // Delete the appliance's mapping.
+    // ================================================================
+    let phys = vpc_map.del(&appliance, g1_cfg.vni).unwrap();
+    assert_eq!(phys.ip, appliance_phys.ip);
+    assert!(v2p.get(&appliance).is_none());
+    assert!(vpc_map.del(&appliance, g1_cfg.vni).is_none());
+}
+
+// Verify that a dump of the V2P mappings is paged in order of VPC IP,
+// from the IPv4 mappings on to the IPv6 ones, and limited to the
+// requested VPC.
+#[test]
+fn v2p_dump_pages() {
+    let g1_cfg = g1_cfg();
+    let g2_cfg = g2_cfg();
+    let vpc_map = VpcMappings::new();
+    let phys = GuestPhysAddr::from(g1_cfg.phys_addr());
+    let mut mappings = vec![];
+    for i in 0..5 {
+        let ip4 = Ipv4Addr::from([172, 30, 0, 10 + i]);
+        mappings.push((IpAddr::Ip4(ip4), phys));
+    }
+    for i in 0..3 {
+        let ip6 = Ipv6Addr::from([
+            0xFD,
+            0x00,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0x10 + i,
+        ]);
+        mappings.push((IpAddr::Ip6(ip6), phys));
+    }
+    vpc_map.sync(g1_cfg.vni, 1, &mappings).unwrap();
+
+    // A mapping in another VPC is left out of the dump.
+    let mut other = g2_cfg.phys_addr();
+    other.vni = Vni::new(7777u32).unwrap();
+    vpc_map.add(g2_cfg.ipv4().private_ip.into(), other);
+
+    let mut req =
+        DumpVirt2PhysReq { vni: Some(g1_cfg.vni), after: None, limit: 3 };
+    let mut ip4 = vec![];
+    let mut ip6 = vec![];
+    let mut pages = 0;
+    loop {
+        let mut dump = vpc_map.dump(&req);
+        assert_eq!(dump.mappings.len(), 1);
+        let vpc = dump.mappings.pop().unwrap();
+        assert_eq!(vpc.vni, g1_cfg.vni);
+        assert_eq!(vpc.len, 8);
+        assert!(vpc.ip4.len() + vpc.ip6.len() <= 3);
+        ip4.extend(vpc.ip4);
+        ip6.extend(vpc.ip6);
+        pages += 1;
+
+        match vpc.next {
+            Some(after) => req.after = Some(after),
+            None => break,
+        }
+    }
+
+    assert_eq!(pages, 3);
+    let ip4_want: Vec<_> = mappings[..5]
+        .iter()
+        .map(|(vip, _)| match vip {
+            IpAddr::Ip4(ip4) => *ip4,
+            _ => panic!("expected IPv4 mapping"),
+        })
+        .collect();
+    let ip6_want: Vec<_> = mappings[5..]
+        .iter()
+        .map(|(vip, _)| match vip {
+            IpAddr::Ip6(ip6) => *ip6,
+            _ => panic!("expected IPv6 mapping"),
+        })
+        .collect();
+    assert_eq!(ip4.iter().map(|(vip, _)| *vip).collect::<Vec<_>>(), ip4_want);
+    assert_eq!(ip6.iter().map(|(vip, _)| *vip).collect::<Vec<_>>(), ip6_want);
+}
+
+// Verify that changing the mapping of a flow's destination, as when
+// an instance migrates to another sled, re-resolves the flow on its
+// next packet; while a change to an unrelated mapping leaves the flow
+// alone.
+#[test]
+fn v2p_change_invalidates_flows() {
+    let g1_cfg = g1_cfg();
+    let g2_cfg = g2_cfg();
+    let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
+    g1.vpc_map.add(g2_cfg.ipv4().private_ip.into(), g2_cfg.phys_addr());
+    g1.port.start();
+    set!(g1, "port_state=running");
+
+    let udp_pkt = || {
+        let udp = UdpMeta {
+            src: 5353,
+            dst: 5353,
+            len: UdpHdr::SIZE as u16,
+            ..Default::default()
+        };
+        let ip = Ipv4Meta {
+            src: g1_cfg.ipv4().private_ip,
+            dst: g2_cfg.ipv4().private_ip,
+            proto: Protocol::UDP,
+            ttl: 64,
+            total_len: (Ipv4Hdr::BASE_SIZE + UdpHdr::SIZE) as u16,
+            ..Default::default()
+        };
+        let eth = EtherMeta {
+            ether_type: EtherType::Ipv4,
+            src: g1_cfg.guest_mac,
+            dst: g1_cfg.gateway_mac,
+        };
+        ulp_pkt(eth, ip, udp, &[])
+    };
+
+    let outer_dst = |pkt: &Packet<Parsed>| match pkt.meta().outer.ip.as_ref() {
+        Some(IpMeta::Ip6(ip6)) => ip6.dst,
+        val => panic!("expected outer IPv6, got: {:?}", val),
+    };
+
+    let mut pkt1 = udp_pkt();
+    let res = g1.port.process(Out, &mut pkt1, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(
+        g1,
+        [
+            "firewall.flows.out, firewall.flows.in",
+            "uft.out",
+            "stats.port.out_modified, stats.port.out_uft_miss",
+        ]
+    );
+    assert_eq!(outer_dst(&pkt1), g2_cfg.phys_ip);
+
+    // ================================================================
+    // A change to another mapping leaves the flow's UFT entry in
+    // place.
+    // ================================================================
+    let other = PhysNet {
+        ether: ox_vpc_mac([0xF0, 0x00, 0x30]),
+        ip: "fd77::30".parse().unwrap(),
+        vni: g1_cfg.vni,
+    };
+    g1.vpc_map.add(IpAddr::Ip4("172.30.0.30".parse().unwrap()), other);
+
+    let mut pkt2 = udp_pkt();
+    let res = g1.port.process(Out, &mut pkt2, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(g1, ["stats.port.out_modified, stats.port.out_uft_hit"]);
+    assert_eq!(outer_dst(&pkt2), g2_cfg.phys_ip);
+
+    // ================================================================
+    // g2 moves to another sled: the flow is re-resolved by the layers
+    // and sent to the new sled, without moving the port's epoch or
+    // touching the firewall's flows.
+    // ================================================================
+    let moved =
+        PhysNet { ip: "fd77::66".parse().unwrap(), ..g2_cfg.phys_addr() };
+    g1.vpc_map.add(g2_cfg.ipv4().private_ip.into(), moved);
+
+    let mut pkt3 = udp_pkt();
+    let res = g1.port.process(Out, &mut pkt3, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(g1, ["stats.port.out_modified, stats.port.out_uft_miss"]);
+    assert_eq!(outer_dst(&pkt3), moved.ip);
+
+    // The new transform is used from then on.
+    let mut pkt4 = udp_pkt();
+    let res = g1.port.process(Out, &mut pkt4, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(g1, ["stats.port.out_modified, stats.port.out_uft_hit"]);
+    assert_eq!(outer_dst(&pkt4), moved.ip);
+
+    // ================================================================
+    // Once the mapping is gone, the flow's packets are dropped.
+    // ================================================================
+    g1.vpc_map.del(&g2_cfg.ipv4().private_ip.into(), g1_cfg.vni);
+    let mut pkt5 = udp_pkt();
+    let res = g1.port.process(Out, &mut pkt5, ActionMeta::new());
+    assert_drop!(
+        res,
+        DropReason::Layer { name: "overlay", reason: DenyReason::Action }
+    );
+    update!(
+        g1,
+        [
+            "decr:uft.out",
+            "incr:stats.port.out_drop, stats.port.out_drop_layer",
+            "incr:stats.port.out_uft_miss",
+        ]
+    );
+}
+
+// Verify that a packet to a VPC IP without a mapping reports a miss
+// to the resolver, which answers with the mapping.
+#[test]
+fn v2p_miss_upcall() {
+    let g1_cfg = g1_cfg();
+    let g2_cfg = g2_cfg();
+    let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
+    g1.port.start();
+    set!(g1, "port_state=running");
+
+    let udp_pkt = |dst: Ipv4Addr| {
+        let udp = UdpMeta {
+            src: 5353,
+            dst: 5353,
+            len: UdpHdr::SIZE as u16,
+            ..Default::default()
+        };
+        let ip = Ipv4Meta {
+            src: g1_cfg.ipv4().private_ip,
+            dst,
+            proto: Protocol::UDP,
+            ttl: 64,
+            total_len: (Ipv4Hdr::BASE_SIZE + UdpHdr::SIZE) as u16,
+            ..Default::default()
+        };
+        let eth = EtherMeta {
+            ether_type: EtherType::Ipv4,
+            src: g1_cfg.guest_mac,
+            dst: g1_cfg.gateway_mac,
+        };
+        ulp_pkt(eth, ip, udp, &[])
+    };
+
+    let misses = g1.vpc_map.misses();
+    assert!(misses.read(false).unwrap().misses.is_empty());
+
+    // ================================================================
+    // A stand-in for the resolver daemon: it waits for misses and
+    // answers those it finds in its directory.
+    // ================================================================
+    let g2_vip = IpAddr::from(g2_cfg.ipv4().private_ip);
+    let directory = vec![(g2_vip, g2_cfg.phys_addr())];
+    let vpc_map = g1.vpc_map.clone();
+    let resolver = std::thread::spawn(move || {
+        let resp = vpc_map.misses().read(true).unwrap();
+
+        for miss in &resp.misses {
+            let found = directory
+                .iter()
+                .find(|(vip, phys)| *vip == miss.vip && phys.vni == miss.vni);
+
+            if let Some((vip, phys)) = found {
+                vpc_map.add(*vip, *phys);
+            }
+        }
+
+        resp.misses
+    });
+
+    let mut pkt1 = udp_pkt(g2_cfg.ipv4().private_ip);
+    let res = g1.port.process(Out, &mut pkt1, ActionMeta::new());
+    assert_drop!(
+        res,
+        DropReason::Layer { name: "overlay", reason: DenyReason::Action }
+    );
+    incr!(
+        g1,
+        [
+            "firewall.flows.out, firewall.flows.in",
+            "stats.port.out_drop, stats.port.out_drop_layer",
+            "stats.port.out_uft_miss",
+        ]
+    );
+
+    let answered = resolver.join().unwrap();
+    assert_eq!(answered, vec![V2pMiss { vni: g1_cfg.vni, vip: g2_vip }]);
+
+    // The guest's retransmission finds the mapping.
+    let mut pkt2 = udp_pkt(g2_cfg.ipv4().private_ip);
+    let res = g1.port.process(Out, &mut pkt2, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(g1, ["uft.out", "stats.port.out_modified, stats.port.out_uft_miss"]);
+    match pkt2.meta().outer.ip.as_ref() {
+        Some(IpMeta::Ip6(ip6)) => assert_eq!(ip6.dst, g2_cfg.phys_ip),
+        val => panic!("expected outer IPv6, got: {:?}", val),
+    }
+
+    // ================================================================
+    // Repeated misses of the same IP are queued only once while the
+    // resolver has yet to answer.
+    // ================================================================
+    let unknown: Ipv4Addr = "172.30.0.99".parse().unwrap();
+    let mut pkt3 = udp_pkt(unknown);
+    let res = g1.port.process(Out, &mut pkt3, ActionMeta::new());
+    assert_drop!(
+        res,
+        DropReason::Layer { name: "overlay", reason: DenyReason::Action }
+    );
+    incr!(
+        g1,
+        [
+            "firewall.flows.out, firewall.flows.in",
+            "stats.port.out_drop, stats.port.out_drop_layer",
+            "stats.port.out_uft_miss",
+        ]
+    );
+
+    let mut pkt4 = udp_pkt(unknown);
+    let res = g1.port.process(Out, &mut pkt4, ActionMeta::new());
+    assert_drop!(
+        res,
+        DropReason::Layer { name: "overlay", reason: DenyReason::Action }
+    );
+    incr!(
+        g1,
+        [
+            "stats.port.out_drop, stats.port.out_drop_layer",
+            "stats.port.out_uft_miss",
+        ]
+    );
+
+    let resp = misses.read(false).unwrap();
+    assert_eq!(
+        resp.misses,
+        vec![V2pMiss { vni: g1_cfg.vni, vip: IpAddr::from(unknown) }]
+    );
+    assert_eq!(resp.dropped, 0);
+    assert!(misses.read(false).unwrap().misses.is_empty());
+}
+
+// Verify that a packet held awaiting a V2P mapping, once processed
+// anew, is neither captured before processing nor accounted to its
+// flow a second time.
+#[test]
+fn v2p_held_reprocess() {
+    let g1_cfg = g1_cfg();
+    let g2_cfg = g2_cfg();
+    let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
+    g1.port.start();
+    set!(g1, "port_state=running");
+    g1.port.set_capture(Some(CaptureConfig::default()));
+
+    let udp = UdpMeta {
+        src: 5353,
+        dst: 5353,
+        len: UdpHdr::SIZE as u16,
+        ..Default::default()
+    };
+    let ip = Ipv4Meta {
+        src: g1_cfg.ipv4().private_ip,
+        dst: g2_cfg.ipv4().private_ip,
+        proto: Protocol::UDP,
+        ttl: 64,
+        total_len: (Ipv4Hdr::BASE_SIZE + UdpHdr::SIZE) as u16,
+        ..Default::default()
+    };
+    let eth = EtherMeta {
+        ether_type: EtherType::Ipv4,
+        src: g1_cfg.guest_mac,
+        dst: g1_cfg.gateway_mac,
+    };
+
+    // ================================================================
+    // The packet is dropped by the overlay for lack of a mapping; the
+    // consumer holds on to its bytes.
+    // ================================================================
+    let mut pkt1 = ulp_pkt(eth, ip, udp, &[]);
+    let guest_flow = *pkt1.flow();
+    let len = pkt1.len() as u64;
+    let held = pkt1.all_bytes();
+    let res = g1.port.process(Out, &mut pkt1, ActionMeta::new());
+    assert_drop!(
+        res,
+        DropReason::Layer { name: "overlay", reason: DenyReason::Action }
+    );
+    incr!(
+        g1,
+        [
+            "firewall.flows.out, firewall.flows.in",
+            "stats.port.out_drop, stats.port.out_drop_layer",
+            "stats.port.out_uft_miss",
+        ]
+    );
+
+    let dump = g1.port.dump_capture(0, CAPTURE_DUMP_MAX).unwrap();
+    assert_eq!(dump.pkts.len(), 2);
+    assert_eq!(dump.pkts[0].point, CapturePoint::Guest);
+    assert_eq!(dump.pkts[1].point, CapturePoint::Drop);
+
+    // ================================================================
+    // Reprocessing the held packet while the mapping is still missing
+    // drops it again, without capturing it.
+    // ================================================================
+    let mut pkt2 = Packet::copy(&held).parse(Out, VpcParser::new()).unwrap();
+    let res = g1.port.reprocess(Out, &mut pkt2, ActionMeta::new());
+    assert_drop!(
+        res,
+        DropReason::Layer { name: "overlay", reason: DenyReason::Action }
+    );
+    incr!(
+        g1,
+        [
+            "stats.port.out_drop, stats.port.out_drop_layer",
+            "stats.port.out_uft_miss",
+        ]
+    );
+    assert_eq!(
+        g1.port.dump_capture(0, CAPTURE_DUMP_MAX).unwrap().pkts.len(),
+        2
+    );
+
+    // ================================================================
+    // Once the mapping is added, reprocessing sends the packet on:
+    // only its encapsulated form is captured.
+    // ================================================================
+    g1.vpc_map.add(g2_cfg.ipv4().private_ip.into(), g2_cfg.phys_addr());
+    let mut pkt3 = Packet::copy(&held).parse(Out, VpcParser::new()).unwrap();
+    let res = g1.port.reprocess(Out, &mut pkt3, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(g1, ["uft.out", "stats.port.out_modified, stats.port.out_uft_miss"]);
+
+    let dump = g1.port.dump_capture(0, CAPTURE_DUMP_MAX).unwrap();
+    assert_eq!(dump.pkts.len(), 3);
+    assert_eq!(dump.pkts[2].point, CapturePoint::Underlay);
+    assert_eq!(&dump.pkts[2].bytes, &pkt3.all_bytes());
+
+    // ================================================================
+    // The flow's record counts the packet once, with the verdict of
+    // its last processing.
+    // ================================================================
+    let now = Moment::now();
+    g1.port
+        .expire_flows(now + Duration::new(FLOW_DEF_EXPIRE_SECS as u64, 0))
+        .unwrap();
+    zero_flows!(g1);
+
+    let dump = g1.port.dump_flow_records(None);
+    assert_eq!(dump.records.len(), 1);
+    let rec = &dump.records[0];
+    assert_eq!(rec.guest_flow, guest_flow);
+    assert_eq!(rec.counters.pkts_out, 1);
+    assert_eq!(rec.counters.bytes_out, len);
+    assert_eq!(rec.verdict, FlowVerdict::Allow);
+}
+
+// Try to send a TCP packet from one guest to another; but in this
+// case the guest has not route to the other guest, resulting in the
+// packet being dropped.
+#[test]
+fn guest_to_guest_no_route() {
+    let g1_cfg = g1_cfg();
+    let g2_cfg = g2_cfg();
+    let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
+    g1.vpc_map.add(g2_cfg.ipv4().private_ip.into(), g2_cfg.phys_addr());
+    g1.port.start();
+    set!(g1, "port_state=running");
+    // Make sure the router is configured to drop all packets.
+    router::del_entry(
+        &g1.port,
+        IpCidr::Ip4(g1_cfg.ipv4().vpc_subnet),
+        RouterTarget::VpcSubnet(IpCidr::Ip4(g1_cfg.ipv4().vpc_subnet)),
+    )
+    .unwrap();
+    update!(g1, ["incr:epoch", "set:router.rules.out=0"]);
+    let mut pkt1 = http_syn(&g1_cfg, &g2_cfg);
+    let res = g1.port.process(Out, &mut pkt1, ActionMeta::new());
+    assert_drop!(
+        res,
+        DropReason::Layer { name: "router", reason: DenyReason::Default }
+    );
+
+    // XXX The firewall layer comes before the router layer (in the
+    // outbound direction). The firewall layer allows this traffic;
+    // and a flow is created, regardless of the fact that a later
+    // layer decides to drop the packet. This means that a flow could
+    // take up space in some of the layers even though no traffic can
+    // actually flow through it. In the future it would be better to
+    // have a way to send "simulated" flow through the layer pipeline
+    // for the effect of removing it from any flow tables in which it
+    // exists.
+    incr!(
+        g1,
+        [
+            "firewall.flows.out, firewall.flows.in",
+            "stats.port.out_drop, stats.port.out_drop_layer",
+            "stats.port.out_uft_miss",
+        ]
+    );
+}
+
+// Verify that two guests on the same VPC can communicate.
+#[test]
+fn guest_to_guest() {
+    let g1_cfg = g1_cfg();
+    let g2_cfg = g2_cfg();
+    let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
+    g1.vpc_map.add(g2_cfg.ipv4().private_ip.into(), g2_cfg.phys_addr());
+    g1.port.start();
+    set!(g1, "port_state=running");
+    let mut g2 = oxide_net_setup("g2_port", &g2_cfg, Some(g1.vpc_map.clone()));
+    g2.port.start();
+    set!(g2, "port_state=running");
+
+    // Allow incoming TCP connection from anyone.
+    let rule = "dir=in action=allow priority=10 protocol=TCP";
+    firewall::add_fw_rule(
+        &g2.port,
+        &AddFwRuleReq {
+            port_name: g2.port.name().to_string(),
+            rule: rule.parse().unwrap(),
+        },
+    )
+    .unwrap();
+    incr!(g2, ["epoch", "firewall.rules.in"]);
+
+    let mut pcap_guest1 =
+        PcapBuilder::new("overlay_guest_to_guest-guest-1.pcap");
+    let mut pcap_phys1 = PcapBuilder::new("overlay_guest_to_guest-phys-1.pcap");
+
+    let mut pcap_guest2 =
+        PcapBuilder::new("overlay_guest_to_guest-guest-2.pcap");
+    let mut pcap_phys2 = PcapBuilder::new("overlay_guest_to_guest-phys-2.pcap");
+
+    let mut pkt1 = http_syn(&g1_cfg, &g2_cfg);
+    pcap_guest1.add_pkt(&pkt1);
+    let ulp_csum_b4 = pkt1.meta().inner.ulp.unwrap().csum();
+    let ip_csum_b4 = pkt1.meta().inner.ip.unwrap().csum();
+
+    // ================================================================
+    // Run the packet through g1's port in the outbound direction and
+    // verify the resulting packet meets expectations.
+    // ================================================================
+    let res = g1.port.process(Out, &mut pkt1, ActionMeta::new());
+    pcap_phys1.add_pkt(&pkt1);
+    assert!(matches!(res, Ok(Modified)));
+    incr!(
+        g1,
+        [
+            "firewall.flows.out, firewall.flows.in",
+            "uft.out",
+            "stats.port.out_modified, stats.port.out_uft_miss",
+        ]
+    );
+
+    assert_eq!(pkt1.body_offset(), VPC_ENCAP_SZ + TCP_SZ + HTTP_SYN_OPTS_LEN);
+    assert_eq!(pkt1.body_seg(), 1);
+    let ulp_csum_after = pkt1.meta().inner.ulp.unwrap().csum();
+    let ip_csum_after = pkt1.meta().inner.ip.unwrap().csum();
+    assert_eq!(ulp_csum_after, ulp_csum_b4);
+    assert_eq!(ip_csum_after, ip_csum_b4);
+
+    let meta = pkt1.meta();
+    match meta.outer.ether.as_ref() {
+        Some(eth) => {
+            assert_eq!(eth.src, MacAddr::ZERO);
+            assert_eq!(eth.dst, MacAddr::ZERO);
+        }
+
+        None => panic!("no outer ether header"),
+    }
+
+    match meta.outer.ip.as_ref().unwrap() {
+        IpMeta::Ip6(ip6) => {
+            assert_eq!(ip6.src, g1_cfg.phys_ip);
+            assert_eq!(ip6.dst, g2_cfg.phys_ip);
+        }
+
+        val => panic!("expected outer IPv6, got: {:?}", val),
+    }
+
+    match meta.outer.encap.as_ref() {
+        Some(EncapMeta::Geneve(geneve)) => {
+            assert_eq!(geneve.entropy, 7777);
+            assert_eq!(geneve.vni, Vni::new(g1_cfg.vni).unwrap());
+        }
+
+        None => panic!("expected outer Geneve metadata"),
+    }
+
+    let eth = meta.inner.ether;
+    assert_eq!(eth.src, g1_cfg.guest_mac);
+    assert_eq!(eth.dst, g2_cfg.guest_mac);
+    assert_eq!(eth.ether_type, EtherType::Ipv4);
+
+    match meta.inner.ip.as_ref().unwrap() {
+        IpMeta::Ip4(ip4) => {
+            assert_eq!(ip4.src, g1_cfg.ipv4_cfg().unwrap().private_ip);
+            assert_eq!(ip4.dst, g2_cfg.ipv4_cfg().unwrap().private_ip);
+            assert_eq!(ip4.proto, Protocol::TCP);
+        }
+
+        ip6 => panic!("execpted inner IPv4 metadata, got IPv6: {:?}", ip6),
+    }
+
+    match meta.inner.ulp.as_ref().unwrap() {
+        UlpMeta::Tcp(tcp) => {
+            assert_eq!(tcp.src, 44490);
+            assert_eq!(tcp.dst, 80);
+        }
+
+        ulp => panic!("expected inner TCP metadata, got: {:?}", ulp),
+    }
+
+    // ================================================================
+    // Now that the packet has been encap'd let's play the role of
+    // router and send this inbound to g2's port. For maximum fidelity
+    // of the real process we first dump the raw bytes of g1's
+    // outgoing packet and then reparse it.
+    // ================================================================
+    let mblk = pkt1.unwrap_mblk();
+    let mut pkt2 = unsafe {
+        Packet::wrap_mblk_and_parse(mblk, In, VpcParser::new()).unwrap()
+    };
+    pcap_phys2.add_pkt(&pkt2);
+
+    let res = g2.port.process(In, &mut pkt2, ActionMeta::new());
+    pcap_guest2.add_pkt(&pkt2);
+    assert!(matches!(res, Ok(Modified)));
+    incr!(
+        g2,
+        [
+            "firewall.flows.in, firewall.flows.out",
+            "uft.in",
+            "stats.port.in_modified, stats.port.in_uft_miss",
+        ]
+    );
+    assert_eq!(pkt2.body_offset(), TCP_SZ + HTTP_SYN_OPTS_LEN);
+    assert_eq!(pkt2.body_seg(), 1);
+
+    let g2_meta = pkt2.meta();
+    assert!(g2_meta.outer.ether.is_none());
+    assert!(g2_meta.outer.ip.is_none());
+    assert!(g2_meta.outer.encap.is_none());
+
+    let g2_eth = g2_meta.inner.ether;
+    assert_eq!(g2_eth.src, g1_cfg.gateway_mac);
+    assert_eq!(g2_eth.dst, g2_cfg.guest_mac);
+    assert_eq!(g2_eth.ether_type, EtherType::Ipv4);
+
+    match g2_meta.inner.ip.as_ref().unwrap() {
+        IpMeta::Ip4(ip4) => {
+            assert_eq!(ip4.src, g1_cfg.ipv4_cfg().unwrap().private_ip);
+            assert_eq!(ip4.dst, g2_cfg.ipv4_cfg().unwrap().private_ip);
+            assert_eq!(ip4.proto, Protocol::TCP);
+        }
+
+        ip6 => panic!("execpted inner IPv4 metadata, got IPv6: {:?}", ip6),
+    }
+
+    match g2_meta.inner.ulp.as_ref().unwrap() {
+        UlpMeta::Tcp(tcp) => {
+            assert_eq!(tcp.src, 44490);
+            assert_eq!(tcp.dst, 80);
+        }
+
+        ulp => panic!("expected inner TCP metadata, got: {:?}", ulp),
+    }
+}
+
+// Two guests on different, non-peered VPCs should not be able to
+// communicate.
+#[test]
+fn guest_to_guest_diff_vpc_no_peer() {
+    // ================================================================
+    // Configure ports for g1 and g2. Place g1 on VNI 99 and g2 on VNI
+    // 100.
+    // ================================================================
+    let g1_cfg = g1_cfg();
+    let mut g2_cfg = g2_cfg();
+    g2_cfg.vni = Vni::new(100u32).unwrap();
+    let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
+    g1.port.start();
+    set!(g1, "port_state=running");
+    let mut g2 = oxide_net_setup("g2_port", &g2_cfg, Some(g1.vpc_map.clone()));
+    g2.port.start();
+    set!(g2, "port_state=running");
+
+    // Allow incoming TCP connection from anyone.
+    let rule = "dir=in action=allow priority=10 protocol=TCP";
+    firewall::add_fw_rule(
+        &g2.port,
+        &AddFwRuleReq {
+            port_name: g2.port.name().to_string(),
+            rule: rule.parse().unwrap(),
+        },
+    )
+    .unwrap();
+    incr!(g2, ["epoch", "firewall.rules.in"]);
+
+    // ================================================================
+    // Run the packet through g1's port in the outbound direction and
+    // verify the packet is dropped.
+    // ================================================================
+    let mut g1_pkt = http_syn(&g1_cfg, &g2_cfg);
+    let res = g1.port.process(Out, &mut g1_pkt, ActionMeta::new());
+    assert_drop!(
+        res,
+        DropReason::Layer { name: "overlay", reason: DenyReason::Action }
+    );
+    incr!(
+        g1,
+        [
+            "firewall.flows.in, firewall.flows.out",
+            "stats.port.out_drop, stats.port.out_drop_layer",
+            "stats.port.out_uft_miss",
+        ]
+    );
+}
+
+// Verify that a guest can communicate with the internet.
+#[test]
+fn guest_to_internet() {
+    let g1_cfg = g1_cfg();
+    let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
+    g1.port.start();
+    set!(g1, "port_state=running");
+
+    // Add router entry that allows g1 to route to internet.
+    router::add_entry(
+        &g1.port,
+        IpCidr::Ip4("0.0.0.0/0".parse().unwrap()),
+        RouterTarget::InternetGateway,
+    )
+    .unwrap();
+    incr!(g1, ["epoch", "router.rules.out"]);
+
+    // ================================================================
+    // Generate a TCP SYN packet from g1 to zinascii.com
+    // ================================================================
+    let dst_ip = "52.10.128.69".parse().unwrap();
+    let mut pkt1 = http_syn2(
+        g1_cfg.guest_mac,
+        g1_cfg.ipv4_cfg().unwrap().private_ip,
+        GW_MAC_ADDR,
+        dst_ip,
+    );
+
+    // ================================================================
+    // Run the packet through g1's port in the outbound direction and
+    // verify the resulting packet meets expectations.
+    // ================================================================
+    let res = g1.port.process(Out, &mut pkt1, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)), "bad result: {:?}", res);
+    incr!(
+        g1,
+        [
+            "firewall.flows.out, firewall.flows.in",
+            "nat.flows.out, nat.flows.in",
+            "uft.out",
+            "stats.port.out_modified, stats.port.out_uft_miss",
+        ]
+    );
+    assert_eq!(pkt1.body_offset(), VPC_ENCAP_SZ + TCP_SZ + HTTP_SYN_OPTS_LEN);
+    assert_eq!(pkt1.body_seg(), 1);
+    let meta = pkt1.meta();
+    match meta.outer.ether.as_ref() {
+        Some(eth) => {
+            assert_eq!(eth.src, MacAddr::ZERO);
+            assert_eq!(eth.dst, MacAddr::ZERO);
+        }
+
+        None => panic!("no outer ether header"),
+    }
+
+    match meta.outer.ip.as_ref().unwrap() {
+        IpMeta::Ip6(ip6) => {
+            assert_eq!(ip6.src, g1_cfg.phys_ip);
+            assert_eq!(ip6.dst, g1_cfg.boundary_services[0].ip);
+        }
+
+        val => panic!("expected outer IPv6, got: {:?}", val),
+    }
+
+    match meta.outer.encap.as_ref() {
+        Some(EncapMeta::Geneve(geneve)) => {
+            assert_eq!(geneve.entropy, 7777);
+            assert_eq!(geneve.vni, g1_cfg.boundary_services[0].vni);
+        }
+
+        None => panic!("expected outer Geneve metadata"),
+    }
+
+    let eth = meta.inner.ether;
+    assert_eq!(eth.src, g1_cfg.guest_mac);
+    assert_eq!(eth.dst, g1_cfg.boundary_services[0].mac);
+    assert_eq!(eth.ether_type, EtherType::Ipv4);
+
+    match meta.inner.ip.as_ref().unwrap() {
+        IpMeta::Ip4(ip4) => {
+            assert_eq!(ip4.src, g1_cfg.snat().external_ip);
+            assert_eq!(ip4.dst, dst_ip);
+            assert_eq!(ip4.proto, Protocol::TCP);
+        }
+
+        ip6 => panic!("execpted inner IPv4 metadata, got IPv6: {:?}", ip6),
+    }
+
+    match meta.inner.ulp.as_ref().unwrap() {
+        UlpMeta::Tcp(tcp) => {
+            assert_eq!(
+                tcp.src,
+                g1_cfg.snat().ports.clone().rev().next().unwrap(),
+            );
+            assert_eq!(tcp.dst, 80);
+        }
+
+        ulp => panic!("expected inner TCP metadata, got: {:?}", ulp),
+    }
+}
+
+// Verify that with an endpoint-independent SNAT mapping, the flows
+// from one private port to different destinations share their
+// external port.
+#[test]
+fn snat_endpoint_independent_mapping() {
+    let mut g1_cfg = g1_cfg();
+    match &mut g1_cfg.ip_cfg {
+        IpCfg::Ipv4(ipv4) | IpCfg::DualStack { ipv4, .. } => {
+            ipv4.snat.as_mut().unwrap().mapping =
+                NatMapping::EndpointIndependent;
+        }
+
+        _ => panic!("expected IPv4 configuration"),
+    }
+    let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
+    g1.port.start();
+    set!(g1, "port_state=running");
+    router::add_entry(
+        &g1.port,
+        IpCidr::Ip4("0.0.0.0/0".parse().unwrap()),
+        RouterTarget::InternetGateway,
+    )
+    .unwrap();
+    incr!(g1, ["epoch", "router.rules.out"]);
+
+    let mut ext_ports = vec![];
+    for dst_ip in ["52.10.128.69", "76.76.21.21"] {
+        let mut pkt = http_syn2(
+            g1_cfg.guest_mac,
+            g1_cfg.ipv4().private_ip,
+            GW_MAC_ADDR,
+            dst_ip.parse().unwrap(),
+        );
+        let res = g1.port.process(Out, &mut pkt, ActionMeta::new());
+        assert!(matches!(res, Ok(Modified)), "bad result: {:?}", res);
+        incr!(
+            g1,
+            [
+                "firewall.flows.out, firewall.flows.in",
+                "nat.flows.out, nat.flows.in",
+                "uft.out",
+                "stats.port.out_modified, stats.port.out_uft_miss",
+            ]
+        );
+
+        match pkt.meta().inner.ip.as_ref().unwrap() {
+            IpMeta::Ip4(ip4) => {
+                assert_eq!(ip4.src, g1_cfg.snat().external_ip);
+            }
+
+            val => panic!("expected inner IPv4, got: {:?}", val),
+        }
+        ext_ports.push(pkt.meta().inner.ulp.unwrap().src_port());
+    }
+
+    assert_eq!(ext_ports[0], ext_ports[1]);
+    assert!(g1_cfg.snat().ports.contains(&ext_ports[0]));
+}
+
+// Verify that a guest is refused new SNAT ports beyond its limit, and
+// that the refusal shows up in the SNAT dump.
+#[test]
+fn snat_port_limit() {
+    let mut g1_cfg = g1_cfg();
+    match &mut g1_cfg.ip_cfg {
+        IpCfg::Ipv4(ipv4) | IpCfg::DualStack { ipv4, .. } => {
+            ipv4.snat.as_mut().unwrap().max_ports = Some(1);
+        }
+
+        _ => panic!("expected IPv4 configuration"),
+    }
+    let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
+    g1.port.start();
+    set!(g1, "port_state=running");
+    router::add_entry(
+        &g1.port,
+        IpCidr::Ip4("0.0.0.0/0".parse().unwrap()),
+        RouterTarget::InternetGateway,
+    )
+    .unwrap();
+    incr!(g1, ["epoch", "router.rules.out"]);
+
+    let dst1: Ipv4Addr = "52.10.128.69".parse().unwrap();
+    let mut pkt = http_syn2(
+        g1_cfg.guest_mac,
+        g1_cfg.ipv4().private_ip,
+        GW_MAC_ADDR,
+        dst1,
+    );
+    let res = g1.port.process(Out, &mut pkt, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)), "bad result: {:?}", res);
+    incr!(
+        g1,
+        [
+            "firewall.flows.out, firewall.flows.in",
+            "nat.flows.out, nat.flows.in",
+            "uft.out",
+            "stats.port.out_modified, stats.port.out_uft_miss",
+        ]
+    );
+    let ext_port = pkt.meta().inner.ulp.unwrap().src_port();
+
+    // The second flow needs a port of its own, which the limit
+    // forbids.
+    let mut pkt = http_syn2(
+        g1_cfg.guest_mac,
+        g1_cfg.ipv4().private_ip,
+        GW_MAC_ADDR,
+        "76.76.21.21".parse().unwrap(),
+    );
+    let res = g1.port.process(Out, &mut pkt, ActionMeta::new());
+    assert!(
+        matches!(
+            res,
+            Err(ProcessError::Layer(LayerError::GenDesc(
+                GenDescError::ResourceExhausted { .. }
+            )))
+        ),
+        "bad result: {:?}",
+        res
+    );
+
+    let resp = nat::dump_snat(&g1.port).unwrap();
+    assert_eq!(resp.mappings.len(), 1);
+    let snat = &resp.mappings[0];
+    assert_eq!(snat.priv_ip, IpAddr::from(g1_cfg.ipv4().private_ip));
+    assert_eq!(snat.max_ports, Some(1));
+    assert_eq!(snat.stats.limited, 1);
+    assert_eq!(snat.stats.exhausted, 0);
+    assert_eq!(snat.ports_used.len(), 1);
+
+    let (port, flows) = &snat.ports_used[0];
+    assert_eq!(*port, ext_port);
+    assert_eq!(flows.len(), 1);
+    assert_eq!(flows[0].dst_ip, IpAddr::from(dst1));
+}
+
+// Verify that two guests configured with the same SNAT IP and ports
+// take them from one shared range, each held to its own limit, and
+// that a range overlapping it is refused.
+#[test]
+fn snat_shared_ports() {
+    let mut g1_cfg = g1_cfg();
+    let mut g2_cfg = g2_cfg();
+    let mut snat = g1_cfg.snat().clone();
+    snat.max_ports = Some(1);
+    for cfg in [&mut g1_cfg, &mut g2_cfg] {
+        match &mut cfg.ip_cfg {
+            IpCfg::Ipv4(ipv4) | IpCfg::DualStack { ipv4, .. } => {
+                ipv4.snat = Some(snat.clone());
+            }
+
+            _ => panic!("expected IPv4 configuration"),
+        }
+    }
+
+    let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
+    let mut g2 = oxide_net_setup("g2_port", &g2_cfg, Some(g1.vpc_map.clone()));
+    let mut ext_ports = vec![];
+    for (cfg, g) in [(&g1_cfg, &mut g1), (&g2_cfg, &mut g2)] {
+        g.port.start();
+        set!(g, "port_state=running");
+        router::add_entry(
+            &g.port,
+            IpCidr::Ip4("0.0.0.0/0".parse().unwrap()),
+            RouterTarget::InternetGateway,
+        )
+        .unwrap();
+        incr!(g, ["epoch", "router.rules.out"]);
+
+        let mut pkt = http_syn2(
+            cfg.guest_mac,
+            cfg.ipv4().private_ip,
+            GW_MAC_ADDR,
+            "52.10.128.69".parse().unwrap(),
+        );
+        let res = g.port.process(Out, &mut pkt, ActionMeta::new());
+        assert!(matches!(res, Ok(Modified)), "bad result: {:?}", res);
+        incr!(
+            g,
+            [
+                "firewall.flows.out, firewall.flows.in",
+                "nat.flows.out, nat.flows.in",
+                "uft.out",
+                "stats.port.out_modified, stats.port.out_uft_miss",
+            ]
+        );
+        ext_ports.push(pkt.meta().inner.ulp.unwrap().src_port());
+    }
+
+    // Each guest got a port of its own from the one range.
+    let last = snat.ports.clone().rev().next().unwrap();
+    assert_eq!(ext_ports, vec![last, last - 1]);
+    for g in [&g1, &g2] {
+        let resp = nat::dump_snat(&g.port).unwrap();
+        assert_eq!(resp.mappings[0].ports_free as usize, snat.ports.len() - 2);
+    }
+
+    let nat_ports = g1.vpc_map.nat_ports();
+    let overlap = 1000..=*snat.ports.start();
+    assert!(matches!(
+        nat_ports.ip4(snat.external_ip, &overlap),
+        Err(OpteError::InvalidNatPorts(_))
+    ));
+    assert!(nat_ports.ip4("10.77.77.99".parse().unwrap(), &overlap).is_ok());
+}
+
+// Verify that flows to the internet are spread across the endpoints
+// of Boundary Services, and that marking an endpoint down moves only
+// the flows which were using it.
+#[test]
+fn bsvc_endpoint_failover() {
+    let mut g1_cfg = g1_cfg();
+    let bs0 = g1_cfg.boundary_services[0];
+    for last in [0x02, 0x03] {
+        let mut ip = bs0.ip.bytes();
+        ip[15] = last;
+        g1_cfg
+            .boundary_services
+            .push(BoundaryServices { ip: Ipv6Addr::from(ip), ..bs0 });
+    }
+    let endpoints: Vec<Ipv6Addr> =
+        g1_cfg.boundary_services.iter().map(|bs| bs.ip).collect();
+
+    let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
+    g1.port.start();
+    set!(g1, "port_state=running");
+    router::add_entry(
+        &g1.port,
+        IpCidr::Ip4("0.0.0.0/0".parse().unwrap()),
+        RouterTarget::InternetGateway,
+    )
+    .unwrap();
+    incr!(g1, ["epoch", "router.rules.out"]);
+
+    let send = |sport: u16| {
+        let udp = UdpMeta {
+            src: sport,
+            dst: 53,
+            len: UdpHdr::SIZE as u16,
+            ..Default::default()
+        };
+        let ip = Ipv4Meta {
+            src: g1_cfg.ipv4().private_ip,
+            dst: "52.10.128.69".parse().unwrap(),
+            proto: Protocol::UDP,
+            ttl: 64,
+            total_len: (Ipv4Hdr::BASE_SIZE + UdpHdr::SIZE) as u16,
+            ..Default::default()
+        };
+        let eth = EtherMeta {
+            ether_type: EtherType::Ipv4,
+            src: g1_cfg.guest_mac,
+            dst: g1_cfg.gateway_mac,
+        };
+        let mut pkt = ulp_pkt(eth, ip, udp, &[]);
+        let res = g1.port.process(Out, &mut pkt, ActionMeta::new());
+        assert!(matches!(res, Ok(Modified)), "bad result: {:?}", res);
+        match pkt.meta().outer.ip.as_ref() {
+            Some(IpMeta::Ip6(ip6)) => ip6.dst,
+            val => panic!("expected outer IPv6, got: {:?}", val),
+        }
+    };
+
+    // ================================================================
+    // The flows are spread across all endpoints.
+    // ================================================================
+    let sports: Vec<u16> = (10000..10032).collect();
+    let before: Vec<Ipv6Addr> = sports.iter().map(|p| send(*p)).collect();
+    for ep in &endpoints {
+        assert!(before.contains(ep), "no flow sent to {}", ep);
+    }
+
+    // ================================================================
+    // Mark the endpoint of the first flow down: only its flows move,
+    // and they are spread across the remaining endpoints.
+    // ================================================================
+    let down = before[0];
+    let bsvc = g1.vpc_map.bsvc();
+    bsvc.set(down, false);
+    assert_eq!(bsvc.dump().down, vec![down]);
+
+    let after: Vec<Ipv6Addr> = sports.iter().map(|p| send(*p)).collect();
+    for (old, new) in before.iter().zip(after.iter()) {
+        if *old == down {
+            assert_ne!(*new, down);
+            assert!(endpoints.contains(new));
+        } else {
+            assert_eq!(old, new);
+        }
+    }
+
+    // ================================================================
+    // Once back up, the endpoint takes new flows, while the flows
+    // moved off of it stay where they are.
+    // ================================================================
+    bsvc.set(down, true);
+    assert!(bsvc.dump().down.is_empty());
+    let again: Vec<Ipv6Addr> = sports.iter().map(|p| send(*p)).collect();
+    assert_eq!(after, again);
+    let new: Vec<Ipv6Addr> = (20000..20032).map(|p| send(p)).collect();
+    assert!(new.contains(&down));
+}
+
+// Verify that an ICMP Echo request has its identifier rewritten by
+// SNAT.
+#[test]
+fn snat_icmp4_echo_rewrite() {
+    let g1_cfg = g1_cfg();
+    let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
+    g1.port.start();
+    set!(g1, "port_state=running");
+    let dst_ip: Ipv4Addr = "45.55.45.205".parse().unwrap();
+    let ident = 7;
+    let mut seq_no = 777;
+    let data = b"reunion\0";
+
+    // Add router entry that allows g1 to route to internet.
+    router::add_entry(
+        &g1.port,
+        IpCidr::Ip4("0.0.0.0/0".parse().unwrap()),
+        RouterTarget::InternetGateway,
+    )
+    .unwrap();
+    incr!(g1, ["epoch", "router.rules.out"]);
+    let mapped_port = g1_cfg.snat().ports.clone().rev().next().unwrap();
+
+    // ================================================================
+    // Verify echo request rewrite.
+    // ================================================================
+    let mut pkt1 = gen_icmp_echo_req(
+        g1_cfg.guest_mac,
+        g1_cfg.gateway_mac,
+        g1_cfg.ipv4().private_ip.into(),
+        dst_ip.into(),
+        ident,
+        seq_no,
+        &data[..],
+    );
+
+    let res = g1.port.process(Out, &mut pkt1, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)), "bad result: {:?}", res);
+    incr!(
+        g1,
+        [
+            "firewall.flows.out, firewall.flows.in",
+            "nat.flows.out, nat.flows.in",
+            "uft.out",
+            "stats.port.out_modified, stats.port.out_uft_miss",
+        ]
+    );
+
+    assert_eq!(pkt1.body_offset(), VPC_ENCAP_SZ + IP_SZ);
+    assert_eq!(pkt1.body_seg(), 1);
+    let meta = pkt1.meta();
+
+    let eth = meta.inner.ether;
+    assert_eq!(eth.src, g1_cfg.guest_mac);
+    assert_eq!(eth.dst, g1_cfg.boundary_services[0].mac);
+    assert_eq!(eth.ether_type, EtherType::Ipv4);
+
+    match meta.inner.ip.as_ref().unwrap() {
+        IpMeta::Ip4(ip4) => {
+            assert_eq!(ip4.src, g1_cfg.snat().external_ip);
+            assert_eq!(ip4.dst, dst_ip);
+            assert_eq!(ip4.proto, Protocol::ICMP);
+        }
+
+        ip6 => panic!("execpted inner IPv4 metadata, got IPv6: {:?}", ip6),
+    }
+
+    let body = pkt1.body_segs().unwrap()[0];
+    let icmp = Icmpv4Packet::new_checked(body).unwrap();
+    assert!(icmp.verify_checksum());
+    assert_eq!(icmp.echo_ident(), mapped_port);
+    assert_eq!(icmp.echo_seq_no(), seq_no);
+
+    // ================================================================
+    // Verify echo reply rewrite.
+    // ================================================================
+    let mut pkt2 = gen_icmp_echo_reply(
+        g1_cfg.boundary_services[0].mac,
+        g1_cfg.guest_mac,
+        dst_ip,
+        g1_cfg.snat().external_ip,
+        mapped_port,
+        seq_no,
+        &data[..],
+    );
+    let bsvc_phys = TestIpPhys {
+        ip: g1_cfg.boundary_services[0].ip,
+        mac: g1_cfg.boundary_services[0].mac,
+        vni: g1_cfg.boundary_services[0].vni,
+    };
+    let g1_phys = TestIpPhys {
+        ip: g1_cfg.phys_ip,
+        mac: g1_cfg.guest_mac,
+        vni: g1_cfg.vni,
+    };
+    pkt2 = encap(pkt2, bsvc_phys, g1_phys);
+
+    let res = g1.port.process(In, &mut pkt2, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)), "bad result: {:?}", res);
+    incr!(g1, ["uft.in", "stats.port.in_modified, stats.port.in_uft_miss"]);
+    assert_eq!(pkt2.body_offset(), IP_SZ);
+    assert_eq!(pkt2.body_seg(), 0);
+    let meta = pkt2.meta();
+
+    let eth = meta.inner.ether;
+    assert_eq!(eth.src, g1_cfg.gateway_mac);
+    assert_eq!(eth.dst, g1_cfg.guest_mac);
+    assert_eq!(eth.ether_type, EtherType::Ipv4);
+
+    match meta.inner.ip.as_ref().unwrap() {
+        IpMeta::Ip4(ip4) => {
+            assert_eq!(ip4.src, dst_ip);
+            assert_eq!(ip4.dst, g1_cfg.ipv4().private_ip);
+            assert_eq!(ip4.proto, Protocol::ICMP);
+        }
+
+        ip6 => panic!("execpted inner IPv4 metadata, got IPv6: {:?}", ip6),
+    }
+
+    let body = pkt2.body_segs().unwrap()[0];
+    let icmp = Icmpv4Packet::new_checked(body).unwrap();
+    assert!(icmp.verify_checksum());
+    assert_eq!(icmp.echo_ident(), ident);
+    assert_eq!(icmp.echo_seq_no(), seq_no);
+
+    // ================================================================
+    // Send ICMP Echo Req a second time. We want to verify that a) the
+    // UFT entry is used and b) that it runs the attached body
+    // transformation.
+    // ================================================================
+    seq_no += 1;
+    let mut pkt3 = gen_icmp_echo_req(
+        g1_cfg.guest_mac,
+        g1_cfg.gateway_mac,
+        g1_cfg.ipv4().private_ip.into(),
+        dst_ip.into(),
+        ident,
+        seq_no,
+        &data[..],
+    );
+
+    assert_eq!(g1.port.stats_snap().out_uft_hit, 0);
+    let res = g1.port.process(Out, &mut pkt3, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)), "bad result: {:?}", res);
+    incr!(g1, ["stats.port.out_modified, stats.port.out_uft_hit"]);
+    assert_eq!(pkt3.body_offset(), VPC_ENCAP_SZ + IP_SZ);
+    assert_eq!(pkt3.body_seg(), 1);
+    let meta = pkt3.meta();
+
+    let eth = meta.inner.ether;
+    assert_eq!(eth.src, g1_cfg.guest_mac);
+    assert_eq!(eth.dst, g1_cfg.boundary_services[0].mac);
+    assert_eq!(eth.ether_type, EtherType::Ipv4);
+
+    match meta.inner.ip.as_ref().unwrap() {
+        IpMeta::Ip4(ip4) => {
+            assert_eq!(ip4.src, g1_cfg.snat().external_ip);
+            assert_eq!(ip4.dst, dst_ip);
+            assert_eq!(ip4.proto, Protocol::ICMP);
+        }
+
+        ip6 => panic!("execpted inner IPv4 metadata, got IPv6: {:?}", ip6),
+    }
+
+    let body = pkt3.body_segs().unwrap()[0];
+    let icmp = Icmpv4Packet::new_checked(body).unwrap();
+    assert!(icmp.verify_checksum());
+    assert_eq!(icmp.echo_ident(), mapped_port);
+    assert_eq!(icmp.echo_seq_no(), seq_no);
+    assert_eq!(g1.port.stats_snap().out_uft_hit, 1);
+
+    // ================================================================
+    // Process ICMP Echo Reply a second time. Once again, this time we
+    // want to verify that the body transformation comes from the UFT
+    // entry.
+    // ================================================================
+    let mut pkt4 = gen_icmp_echo_reply(
+        g1_cfg.boundary_services[0].mac,
+        g1_cfg.guest_mac,
+        dst_ip,
+        g1_cfg.snat().external_ip,
+        mapped_port,
+        seq_no,
+        &data[..],
+    );
+
+    assert_eq!(g1.port.stats_snap().in_uft_hit, 0);
+    let res = g1.port.process(In, &mut pkt4, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)), "bad result: {:?}", res);
+    incr!(g1, ["stats.port.in_modified, stats.port.in_uft_hit"]);
+    assert_eq!(pkt4.body_offset(), IP_SZ);
+    assert_eq!(pkt4.body_seg(), 0);
+    let meta = pkt4.meta();
+
+    let eth = meta.inner.ether;
+    assert_eq!(eth.src, g1_cfg.gateway_mac);
+    assert_eq!(eth.dst, g1_cfg.guest_mac);
     assert_eq!(eth.ether_type, EtherType::Ipv4);
 
     match meta.inner.ip.as_ref().unwrap() {
-        IpMeta::Ip4(ip4) => {
-            assert_eq!(ip4.src, g1_cfg.ipv4_cfg().unwrap().private_ip);
-            assert_eq!(ip4.dst, g2_cfg.ipv4_cfg().unwrap().private_ip);
-            assert_eq!(ip4.proto, Protocol::TCP);
+        IpMeta::Ip4(ip4) => {
+            assert_eq!(ip4.src, dst_ip);
+            assert_eq!(ip4.dst, g1_cfg.ipv4().private_ip);
+            assert_eq!(ip4.proto, Protocol::ICMP);
+        }
+
+        ip6 => panic!("execpted inner IPv4 metadata, got IPv6: {:?}", ip6),
+    }
+
+    let body = pkt4.body_segs().unwrap()[0];
+    let icmp = Icmpv4Packet::new_checked(body).unwrap();
+    assert!(icmp.verify_checksum());
+    assert_eq!(icmp.echo_ident(), ident);
+    assert_eq!(icmp.echo_seq_no(), seq_no);
+    assert_eq!(g1.port.stats_snap().in_uft_hit, 1);
+}
+
+// Verify that an IPv6 guest reaches the internet through SNAT.
+#[test]
+fn guest_to_internet_ipv6() {
+    let g1_cfg = g1_cfg();
+    let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
+    g1.port.start();
+    set!(g1, "port_state=running");
+
+    // Add router entry that allows g1 to route to internet.
+    router::add_entry(
+        &g1.port,
+        IpCidr::Ip6("::/0".parse().unwrap()),
+        RouterTarget::InternetGateway,
+    )
+    .unwrap();
+    incr!(g1, ["epoch", "router.rules.out"]);
+
+    // ================================================================
+    // Generate a TCP SYN packet from g1 to an internet host.
+    // ================================================================
+    let dst_ip: Ipv6Addr = "2001:4860:4860::8888".parse().unwrap();
+    let tcp = TcpMeta {
+        src: 44490,
+        dst: 80,
+        flags: TcpFlags::SYN,
+        seq: 2382112979,
+        ..Default::default()
+    };
+    let ip6 = Ipv6Meta {
+        src: g1_cfg.ipv6().private_ip,
+        dst: dst_ip,
+        proto: Protocol::TCP,
+        next_hdr: IpProtocol::Tcp,
+        hop_limit: 64,
+        pay_len: tcp.hdr_len() as u16,
+        ..Default::default()
+    };
+    let eth = EtherMeta {
+        ether_type: EtherType::Ipv6,
+        src: g1_cfg.guest_mac,
+        dst: GW_MAC_ADDR,
+    };
+    let mut pkt1 = ulp_pkt(eth, ip6, tcp, &[]);
+
+    // ================================================================
+    // Run the packet through g1's port in the outbound direction and
+    // verify the resulting packet meets expectations.
+    // ================================================================
+    let res = g1.port.process(Out, &mut pkt1, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)), "bad result: {:?}", res);
+    incr!(
+        g1,
+        [
+            "firewall.flows.out, firewall.flows.in",
+            "nat.flows.out, nat.flows.in",
+            "uft.out",
+            "stats.port.out_modified, stats.port.out_uft_miss",
+        ]
+    );
+    let meta = pkt1.meta();
+
+    match meta.outer.ip.as_ref().unwrap() {
+        IpMeta::Ip6(ip6) => {
+            assert_eq!(ip6.src, g1_cfg.phys_ip);
+            assert_eq!(ip6.dst, g1_cfg.boundary_services[0].ip);
+        }
+
+        val => panic!("expected outer IPv6, got: {:?}", val),
+    }
+
+    let eth = meta.inner.ether;
+    assert_eq!(eth.src, g1_cfg.guest_mac);
+    assert_eq!(eth.dst, g1_cfg.boundary_services[0].mac);
+    assert_eq!(eth.ether_type, EtherType::Ipv6);
+
+    match meta.inner.ip.as_ref().unwrap() {
+        IpMeta::Ip6(ip6) => {
+            assert_eq!(ip6.src, g1_cfg.snat6().external_ip);
+            assert_eq!(ip6.dst, dst_ip);
+            assert_eq!(ip6.proto, Protocol::TCP);
+        }
+
+        ip4 => panic!("execpted inner IPv6 metadata, got IPv4: {:?}", ip4),
+    }
+
+    let mapped_port = match meta.inner.ulp.as_ref().unwrap() {
+        UlpMeta::Tcp(tcp) => {
+            assert_eq!(
+                tcp.src,
+                g1_cfg.snat6().ports.clone().rev().next().unwrap(),
+            );
+            assert_eq!(tcp.dst, 80);
+            tcp.src
+        }
+
+        ulp => panic!("expected inner TCP metadata, got: {:?}", ulp),
+    };
+
+    // ================================================================
+    // The SYN+ACK from the internet host is mapped back to the
+    // guest's private address and port.
+    // ================================================================
+    let tcp = TcpMeta {
+        src: 80,
+        dst: mapped_port,
+        flags: TcpFlags::SYN | TcpFlags::ACK,
+        seq: 44161351,
+        ack: 2382112980,
+        ..Default::default()
+    };
+    let ip6 = Ipv6Meta {
+        src: dst_ip,
+        dst: g1_cfg.snat6().external_ip,
+        proto: Protocol::TCP,
+        next_hdr: IpProtocol::Tcp,
+        hop_limit: 64,
+        pay_len: tcp.hdr_len() as u16,
+        ..Default::default()
+    };
+    let eth = EtherMeta {
+        ether_type: EtherType::Ipv6,
+        src: g1_cfg.boundary_services[0].mac,
+        dst: g1_cfg.guest_mac,
+    };
+    let bsvc_phys = TestIpPhys {
+        ip: g1_cfg.boundary_services[0].ip,
+        mac: g1_cfg.boundary_services[0].mac,
+        vni: g1_cfg.boundary_services[0].vni,
+    };
+    let g1_phys = TestIpPhys {
+        ip: g1_cfg.phys_ip,
+        mac: g1_cfg.guest_mac,
+        vni: g1_cfg.vni,
+    };
+    let mut pkt2 = encap(ulp_pkt(eth, ip6, tcp, &[]), bsvc_phys, g1_phys);
+    let res = g1.port.process(In, &mut pkt2, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)), "bad result: {:?}", res);
+    incr!(g1, ["uft.in", "stats.port.in_modified, stats.port.in_uft_miss"]);
+    let meta = pkt2.meta();
+
+    match meta.inner.ip.as_ref().unwrap() {
+        IpMeta::Ip6(ip6) => {
+            assert_eq!(ip6.src, dst_ip);
+            assert_eq!(ip6.dst, g1_cfg.ipv6().private_ip);
+        }
+
+        ip4 => panic!("execpted inner IPv6 metadata, got IPv4: {:?}", ip4),
+    }
+
+    match meta.inner.ulp.as_ref().unwrap() {
+        UlpMeta::Tcp(tcp) => {
+            assert_eq!(tcp.src, 80);
+            assert_eq!(tcp.dst, 44490);
+        }
+
+        ulp => panic!("expected inner TCP metadata, got: {:?}", ulp),
+    }
+}
+
+// Verify that an ICMPv6 Echo request has its identifier rewritten by
+// SNAT, and its checksum updated for the new source address.
+#[test]
+fn snat_icmp6_echo_rewrite() {
+    let g1_cfg = g1_cfg();
+    let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
+    g1.port.start();
+    set!(g1, "port_state=running");
+    let dst_ip: Ipv6Addr = "2001:4860:4860::8888".parse().unwrap();
+    let ident = 7;
+    let mut seq_no = 777;
+    let data = b"reunion\0";
+
+    // Add router entry that allows g1 to route to internet.
+    router::add_entry(
+        &g1.port,
+        IpCidr::Ip6("::/0".parse().unwrap()),
+        RouterTarget::InternetGateway,
+    )
+    .unwrap();
+    incr!(g1, ["epoch", "router.rules.out"]);
+    let mapped_port = g1_cfg.snat6().ports.clone().rev().next().unwrap();
+    let ext_ip = g1_cfg.snat6().external_ip;
+    let priv_ip = g1_cfg.ipv6().private_ip;
+    let bsvc_phys = TestIpPhys {
+        ip: g1_cfg.boundary_services[0].ip,
+        mac: g1_cfg.boundary_services[0].mac,
+        vni: g1_cfg.boundary_services[0].vni,
+    };
+    let g1_phys = TestIpPhys {
+        ip: g1_cfg.phys_ip,
+        mac: g1_cfg.guest_mac,
+        vni: g1_cfg.vni,
+    };
+
+    // ================================================================
+    // Verify echo request rewrite.
+    // ================================================================
+    let mut pkt1 = gen_icmpv6_echo_req(
+        g1_cfg.guest_mac,
+        g1_cfg.gateway_mac,
+        priv_ip,
+        dst_ip,
+        ident,
+        seq_no,
+        &data[..],
+    );
+
+    let res = g1.port.process(Out, &mut pkt1, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)), "bad result: {:?}", res);
+    incr!(
+        g1,
+        [
+            "firewall.flows.out, firewall.flows.in",
+            "nat.flows.out, nat.flows.in",
+            "uft.out",
+            "stats.port.out_modified, stats.port.out_uft_miss",
+        ]
+    );
+    let meta = pkt1.meta();
+
+    let eth = meta.inner.ether;
+    assert_eq!(eth.src, g1_cfg.guest_mac);
+    assert_eq!(eth.dst, g1_cfg.boundary_services[0].mac);
+    assert_eq!(eth.ether_type, EtherType::Ipv6);
+
+    match meta.inner.ip.as_ref().unwrap() {
+        IpMeta::Ip6(ip6) => {
+            assert_eq!(ip6.src, ext_ip);
+            assert_eq!(ip6.dst, dst_ip);
+            assert_eq!(ip6.proto, Protocol::ICMPv6);
         }
 
-        ip6 => panic!("execpted inner IPv4 metadata, got IPv6: {:?}", ip6),
+        ip4 => panic!("execpted inner IPv6 metadata, got IPv4: {:?}", ip4),
     }
 
-    match meta.inner.ulp.as_ref().unwrap() {
-        UlpMeta::Tcp(tcp) => {
-            assert_eq!(tcp.src, 44490);
-            assert_eq!(tcp.dst, 80);
+    let body = pkt1.body_segs().unwrap()[0];
+    let icmp = Icmpv6Packet::new_checked(body).unwrap();
+    assert!(icmp.verify_checksum(
+        &IpAddress::Ipv6(ext_ip.into()),
+        &IpAddress::Ipv6(dst_ip.into()),
+    ));
+    assert_eq!(icmp.echo_ident(), mapped_port);
+    assert_eq!(icmp.echo_seq_no(), seq_no);
+
+    // ================================================================
+    // Verify echo reply rewrite.
+    // ================================================================
+    let pkt2 = gen_icmpv6_echo_reply(
+        g1_cfg.boundary_services[0].mac,
+        g1_cfg.guest_mac,
+        dst_ip,
+        ext_ip,
+        mapped_port,
+        seq_no,
+        &data[..],
+    );
+    let mut pkt2 = encap(pkt2, bsvc_phys, g1_phys);
+
+    let res = g1.port.process(In, &mut pkt2, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)), "bad result: {:?}", res);
+    incr!(g1, ["uft.in", "stats.port.in_modified, stats.port.in_uft_miss"]);
+    let meta = pkt2.meta();
+
+    let eth = meta.inner.ether;
+    assert_eq!(eth.src, g1_cfg.gateway_mac);
+    assert_eq!(eth.dst, g1_cfg.guest_mac);
+    assert_eq!(eth.ether_type, EtherType::Ipv6);
+
+    match meta.inner.ip.as_ref().unwrap() {
+        IpMeta::Ip6(ip6) => {
+            assert_eq!(ip6.src, dst_ip);
+            assert_eq!(ip6.dst, priv_ip);
+            assert_eq!(ip6.proto, Protocol::ICMPv6);
         }
 
-        ulp => panic!("expected inner TCP metadata, got: {:?}", ulp),
+        ip4 => panic!("execpted inner IPv6 metadata, got IPv4: {:?}", ip4),
     }
 
+    let body = pkt2.body_segs().unwrap()[0];
+    let icmp = Icmpv6Packet::new_checked(body).unwrap();
+    assert!(icmp.verify_checksum(
+        &IpAddress::Ipv6(dst_ip.into()),
+        &IpAddress::Ipv6(priv_ip.into()),
+    ));
+    assert_eq!(icmp.echo_ident(), ident);
+    assert_eq!(icmp.echo_seq_no(), seq_no);
+
     // ================================================================
-    // Now that the packet has been encap'd let's play the role of
-    // router and send this inbound to g2's port. For maximum fidelity
-    // of the real process we first dump the raw bytes of g1's
-    // outgoing packet and then reparse it.
+    // Send ICMPv6 Echo Req a second time, verifying that the UFT
+    // entry runs the attached body transformation.
     // ================================================================
-    let mblk = pkt1.unwrap_mblk();
-    let mut pkt2 = unsafe {
-        Packet::wrap_mblk_and_parse(mblk, In, VpcParser::new()).unwrap()
+    seq_no += 1;
+    let mut pkt3 = gen_icmpv6_echo_req(
+        g1_cfg.guest_mac,
+        g1_cfg.gateway_mac,
+        priv_ip,
+        dst_ip,
+        ident,
+        seq_no,
+        &data[..],
+    );
+
+    let res = g1.port.process(Out, &mut pkt3, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)), "bad result: {:?}", res);
+    incr!(g1, ["stats.port.out_modified, stats.port.out_uft_hit"]);
+
+    let body = pkt3.body_segs().unwrap()[0];
+    let icmp = Icmpv6Packet::new_checked(body).unwrap();
+    assert!(icmp.verify_checksum(
+        &IpAddress::Ipv6(ext_ip.into()),
+        &IpAddress::Ipv6(dst_ip.into()),
+    ));
+    assert_eq!(icmp.echo_ident(), mapped_port);
+    assert_eq!(icmp.echo_seq_no(), seq_no);
+
+    // ================================================================
+    // Process the ICMPv6 Echo Reply a second time, from the UFT.
+    // ================================================================
+    let pkt4 = gen_icmpv6_echo_reply(
+        g1_cfg.boundary_services[0].mac,
+        g1_cfg.guest_mac,
+        dst_ip,
+        ext_ip,
+        mapped_port,
+        seq_no,
+        &data[..],
+    );
+    let mut pkt4 = encap(pkt4, bsvc_phys, g1_phys);
+
+    let res = g1.port.process(In, &mut pkt4, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)), "bad result: {:?}", res);
+    incr!(g1, ["stats.port.in_modified, stats.port.in_uft_hit"]);
+
+    let body = pkt4.body_segs().unwrap()[0];
+    let icmp = Icmpv6Packet::new_checked(body).unwrap();
+    assert!(icmp.verify_checksum(
+        &IpAddress::Ipv6(dst_ip.into()),
+        &IpAddress::Ipv6(priv_ip.into()),
+    ));
+    assert_eq!(icmp.echo_ident(), ident);
+    assert_eq!(icmp.echo_seq_no(), seq_no);
+}
+
+// Verify that an IPv6-only guest reaches an IPv4 host by its address
+// under the NAT64 prefix, and that the reply is translated back.
+#[test]
+fn nat64_tcp() {
+    let g1_cfg = g1_nat64_cfg();
+    let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
+    g1.port.start();
+    set!(g1, "port_state=running");
+
+    // Add router entry that allows g1 to route to internet.
+    router::add_entry(
+        &g1.port,
+        IpCidr::Ip6("::/0".parse().unwrap()),
+        RouterTarget::InternetGateway,
+    )
+    .unwrap();
+    incr!(g1, ["epoch", "router.rules.out"]);
+    let nat64 = g1_cfg.ipv6().nat64.as_ref().unwrap();
+    let priv_ip = g1_cfg.ipv6().private_ip;
+
+    // ================================================================
+    // Generate a TCP SYN packet from g1 to an IPv4 internet host.
+    // ================================================================
+    let dst_ip: Ipv4Addr = "52.10.128.69".parse().unwrap();
+    let dst_ip6 = Ipv6Addr::from_nat64(dst_ip);
+    let tcp = TcpMeta {
+        src: 44490,
+        dst: 80,
+        flags: TcpFlags::SYN,
+        seq: 2382112979,
+        ..Default::default()
     };
-    pcap_phys2.add_pkt(&pkt2);
+    let ip6 = Ipv6Meta {
+        src: priv_ip,
+        dst: dst_ip6,
+        proto: Protocol::TCP,
+        next_hdr: IpProtocol::Tcp,
+        hop_limit: 64,
+        pay_len: tcp.hdr_len() as u16,
+        ..Default::default()
+    };
+    let eth = EtherMeta {
+        ether_type: EtherType::Ipv6,
+        src: g1_cfg.guest_mac,
+        dst: GW_MAC_ADDR,
+    };
+    let mut pkt1 = ulp_pkt(eth, ip6, tcp, &[]);
 
-    let res = g2.port.process(In, &mut pkt2, ActionMeta::new());
-    pcap_guest2.add_pkt(&pkt2);
-    assert!(matches!(res, Ok(Modified)));
+    // ================================================================
+    // The packet leaves g1's port as IPv4, sourced from the NAT64
+    // address.
+    // ================================================================
+    let res = g1.port.process(Out, &mut pkt1, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)), "bad result: {:?}", res);
     incr!(
-        g2,
+        g1,
         [
-            "firewall.flows.in, firewall.flows.out",
-            "uft.in",
-            "stats.port.in_modified, stats.port.in_uft_miss",
+            "firewall.flows.out, firewall.flows.in",
+            "nat.flows.out, nat.flows.in",
+            "uft.out",
+            "stats.port.out_modified, stats.port.out_uft_miss",
         ]
     );
-    assert_eq!(pkt2.body_offset(), TCP_SZ + HTTP_SYN_OPTS_LEN);
-    assert_eq!(pkt2.body_seg(), 1);
+    let meta = pkt1.meta();
 
-    let g2_meta = pkt2.meta();
-    assert!(g2_meta.outer.ether.is_none());
-    assert!(g2_meta.outer.ip.is_none());
-    assert!(g2_meta.outer.encap.is_none());
+    match meta.outer.ip.as_ref().unwrap() {
+        IpMeta::Ip6(ip6) => {
+            assert_eq!(ip6.src, g1_cfg.phys_ip);
+            assert_eq!(ip6.dst, g1_cfg.boundary_services[0].ip);
+        }
 
-    let g2_eth = g2_meta.inner.ether;
-    assert_eq!(g2_eth.src, g1_cfg.gateway_mac);
-    assert_eq!(g2_eth.dst, g2_cfg.guest_mac);
-    assert_eq!(g2_eth.ether_type, EtherType::Ipv4);
+        val => panic!("expected outer IPv6, got: {:?}", val),
+    }
 
-    match g2_meta.inner.ip.as_ref().unwrap() {
+    let eth = meta.inner.ether;
+    assert_eq!(eth.src, g1_cfg.guest_mac);
+    assert_eq!(eth.dst, g1_cfg.boundary_services[0].mac);
+    assert_eq!(eth.ether_type, EtherType::Ipv4);
+
+    match meta.inner.ip.as_ref().unwrap() {
         IpMeta::Ip4(ip4) => {
-            assert_eq!(ip4.src, g1_cfg.ipv4_cfg().unwrap().private_ip);
-            assert_eq!(ip4.dst, g2_cfg.ipv4_cfg().unwrap().private_ip);
+            assert_eq!(ip4.src, nat64.external_ip);
+            assert_eq!(ip4.dst, dst_ip);
             assert_eq!(ip4.proto, Protocol::TCP);
+            assert_eq!(ip4.ttl, 64);
+            assert_eq!(
+                usize::from(ip4.total_len),
+                Ipv4Hdr::BASE_SIZE + TcpHdr::BASE_SIZE
+            );
+
+            // The header checksum is filled in for the final header.
+            let mut check = *ip4;
+            check.compute_hdr_csum();
+            assert_eq!(ip4.csum, check.csum);
         }
 
-        ip6 => panic!("execpted inner IPv4 metadata, got IPv6: {:?}", ip6),
+        ip6 => panic!("expected inner IPv4 metadata, got IPv6: {:?}", ip6),
     }
 
-    match g2_meta.inner.ulp.as_ref().unwrap() {
+    let mapped_port = match meta.inner.ulp.as_ref().unwrap() {
         UlpMeta::Tcp(tcp) => {
-            assert_eq!(tcp.src, 44490);
+            assert_eq!(tcp.src, nat64.ports.clone().rev().next().unwrap());
             assert_eq!(tcp.dst, 80);
+            tcp.src
         }
 
         ulp => panic!("expected inner TCP metadata, got: {:?}", ulp),
-    }
-}
+    };
 
-// Two guests on different, non-peered VPCs should not be able to
-// communicate.
-#[test]
-fn guest_to_guest_diff_vpc_no_peer() {
     // ================================================================
-    // Configure ports for g1 and g2. Place g1 on VNI 99 and g2 on VNI
-    // 100.
+    // The SYN+ACK from the IPv4 host is translated back to IPv6, from
+    // the host's address under the NAT64 prefix.
     // ================================================================
-    let g1_cfg = g1_cfg();
-    let mut g2_cfg = g2_cfg();
-    g2_cfg.vni = Vni::new(100u32).unwrap();
-    let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
-    g1.port.start();
-    set!(g1, "port_state=running");
-    let mut g2 = oxide_net_setup("g2_port", &g2_cfg, Some(g1.vpc_map.clone()));
-    g2.port.start();
-    set!(g2, "port_state=running");
+    let tcp = TcpMeta {
+        src: 80,
+        dst: mapped_port,
+        flags: TcpFlags::SYN | TcpFlags::ACK,
+        seq: 44161351,
+        ack: 2382112980,
+        ..Default::default()
+    };
+    let mut ip4 = Ipv4Meta {
+        src: dst_ip,
+        dst: nat64.external_ip,
+        proto: Protocol::TCP,
+        ttl: 50,
+        total_len: (Ipv4Hdr::BASE_SIZE + tcp.hdr_len()) as u16,
+        ..Default::default()
+    };
+    ip4.compute_hdr_csum();
+    let eth = EtherMeta {
+        ether_type: EtherType::Ipv4,
+        src: g1_cfg.boundary_services[0].mac,
+        dst: g1_cfg.guest_mac,
+    };
+    let bsvc_phys = TestIpPhys {
+        ip: g1_cfg.boundary_services[0].ip,
+        mac: g1_cfg.boundary_services[0].mac,
+        vni: g1_cfg.boundary_services[0].vni,
+    };
+    let g1_phys = TestIpPhys {
+        ip: g1_cfg.phys_ip,
+        mac: g1_cfg.guest_mac,
+        vni: g1_cfg.vni,
+    };
+    let mut pkt2 = encap(ulp_pkt(eth, ip4, tcp, &[]), bsvc_phys, g1_phys);
+    let res = g1.port.process(In, &mut pkt2, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)), "bad result: {:?}", res);
+    incr!(g1, ["uft.in", "stats.port.in_modified, stats.port.in_uft_miss"]);
+    let meta = pkt2.meta();
 
-    // Allow incoming TCP connection from anyone.
-    let rule = "dir=in action=allow priority=10 protocol=TCP";
-    firewall::add_fw_rule(
-        &g2.port,
-        &AddFwRuleReq {
-            port_name: g2.port.name().to_string(),
-            rule: rule.parse().unwrap(),
-        },
-    )
-    .unwrap();
-    incr!(g2, ["epoch", "firewall.rules.in"]);
+    let eth = meta.inner.ether;
+    assert_eq!(eth.src, g1_cfg.gateway_mac);
+    assert_eq!(eth.dst, g1_cfg.guest_mac);
+    assert_eq!(eth.ether_type, EtherType::Ipv6);
+
+    match meta.inner.ip.as_ref().unwrap() {
+        IpMeta::Ip6(ip6) => {
+            assert_eq!(ip6.src, dst_ip6);
+            assert_eq!(ip6.dst, priv_ip);
+            assert_eq!(ip6.proto, Protocol::TCP);
+            assert_eq!(ip6.next_hdr, IpProtocol::Tcp);
+            assert_eq!(ip6.hop_limit, 50);
+            assert_eq!(usize::from(ip6.pay_len), TcpHdr::BASE_SIZE);
+        }
 
-    // ================================================================
-    // Run the packet through g1's port in the outbound direction and
-    // verify the packet is dropped.
-    // ================================================================
-    let mut g1_pkt = http_syn(&g1_cfg, &g2_cfg);
-    let res = g1.port.process(Out, &mut g1_pkt, ActionMeta::new());
-    assert_drop!(
-        res,
-        DropReason::Layer { name: "overlay", reason: DenyReason::Action }
-    );
-    incr!(
-        g1,
-        [
-            "firewall.flows.in, firewall.flows.out",
-            "stats.port.out_drop, stats.port.out_drop_layer",
-            "stats.port.out_uft_miss",
-        ]
-    );
+        ip4 => panic!("expected inner IPv6 metadata, got IPv4: {:?}", ip4),
+    }
+
+    match meta.inner.ulp.as_ref().unwrap() {
+        UlpMeta::Tcp(tcp) => {
+            assert_eq!(tcp.src, 80);
+            assert_eq!(tcp.dst, 44490);
+        }
+
+        ulp => panic!("expected inner TCP metadata, got: {:?}", ulp),
+    }
 }
 
-// Verify that a guest can communicate with the internet.
+// Verify that a UDP reply from an IPv4 host which omits its checksum
+// is given a valid checksum when translated to IPv6, as IPv6
+// requires one.
 #[test]
-fn guest_to_internet() {
-    let g1_cfg = g1_cfg();
+fn nat64_udp_zero_csum() {
+    let g1_cfg = g1_nat64_cfg();
     let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
     g1.port.start();
     set!(g1, "port_state=running");
@@ -703,27 +2851,41 @@ fn guest_to_internet() {
     // Add router entry that allows g1 to route to internet.
     router::add_entry(
         &g1.port,
-        IpCidr::Ip4("0.0.0.0/0".parse().unwrap()),
+        IpCidr::Ip6("::/0".parse().unwrap()),
         RouterTarget::InternetGateway,
     )
     .unwrap();
     incr!(g1, ["epoch", "router.rules.out"]);
+    let nat64 = g1_cfg.ipv6().nat64.as_ref().unwrap();
+    let priv_ip = g1_cfg.ipv6().private_ip;
 
     // ================================================================
-    // Generate a TCP SYN packet from g1 to zinascii.com
-    // ================================================================
-    let dst_ip = "52.10.128.69".parse().unwrap();
-    let mut pkt1 = http_syn2(
-        g1_cfg.guest_mac,
-        g1_cfg.ipv4_cfg().unwrap().private_ip,
-        GW_MAC_ADDR,
-        dst_ip,
-    );
-
-    // ================================================================
-    // Run the packet through g1's port in the outbound direction and
-    // verify the resulting packet meets expectations.
+    // Generate a UDP datagram from g1 to an IPv4 internet host.
     // ================================================================
+    let dst_ip: Ipv4Addr = "52.10.128.69".parse().unwrap();
+    let dst_ip6 = Ipv6Addr::from_nat64(dst_ip);
+    let data = b"question";
+    let udp = UdpMeta {
+        src: 44490,
+        dst: 53,
+        len: (UdpHdr::SIZE + data.len()) as u16,
+        ..Default::default()
+    };
+    let ip6 = Ipv6Meta {
+        src: priv_ip,
+        dst: dst_ip6,
+        proto: Protocol::UDP,
+        next_hdr: IpProtocol::Udp,
+        hop_limit: 64,
+        pay_len: udp.len,
+        ..Default::default()
+    };
+    let eth = EtherMeta {
+        ether_type: EtherType::Ipv6,
+        src: g1_cfg.guest_mac,
+        dst: GW_MAC_ADDR,
+    };
+    let mut pkt1 = ulp_pkt(eth, ip6, udp, data);
     let res = g1.port.process(Out, &mut pkt1, ActionMeta::new());
     assert!(matches!(res, Ok(Modified)), "bad result: {:?}", res);
     incr!(
@@ -735,95 +2897,146 @@ fn guest_to_internet() {
             "stats.port.out_modified, stats.port.out_uft_miss",
         ]
     );
-    assert_eq!(pkt1.body_offset(), VPC_ENCAP_SZ + TCP_SZ + HTTP_SYN_OPTS_LEN);
-    assert_eq!(pkt1.body_seg(), 1);
-    let meta = pkt1.meta();
-    match meta.outer.ether.as_ref() {
-        Some(eth) => {
-            assert_eq!(eth.src, MacAddr::ZERO);
-            assert_eq!(eth.dst, MacAddr::ZERO);
-        }
-
-        None => panic!("no outer ether header"),
-    }
 
-    match meta.outer.ip.as_ref().unwrap() {
-        IpMeta::Ip6(ip6) => {
-            assert_eq!(ip6.src, g1_cfg.phys_ip);
-            assert_eq!(ip6.dst, g1_cfg.boundary_services.ip);
+    let mapped_port = match pkt1.meta().inner.ulp.as_ref().unwrap() {
+        UlpMeta::Udp(udp) => {
+            assert_eq!(udp.src, nat64.ports.clone().rev().next().unwrap());
+            assert_eq!(udp.dst, 53);
+            udp.src
         }
 
-        val => panic!("expected outer IPv6, got: {:?}", val),
-    }
-
-    match meta.outer.encap.as_ref() {
-        Some(EncapMeta::Geneve(geneve)) => {
-            assert_eq!(geneve.entropy, 7777);
-            assert_eq!(geneve.vni, g1_cfg.boundary_services.vni);
-        }
+        ulp => panic!("expected inner UDP metadata, got: {:?}", ulp),
+    };
 
-        None => panic!("expected outer Geneve metadata"),
-    }
+    // ================================================================
+    // The reply from the IPv4 host carries no UDP checksum.
+    // ================================================================
+    let data = b"answer";
+    let udp = UdpMeta {
+        src: 53,
+        dst: mapped_port,
+        len: (UdpHdr::SIZE + data.len()) as u16,
+        csum: [0; 2],
+    };
+    let mut ip4 = Ipv4Meta {
+        src: dst_ip,
+        dst: nat64.external_ip,
+        proto: Protocol::UDP,
+        ttl: 50,
+        total_len: (Ipv4Hdr::BASE_SIZE + usize::from(udp.len)) as u16,
+        ..Default::default()
+    };
+    ip4.compute_hdr_csum();
+    let eth = EtherMeta {
+        ether_type: EtherType::Ipv4,
+        src: g1_cfg.boundary_services[0].mac,
+        dst: g1_cfg.guest_mac,
+    };
+    let total_len = EtherHdr::SIZE + Ipv4Hdr::BASE_SIZE + usize::from(udp.len);
+    let mut pkt2 = Packet::alloc_and_expand(total_len);
+    let mut wtr = pkt2.seg0_wtr();
+    eth.emit(wtr.slice_mut(EtherHdr::SIZE).unwrap());
+    ip4.emit(wtr.slice_mut(Ipv4Hdr::BASE_SIZE).unwrap());
+    udp.emit(wtr.slice_mut(UdpHdr::SIZE).unwrap());
+    wtr.write(data).unwrap();
+    let pkt2 = pkt2.parse(In, GenericUlp {}).unwrap();
+    assert!(pkt2.body_csum().is_none());
 
-    let eth = meta.inner.ether;
-    assert_eq!(eth.src, g1_cfg.guest_mac);
-    assert_eq!(eth.dst, g1_cfg.boundary_services.mac);
-    assert_eq!(eth.ether_type, EtherType::Ipv4);
+    let bsvc_phys = TestIpPhys {
+        ip: g1_cfg.boundary_services[0].ip,
+        mac: g1_cfg.boundary_services[0].mac,
+        vni: g1_cfg.boundary_services[0].vni,
+    };
+    let g1_phys = TestIpPhys {
+        ip: g1_cfg.phys_ip,
+        mac: g1_cfg.guest_mac,
+        vni: g1_cfg.vni,
+    };
+    let mut pkt2 = encap(pkt2, bsvc_phys, g1_phys);
+    let res = g1.port.process(In, &mut pkt2, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)), "bad result: {:?}", res);
+    incr!(g1, ["uft.in", "stats.port.in_modified, stats.port.in_uft_miss"]);
+    let meta = pkt2.meta();
 
     match meta.inner.ip.as_ref().unwrap() {
-        IpMeta::Ip4(ip4) => {
-            assert_eq!(ip4.src, g1_cfg.snat().external_ip);
-            assert_eq!(ip4.dst, dst_ip);
-            assert_eq!(ip4.proto, Protocol::TCP);
+        IpMeta::Ip6(ip6) => {
+            assert_eq!(ip6.src, dst_ip6);
+            assert_eq!(ip6.dst, priv_ip);
+            assert_eq!(ip6.proto, Protocol::UDP);
         }
 
-        ip6 => panic!("execpted inner IPv4 metadata, got IPv6: {:?}", ip6),
+        ip4 => panic!("expected inner IPv6 metadata, got IPv4: {:?}", ip4),
     }
 
-    match meta.inner.ulp.as_ref().unwrap() {
-        UlpMeta::Tcp(tcp) => {
-            assert_eq!(
-                tcp.src,
-                g1_cfg.snat().ports.clone().rev().next().unwrap(),
-            );
-            assert_eq!(tcp.dst, 80);
+    // The translated datagram carries a valid checksum.
+    let udp = match meta.inner.ulp.as_ref().unwrap() {
+        UlpMeta::Udp(udp) => {
+            assert_eq!(udp.src, 53);
+            assert_eq!(udp.dst, 44490);
+            assert_ne!(udp.csum, [0; 2]);
+            *udp
         }
 
-        ulp => panic!("expected inner TCP metadata, got: {:?}", ulp),
-    }
+        ulp => panic!("expected inner UDP metadata, got: {:?}", ulp),
+    };
+
+    let mut bytes = vec![0; UdpHdr::SIZE];
+    udp.emit(&mut bytes);
+    bytes.extend_from_slice(pkt2.body_segs().unwrap()[0]);
+    let udp_pkt = UdpPacket::new_checked(&bytes[..]).unwrap();
+    assert!(udp_pkt.verify_checksum(
+        &IpAddress::Ipv6(dst_ip6.into()),
+        &IpAddress::Ipv6(priv_ip.into()),
+    ));
+    assert_eq!(udp_pkt.payload(), &data[..]);
 }
 
-// Verify that an ICMP Echo request has its identifier rewritten by
-// SNAT.
+// Verify that an ICMPv6 Echo Request to the NAT64 prefix is translated
+// to an ICMP Echo Request, and that the ICMP Echo Reply is translated
+// back to ICMPv6, both with valid checksums.
 #[test]
-fn snat_icmp4_echo_rewrite() {
-    let g1_cfg = g1_cfg();
+fn nat64_icmp_echo() {
+    let g1_cfg = g1_nat64_cfg();
     let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
     g1.port.start();
     set!(g1, "port_state=running");
-    let dst_ip: Ipv4Addr = "45.55.45.205".parse().unwrap();
+    let dst_ip: Ipv4Addr = "52.10.128.69".parse().unwrap();
+    let dst_ip6 = Ipv6Addr::from_nat64(dst_ip);
     let ident = 7;
-    let mut seq_no = 777;
+    let seq_no = 777;
     let data = b"reunion\0";
 
     // Add router entry that allows g1 to route to internet.
     router::add_entry(
         &g1.port,
-        IpCidr::Ip4("0.0.0.0/0".parse().unwrap()),
+        IpCidr::Ip6("::/0".parse().unwrap()),
         RouterTarget::InternetGateway,
     )
     .unwrap();
     incr!(g1, ["epoch", "router.rules.out"]);
-    let mapped_port = g1_cfg.snat().ports.clone().rev().next().unwrap();
+    let nat64 = g1_cfg.ipv6().nat64.as_ref().unwrap();
+    let mapped_port = nat64.ports.clone().rev().next().unwrap();
+    let ext_ip = nat64.external_ip;
+    let priv_ip = g1_cfg.ipv6().private_ip;
+    let bsvc_phys = TestIpPhys {
+        ip: g1_cfg.boundary_services[0].ip,
+        mac: g1_cfg.boundary_services[0].mac,
+        vni: g1_cfg.boundary_services[0].vni,
+    };
+    let g1_phys = TestIpPhys {
+        ip: g1_cfg.phys_ip,
+        mac: g1_cfg.guest_mac,
+        vni: g1_cfg.vni,
+    };
 
     // ================================================================
-    // Verify echo request rewrite.
+    // Verify echo request translation.
     // ================================================================
-    let mut pkt1 = gen_icmp_echo_req(
+    let mut pkt1 = gen_icmpv6_echo_req(
         g1_cfg.guest_mac,
         g1_cfg.gateway_mac,
-        g1_cfg.ipv4().private_ip.into(),
-        dst_ip.into(),
+        priv_ip,
+        dst_ip6,
         ident,
         seq_no,
         &data[..],
@@ -840,174 +3053,234 @@ fn snat_icmp4_echo_rewrite() {
             "stats.port.out_modified, stats.port.out_uft_miss",
         ]
     );
-
-    assert_eq!(pkt1.body_offset(), VPC_ENCAP_SZ + IP_SZ);
-    assert_eq!(pkt1.body_seg(), 1);
     let meta = pkt1.meta();
-
-    let eth = meta.inner.ether;
-    assert_eq!(eth.src, g1_cfg.guest_mac);
-    assert_eq!(eth.dst, g1_cfg.boundary_services.mac);
-    assert_eq!(eth.ether_type, EtherType::Ipv4);
+    assert_eq!(meta.inner.ether.ether_type, EtherType::Ipv4);
 
     match meta.inner.ip.as_ref().unwrap() {
         IpMeta::Ip4(ip4) => {
-            assert_eq!(ip4.src, g1_cfg.snat().external_ip);
+            assert_eq!(ip4.src, ext_ip);
             assert_eq!(ip4.dst, dst_ip);
             assert_eq!(ip4.proto, Protocol::ICMP);
         }
 
-        ip6 => panic!("execpted inner IPv4 metadata, got IPv6: {:?}", ip6),
+        ip6 => panic!("expected inner IPv4 metadata, got IPv6: {:?}", ip6),
     }
 
     let body = pkt1.body_segs().unwrap()[0];
     let icmp = Icmpv4Packet::new_checked(body).unwrap();
     assert!(icmp.verify_checksum());
+    assert_eq!(icmp.msg_type(), Icmpv4Message::EchoRequest);
     assert_eq!(icmp.echo_ident(), mapped_port);
     assert_eq!(icmp.echo_seq_no(), seq_no);
+    assert_eq!(icmp.data(), &data[..]);
 
     // ================================================================
-    // Verify echo reply rewrite.
+    // Verify echo reply translation.
     // ================================================================
-    let mut pkt2 = gen_icmp_echo_reply(
-        g1_cfg.boundary_services.mac,
+    let pkt2 = gen_icmp_echo_reply(
+        g1_cfg.boundary_services[0].mac,
         g1_cfg.guest_mac,
         dst_ip,
-        g1_cfg.snat().external_ip,
+        ext_ip,
         mapped_port,
         seq_no,
         &data[..],
     );
-    let bsvc_phys = TestIpPhys {
-        ip: g1_cfg.boundary_services.ip,
-        mac: g1_cfg.boundary_services.mac,
-        vni: g1_cfg.boundary_services.vni,
-    };
-    let g1_phys = TestIpPhys {
-        ip: g1_cfg.phys_ip,
-        mac: g1_cfg.guest_mac,
-        vni: g1_cfg.vni,
-    };
-    pkt2 = encap(pkt2, bsvc_phys, g1_phys);
+    let mut pkt2 = encap(pkt2, bsvc_phys, g1_phys);
 
     let res = g1.port.process(In, &mut pkt2, ActionMeta::new());
     assert!(matches!(res, Ok(Modified)), "bad result: {:?}", res);
     incr!(g1, ["uft.in", "stats.port.in_modified, stats.port.in_uft_miss"]);
-    assert_eq!(pkt2.body_offset(), IP_SZ);
-    assert_eq!(pkt2.body_seg(), 0);
     let meta = pkt2.meta();
-
-    let eth = meta.inner.ether;
-    assert_eq!(eth.src, g1_cfg.gateway_mac);
-    assert_eq!(eth.dst, g1_cfg.guest_mac);
-    assert_eq!(eth.ether_type, EtherType::Ipv4);
+    assert_eq!(meta.inner.ether.ether_type, EtherType::Ipv6);
 
     match meta.inner.ip.as_ref().unwrap() {
-        IpMeta::Ip4(ip4) => {
-            assert_eq!(ip4.src, dst_ip);
-            assert_eq!(ip4.dst, g1_cfg.ipv4().private_ip);
-            assert_eq!(ip4.proto, Protocol::ICMP);
+        IpMeta::Ip6(ip6) => {
+            assert_eq!(ip6.src, dst_ip6);
+            assert_eq!(ip6.dst, priv_ip);
+            assert_eq!(ip6.proto, Protocol::ICMPv6);
         }
 
-        ip6 => panic!("execpted inner IPv4 metadata, got IPv6: {:?}", ip6),
+        ip4 => panic!("expected inner IPv6 metadata, got IPv4: {:?}", ip4),
     }
 
     let body = pkt2.body_segs().unwrap()[0];
-    let icmp = Icmpv4Packet::new_checked(body).unwrap();
-    assert!(icmp.verify_checksum());
+    let icmp = Icmpv6Packet::new_checked(body).unwrap();
+    assert!(icmp.verify_checksum(
+        &IpAddress::Ipv6(dst_ip6.into()),
+        &IpAddress::Ipv6(priv_ip.into()),
+    ));
+    assert_eq!(icmp.msg_type(), Icmpv6Message::EchoReply);
     assert_eq!(icmp.echo_ident(), ident);
     assert_eq!(icmp.echo_seq_no(), seq_no);
+}
+
+// Verify that an ICMP Fragmentation Needed from a router on the path
+// of a NAT64 flow is translated to an ICMPv6 Packet Too Big, along
+// with the packet in error it carries, for the guest to match it to
+// its connection.
+#[test]
+fn nat64_icmp_frag_needed() {
+    let g1_cfg = g1_nat64_cfg();
+    let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
+    g1.port.start();
+    set!(g1, "port_state=running");
+
+    // Add router entry that allows g1 to route to internet.
+    router::add_entry(
+        &g1.port,
+        IpCidr::Ip6("::/0".parse().unwrap()),
+        RouterTarget::InternetGateway,
+    )
+    .unwrap();
+    incr!(g1, ["epoch", "router.rules.out"]);
+    let nat64 = g1_cfg.ipv6().nat64.as_ref().unwrap();
+    let priv_ip = g1_cfg.ipv6().private_ip;
+    let dst_ip: Ipv4Addr = "52.10.128.69".parse().unwrap();
+    let dst_ip6 = Ipv6Addr::from_nat64(dst_ip);
+    let router_ip: Ipv4Addr = "198.51.100.1".parse().unwrap();
+    let router_ip6 = Ipv6Addr::from_nat64(router_ip);
 
     // ================================================================
-    // Send ICMP Echo Req a second time. We want to verify that a) the
-    // UFT entry is used and b) that it runs the attached body
-    // transformation.
+    // Send a TCP SYN from g1 to an IPv4 internet host, keeping the
+    // IPv4 packet that leaves the port.
     // ================================================================
-    seq_no += 1;
-    let mut pkt3 = gen_icmp_echo_req(
-        g1_cfg.guest_mac,
-        g1_cfg.gateway_mac,
-        g1_cfg.ipv4().private_ip.into(),
-        dst_ip.into(),
-        ident,
-        seq_no,
-        &data[..],
-    );
-
-    assert_eq!(g1.port.stats_snap().out_uft_hit, 0);
-    let res = g1.port.process(Out, &mut pkt3, ActionMeta::new());
+    let tcp = TcpMeta {
+        src: 44490,
+        dst: 80,
+        flags: TcpFlags::SYN,
+        seq: 2382112979,
+        ..Default::default()
+    };
+    let ip6 = Ipv6Meta {
+        src: priv_ip,
+        dst: dst_ip6,
+        proto: Protocol::TCP,
+        next_hdr: IpProtocol::Tcp,
+        hop_limit: 64,
+        pay_len: tcp.hdr_len() as u16,
+        ..Default::default()
+    };
+    let eth = EtherMeta {
+        ether_type: EtherType::Ipv6,
+        src: g1_cfg.guest_mac,
+        dst: GW_MAC_ADDR,
+    };
+    let mut pkt1 = ulp_pkt(eth, ip6, tcp, &[]);
+    let res = g1.port.process(Out, &mut pkt1, ActionMeta::new());
     assert!(matches!(res, Ok(Modified)), "bad result: {:?}", res);
-    incr!(g1, ["stats.port.out_modified, stats.port.out_uft_hit"]);
-    assert_eq!(pkt3.body_offset(), VPC_ENCAP_SZ + IP_SZ);
-    assert_eq!(pkt3.body_seg(), 1);
-    let meta = pkt3.meta();
-
-    let eth = meta.inner.ether;
-    assert_eq!(eth.src, g1_cfg.guest_mac);
-    assert_eq!(eth.dst, g1_cfg.boundary_services.mac);
-    assert_eq!(eth.ether_type, EtherType::Ipv4);
-
-    match meta.inner.ip.as_ref().unwrap() {
-        IpMeta::Ip4(ip4) => {
-            assert_eq!(ip4.src, g1_cfg.snat().external_ip);
-            assert_eq!(ip4.dst, dst_ip);
-            assert_eq!(ip4.proto, Protocol::ICMP);
-        }
-
-        ip6 => panic!("execpted inner IPv4 metadata, got IPv6: {:?}", ip6),
-    }
-
-    let body = pkt3.body_segs().unwrap()[0];
-    let icmp = Icmpv4Packet::new_checked(body).unwrap();
-    assert!(icmp.verify_checksum());
-    assert_eq!(icmp.echo_ident(), mapped_port);
-    assert_eq!(icmp.echo_seq_no(), seq_no);
-    assert_eq!(g1.port.stats_snap().out_uft_hit, 1);
+    incr!(
+        g1,
+        [
+            "firewall.flows.out, firewall.flows.in",
+            "nat.flows.out, nat.flows.in",
+            "uft.out",
+            "stats.port.out_modified, stats.port.out_uft_miss",
+        ]
+    );
+
+    let mut sent = vec![0; Ipv4Hdr::BASE_SIZE + TcpHdr::BASE_SIZE];
+    match pkt1.meta().inner.ip.as_ref().unwrap() {
+        IpMeta::Ip4(ip4) => ip4.emit(&mut sent[..Ipv4Hdr::BASE_SIZE]),
+        ip6 => panic!("expected inner IPv4 metadata, got IPv6: {:?}", ip6),
+    }
+    match pkt1.meta().inner.ulp.as_ref().unwrap() {
+        UlpMeta::Tcp(tcp) => tcp.emit(&mut sent[Ipv4Hdr::BASE_SIZE..]),
+        ulp => panic!("expected inner TCP metadata, got: {:?}", ulp),
+    }
 
     // ================================================================
-    // Process ICMP Echo Reply a second time. Once again, this time we
-    // want to verify that the body transformation comes from the UFT
-    // entry.
+    // A router on the path reports that the packet needs
+    // fragmentation, carrying the packet in error.
     // ================================================================
-    let mut pkt4 = gen_icmp_echo_reply(
-        g1_cfg.boundary_services.mac,
-        g1_cfg.guest_mac,
-        dst_ip,
-        g1_cfg.snat().external_ip,
-        mapped_port,
-        seq_no,
-        &data[..],
-    );
+    let mut icmp_bytes = vec![3, 4, 0, 0, 0, 0];
+    icmp_bytes.extend_from_slice(&1400u16.to_be_bytes());
+    icmp_bytes.extend_from_slice(&sent);
+    Icmpv4Packet::new_unchecked(&mut icmp_bytes[..]).fill_checksum();
+    let mut ip4 = Ipv4Meta {
+        src: router_ip,
+        dst: nat64.external_ip,
+        proto: Protocol::ICMP,
+        ttl: 60,
+        total_len: (Ipv4Hdr::BASE_SIZE + icmp_bytes.len()) as u16,
+        ..Default::default()
+    };
+    ip4.compute_hdr_csum();
+    let eth = EtherMeta {
+        ether_type: EtherType::Ipv4,
+        src: g1_cfg.boundary_services[0].mac,
+        dst: g1_cfg.guest_mac,
+    };
+    let total_len = EtherHdr::SIZE + usize::from(ip4.total_len);
+    let mut pkt2 = Packet::alloc_and_expand(total_len);
+    let mut wtr = pkt2.seg0_wtr();
+    eth.emit(wtr.slice_mut(EtherHdr::SIZE).unwrap());
+    ip4.emit(wtr.slice_mut(Ipv4Hdr::BASE_SIZE).unwrap());
+    wtr.write(&icmp_bytes).unwrap();
+    let pkt2 = pkt2.parse(In, GenericUlp {}).unwrap();
 
-    assert_eq!(g1.port.stats_snap().in_uft_hit, 0);
-    let res = g1.port.process(In, &mut pkt4, ActionMeta::new());
-    assert!(matches!(res, Ok(Modified)), "bad result: {:?}", res);
-    incr!(g1, ["stats.port.in_modified, stats.port.in_uft_hit"]);
-    assert_eq!(pkt4.body_offset(), IP_SZ);
-    assert_eq!(pkt4.body_seg(), 0);
-    let meta = pkt4.meta();
+    let bsvc_phys = TestIpPhys {
+        ip: g1_cfg.boundary_services[0].ip,
+        mac: g1_cfg.boundary_services[0].mac,
+        vni: g1_cfg.boundary_services[0].vni,
+    };
+    let g1_phys = TestIpPhys {
+        ip: g1_cfg.phys_ip,
+        mac: g1_cfg.guest_mac,
+        vni: g1_cfg.vni,
+    };
+    let mut pkt2 = encap(pkt2, bsvc_phys, g1_phys);
 
-    let eth = meta.inner.ether;
-    assert_eq!(eth.src, g1_cfg.gateway_mac);
-    assert_eq!(eth.dst, g1_cfg.guest_mac);
-    assert_eq!(eth.ether_type, EtherType::Ipv4);
+    // ================================================================
+    // The error reaches g1 as an ICMPv6 Packet Too Big from the
+    // router's address under the NAT64 prefix. It's processed by the
+    // flow of the packet in error, and thus creates no flow of its
+    // own.
+    // ================================================================
+    let res = g1.port.process(In, &mut pkt2, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)), "bad result: {:?}", res);
+    incr!(g1, ["stats.port.in_modified, stats.port.in_uft_miss"]);
+    let meta = pkt2.meta();
+    assert_eq!(meta.inner.ether.ether_type, EtherType::Ipv6);
 
+    let body = pkt2.get_body_rdr().copy_remaining();
     match meta.inner.ip.as_ref().unwrap() {
-        IpMeta::Ip4(ip4) => {
-            assert_eq!(ip4.src, dst_ip);
-            assert_eq!(ip4.dst, g1_cfg.ipv4().private_ip);
-            assert_eq!(ip4.proto, Protocol::ICMP);
+        IpMeta::Ip6(ip6) => {
+            assert_eq!(ip6.src, router_ip6);
+            assert_eq!(ip6.dst, priv_ip);
+            assert_eq!(ip6.proto, Protocol::ICMPv6);
+            assert_eq!(ip6.hop_limit, 60);
+            assert_eq!(usize::from(ip6.pay_len), body.len());
         }
 
-        ip6 => panic!("execpted inner IPv4 metadata, got IPv6: {:?}", ip6),
+        ip4 => panic!("expected inner IPv6 metadata, got IPv4: {:?}", ip4),
     }
 
-    let body = pkt4.body_segs().unwrap()[0];
-    let icmp = Icmpv4Packet::new_checked(body).unwrap();
-    assert!(icmp.verify_checksum());
-    assert_eq!(icmp.echo_ident(), ident);
-    assert_eq!(icmp.echo_seq_no(), seq_no);
-    assert_eq!(g1.port.stats_snap().in_uft_hit, 1);
+    // The MTU accounts for the larger IPv6 header.
+    let icmp = Icmpv6Packet::new_checked(&body[..]).unwrap();
+    assert!(icmp.verify_checksum(
+        &IpAddress::Ipv6(router_ip6.into()),
+        &IpAddress::Ipv6(priv_ip.into()),
+    ));
+    assert_eq!(icmp.msg_type(), Icmpv6Message::PktTooBig);
+    assert_eq!(icmp.msg_code(), 0);
+    assert_eq!(icmp.pkt_too_big_mtu(), 1420);
+
+    // The packet in error is the one g1 sent, with a valid checksum.
+    let ip6 = Ipv6Packet::new_checked(icmp.payload()).unwrap();
+    assert_eq!(Ipv6Addr::from(ip6.src_addr()), priv_ip);
+    assert_eq!(Ipv6Addr::from(ip6.dst_addr()), dst_ip6);
+    assert_eq!(ip6.next_header(), IpProtocol::Tcp);
+    assert_eq!(ip6.hop_limit(), 64);
+    assert_eq!(usize::from(ip6.payload_len()), TcpHdr::BASE_SIZE);
+
+    let tcp = TcpPacket::new_checked(ip6.payload()).unwrap();
+    assert!(tcp.verify_checksum(
+        &IpAddress::Ipv6(priv_ip.into()),
+        &IpAddress::Ipv6(dst_ip6.into()),
+    ));
+    assert_eq!(tcp.src_port(), 44490);
+    assert_eq!(tcp.dst_port(), 80);
 }
 
 #[test]
@@ -2099,16 +4372,16 @@ fn establish_http_conn(
     // direction and verify it is accepted.
     // ================================================================
     let mut pkt2 = http_syn_ack2(
-        g1_cfg.boundary_services.mac,
+        g1_cfg.boundary_services[0].mac,
         dst_ip,
         g1_cfg.guest_mac,
         g1_cfg.snat().external_ip,
         snat_port,
     );
     let bs_phys = TestIpPhys {
-        ip: g1_cfg.boundary_services.ip,
-        mac: g1_cfg.boundary_services.mac,
-        vni: g1_cfg.boundary_services.vni,
+        ip: g1_cfg.boundary_services[0].ip,
+        mac: g1_cfg.boundary_services[0].mac,
+        vni: g1_cfg.boundary_services[0].vni,
     };
     let g1_phys = TestIpPhys {
         ip: g1_cfg.phys_ip,
@@ -2261,9 +4534,9 @@ fn uft_lft_invalidation_in() {
     // ================================================================
     let dst_ip = "52.10.128.69".parse().unwrap();
     let bs_phys = TestIpPhys {
-        ip: g1_cfg.boundary_services.ip,
-        mac: g1_cfg.boundary_services.mac,
-        vni: g1_cfg.boundary_services.vni,
+        ip: g1_cfg.boundary_services[0].ip,
+        mac: g1_cfg.boundary_services[0].mac,
+        vni: g1_cfg.boundary_services[0].vni,
     };
     let g1_phys = TestIpPhys {
         ip: g1_cfg.phys_ip,
@@ -2283,7 +4556,7 @@ fn uft_lft_invalidation_in() {
     incr!(g1, ["stats.port.out_modified, stats.port.out_uft_hit"]);
 
     let mut pkt2 = http_get_ack2(
-        g1_cfg.boundary_services.mac,
+        g1_cfg.boundary_services[0].mac,
         dst_ip,
         g1_cfg.guest_mac,
         g1_cfg.snat().external_ip,
@@ -2319,7 +4592,7 @@ fn uft_lft_invalidation_in() {
     // Step 4
     // ================================================================
     let mut pkt3 = http_301_reply2(
-        g1_cfg.boundary_services.mac,
+        g1_cfg.boundary_services[0].mac,
         dst_ip,
         g1_cfg.guest_mac,
         g1_cfg.snat().external_ip,
@@ -2363,9 +4636,9 @@ fn tcp_outbound() {
     incr!(g1, ["epoch", "router.rules.out"]);
 
     let bs_phys = TestIpPhys {
-        ip: g1_cfg.boundary_services.ip,
-        mac: g1_cfg.boundary_services.mac,
-        vni: g1_cfg.boundary_services.vni,
+        ip: g1_cfg.boundary_services[0].ip,
+        mac: g1_cfg.boundary_services[0].mac,
+        vni: g1_cfg.boundary_services[0].vni,
     };
     let g1_phys = TestIpPhys {
         ip: g1_cfg.phys_ip,
@@ -2402,7 +4675,7 @@ fn tcp_outbound() {
     // SYN+ACK: Server -> Client
     // ================================================================
     let mut pkt2 = http_syn_ack2(
-        g1_cfg.boundary_services.mac,
+        g1_cfg.boundary_services[0].mac,
         dst_ip,
         g1_cfg.guest_mac,
         g1_cfg.snat().external_ip,
@@ -2446,7 +4719,7 @@ fn tcp_outbound() {
     // ACK HTTP GET: Server -> Client
     // ================================================================
     let mut pkt5 = http_get_ack2(
-        g1_cfg.boundary_services.mac,
+        g1_cfg.boundary_services[0].mac,
         dst_ip,
         g1_cfg.guest_mac,
         g1_cfg.snat().external_ip,
@@ -2462,7 +4735,7 @@ fn tcp_outbound() {
     // HTTP 301 Reply: Server -> Client
     // ================================================================
     let mut pkt6 = http_301_reply2(
-        g1_cfg.boundary_services.mac,
+        g1_cfg.boundary_services[0].mac,
         dst_ip,
         g1_cfg.guest_mac,
         g1_cfg.snat().external_ip,
@@ -2506,7 +4779,7 @@ fn tcp_outbound() {
     // ACK FIN: Server -> Client
     // ================================================================
     let mut pkt9 = http_server_ack_fin2(
-        g1_cfg.boundary_services.mac,
+        g1_cfg.boundary_services[0].mac,
         dst_ip,
         g1_cfg.guest_mac,
         g1_cfg.snat().external_ip,
@@ -2522,7 +4795,7 @@ fn tcp_outbound() {
     // FIN: Server -> Client
     // ================================================================
     let mut pkt10 = http_server_fin2(
-        g1_cfg.boundary_services.mac,
+        g1_cfg.boundary_services[0].mac,
         dst_ip,
         g1_cfg.guest_mac,
         g1_cfg.snat().external_ip,
@@ -2573,8 +4846,10 @@ fn tcp_inbound() {
             snat: Some(SNat4Cfg {
                 external_ip: "10.77.77.13".parse().unwrap(),
                 ports: 1025..=4096,
+                mapping: NatMapping::PerFlow,
+                max_ports: None,
             }),
-            external_ips: Some("10.60.1.20".parse().unwrap()),
+            external_ips: vec!["10.60.1.20".parse().unwrap()],
         },
         ipv6: Ipv6Cfg {
             vpc_subnet: "fd00::/64".parse().unwrap(),
@@ -2583,8 +4858,11 @@ fn tcp_inbound() {
             snat: Some(SNat6Cfg {
                 external_ip: "2001:db8::1".parse().unwrap(),
                 ports: 1025..=4096,
+                mapping: NatMapping::PerFlow,
+                max_ports: None,
             }),
-            external_ips: None,
+            external_ips: vec![],
+            nat64: None,
         },
     };
 
@@ -2603,13 +4881,13 @@ fn tcp_inbound() {
     incr!(g1, ["epoch", "router.rules.out"]);
 
     let client_ip = "52.10.128.69".parse().unwrap();
-    let bs_mac = g1_cfg.boundary_services.mac;
+    let bs_mac = g1_cfg.boundary_services[0].mac;
     let serv_mac = g1_cfg.guest_mac;
-    let serv_ext_ip = g1_cfg.ipv4().external_ips.unwrap();
+    let serv_ext_ip = g1_cfg.ipv4().external_ips[0];
     let bs_phys = TestIpPhys {
-        ip: g1_cfg.boundary_services.ip,
-        mac: g1_cfg.boundary_services.mac,
-        vni: g1_cfg.boundary_services.vni,
+        ip: g1_cfg.boundary_services[0].ip,
+        mac: g1_cfg.boundary_services[0].mac,
+        vni: g1_cfg.boundary_services[0].vni,
     };
     let g1_phys = TestIpPhys {
         ip: g1_cfg.phys_ip,
@@ -2656,7 +4934,7 @@ fn tcp_inbound() {
     // ACK: Client -> Server
     // ================================================================
     let mut pkt3 = http_ack2(
-        g1_cfg.boundary_services.mac,
+        g1_cfg.boundary_services[0].mac,
         client_ip,
         serv_mac,
         serv_ext_ip,
@@ -2737,42 +5015,409 @@ fn tcp_inbound() {
         client_ip,
         sport,
     );
-    let res = g1.port.process(Out, &mut pkt9, ActionMeta::new());
-    assert!(matches!(res, Ok(Modified)));
-    incr!(g1, ["stats.port.out_modified, stats.port.out_uft_hit"]);
-    assert_eq!(TcpState::CloseWait, g1.port.tcp_state(&flow).unwrap());
+    let res = g1.port.process(Out, &mut pkt9, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(g1, ["stats.port.out_modified, stats.port.out_uft_hit"]);
+    assert_eq!(TcpState::CloseWait, g1.port.tcp_state(&flow).unwrap());
+
+    // ================================================================
+    // FIN: Server -> Client
+    // ================================================================
+    let mut pkt10 = http_server_fin2(
+        serv_mac,
+        g1_cfg.ipv4().private_ip,
+        GW_MAC_ADDR,
+        client_ip,
+        sport,
+    );
+    let res = g1.port.process(Out, &mut pkt10, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(g1, ["stats.port.out_modified, stats.port.out_uft_hit"]);
+    assert_eq!(TcpState::LastAck, g1.port.tcp_state(&flow).unwrap());
+
+    // ================================================================
+    // ACK Server FIN: Client -> Server
+    // ================================================================
+    let mut pkt11 =
+        http_guest_ack_fin2(bs_mac, client_ip, serv_mac, serv_ext_ip);
+    pkt11 = encap(pkt11, bs_phys, g1_phys);
+    let res = g1.port.process(In, &mut pkt11, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    update!(
+        g1,
+        [
+            "incr:stats.port.in_modified, stats.port.in_uft_hit",
+            "set:uft.in=1, uft.out=0",
+        ]
+    );
+    assert_eq!(None, g1.port.tcp_state(&flow));
+}
+
+// Verify that floating IPs may be attached and detached at runtime:
+// each one is 1:1 NAT'd to the private IP inbound, the first one is
+// used outbound, and a detached IP is no longer used either way.
+#[test]
+fn floating_ip_attach_detach() {
+    let g1_cfg = g1_cfg();
+    let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
+    g1.port.start();
+    set!(g1, "port_state=running");
+    router::add_entry(
+        &g1.port,
+        IpCidr::Ip4("0.0.0.0/0".parse().unwrap()),
+        RouterTarget::InternetGateway,
+    )
+    .unwrap();
+    incr!(g1, ["epoch", "router.rules.out"]);
+
+    // Send a TCP SYN from the guest to `dst_ip`, returning the source
+    // IP it's NAT'd to.
+    fn out_src(
+        g1: &mut PortAndVps,
+        cfg: &VpcCfg,
+        dst_ip: Ipv4Addr,
+    ) -> Ipv4Addr {
+        let mut pkt = http_syn2(
+            cfg.guest_mac,
+            cfg.ipv4().private_ip,
+            GW_MAC_ADDR,
+            dst_ip,
+        );
+        let res = g1.port.process(Out, &mut pkt, ActionMeta::new());
+        assert!(matches!(res, Ok(Modified)), "bad result: {:?}", res);
+        incr!(
+            g1,
+            [
+                "firewall.flows.out, firewall.flows.in",
+                "nat.flows.out, nat.flows.in",
+                "uft.out",
+                "stats.port.out_modified, stats.port.out_uft_miss",
+            ]
+        );
+        match pkt.meta().inner.ip.as_ref().unwrap() {
+            IpMeta::Ip4(ip4) => ip4.src,
+            val => panic!("expected inner IPv4, got: {:?}", val),
+        }
+    }
+
+    let fip1: Ipv4Addr = "10.60.1.20".parse().unwrap();
+    let fip2: Ipv4Addr = "10.60.1.21".parse().unwrap();
+
+    // ================================================================
+    // Attach two floating IPs. Only the first one adds an outbound
+    // rule, and attaching an IP twice is a no-op.
+    // ================================================================
+    nat::attach_floating_ip(&g1.port, &g1_cfg, fip1.into()).unwrap();
+    incr!(g1, ["epoch", "nat.rules.in", "nat.rules.out"]);
+    nat::attach_floating_ip(&g1.port, &g1_cfg, fip2.into()).unwrap();
+    incr!(g1, ["epoch", "nat.rules.in"]);
+    nat::attach_floating_ip(&g1.port, &g1_cfg, fip2.into()).unwrap();
+    assert_port!(g1);
+
+    let fips = nat::floating_ips(&g1.port).unwrap();
+    assert_eq!(fips.len(), 2);
+    assert!(fips.contains(&fip1.into()));
+    assert!(fips.contains(&fip2.into()));
+
+    // The SNAT IP cannot be used as a floating IP.
+    assert!(matches!(
+        nat::attach_floating_ip(
+            &g1.port,
+            &g1_cfg,
+            g1_cfg.snat().external_ip.into()
+        ),
+        Err(OpteError::InvalidFloatingIp(_))
+    ));
+
+    // ================================================================
+    // Outbound traffic uses the first floating IP.
+    // ================================================================
+    let dst1 = "52.10.128.69".parse().unwrap();
+    assert_eq!(out_src(&mut g1, &g1_cfg, dst1), fip1);
+
+    // ================================================================
+    // Inbound traffic to the second floating IP, delivered by
+    // Boundary Services, reaches the guest's private IP.
+    // ================================================================
+    let client_ip = "52.10.128.70".parse().unwrap();
+    let bs_phys = TestIpPhys {
+        ip: g1_cfg.boundary_services[0].ip,
+        mac: g1_cfg.boundary_services[0].mac,
+        vni: g1_cfg.vni,
+    };
+    let g1_phys = TestIpPhys {
+        ip: g1_cfg.phys_ip,
+        mac: g1_cfg.guest_mac,
+        vni: g1_cfg.vni,
+    };
+    let mut pkt = http_syn2(
+        g1_cfg.boundary_services[0].mac,
+        client_ip,
+        g1_cfg.guest_mac,
+        fip2,
+    );
+    pkt = encap(pkt, bs_phys, g1_phys);
+    let res = g1.port.process(In, &mut pkt, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)), "bad result: {:?}", res);
+    incr!(
+        g1,
+        [
+            "firewall.flows.out, firewall.flows.in",
+            "nat.flows.in, nat.flows.out",
+            "uft.in",
+            "stats.port.in_modified, stats.port.in_uft_miss",
+        ]
+    );
+    match pkt.meta().inner.ip.as_ref().unwrap() {
+        IpMeta::Ip4(ip4) => {
+            assert_eq!(ip4.src, client_ip);
+            assert_eq!(ip4.dst, g1_cfg.ipv4().private_ip);
+        }
+
+        val => panic!("expected inner IPv4, got: {:?}", val),
+    }
+
+    // ================================================================
+    // Detaching the first floating IP moves outbound traffic to the
+    // second one. Only the flows of the first one are invalidated.
+    // ================================================================
+    assert!(matches!(
+        nat::detach_floating_ip(&g1.port, fip1.into()),
+        Ok(DetachFloatingIpResp::Ok)
+    ));
+    update!(
+        g1,
+        ["incr:epoch", "decr:nat.rules.in", "decr:nat.flows.in, nat.flows.out"]
+    );
+    assert!(matches!(
+        nat::detach_floating_ip(&g1.port, fip1.into()),
+        Ok(DetachFloatingIpResp::NotFound)
+    ));
+    assert_eq!(nat::floating_ips(&g1.port).unwrap(), vec![IpAddr::from(fip2)]);
+
+    // The guest's reply on the connection made to the second floating
+    // IP still goes out through its flow.
+    let mut pkt = http_syn_ack2(
+        g1_cfg.guest_mac,
+        g1_cfg.ipv4().private_ip,
+        GW_MAC_ADDR,
+        client_ip,
+        44490,
+    );
+    let res = g1.port.process(Out, &mut pkt, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)), "bad result: {:?}", res);
+    incr!(g1, ["uft.out, stats.port.out_modified, stats.port.out_uft_miss"]);
+    match pkt.meta().inner.ip.as_ref().unwrap() {
+        IpMeta::Ip4(ip4) => {
+            assert_eq!(ip4.src, fip2);
+            assert_eq!(ip4.dst, client_ip);
+        }
+
+        val => panic!("expected inner IPv4, got: {:?}", val),
+    }
+
+    let dst2 = "52.10.128.71".parse().unwrap();
+    assert_eq!(out_src(&mut g1, &g1_cfg, dst2), fip2);
+
+    // ================================================================
+    // Detaching the last floating IP falls back to SNAT.
+    // ================================================================
+    assert!(matches!(
+        nat::detach_floating_ip(&g1.port, fip2.into()),
+        Ok(DetachFloatingIpResp::Ok)
+    ));
+    update!(
+        g1,
+        [
+            "incr:epoch",
+            "decr:nat.rules.in, nat.rules.out",
+            "set:nat.flows.in=0, nat.flows.out=0",
+        ]
+    );
+    assert!(nat::floating_ips(&g1.port).unwrap().is_empty());
+
+    let dst3 = "52.10.128.72".parse().unwrap();
+    assert_eq!(out_src(&mut g1, &g1_cfg, dst3), g1_cfg.snat().external_ip);
+}
+
+// Verify that a port forwarding rule translates inbound connections
+// to a range of external ports to the private IP and ports, and the
+// replies back; and that deleting it removes its flows.
+#[test]
+fn port_forward_inbound() {
+    let g1_cfg = g1_cfg();
+    let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
+    g1.port.start();
+    set!(g1, "port_state=running");
+    router::add_entry(
+        &g1.port,
+        IpCidr::Ip4("0.0.0.0/0".parse().unwrap()),
+        RouterTarget::InternetGateway,
+    )
+    .unwrap();
+    incr!(g1, ["epoch", "router.rules.out"]);
+
+    fn tcp_pkt(
+        eth_src: MacAddr,
+        ip_src: Ipv4Addr,
+        sport: u16,
+        eth_dst: MacAddr,
+        ip_dst: Ipv4Addr,
+        dport: u16,
+        flags: u8,
+    ) -> Packet<Parsed> {
+        let tcp = TcpMeta {
+            src: sport,
+            dst: dport,
+            flags,
+            seq: 2382112979,
+            ..Default::default()
+        };
+        let ip4 = Ipv4Meta {
+            src: ip_src,
+            dst: ip_dst,
+            proto: Protocol::TCP,
+            total_len: (Ipv4Hdr::BASE_SIZE + tcp.hdr_len()) as u16,
+            ..Default::default()
+        };
+        let eth = EtherMeta {
+            ether_type: EtherType::Ipv4,
+            src: eth_src,
+            dst: eth_dst,
+        };
+        ulp_pkt(eth, ip4, tcp, &[])
+    }
+
+    let ext_ip: Ipv4Addr = "10.60.1.30".parse().unwrap();
+    let fwd = PortForward {
+        external_ip: ext_ip.into(),
+        proto: Protocol::TCP,
+        external_ports: 8080..=8089,
+        private_port: 80,
+    };
+
+    // ================================================================
+    // Add the rule. Adding it twice is a no-op, while overlapping
+    // rules are rejected.
+    // ================================================================
+    nat::add_port_forward(&g1.port, &g1_cfg, fwd.clone()).unwrap();
+    incr!(g1, ["epoch", "nat.rules.in"]);
+    nat::add_port_forward(&g1.port, &g1_cfg, fwd.clone()).unwrap();
+    assert_port!(g1);
+    assert_eq!(nat::port_forwards(&g1.port).unwrap(), vec![fwd.clone()]);
+
+    let overlap = PortForward { external_ports: 8089..=8090, ..fwd.clone() };
+    assert!(matches!(
+        nat::add_port_forward(&g1.port, &g1_cfg, overlap),
+        Err(OpteError::InvalidPortForward(_))
+    ));
+
+    let snat = g1_cfg.snat();
+    let snat_overlap = PortForward {
+        external_ip: snat.external_ip.into(),
+        external_ports: *snat.ports.start()..=*snat.ports.start(),
+        ..fwd.clone()
+    };
+    assert!(matches!(
+        nat::add_port_forward(&g1.port, &g1_cfg, snat_overlap),
+        Err(OpteError::InvalidPortForward(_))
+    ));
+
+    // ================================================================
+    // SYN: Client -> Server, via Boundary Services
+    // ================================================================
+    let client_ip = "52.10.128.69".parse().unwrap();
+    let bs_phys = TestIpPhys {
+        ip: g1_cfg.boundary_services[0].ip,
+        mac: g1_cfg.boundary_services[0].mac,
+        vni: g1_cfg.vni,
+    };
+    let g1_phys = TestIpPhys {
+        ip: g1_cfg.phys_ip,
+        mac: g1_cfg.guest_mac,
+        vni: g1_cfg.vni,
+    };
+    let mut pkt1 = tcp_pkt(
+        g1_cfg.boundary_services[0].mac,
+        client_ip,
+        44490,
+        g1_cfg.guest_mac,
+        ext_ip,
+        8082,
+        TcpFlags::SYN,
+    );
+    pkt1 = encap(pkt1, bs_phys, g1_phys);
+    let res = g1.port.process(In, &mut pkt1, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)), "bad result: {:?}", res);
+    incr!(
+        g1,
+        [
+            "firewall.flows.out, firewall.flows.in",
+            "nat.flows.in, nat.flows.out",
+            "uft.in",
+            "stats.port.in_modified, stats.port.in_uft_miss",
+        ]
+    );
+    match pkt1.meta().inner.ip.as_ref().unwrap() {
+        IpMeta::Ip4(ip4) => {
+            assert_eq!(ip4.src, client_ip);
+            assert_eq!(ip4.dst, g1_cfg.ipv4().private_ip);
+        }
+
+        val => panic!("expected inner IPv4, got: {:?}", val),
+    }
+    let ulp = pkt1.meta().inner.ulp.unwrap();
+    assert_eq!(ulp.src_port(), 44490);
+    assert_eq!(ulp.dst_port(), 82);
 
     // ================================================================
-    // FIN: Server -> Client
+    // SYN+ACK: Server -> Client, translated back to the external IP
+    // and port.
     // ================================================================
-    let mut pkt10 = http_server_fin2(
-        serv_mac,
+    let mut pkt2 = tcp_pkt(
+        g1_cfg.guest_mac,
         g1_cfg.ipv4().private_ip,
+        82,
         GW_MAC_ADDR,
         client_ip,
-        sport,
+        44490,
+        TcpFlags::SYN | TcpFlags::ACK,
     );
-    let res = g1.port.process(Out, &mut pkt10, ActionMeta::new());
-    assert!(matches!(res, Ok(Modified)));
-    incr!(g1, ["stats.port.out_modified, stats.port.out_uft_hit"]);
-    assert_eq!(TcpState::LastAck, g1.port.tcp_state(&flow).unwrap());
+    let res = g1.port.process(Out, &mut pkt2, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)), "bad result: {:?}", res);
+    incr!(g1, ["uft.out, stats.port.out_modified, stats.port.out_uft_miss"]);
+    match pkt2.meta().inner.ip.as_ref().unwrap() {
+        IpMeta::Ip4(ip4) => {
+            assert_eq!(ip4.src, ext_ip);
+            assert_eq!(ip4.dst, client_ip);
+        }
+
+        val => panic!("expected inner IPv4, got: {:?}", val),
+    }
+    let ulp = pkt2.meta().inner.ulp.unwrap();
+    assert_eq!(ulp.src_port(), 8082);
+    assert_eq!(ulp.dst_port(), 44490);
 
     // ================================================================
-    // ACK Server FIN: Client -> Server
+    // Delete the rule, along with its flows.
     // ================================================================
-    let mut pkt11 =
-        http_guest_ack_fin2(bs_mac, client_ip, serv_mac, serv_ext_ip);
-    pkt11 = encap(pkt11, bs_phys, g1_phys);
-    let res = g1.port.process(In, &mut pkt11, ActionMeta::new());
-    assert!(matches!(res, Ok(Modified)));
+    assert!(matches!(
+        nat::del_port_forward(&g1.port, &fwd),
+        Ok(DelPortForwardResp::Ok)
+    ));
     update!(
         g1,
         [
-            "incr:stats.port.in_modified, stats.port.in_uft_hit",
-            "set:uft.in=1, uft.out=0",
+            "incr:epoch",
+            "decr:nat.rules.in",
+            "set:nat.flows.in=0, nat.flows.out=0",
         ]
     );
-    assert_eq!(None, g1.port.tcp_state(&flow));
+    assert!(matches!(
+        nat::del_port_forward(&g1.port, &fwd),
+        Ok(DelPortForwardResp::NotFound)
+    ));
+    assert!(nat::port_forwards(&g1.port).unwrap().is_empty());
 }
 
 // Verify that the guest cannot spoof outbound packets.
@@ -2850,3 +5495,404 @@ fn anti_spoof() {
         ]
     );
 }
+
+// Verify that the capture ring records packets at the configured
+// points, that it honors its predicates, and that drops carry their
+// reason.
+#[test]
+fn port_capture() {
+    let g1_cfg = g1_cfg();
+    let g2_cfg = g2_cfg();
+    let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
+    g1.vpc_map.add(g2_cfg.ipv4().private_ip.into(), g2_cfg.phys_addr());
+    g1.port.start();
+    set!(g1, "port_state=running");
+
+    // Capture is disabled by default.
+    assert!(matches!(
+        g1.port.dump_capture(0, CAPTURE_DUMP_MAX),
+        Err(OpteError::CaptureNotEnabled(_))
+    ));
+
+    // Only capture HTTP traffic.
+    g1.port.set_capture(Some(CaptureConfig {
+        preds: vec!["ulp.dport=80".parse().unwrap()],
+        ..Default::default()
+    }));
+
+    // ================================================================
+    // An allowed packet is captured on the guest side before
+    // processing and on the underlay side after encapsulation.
+    // ================================================================
+    let mut pkt1 = http_syn(&g1_cfg, &g2_cfg);
+    let guest_len = pkt1.len() as u32;
+    let res = g1.port.process(Out, &mut pkt1, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(
+        g1,
+        [
+            "firewall.flows.out, firewall.flows.in",
+            "uft.out",
+            "stats.port.out_modified, stats.port.out_uft_miss",
+        ]
+    );
+
+    let dump = g1.port.dump_capture(0, CAPTURE_DUMP_MAX).unwrap();
+    assert_eq!(dump.pkts.len(), 2);
+    assert_eq!(dump.evicted, 0);
+    let guest = &dump.pkts[0];
+    assert_eq!(guest.point, CapturePoint::Guest);
+    assert_eq!(guest.dir, Out);
+    assert_eq!(guest.orig_len, guest_len);
+    assert_eq!(guest.bytes.len() as u32, guest_len);
+    assert!(guest.drop_reason.is_none());
+    let underlay = &dump.pkts[1];
+    assert_eq!(underlay.point, CapturePoint::Underlay);
+    assert_eq!(underlay.orig_len, guest_len + VPC_ENCAP_SZ as u32);
+    assert_eq!(&underlay.bytes, &pkt1.all_bytes());
+    assert!(underlay.ts_ns >= guest.ts_ns);
+    assert_eq!(dump.next, 2);
+    assert_eq!(dump.remaining, 0);
+
+    // The ring may also be dumped one page at a time.
+    let page = g1.port.dump_capture(0, 1).unwrap();
+    assert_eq!(page.pkts.len(), 1);
+    assert_eq!(page.pkts[0].point, CapturePoint::Guest);
+    assert_eq!(page.next, 1);
+    assert_eq!(page.remaining, 1);
+    let page = g1.port.dump_capture(page.next, 1).unwrap();
+    assert_eq!(page.pkts.len(), 1);
+    assert_eq!(page.pkts[0].point, CapturePoint::Underlay);
+    assert_eq!(page.next, 2);
+    assert_eq!(page.remaining, 0);
+    assert!(g1.port.dump_capture(page.next, 1).unwrap().pkts.is_empty());
+
+    // ================================================================
+    // A packet which does not match the predicates is not captured.
+    // Setting a new configuration also discards the ring.
+    // ================================================================
+    g1.port.set_capture(Some(CaptureConfig {
+        preds: vec!["ulp.dport=443".parse().unwrap()],
+        ..Default::default()
+    }));
+    let mut pkt2 = http_syn(&g1_cfg, &g2_cfg);
+    let res = g1.port.process(Out, &mut pkt2, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(g1, ["stats.port.out_modified, stats.port.out_uft_hit"]);
+    assert_eq!(
+        g1.port.dump_capture(0, CAPTURE_DUMP_MAX).unwrap().pkts.len(),
+        0
+    );
+
+    // ================================================================
+    // Reconfigure the capture to only record drops, truncated to the
+    // Ethernet and IPv4 headers; a packet with a spoofed source MAC
+    // is dropped by the gateway layer.
+    // ================================================================
+    g1.port.set_capture(Some(CaptureConfig {
+        points: vec![CapturePoint::Drop],
+        snaplen: 34,
+        ..Default::default()
+    }));
+    assert_eq!(
+        g1.port.dump_capture(0, CAPTURE_DUMP_MAX).unwrap().pkts.len(),
+        0
+    );
+
+    let src_mac = ox_vpc_mac([0x0, 0x11, 0x22]);
+    let mut pkt3 = http_syn2(
+        src_mac,
+        g1_cfg.ipv4().private_ip,
+        GW_MAC_ADDR,
+        g2_cfg.ipv4().private_ip,
+    );
+    let res = g1.port.process(Out, &mut pkt3, ActionMeta::new());
+    assert_drop!(
+        res,
+        DropReason::Layer { name: "gateway", reason: DenyReason::Default }
+    );
+
+    let dump = g1.port.dump_capture(0, CAPTURE_DUMP_MAX).unwrap();
+    assert_eq!(dump.pkts.len(), 1);
+    assert_eq!(dump.pkts[0].point, CapturePoint::Drop);
+    assert!(dump.pkts[0].drop_reason.as_ref().unwrap().contains("gateway"));
+    assert_eq!(dump.pkts[0].orig_len, pkt3.len() as u32);
+    assert_eq!(&dump.pkts[0].bytes, &pkt3.all_bytes()[..34]);
+    incr!(
+        g1,
+        [
+            "stats.port.out_drop, stats.port.out_drop_layer",
+            "stats.port.out_uft_miss",
+        ]
+    );
+
+    // ================================================================
+    // Disabling the capture discards the ring.
+    // ================================================================
+    g1.port.set_capture(None);
+    assert!(g1.port.dump_capture(0, CAPTURE_DUMP_MAX).is_err());
+}
+
+// Verify that a mirror copies the matching packets, in either
+// direction, to its target.
+#[test]
+fn port_mirror() {
+    let g1_cfg = g1_cfg();
+    let g2_cfg = g2_cfg();
+    let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
+    g1.vpc_map.add(g2_cfg.ipv4().private_ip.into(), g2_cfg.phys_addr());
+    g1.port.start();
+    set!(g1, "port_state=running");
+    let mut g2 = oxide_net_setup("g2_port", &g2_cfg, Some(g1.vpc_map.clone()));
+    g2.port.start();
+    set!(g2, "port_state=running");
+
+    // Allow incoming TCP connection from anyone.
+    let rule = "dir=in action=allow priority=10 protocol=TCP";
+    firewall::add_fw_rule(
+        &g2.port,
+        &AddFwRuleReq {
+            port_name: g2.port.name().to_string(),
+            rule: rule.parse().unwrap(),
+        },
+    )
+    .unwrap();
+    incr!(g2, ["epoch", "firewall.rules.in"]);
+
+    // Mirror every other outbound TCP packet of g1 to a local port,
+    // truncating the copy as much as possible.
+    let g1_mirror: MirrorCfg = "name=ids-local dir=out dest=port:g3_port \
+                                trunc=1 rate=2 priority=10 protocol=TCP"
+        .parse()
+        .unwrap();
+    mirror::set_mirrors(
+        &g1.port,
+        &g1_cfg,
+        &SetMirrorsReq {
+            port_name: g1.port.name().to_string(),
+            mirrors: vec![g1_mirror],
+        },
+    )
+    .unwrap();
+    incr!(g1, ["epoch", "mirror.rules.out"]);
+
+    // Mirror all inbound traffic of g2 to a collector on the
+    // underlay.
+    let collector = PhysNet {
+        ether: MacAddr::from([0xA8, 0x40, 0x25, 0x00, 0x00, 0x99]),
+        ip: "fd00::99".parse().unwrap(),
+        vni: Vni::new(7u32).unwrap(),
+    };
+    mirror::set_mirrors(
+        &g2.port,
+        &g2_cfg,
+        &SetMirrorsReq {
+            port_name: g2.port.name().to_string(),
+            mirrors: vec![MirrorCfg {
+                name: "ids-remote".to_string(),
+                direction: In,
+                filters: Filters::new(),
+                dest: MirrorDest::Underlay(collector),
+                trunc_len: 0,
+                sample_rate: NonZeroU32::new(1).unwrap(),
+                priority: 10,
+            }],
+        },
+    )
+    .unwrap();
+    incr!(g2, ["epoch", "mirror.rules.in"]);
+
+    // ================================================================
+    // The first packet is sampled. Even though the truncation length
+    // is one byte the headers are copied in full, which for a SYN is
+    // the entire inner frame.
+    // ================================================================
+    let mut pkt1 = http_syn(&g1_cfg, &g2_cfg);
+    let res = g1.port.process(Out, &mut pkt1, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(
+        g1,
+        [
+            "firewall.flows.out, firewall.flows.in",
+            "uft.out",
+            "stats.port.out_modified, stats.port.out_uft_miss",
+            "stats.port.mirror",
+        ]
+    );
+
+    let mut mirrored = g1.port.take_mirrored();
+    assert_eq!(mirrored.len(), 1);
+    let mpkt = mirrored.pop().unwrap();
+    assert_eq!(mpkt.mirror.name(), "ids-local");
+    match mpkt.mirror.target() {
+        MirrorTarget::Port(name) => assert_eq!(name, "g3_port"),
+        target => panic!("expected port target, got: {}", target),
+    }
+    assert_eq!(mpkt.pkt.all_bytes(), &pkt1.all_bytes()[VPC_ENCAP_SZ..]);
+    assert!(g1.port.take_mirrored().is_empty());
+
+    // ================================================================
+    // The second packet hits the UFT but is not sampled, the third
+    // one is.
+    // ================================================================
+    let mut pkt2 = http_syn(&g1_cfg, &g2_cfg);
+    let res = g1.port.process(Out, &mut pkt2, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(g1, ["stats.port.out_modified, stats.port.out_uft_hit"]);
+    assert!(g1.port.take_mirrored().is_empty());
+
+    let mut pkt3 = http_syn(&g1_cfg, &g2_cfg);
+    let res = g1.port.process(Out, &mut pkt3, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(
+        g1,
+        ["stats.port.out_modified, stats.port.out_uft_hit, stats.port.mirror"]
+    );
+    assert_eq!(g1.port.take_mirrored().len(), 1);
+
+    // ================================================================
+    // Deliver g1's packet inbound to g2, where it is mirrored to the
+    // collector: the copy is the frame as delivered to the guest,
+    // encapsulated to the collector with its inner destination
+    // left intact.
+    // ================================================================
+    let mblk = pkt1.unwrap_mblk();
+    let mut pkt4 = unsafe {
+        Packet::wrap_mblk_and_parse(mblk, In, VpcParser::new()).unwrap()
+    };
+    let res = g2.port.process(In, &mut pkt4, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(
+        g2,
+        [
+            "firewall.flows.in, firewall.flows.out",
+            "uft.in",
+            "stats.port.in_modified, stats.port.in_uft_miss",
+            "stats.port.mirror",
+        ]
+    );
+
+    let mut mirrored = g2.port.take_mirrored();
+    assert_eq!(mirrored.len(), 1);
+    let mpkt = mirrored.pop().unwrap();
+    assert_eq!(mpkt.mirror.name(), "ids-remote");
+    let meta = mpkt.pkt.meta();
+    match meta.outer.ip.as_ref().unwrap() {
+        IpMeta::Ip6(ip6) => {
+            assert_eq!(ip6.src, g2_cfg.phys_ip);
+            assert_eq!(ip6.dst, collector.ip);
+        }
+
+        val => panic!("expected outer IPv6, got: {:?}", val),
+    }
+
+    match meta.outer.encap.as_ref() {
+        Some(EncapMeta::Geneve(geneve)) => {
+            assert_eq!(geneve.vni, collector.vni);
+        }
+
+        None => panic!("expected outer Geneve metadata"),
+    }
+
+    assert_eq!(meta.inner.ether.dst, g2_cfg.guest_mac);
+    assert_eq!(&mpkt.pkt.all_bytes()[VPC_ENCAP_SZ..], &pkt4.all_bytes());
+
+    // ================================================================
+    // Clearing the mirrors stops the copies.
+    // ================================================================
+    mirror::set_mirrors(
+        &g2.port,
+        &g2_cfg,
+        &SetMirrorsReq {
+            port_name: g2.port.name().to_string(),
+            mirrors: vec![],
+        },
+    )
+    .unwrap();
+    update!(g2, ["incr:epoch", "set:mirror.rules.in=0"]);
+
+    let mut pkt5 = http_syn(&g1_cfg, &g2_cfg);
+    let res = g1.port.process(Out, &mut pkt5, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(g1, ["stats.port.out_modified, stats.port.out_uft_hit"]);
+    let mblk = pkt5.unwrap_mblk();
+    let mut pkt6 = unsafe {
+        Packet::wrap_mblk_and_parse(mblk, In, VpcParser::new()).unwrap()
+    };
+    let res = g2.port.process(In, &mut pkt6, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    // The UFT entry from the previous epoch is replaced.
+    incr!(g2, ["stats.port.in_modified, stats.port.in_uft_miss"]);
+    assert!(g2.port.take_mirrored().is_empty());
+}
+
+// Verify that a port generates a record when a flow expires, and
+// that the records are only released once acknowledged.
+#[test]
+fn port_flow_records() {
+    let g1_cfg = g1_cfg();
+    let g2_cfg = g2_cfg();
+    let mut g1 = oxide_net_setup("g1_port", &g1_cfg, None);
+    g1.vpc_map.add(g2_cfg.ipv4().private_ip.into(), g2_cfg.phys_addr());
+    g1.port.start();
+    set!(g1, "port_state=running");
+
+    // ================================================================
+    // Send two packets of the same flow: they are accounted to a
+    // single flow, but no record is generated until it expires.
+    // ================================================================
+    let mut pkt1 = http_syn(&g1_cfg, &g2_cfg);
+    let guest_flow = *pkt1.flow();
+    let len = pkt1.len() as u64;
+    let res = g1.port.process(Out, &mut pkt1, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(
+        g1,
+        [
+            "firewall.flows.out, firewall.flows.in",
+            "uft.out",
+            "stats.port.out_modified, stats.port.out_uft_miss",
+        ]
+    );
+
+    let mut pkt2 = http_syn(&g1_cfg, &g2_cfg);
+    let res = g1.port.process(Out, &mut pkt2, ActionMeta::new());
+    assert!(matches!(res, Ok(Modified)));
+    incr!(g1, ["stats.port.out_modified, stats.port.out_uft_hit"]);
+
+    let dump = g1.port.dump_flow_records(None);
+    assert!(dump.records.is_empty());
+    assert_eq!(dump.dropped, 0);
+
+    // ================================================================
+    // Expire the flow and verify its record.
+    // ================================================================
+    let now = Moment::now();
+    g1.port
+        .expire_flows(now + Duration::new(FLOW_DEF_EXPIRE_SECS as u64, 0))
+        .unwrap();
+    zero_flows!(g1);
+
+    let dump = g1.port.dump_flow_records(None);
+    assert_eq!(dump.records.len(), 1);
+    let rec = &dump.records[0];
+    assert_eq!(rec.seq, 1);
+    assert_eq!(rec.guest_flow, guest_flow);
+    // There is no NAT between guests of the same VPC.
+    assert_eq!(rec.net_flow, guest_flow);
+    assert_eq!(rec.counters.pkts_out, 2);
+    assert_eq!(rec.counters.bytes_out, 2 * len);
+    assert_eq!(rec.counters.pkts_in, 0);
+    assert_eq!(rec.counters.bytes_in, 0);
+    assert_eq!(rec.counters.tcp_flags, TcpFlags::SYN);
+    assert_eq!(rec.verdict, FlowVerdict::Allow);
+    assert_eq!(rec.end_reason, FlowEndReason::IdleTimeout);
+    assert!(rec.start_ns <= rec.end_ns);
+    assert!(rec.end_ns <= dump.elapsed_ns);
+
+    // ================================================================
+    // The records stay queued until acknowledged.
+    // ================================================================
+    assert_eq!(g1.port.dump_flow_records(None).records.len(), 1);
+    assert!(g1.port.dump_flow_records(Some(rec.seq)).records.is_empty());
+}
@@ -26,6 +26,7 @@ use crate::secpolicy;
 use crate::sys;
 use crate::warn;
 use alloc::boxed::Box;
+use alloc::collections::btree_set::BTreeSet;
 use alloc::ffi::CString;
 use alloc::string::String;
 use alloc::string::ToString;
@@ -39,7 +40,6 @@ use core::time::Duration;
 use illumos_sys_hdrs::*;
 use opte::api::CmdOk;
 use opte::api::Direction;
-use opte::api::MacAddr;
 use opte::api::NoResp;
 use opte::api::OpteCmd;
 use opte::api::OpteCmdIoctl;
@@ -59,30 +59,66 @@ use opte::engine::headers::IpAddr;
 use opte::engine::headers::IpMeta;
 use opte::engine::ioctl::{self as api};
 use opte::engine::ip6::Ipv6Addr;
+use opte::engine::layer::DenyReason;
+use opte::engine::mirror::MirrorTarget;
+use opte::engine::mirror::MirroredPacket;
 use opte::engine::packet::Initialized;
 use opte::engine::packet::Packet;
 use opte::engine::packet::PacketError;
 use opte::engine::packet::PacketRead;
+use opte::engine::packet::PacketState;
 use opte::engine::packet::Parsed;
 use opte::engine::port::meta::ActionMeta;
+use opte::engine::port::DropReason;
 use opte::engine::port::Port;
 use opte::engine::port::PortBuilder;
+use opte::engine::port::ProcessError;
 use opte::engine::port::ProcessResult;
 use opte::ExecCtx;
 use oxide_vpc::api::AddFwRuleReq;
+use oxide_vpc::api::AddPortForwardReq;
 use oxide_vpc::api::AddRouterEntryReq;
+use oxide_vpc::api::AttachFloatingIpReq;
 use oxide_vpc::api::CreateXdeReq;
+use oxide_vpc::api::DelAddrSetReq;
+use oxide_vpc::api::DelAddrSetResp;
+use oxide_vpc::api::DelPortForwardReq;
+use oxide_vpc::api::DelPortForwardResp;
+use oxide_vpc::api::DelRouterEntryReq;
+use oxide_vpc::api::DelRouterEntryResp;
+use oxide_vpc::api::DelVirt2PhysReq;
+use oxide_vpc::api::DelVirt2PhysResp;
 use oxide_vpc::api::DeleteXdeReq;
+use oxide_vpc::api::DetachFloatingIpReq;
+use oxide_vpc::api::DetachFloatingIpResp;
+use oxide_vpc::api::DumpAddrSetsReq;
+use oxide_vpc::api::DumpAddrSetsResp;
+use oxide_vpc::api::DumpBsvcStateReq;
+use oxide_vpc::api::DumpBsvcStateResp;
 use oxide_vpc::api::IpCfg;
+use oxide_vpc::api::ListPortForwardsReq;
+use oxide_vpc::api::ListPortForwardsResp;
 use oxide_vpc::api::ListPortsResp;
+use oxide_vpc::api::ListRouterEntriesReq;
+use oxide_vpc::api::ListRouterEntriesResp;
+use oxide_vpc::api::MirrorDest;
 use oxide_vpc::api::PhysNet;
 use oxide_vpc::api::PortInfo;
+use oxide_vpc::api::ReadV2pMissesReq;
+use oxide_vpc::api::ReadV2pMissesResp;
 use oxide_vpc::api::RemFwRuleReq;
+use oxide_vpc::api::RouterEntry;
+use oxide_vpc::api::SetAddrSetReq;
+use oxide_vpc::api::SetBsvcStateReq;
 use oxide_vpc::api::SetFwRulesReq;
+use oxide_vpc::api::SetFwRulesResp;
+use oxide_vpc::api::SetMirrorsReq;
 use oxide_vpc::api::SetVirt2PhysReq;
+use oxide_vpc::api::SyncVirt2PhysReq;
 use oxide_vpc::api::VpcCfg;
 use oxide_vpc::engine::firewall;
 use oxide_vpc::engine::gateway;
+use oxide_vpc::engine::mirror;
 use oxide_vpc::engine::nat;
 use oxide_vpc::engine::overlay;
 use oxide_vpc::engine::router;
@@ -110,9 +146,15 @@ static mut xde_devs: KRwLock<Vec<Box<XdeDev>>> = KRwLock::new(Vec::new());
 /// DDI dev info pointer to the attached xde device.
 static mut xde_dip: *mut dev_info = 0 as *mut dev_info;
 
-// XXX-EXT-IP
+/// The maximum number of outbound packets each port holds while
+/// their destination awaits a V2P mapping from the resolver. Setting
+/// this to zero disables holding: such packets are dropped.
 #[no_mangle]
-pub static mut xde_ext_ip_hack: i32 = 0;
+pub static mut xde_v2p_hold_max: u32 = 4;
+
+/// The number of milliseconds a packet may be held awaiting a V2P
+/// mapping, before it's dropped.
+const V2P_HOLD_MILLIS: u64 = 1_000;
 
 // This block is purely for SDT probes.
 extern "C" {
@@ -235,6 +277,52 @@ impl XdeState {
     }
 }
 
+// A handle on the mac of an xde device, used to deliver packets to
+// its guest without holding `xde_devs`, e.g. the packets mirrored to
+// it by another port. The handle is closed before the device's mac is
+// unregistered; a packet delivered through a closed handle is
+// dropped.
+struct RxHandle {
+    mh: KRwLock<*mut mac::mac_handle>,
+}
+
+impl RxHandle {
+    fn new() -> Self {
+        let mut mh = KRwLock::new(ptr::null_mut());
+        mh.init(KRwLockType::Driver);
+        Self { mh }
+    }
+
+    fn open(&self, mh: *mut mac::mac_handle) {
+        *self.mh.write() = mh;
+    }
+
+    fn close(&self) {
+        *self.mh.write() = ptr::null_mut();
+    }
+
+    // Deliver the packet to the guest, returning false if the handle
+    // is closed.
+    fn deliver<S: PacketState>(&self, pkt: Packet<S>) -> bool {
+        let mh = self.mh.read();
+        if mh.is_null() {
+            return false;
+        }
+
+        unsafe {
+            mac::mac_rx(
+                *mh,
+                0 as *mut mac::mac_resource_handle,
+                pkt.unwrap_mblk(),
+            )
+        };
+        true
+    }
+}
+
+type HeldPkts = KMutex<Vec<(Moment, Packet<Initialized>)>>;
+type MirrorTargets = KMutex<Vec<(String, Arc<RxHandle>)>>;
+
 #[repr(C)]
 struct XdeDev {
     devname: String,
@@ -252,6 +340,18 @@ struct XdeDev {
     port_periodic: Periodic<Arc<Port<VpcNetwork>>>,
     port_v2p: Arc<overlay::Virt2Phys>,
 
+    // The outbound packets held awaiting a V2P mapping, along with
+    // when each was first held.
+    held: Arc<HeldPkts>,
+
+    // The handle other ports use to deliver mirrored packets to this
+    // device's guest.
+    rx_handle: Arc<RxHandle>,
+
+    // The handles of the ports targeted by this port's mirrors, by
+    // port name. These are resolved when the mirrors are set.
+    mirror_targets: Arc<MirrorTargets>,
+
     // Pass the packets through to the underlay devices, skipping
     // opte-core processing.
     passthrough: bool,
@@ -389,6 +489,21 @@ unsafe extern "C" fn xde_dld_ioc_opte_cmd(
             hdlr_resp(&mut env, resp)
         }
 
+        OpteCmd::SetAddrSet => {
+            let resp = set_addr_set_hdlr(&mut env);
+            hdlr_resp(&mut env, resp)
+        }
+
+        OpteCmd::DelAddrSet => {
+            let resp = del_addr_set_hdlr(&mut env);
+            hdlr_resp(&mut env, resp)
+        }
+
+        OpteCmd::DumpAddrSets => {
+            let resp = dump_addr_sets_hdlr(&mut env);
+            hdlr_resp(&mut env, resp)
+        }
+
         OpteCmd::CreateXde => {
             let resp = create_xde_hdlr(&mut env);
             hdlr_resp(&mut env, resp)
@@ -409,6 +524,11 @@ unsafe extern "C" fn xde_dld_ioc_opte_cmd(
             hdlr_resp(&mut env, resp)
         }
 
+        OpteCmd::SetDefaultAction => {
+            let resp = set_default_action_hdlr(&mut env);
+            hdlr_resp(&mut env, resp)
+        }
+
         OpteCmd::ClearUft => {
             let resp = clear_uft_hdlr(&mut env);
             hdlr_resp(&mut env, resp)
@@ -434,15 +554,105 @@ unsafe extern "C" fn xde_dld_ioc_opte_cmd(
             hdlr_resp(&mut env, resp)
         }
 
+        OpteCmd::DelVirt2Phys => {
+            let resp = del_v2p_hdlr(&mut env);
+            hdlr_resp(&mut env, resp)
+        }
+
+        OpteCmd::SyncVirt2Phys => {
+            let resp = sync_v2p_hdlr(&mut env);
+            hdlr_resp(&mut env, resp)
+        }
+
+        OpteCmd::ReadV2pMisses => {
+            let resp = read_v2p_misses_hdlr(&mut env);
+            hdlr_resp(&mut env, resp)
+        }
+
         OpteCmd::AddRouterEntry => {
             let resp = add_router_entry_hdlr(&mut env);
             hdlr_resp(&mut env, resp)
         }
 
+        OpteCmd::DelRouterEntry => {
+            let resp = del_router_entry_hdlr(&mut env);
+            hdlr_resp(&mut env, resp)
+        }
+
+        OpteCmd::ListRouterEntries => {
+            let resp = list_router_entries_hdlr(&mut env);
+            hdlr_resp(&mut env, resp)
+        }
+
         OpteCmd::DumpTcpFlows => {
             let resp = dump_tcp_flows_hdlr(&mut env);
             hdlr_resp(&mut env, resp)
         }
+
+        OpteCmd::SetCapture => {
+            let resp = set_capture_hdlr(&mut env);
+            hdlr_resp(&mut env, resp)
+        }
+
+        OpteCmd::DumpCapture => {
+            let resp = dump_capture_hdlr(&mut env);
+            hdlr_resp(&mut env, resp)
+        }
+
+        OpteCmd::DumpFlowRecords => {
+            let resp = dump_flow_records_hdlr(&mut env);
+            hdlr_resp(&mut env, resp)
+        }
+
+        OpteCmd::DumpRuleLog => {
+            let resp = dump_rule_log_hdlr(&mut env);
+            hdlr_resp(&mut env, resp)
+        }
+
+        OpteCmd::SetMirrors => {
+            let resp = set_mirrors_hdlr(&mut env);
+            hdlr_resp(&mut env, resp)
+        }
+
+        OpteCmd::SetBsvcState => {
+            let resp = set_bsvc_state_hdlr(&mut env);
+            hdlr_resp(&mut env, resp)
+        }
+
+        OpteCmd::DumpBsvcState => {
+            let resp = dump_bsvc_state_hdlr(&mut env);
+            hdlr_resp(&mut env, resp)
+        }
+
+        OpteCmd::AttachFloatingIp => {
+            let resp = attach_floating_ip_hdlr(&mut env);
+            hdlr_resp(&mut env, resp)
+        }
+
+        OpteCmd::DetachFloatingIp => {
+            let resp = detach_floating_ip_hdlr(&mut env);
+            hdlr_resp(&mut env, resp)
+        }
+
+        OpteCmd::AddPortForward => {
+            let resp = add_port_forward_hdlr(&mut env);
+            hdlr_resp(&mut env, resp)
+        }
+
+        OpteCmd::DelPortForward => {
+            let resp = del_port_forward_hdlr(&mut env);
+            hdlr_resp(&mut env, resp)
+        }
+
+        OpteCmd::ListPortForwards => {
+            let resp = list_port_forwards_hdlr(&mut env);
+            hdlr_resp(&mut env, resp)
+        }
+
+        OpteCmd::DumpSnat => {
+            let resp = dump_snat_hdlr(&mut env);
+            hdlr_resp(&mut env, resp)
+        }
     }
 }
 
@@ -499,15 +709,23 @@ fn create_xde(req: &CreateXdeReq) -> Result<NoResp, OpteError> {
         None => (),
     }
 
-    // XXX-EXT-IP Copy the configuration, modifying the external IP hack
-    // fields depending on the value of the `xde_ext_ip_hack` tunable.
-    let proxy_arp_enable = unsafe { xde_ext_ip_hack == 1 };
-    let vpc_cfg = VpcCfg {
-        proxy_arp_enable,
-        phys_gw_mac: if proxy_arp_enable { cfg.phys_gw_mac } else { None },
-        ..cfg.clone()
+    // The overlay spreads flows across the Boundary Services
+    // endpoints, which must all be reached on the same VNI.
+    let bsvc_vni = match cfg.boundary_services.first() {
+        Some(bs) => bs.vni,
+        None => {
+            return Err(OpteError::PortCreate(
+                "no Boundary Services endpoints".to_string(),
+            ))
+        }
     };
 
+    if cfg.boundary_services.iter().any(|bs| bs.vni != bsvc_vni) {
+        return Err(OpteError::PortCreate(
+            "Boundary Services endpoints differ in VNI".to_string(),
+        ));
+    }
+
     // If this is the first guest in this VPC, then create a new
     // mapping for said VPC. Otherwise, pull the existing one.
     //
@@ -516,7 +734,7 @@ fn create_xde(req: &CreateXdeReq) -> Result<NoResp, OpteError> {
     // they're mapping both IP addresses to the same host.
     let phys_net =
         PhysNet { ether: cfg.guest_mac, ip: cfg.phys_ip, vni: cfg.vni };
-    let port_v2p = match vpc_cfg.ip_cfg {
+    let port_v2p = match cfg.ip_cfg {
         IpCfg::Ipv4(ref ipv4) => {
             state.vpc_map.add(IpAddr::Ip4(ipv4.private_ip), phys_net)
         }
@@ -531,7 +749,7 @@ fn create_xde(req: &CreateXdeReq) -> Result<NoResp, OpteError> {
 
     let port = new_port(
         req.xde_devname.clone(),
-        &vpc_cfg,
+        cfg,
         state.vpc_map.clone(),
         port_v2p.clone(),
         state.ectx.clone(),
@@ -552,7 +770,10 @@ fn create_xde(req: &CreateXdeReq) -> Result<NoResp, OpteError> {
         port,
         port_periodic,
         port_v2p,
-        vpc_cfg,
+        held: Arc::new(KMutex::new(Vec::new(), KMutexType::Driver)),
+        rx_handle: Arc::new(RxHandle::new()),
+        mirror_targets: Arc::new(KMutex::new(Vec::new(), KMutexType::Driver)),
+        vpc_cfg: cfg.clone(),
         passthrough: req.passthrough,
         vni: cfg.vni,
         u1: underlay.u1.clone(),
@@ -635,6 +856,7 @@ fn create_xde(req: &CreateXdeReq) -> Result<NoResp, OpteError> {
         mac::mac_tx_update(xde.mh);
     }
 
+    xde.rx_handle.open(xde.mh);
     devs.push(xde);
     Ok(NoResp::default())
 }
@@ -665,10 +887,13 @@ fn delete_xde(req: &DeleteXdeReq) -> Result<NoResp, OpteError> {
         }
     }
 
-    // Unregister this xde's mac handle.
+    // Unregister this xde's mac handle. Once the rx handle is closed
+    // no other port delivers its mirrored packets to this device.
+    xde.rx_handle.close();
     match unsafe { mac::mac_unregister(xde.mh) } {
         0 => {}
         err => {
+            xde.rx_handle.open(xde.mh);
             match unsafe { dls::dls_devnet_create(xde.mh, xde.linkid, 0) } {
                 0 => {}
                 err => {
@@ -684,18 +909,16 @@ fn delete_xde(req: &DeleteXdeReq) -> Result<NoResp, OpteError> {
 
     // Remove the VPC mappings for this port.
     let cfg = &xde.vpc_cfg;
-    let phys_net =
-        PhysNet { ether: cfg.guest_mac, ip: cfg.phys_ip, vni: cfg.vni };
     match cfg.ip_cfg {
         IpCfg::Ipv4(ref ipv4) => {
-            state.vpc_map.del(&IpAddr::Ip4(ipv4.private_ip), &phys_net)
+            state.vpc_map.del(&IpAddr::Ip4(ipv4.private_ip), cfg.vni)
         }
         IpCfg::Ipv6(ref ipv6) => {
-            state.vpc_map.del(&IpAddr::Ip6(ipv6.private_ip), &phys_net)
+            state.vpc_map.del(&IpAddr::Ip6(ipv6.private_ip), cfg.vni)
         }
         IpCfg::DualStack { ref ipv4, ref ipv6 } => {
-            state.vpc_map.del(&IpAddr::Ip4(ipv4.private_ip), &phys_net);
-            state.vpc_map.del(&IpAddr::Ip6(ipv6.private_ip), &phys_net)
+            state.vpc_map.del(&IpAddr::Ip4(ipv4.private_ip), cfg.vni);
+            state.vpc_map.del(&IpAddr::Ip6(ipv6.private_ip), cfg.vni)
         }
     };
 
@@ -744,29 +967,6 @@ unsafe extern "C" fn xde_attach(
 
     xde_dip = dip;
 
-    // XXX-EXT-IP
-    if !driver_prop_exists("ext_ip_hack") {
-        warn!("failed to find 'ext_ip_hack' property in xde.conf");
-        return DDI_FAILURE;
-    }
-
-    match get_driver_prop_bool("ext_ip_hack") {
-        Some(true) => {
-            warn!("ext_ip_hack enabled: traffic will NOT be encapsulated");
-            xde_ext_ip_hack = 1;
-        }
-
-        Some(_) => {
-            warn!("ext_ip_hack disabled: traffic will be encapsulated");
-            xde_ext_ip_hack = 0;
-        }
-
-        None => {
-            warn!("failed to read 'ext_ip_hack' from xde.conf, disabled");
-            xde_ext_ip_hack = 0;
-        }
-    };
-
     let state = Box::new(XdeState::new());
     ddi_set_driver_private(xde_dip, Box::into_raw(state) as *mut c_void);
     opte::engine::dbg(format!("dld_ioc_add: {:#?}", xde_ioc_list));
@@ -1231,7 +1431,11 @@ unsafe extern "C" fn xde_mc_unicst(
     0
 }
 
-fn guest_loopback_probe(pkt: &Packet<Parsed>, src: &XdeDev, dst: &XdeDev) {
+fn guest_loopback_probe(
+    pkt: &Packet<Parsed>,
+    src: &Port<VpcNetwork>,
+    dst: &Port<VpcNetwork>,
+) {
     use opte::engine::rule::flow_id_sdt_arg;
 
     let fid_arg = flow_id_sdt_arg::from(pkt.flow());
@@ -1240,15 +1444,15 @@ fn guest_loopback_probe(pkt: &Packet<Parsed>, src: &XdeDev, dst: &XdeDev) {
         __dtrace_probe_guest__loopback(
             pkt.mblk_addr(),
             &fid_arg as *const flow_id_sdt_arg as uintptr_t,
-            src.port.name_cstr().as_ptr() as uintptr_t,
-            dst.port.name_cstr().as_ptr() as uintptr_t,
+            src.name_cstr().as_ptr() as uintptr_t,
+            dst.name_cstr().as_ptr() as uintptr_t,
         )
     };
 }
 
 #[no_mangle]
 fn guest_loopback(
-    src_dev: &XdeDev,
+    src_port: &Port<VpcNetwork>,
     mut pkt: Packet<Parsed>,
     vni: Vni,
 ) -> *mut mblk_t {
@@ -1260,12 +1464,18 @@ fn guest_loopback(
 
     match maybe_dest_dev {
         Some(dest_dev) => {
-            guest_loopback_probe(&pkt, src_dev, dest_dev);
+            guest_loopback_probe(&pkt, src_port, &dest_dev.port);
 
             // We have found a matching Port on this host; "loop back"
             // the packet into the inbound processing path of the
             // destination Port.
-            match dest_dev.port.process(In, &mut pkt, ActionMeta::new()) {
+            let res = dest_dev.port.process(In, &mut pkt, ActionMeta::new());
+            deliver_mirrored(
+                &dest_dev.mirror_targets,
+                &dest_dev.u1,
+                dest_dev.port.take_mirrored(),
+            );
+            match res {
                 Ok(ProcessResult::Modified) => {
                     unsafe {
                         mac::mac_rx(
@@ -1310,7 +1520,7 @@ fn guest_loopback(
                 Err(e) => {
                     opte::engine::dbg(format!(
                         "loopback port process error: {} -> {} {:?}",
-                        src_dev.port.name(),
+                        src_port.name(),
                         dest_dev.port.name(),
                         e
                     ));
@@ -1402,55 +1612,43 @@ unsafe extern "C" fn xde_mc_tx(
     // action was taken -- there should be no need to add probes or
     // prints here.
     let res = port.process(Direction::Out, &mut pkt, ActionMeta::new());
-    match res {
-        Ok(ProcessResult::Modified) => {
-            // XXX-EXT-IP
-            //
-            // XXX Doing all these shenanigans because we don't
-            // have the overlay layer in place which would
-            // normally rewrite the dst MAC addr to that of the
-            // dest guest.
-            if xde_ext_ip_hack == 1 {
-                match pkt.meta().inner.ip {
-                    Some(IpMeta::Ip4(ip4)) => {
-                        let devs = xde_devs.read();
-                        for d in devs.iter() {
-                            if let Some(cfg) = d.vpc_cfg.ipv4_cfg() {
-                                if cfg.private_ip == ip4.dst {
-                                    pkt.write_dst_mac(d.vpc_cfg.guest_mac);
-                                    return guest_loopback(
-                                        src_dev,
-                                        pkt,
-                                        d.vpc_cfg.vni,
-                                    );
-                                }
-                            }
-                        }
-                    }
 
-                    Some(IpMeta::Ip6(ip6)) => {
-                        let devs = xde_devs.read();
-                        for d in devs.iter() {
-                            if let Some(cfg) = d.vpc_cfg.ipv6_cfg() {
-                                if cfg.private_ip == ip6.dst {
-                                    pkt.write_dst_mac(d.vpc_cfg.guest_mac);
-                                    return guest_loopback(
-                                        src_dev,
-                                        pkt,
-                                        d.vpc_cfg.vni,
-                                    );
-                                }
-                            }
-                        }
-                    }
+    deliver_mirrored(
+        &src_dev.mirror_targets,
+        &src_dev.u1,
+        port.take_mirrored(),
+    );
 
-                    None => (),
-                }
+    if let Some(pkt) =
+        tx_processed(port, &src_dev.u1, &src_dev.rx_handle, pkt, res)
+    {
+        hold_pkt(&src_dev.held, pkt, None);
+    }
 
-                mch.tx_drop_on_no_desc(pkt, hint, MacTxFlags::empty());
-                return ptr::null_mut();
-            }
+    ptr::null_mut()
+}
+
+// Send on an outbound packet processed by `port`, the port of the xde
+// device owning `u1` and `rx_handle`.
+//
+// The overlay denies an outbound packet only when there is no V2P
+// mapping for its destination, in which case the miss has been
+// reported to the resolver. Such a packet is returned to be held
+// until the resolver answers, see `hold_pkt()`.
+unsafe fn tx_processed(
+    port: &Port<VpcNetwork>,
+    u1: &xde_underlay_port,
+    rx_handle: &RxHandle,
+    pkt: Packet<Parsed>,
+    res: Result<ProcessResult, ProcessError>,
+) -> Option<Packet<Parsed>> {
+    // TODO Arbitrarily choose u1, later when we integrate with DDM
+    // we'll have the information needed to make a real choice.
+    let mch = &u1.mch;
+    let hint = 0;
 
+    match res {
+        Ok(ProcessResult::Modified) => {
             let meta = pkt.meta();
 
             // If the outer IPv6 destination is the same as the
@@ -1462,7 +1660,7 @@ unsafe extern "C" fn xde_mc_tx(
                     // XXX add SDT probe
                     // XXX add stat
                     opte::engine::dbg(format!("no outer ip header, dropping"));
-                    return ptr::null_mut();
+                    return None;
                 }
             };
 
@@ -1472,7 +1670,7 @@ unsafe extern "C" fn xde_mc_tx(
                     opte::engine::dbg(format!(
                         "outer IP header is not v6, dropping"
                     ));
-                    return ptr::null_mut();
+                    return None;
                 }
             };
 
@@ -1482,12 +1680,13 @@ unsafe extern "C" fn xde_mc_tx(
                     // XXX add SDT probe
                     // XXX add stat
                     opte::engine::dbg(format!("no geneve header, dropping"));
-                    return ptr::null_mut();
+                    return None;
                 }
             };
 
             if ip6.dst == ip6.src {
-                return guest_loopback(src_dev, pkt, vni);
+                guest_loopback(port, pkt, vni);
+                return None;
             }
 
             // Currently the overlay layer leaves the outer frame
@@ -1510,16 +1709,22 @@ unsafe extern "C" fn xde_mc_tx(
             mch.tx_drop_on_no_desc(new_pkt, hint, MacTxFlags::empty());
         }
 
+        Ok(ProcessResult::Drop {
+            reason:
+                DropReason::Layer {
+                    name: overlay::OVERLAY_LAYER_NAME,
+                    reason: DenyReason::Action,
+                },
+        }) => {
+            return Some(pkt);
+        }
+
         Ok(ProcessResult::Drop { .. }) => {
-            return ptr::null_mut();
+            return None;
         }
 
         Ok(ProcessResult::Hairpin(hpkt)) => {
-            mac::mac_rx(
-                src_dev.mh,
-                0 as *mut mac::mac_resource_handle,
-                hpkt.unwrap_mblk(),
-            );
+            rx_handle.deliver(hpkt);
         }
 
         Ok(ProcessResult::Bypass) => {
@@ -1531,7 +1736,177 @@ unsafe extern "C" fn xde_mc_tx(
 
     // On return the Packet is dropped and its underlying mblk
     // segments are freed.
-    ptr::null_mut()
+    None
+}
+
+// Hold an outbound packet awaiting a V2P mapping in `held`, the held
+// packets of its device.
+//
+// The packet is dropped instead if holding is disabled, if the port
+// already holds `xde_v2p_hold_max` packets, or if it was first held
+// more than `V2P_HOLD_MILLIS` ago, as passed in `held_since`. As the
+// processing of a dropped packet leaves its bytes untouched, the held
+// packet is processed anew once released, see `release_held()`.
+fn hold_pkt(held: &HeldPkts, pkt: Packet<Parsed>, held_since: Option<Moment>) {
+    let max = unsafe { xde_v2p_hold_max } as usize;
+    let now = Moment::now();
+    let since = held_since.unwrap_or(now);
+
+    if max == 0 || now.delta_as_millis(since) >= V2P_HOLD_MILLIS {
+        return;
+    }
+
+    let mut held = held.lock();
+    held.retain(|(since, _)| now.delta_as_millis(*since) < V2P_HOLD_MILLIS);
+
+    if held.len() >= max {
+        return;
+    }
+
+    // Unwrap: We know the packet is good because we just unwrapped
+    // it.
+    let pkt = Packet::<Initialized>::wrap_mblk(pkt.unwrap_mblk()).unwrap();
+    held.push((since, pkt));
+}
+
+// The packets released from an xde device, along with the handles
+// needed to send them on without holding `xde_devs`.
+struct Released {
+    port: Arc<Port<VpcNetwork>>,
+    u1: Arc<xde_underlay_port>,
+    rx_handle: Arc<RxHandle>,
+    mirror_targets: Arc<MirrorTargets>,
+    held: Arc<HeldPkts>,
+    pkts: Vec<(Moment, Packet<Initialized>)>,
+}
+
+// Transmit the packets held by the ports of `vni`, now that the
+// resolver has provided new mappings. A packet whose destination is
+// still without a mapping is held again, until it expires.
+//
+// The devices lock is held only to take the held packets: sending
+// them on may take it again, e.g. for a packet looped back to a guest
+// on this host. As each packet was captured and accounted by its port
+// when first processed, it is reprocessed rather than transmitted as
+// if sent anew by the guest, see `Port::reprocess()`. The handles
+// remain safe to use should the device be deleted in the meantime:
+// its closed rx handle drops any hairpin reply.
+fn release_held(vni: Vni) {
+    let devs = unsafe { xde_devs.read() };
+    let released: Vec<Released> = devs
+        .iter()
+        .filter(|dev| dev.vni == vni && !dev.passthrough)
+        .filter_map(|dev| {
+            let pkts = core::mem::take(&mut *dev.held.lock());
+            if pkts.is_empty() {
+                return None;
+            }
+
+            Some(Released {
+                port: dev.port.clone(),
+                u1: dev.u1.clone(),
+                rx_handle: dev.rx_handle.clone(),
+                mirror_targets: dev.mirror_targets.clone(),
+                held: dev.held.clone(),
+                pkts,
+            })
+        })
+        .collect();
+    drop(devs);
+
+    for rel in released {
+        let parser = rel.port.network().parser();
+
+        for (since, pkt) in rel.pkts {
+            let mut pkt = match pkt.parse(Direction::Out, parser) {
+                Ok(pkt) => pkt,
+                Err(e) => {
+                    opte::engine::dbg(format!("held packet reparse: {:?}", e));
+                    continue;
+                }
+            };
+
+            let res =
+                rel.port.reprocess(Direction::Out, &mut pkt, ActionMeta::new());
+            deliver_mirrored(
+                &rel.mirror_targets,
+                &rel.u1,
+                rel.port.take_mirrored(),
+            );
+
+            let still_held = unsafe {
+                tx_processed(&rel.port, &rel.u1, &rel.rx_handle, pkt, res)
+            };
+            if let Some(pkt) = still_held {
+                hold_pkt(&rel.held, pkt, Some(since));
+            }
+        }
+    }
+}
+
+// Deliver the packets mirrored by a port to their targets, where
+// `targets` and `u1` belong to the port's xde device.
+//
+// A port target is handed the copy through the rx handle resolved
+// when the mirrors were set, see `set_mirrors_hdlr()`; this does not
+// take the devices lock. An underlay target already has its outer
+// headers in place, minus the outer frame addresses, which are filled
+// in the same way as for any other encapsulated packet.
+#[no_mangle]
+fn deliver_mirrored(
+    targets: &MirrorTargets,
+    u1: &xde_underlay_port,
+    mirrored: Vec<MirroredPacket>,
+) {
+    for mpkt in mirrored {
+        match mpkt.mirror.target() {
+            MirrorTarget::Port(name) => {
+                let target = targets
+                    .lock()
+                    .iter()
+                    .find(|(n, _)| n == name)
+                    .map(|(_, rx)| rx.clone());
+
+                let delivered = match target {
+                    Some(rx) => rx.deliver(mpkt.pkt),
+                    None => false,
+                };
+
+                if !delivered {
+                    opte::engine::dbg(format!(
+                        "mirror {}: port {} is gone",
+                        mpkt.mirror.name(),
+                        name
+                    ));
+                }
+            }
+
+            MirrorTarget::Underlay(_) => {
+                let ip6 = match mpkt.pkt.meta().outer.ip {
+                    Some(IpMeta::Ip6(ip6)) => ip6,
+                    _ => {
+                        opte::engine::dbg(format!(
+                            "mirror {}: no outer IPv6 header",
+                            mpkt.mirror.name()
+                        ));
+                        continue;
+                    }
+                };
+
+                let (src, dst) = next_hop(&ip6.dst);
+                let mblk = mpkt.pkt.unwrap_mblk();
+                unsafe {
+                    let rptr = (*mblk).b_rptr as *mut u8;
+                    ptr::copy(dst.as_ptr(), rptr, 6);
+                    ptr::copy(src.as_ptr(), rptr.add(6), 6);
+                }
+                // Unwrap: We know the packet is good because we just
+                // unwrapped it above.
+                let new_pkt = Packet::<Initialized>::wrap_mblk(mblk).unwrap();
+                u1.mch.tx_drop_on_no_desc(new_pkt, 0, MacTxFlags::empty());
+            }
+        }
+    }
 }
 
 // At this point the core engine of OPTE has delivered a Geneve
@@ -1886,19 +2261,28 @@ fn new_port(
 
     let mut pb = PortBuilder::new(&name, name_cstr, cfg.guest_mac.into(), ectx);
     firewall::setup(&mut pb, FW_FT_LIMIT.unwrap())?;
+    // The mirror layer must be added after the firewall so that it
+    // sits closest to the guest.
+    mirror::setup(&mut pb, FT_LIMIT_ONE.unwrap())?;
     // XXX some layers have no need for LFT, perhaps have two types
     // of Layer: one with, one without?
+    let addr_sets = vpc_map.addr_sets(cfg.vni);
+    let misses = vpc_map.misses();
+    let bsvc = vpc_map.bsvc();
+    let nat_ports = vpc_map.nat_ports();
     gateway::setup(&mut pb, &cfg, vpc_map, FT_LIMIT_ONE.unwrap())?;
     router::setup(&mut pb, &cfg, FT_LIMIT_ONE.unwrap())?;
-    nat::setup(&mut pb, &cfg, NAT_FT_LIMIT.unwrap())?;
-
-    // XXX-EXT-IP
-    if unsafe { xde_ext_ip_hack != 1 } {
-        warn!("enabling overlay for port: {}", name);
-        overlay::setup(&pb, &cfg, v2p, FT_LIMIT_ONE.unwrap())?;
-    } else {
-        warn!("disabling overlay for port: {}", name);
-    }
+    nat::setup(&mut pb, &cfg, &nat_ports, NAT_FT_LIMIT.unwrap())?;
+
+    overlay::setup(
+        &pb,
+        &cfg,
+        v2p,
+        addr_sets,
+        misses,
+        bsvc,
+        FT_LIMIT_ONE.unwrap(),
+    )?;
 
     let net = VpcNetwork { cfg: cfg.clone() };
     Ok(Arc::new(pb.create(
@@ -1925,8 +2309,7 @@ unsafe extern "C" fn xde_rx(
 
     // We must first parse the packet in order to determine where it
     // is to be delivered.
-    let parser =
-        VpcParser { proxy_arp_enable: unsafe { xde_ext_ip_hack == 1 } };
+    let parser = VpcParser::new();
     let mut pkt =
         match Packet::wrap_mblk_and_parse(mp_chain, Direction::In, parser) {
             Ok(pkt) => pkt,
@@ -1947,96 +2330,36 @@ unsafe extern "C" fn xde_rx(
     let meta = pkt.meta();
     let devs = xde_devs.read();
 
-    let dev = if xde_ext_ip_hack == 0 {
-        // Determine where to send packet based on Geneve VNI and
-        // destination MAC address.
-        let geneve = match meta.outer.encap {
-            Some(EncapMeta::Geneve(geneve)) => geneve,
-            None => {
-                // TODO add stat
-                let msg = "no geneve header, dropping";
-                bad_packet_probe(None, Direction::In, mp_chain, msg);
-                opte::engine::dbg(format!("{}", msg));
-                return;
-            }
-        };
-
-        let vni = geneve.vni;
-        let ether_dst = meta.inner.ether.dst;
-        let dev = match devs
-            .iter()
-            .find(|x| x.vni == vni && x.port.mac_addr() == ether_dst)
-        {
-            Some(dev) => dev,
-            None => {
-                // TODO add SDT probe
-                // TODO add stat
-                opte::engine::dbg(format!(
-                    "[encap] no device found for vni: {} mac: {}",
-                    vni, ether_dst
-                ));
-                return;
-            }
-        };
-        dev
-    } else {
-        // XXX-EXT-IP
-        let ether_dst = meta.inner.ether.dst;
-
-        if ether_dst == MacAddr::from(MacAddr::BROADCAST) {
-            let bytes = pkt.get_rdr().copy_remaining();
-
-            for dev in devs.iter() {
-                // just go straight to overlay in passthrough mode
-                if (*dev).passthrough {
-                    mac::mac_rx((*dev).mh, mrh, mp_chain);
-                }
-
-                let port = &(*dev).port;
-                let parser = port.network().parser();
-                // Unwrap: We are copying bytes from a packet that we
-                // previously parsed; we know it's good.
-                let mut pkt_copy =
-                    Packet::copy(&bytes).parse(Direction::In, parser).unwrap();
-                let res = port.process(
-                    Direction::In,
-                    &mut pkt_copy,
-                    ActionMeta::new(),
-                );
-
-                match res {
-                    Ok(ProcessResult::Modified) => {
-                        mac::mac_rx((*dev).mh, mrh, pkt_copy.unwrap_mblk());
-                    }
-                    Ok(ProcessResult::Hairpin(hppkt)) => {
-                        // TODO assuming underlay device 1
-                        (*dev).u1.mch.tx_drop_on_no_desc(
-                            hppkt,
-                            0,
-                            MacTxFlags::empty(),
-                        );
-                    }
-                    Ok(ProcessResult::Bypass) => {
-                        mac::mac_rx((*dev).mh, mrh, mp_chain);
-                    }
-                    _ => {}
-                }
-            }
+    // Determine where to send packet based on Geneve VNI and
+    // destination MAC address. This includes the traffic from
+    // external networks, which Boundary Services encapsulates on the
+    // VNI of the VPC the floating IP belongs to.
+    let geneve = match meta.outer.encap {
+        Some(EncapMeta::Geneve(geneve)) => geneve,
+        None => {
+            // TODO add stat
+            let msg = "no geneve header, dropping";
+            bad_packet_probe(None, Direction::In, mp_chain, msg);
+            opte::engine::dbg(format!("{}", msg));
+            return;
+        }
+    };
 
+    let vni = geneve.vni;
+    let ether_dst = meta.inner.ether.dst;
+    let dev = match devs
+        .iter()
+        .find(|x| x.vni == vni && x.port.mac_addr() == ether_dst)
+    {
+        Some(dev) => dev,
+        None => {
+            // TODO add SDT probe
+            // TODO add stat
+            opte::engine::dbg(format!(
+                "[encap] no device found for vni: {} mac: {}",
+                vni, ether_dst
+            ));
             return;
-        } else {
-            match devs.iter().find(|x| x.port.mac_addr() == ether_dst) {
-                Some(dev) => dev,
-                None => {
-                    // TODO add SDT probe
-                    // TODO add stat
-                    opte::engine::dbg(format!(
-                        "[ext_ip_hack] no device found for mac: {}",
-                        ether_dst
-                    ));
-                    return;
-                }
-            }
         }
     };
 
@@ -2048,6 +2371,7 @@ unsafe extern "C" fn xde_rx(
 
     let port = &(*dev).port;
     let res = port.process(Direction::In, &mut pkt, ActionMeta::new());
+    deliver_mirrored(&dev.mirror_targets, &dev.u1, port.take_mirrored());
     match res {
         Ok(ProcessResult::Modified) => {
             mac::mac_rx((*dev).mh, mrh, pkt.unwrap_mblk());
@@ -2073,7 +2397,49 @@ fn add_router_entry_hdlr(env: &mut IoctlEnvelope) -> Result<NoResp, OpteError> {
         None => return Err(OpteError::PortNotFound(req.port_name)),
     };
 
-    router::add_entry(&dev.port, req.dest.into(), req.target.into())
+    let entry = RouterEntry {
+        dest: req.dest,
+        target: req.target,
+        filters: req.filters,
+        priority: req.priority,
+    };
+    router::add_route(&dev.port, entry)
+}
+
+#[no_mangle]
+fn del_router_entry_hdlr(
+    env: &mut IoctlEnvelope,
+) -> Result<DelRouterEntryResp, OpteError> {
+    let req: DelRouterEntryReq = env.copy_in_req()?;
+    let devs = unsafe { xde_devs.read() };
+    let mut iter = devs.iter();
+    let dev = match iter.find(|x| x.devname == req.port_name) {
+        Some(dev) => dev,
+        None => return Err(OpteError::PortNotFound(req.port_name)),
+    };
+
+    let entry = RouterEntry {
+        dest: req.dest,
+        target: req.target,
+        filters: req.filters,
+        priority: req.priority,
+    };
+    router::del_route(&dev.port, entry)
+}
+
+#[no_mangle]
+fn list_router_entries_hdlr(
+    env: &mut IoctlEnvelope,
+) -> Result<ListRouterEntriesResp, OpteError> {
+    let req: ListRouterEntriesReq = env.copy_in_req()?;
+    let devs = unsafe { xde_devs.read() };
+    let mut iter = devs.iter();
+    let dev = match iter.find(|x| x.devname == req.port_name) {
+        Some(dev) => dev,
+        None => return Err(OpteError::PortNotFound(req.port_name)),
+    };
+
+    router::list_entries(&dev.port)
 }
 
 #[no_mangle]
@@ -2105,7 +2471,9 @@ fn rem_fw_rule_hdlr(env: &mut IoctlEnvelope) -> Result<NoResp, OpteError> {
 }
 
 #[no_mangle]
-fn set_fw_rules_hdlr(env: &mut IoctlEnvelope) -> Result<NoResp, OpteError> {
+fn set_fw_rules_hdlr(
+    env: &mut IoctlEnvelope,
+) -> Result<SetFwRulesResp, OpteError> {
     let req: SetFwRulesReq = env.copy_in_req()?;
     let devs = unsafe { xde_devs.read() };
     let mut iter = devs.iter();
@@ -2114,25 +2482,127 @@ fn set_fw_rules_hdlr(env: &mut IoctlEnvelope) -> Result<NoResp, OpteError> {
         None => return Err(OpteError::PortNotFound(req.port_name)),
     };
 
-    firewall::set_fw_rules(&dev.port, &req)?;
+    firewall::set_fw_rules(&dev.port, &req)
+}
+
+#[no_mangle]
+fn set_addr_set_hdlr(env: &mut IoctlEnvelope) -> Result<NoResp, OpteError> {
+    let req: SetAddrSetReq = env.copy_in_req()?;
+    let state = get_xde_state();
+    let changed =
+        state.vpc_map.addr_sets(req.vni).set(&req.name, &req.addrs)?;
+    addr_set_changed(req.vni, &changed)?;
     Ok(NoResp::default())
 }
 
+#[no_mangle]
+fn del_addr_set_hdlr(
+    env: &mut IoctlEnvelope,
+) -> Result<DelAddrSetResp, OpteError> {
+    let req: DelAddrSetReq = env.copy_in_req()?;
+    let state = get_xde_state();
+    let removed = match state.vpc_map.find_addr_sets(req.vni) {
+        Some(sets) => sets.del(&req.name),
+        None => None,
+    };
+
+    match removed {
+        Some(addrs) => {
+            addr_set_changed(req.vni, &addrs)?;
+            Ok(DelAddrSetResp::Ok)
+        }
+
+        None => Ok(DelAddrSetResp::NotFound),
+    }
+}
+
+// Invalidate the flows of the ports on `vni` which may have been
+// given their verdict based on the previous membership of `addrs` in
+// one of the VPC's address sets.
+fn addr_set_changed(
+    vni: Vni,
+    addrs: &BTreeSet<IpAddr>,
+) -> Result<(), OpteError> {
+    let devs = unsafe { xde_devs.read() };
+    for dev in devs.iter().filter(|dev| dev.vni == vni && !dev.passthrough) {
+        firewall::addr_set_changed(&dev.port, addrs)?;
+    }
+
+    Ok(())
+}
+
+#[no_mangle]
+fn dump_addr_sets_hdlr(
+    env: &mut IoctlEnvelope,
+) -> Result<DumpAddrSetsResp, OpteError> {
+    let _req: DumpAddrSetsReq = env.copy_in_req()?;
+    let state = get_xde_state();
+    Ok(state.vpc_map.dump_addr_sets())
+}
+
 #[no_mangle]
 fn set_v2p_hdlr(env: &mut IoctlEnvelope) -> Result<NoResp, OpteError> {
     let req: SetVirt2PhysReq = env.copy_in_req()?;
     let state = get_xde_state();
     state.vpc_map.add(req.vip, req.phys);
+    release_held(req.phys.vni);
     Ok(NoResp::default())
 }
 
+#[no_mangle]
+fn del_v2p_hdlr(
+    env: &mut IoctlEnvelope,
+) -> Result<DelVirt2PhysResp, OpteError> {
+    let req: DelVirt2PhysReq = env.copy_in_req()?;
+    let state = get_xde_state();
+    match state.vpc_map.del(&req.vip, req.vni) {
+        Some(_) => Ok(DelVirt2PhysResp::Ok),
+        None => Ok(DelVirt2PhysResp::NotFound),
+    }
+}
+
+#[no_mangle]
+fn sync_v2p_hdlr(env: &mut IoctlEnvelope) -> Result<NoResp, OpteError> {
+    let req: SyncVirt2PhysReq = env.copy_in_req()?;
+    let state = get_xde_state();
+    state.vpc_map.sync(req.vni, req.gen, &req.mappings)?;
+    release_held(req.vni);
+    Ok(NoResp::default())
+}
+
+#[no_mangle]
+fn read_v2p_misses_hdlr(
+    env: &mut IoctlEnvelope,
+) -> Result<ReadV2pMissesResp, OpteError> {
+    let req: ReadV2pMissesReq = env.copy_in_req()?;
+    let state = get_xde_state();
+    state.vpc_map.misses().read(req.block)
+}
+
+#[no_mangle]
+fn set_bsvc_state_hdlr(env: &mut IoctlEnvelope) -> Result<NoResp, OpteError> {
+    let req: SetBsvcStateReq = env.copy_in_req()?;
+    let state = get_xde_state();
+    state.vpc_map.bsvc().set(req.ip, req.up);
+    Ok(NoResp::default())
+}
+
+#[no_mangle]
+fn dump_bsvc_state_hdlr(
+    env: &mut IoctlEnvelope,
+) -> Result<DumpBsvcStateResp, OpteError> {
+    let _req: DumpBsvcStateReq = env.copy_in_req()?;
+    let state = get_xde_state();
+    Ok(state.vpc_map.bsvc().dump())
+}
+
 #[no_mangle]
 fn dump_v2p_hdlr(
     env: &mut IoctlEnvelope,
 ) -> Result<overlay::DumpVirt2PhysResp, OpteError> {
-    let _req: overlay::DumpVirt2PhysReq = env.copy_in_req()?;
+    let req: overlay::DumpVirt2PhysReq = env.copy_in_req()?;
     let state = get_xde_state();
-    Ok(state.vpc_map.dump())
+    Ok(state.vpc_map.dump(&req))
 }
 
 #[no_mangle]
@@ -2150,6 +2620,22 @@ fn list_layers_hdlr(
     Ok(dev.port.list_layers())
 }
 
+#[no_mangle]
+fn set_default_action_hdlr(
+    env: &mut IoctlEnvelope,
+) -> Result<NoResp, OpteError> {
+    let req: api::SetDefaultActionReq = env.copy_in_req()?;
+    let devs = unsafe { xde_devs.read() };
+    let mut iter = devs.iter();
+    let dev = match iter.find(|x| x.devname == req.port_name) {
+        Some(dev) => dev,
+        None => return Err(OpteError::PortNotFound(req.port_name)),
+    };
+
+    api::set_default_action(&dev.port, &req)?;
+    Ok(NoResp::default())
+}
+
 #[no_mangle]
 fn clear_uft_hdlr(env: &mut IoctlEnvelope) -> Result<NoResp, OpteError> {
     let req: api::ClearUftReq = env.copy_in_req()?;
@@ -2209,26 +2695,221 @@ fn dump_tcp_flows_hdlr(
     api::dump_tcp_flows(&dev.port, &req)
 }
 
+#[no_mangle]
+fn set_capture_hdlr(env: &mut IoctlEnvelope) -> Result<NoResp, OpteError> {
+    let req: api::SetCaptureReq = env.copy_in_req()?;
+    let devs = unsafe { xde_devs.read() };
+    let mut iter = devs.iter();
+    let dev = match iter.find(|x| x.devname == req.port_name) {
+        Some(dev) => dev,
+        None => return Err(OpteError::PortNotFound(req.port_name)),
+    };
+
+    api::set_capture(&dev.port, &req)?;
+    Ok(NoResp::default())
+}
+
+#[no_mangle]
+fn dump_capture_hdlr(
+    env: &mut IoctlEnvelope,
+) -> Result<api::DumpCaptureResp, OpteError> {
+    let req: api::DumpCaptureReq = env.copy_in_req()?;
+    let devs = unsafe { xde_devs.read() };
+    let mut iter = devs.iter();
+    let dev = match iter.find(|x| x.devname == req.port_name) {
+        Some(dev) => dev,
+        None => return Err(OpteError::PortNotFound(req.port_name)),
+    };
+
+    api::dump_capture(&dev.port, &req)
+}
+
+#[no_mangle]
+fn dump_flow_records_hdlr(
+    env: &mut IoctlEnvelope,
+) -> Result<api::DumpFlowRecordsResp, OpteError> {
+    let req: api::DumpFlowRecordsReq = env.copy_in_req()?;
+    let devs = unsafe { xde_devs.read() };
+    let mut iter = devs.iter();
+    let dev = match iter.find(|x| x.devname == req.port_name) {
+        Some(dev) => dev,
+        None => return Err(OpteError::PortNotFound(req.port_name)),
+    };
+
+    api::dump_flow_records(&dev.port, &req)
+}
+
+#[no_mangle]
+fn dump_rule_log_hdlr(
+    env: &mut IoctlEnvelope,
+) -> Result<api::DumpRuleLogResp, OpteError> {
+    let req: api::DumpRuleLogReq = env.copy_in_req()?;
+    let devs = unsafe { xde_devs.read() };
+    let mut iter = devs.iter();
+    let dev = match iter.find(|x| x.devname == req.port_name) {
+        Some(dev) => dev,
+        None => return Err(OpteError::PortNotFound(req.port_name)),
+    };
+
+    api::dump_rule_log(&dev.port, &req)
+}
+
+#[no_mangle]
+fn set_mirrors_hdlr(env: &mut IoctlEnvelope) -> Result<NoResp, OpteError> {
+    let req: SetMirrorsReq = env.copy_in_req()?;
+    let devs = unsafe { xde_devs.read() };
+    let mut iter = devs.iter();
+    let dev = match iter.find(|x| x.devname == req.port_name) {
+        Some(dev) => dev,
+        None => return Err(OpteError::PortNotFound(req.port_name)),
+    };
+
+    // Resolve the port targets now, so that the mirrored packets may
+    // be delivered without taking the devices lock.
+    let mut targets = vec![];
+    for mcfg in &req.mirrors {
+        let name = match &mcfg.dest {
+            MirrorDest::Port(name) => name,
+            MirrorDest::Underlay(_) => continue,
+        };
+
+        match devs.iter().find(|x| &x.devname == name) {
+            Some(target) if !target.passthrough => {
+                targets.push((name.clone(), target.rx_handle.clone()));
+            }
+
+            Some(_) => {
+                return Err(OpteError::System {
+                    errno: EINVAL,
+                    msg: format!(
+                        "mirror {}: port {} is in passthrough mode",
+                        mcfg.name, name
+                    ),
+                });
+            }
+
+            None => return Err(OpteError::PortNotFound(name.clone())),
+        }
+    }
+
+    let old = core::mem::replace(&mut *dev.mirror_targets.lock(), targets);
+    if let Err(e) = mirror::set_mirrors(&dev.port, &dev.vpc_cfg, &req) {
+        *dev.mirror_targets.lock() = old;
+        return Err(e);
+    }
+
+    Ok(NoResp::default())
+}
+
+#[no_mangle]
+fn attach_floating_ip_hdlr(
+    env: &mut IoctlEnvelope,
+) -> Result<NoResp, OpteError> {
+    let req: AttachFloatingIpReq = env.copy_in_req()?;
+    let devs = unsafe { xde_devs.read() };
+    let mut iter = devs.iter();
+    let dev = match iter.find(|x| x.devname == req.port_name) {
+        Some(dev) => dev,
+        None => return Err(OpteError::PortNotFound(req.port_name)),
+    };
+
+    nat::attach_floating_ip(&dev.port, &dev.vpc_cfg, req.ip)
+}
+
+#[no_mangle]
+fn detach_floating_ip_hdlr(
+    env: &mut IoctlEnvelope,
+) -> Result<DetachFloatingIpResp, OpteError> {
+    let req: DetachFloatingIpReq = env.copy_in_req()?;
+    let devs = unsafe { xde_devs.read() };
+    let mut iter = devs.iter();
+    let dev = match iter.find(|x| x.devname == req.port_name) {
+        Some(dev) => dev,
+        None => return Err(OpteError::PortNotFound(req.port_name)),
+    };
+
+    nat::detach_floating_ip(&dev.port, req.ip)
+}
+
+#[no_mangle]
+fn add_port_forward_hdlr(env: &mut IoctlEnvelope) -> Result<NoResp, OpteError> {
+    let req: AddPortForwardReq = env.copy_in_req()?;
+    let devs = unsafe { xde_devs.read() };
+    let mut iter = devs.iter();
+    let dev = match iter.find(|x| x.devname == req.port_name) {
+        Some(dev) => dev,
+        None => return Err(OpteError::PortNotFound(req.port_name)),
+    };
+
+    nat::add_port_forward(&dev.port, &dev.vpc_cfg, req.forward)
+}
+
+#[no_mangle]
+fn del_port_forward_hdlr(
+    env: &mut IoctlEnvelope,
+) -> Result<DelPortForwardResp, OpteError> {
+    let req: DelPortForwardReq = env.copy_in_req()?;
+    let devs = unsafe { xde_devs.read() };
+    let mut iter = devs.iter();
+    let dev = match iter.find(|x| x.devname == req.port_name) {
+        Some(dev) => dev,
+        None => return Err(OpteError::PortNotFound(req.port_name)),
+    };
+
+    nat::del_port_forward(&dev.port, &req.forward)
+}
+
+#[no_mangle]
+fn list_port_forwards_hdlr(
+    env: &mut IoctlEnvelope,
+) -> Result<ListPortForwardsResp, OpteError> {
+    let req: ListPortForwardsReq = env.copy_in_req()?;
+    let devs = unsafe { xde_devs.read() };
+    let mut iter = devs.iter();
+    let dev = match iter.find(|x| x.devname == req.port_name) {
+        Some(dev) => dev,
+        None => return Err(OpteError::PortNotFound(req.port_name)),
+    };
+
+    Ok(ListPortForwardsResp { forwards: nat::port_forwards(&dev.port)? })
+}
+
+#[no_mangle]
+fn dump_snat_hdlr(
+    env: &mut IoctlEnvelope,
+) -> Result<nat::DumpSnatResp, OpteError> {
+    let req: nat::DumpSnatReq = env.copy_in_req()?;
+    let devs = unsafe { xde_devs.read() };
+    let mut iter = devs.iter();
+    let dev = match iter.find(|x| x.devname == req.port_name) {
+        Some(dev) => dev,
+        None => return Err(OpteError::PortNotFound(req.port_name)),
+    };
+
+    nat::dump_snat(&dev.port)
+}
+
 #[no_mangle]
 fn list_ports_hdlr() -> Result<ListPortsResp, OpteError> {
     let mut resp = ListPortsResp { ports: vec![] };
     let devs = unsafe { xde_devs.read() };
     for dev in devs.iter() {
+        let mut external_ip4_addrs = vec![];
+        let mut external_ip6_addrs = vec![];
+        for ip in nat::floating_ips(&dev.port)? {
+            match ip {
+                IpAddr::Ip4(ip4) => external_ip4_addrs.push(ip4),
+                IpAddr::Ip6(ip6) => external_ip6_addrs.push(ip6),
+            }
+        }
+
         resp.ports.push(PortInfo {
             name: dev.port.name().to_string(),
             mac_addr: dev.port.mac_addr().into(),
             ip4_addr: dev.vpc_cfg.ipv4_cfg().map(|cfg| cfg.private_ip),
-            external_ip4_addr: dev
-                .vpc_cfg
-                .ipv4_cfg()
-                .map(|cfg| cfg.external_ips)
-                .flatten(),
+            external_ip4_addrs,
             ip6_addr: dev.vpc_cfg.ipv6_cfg().map(|cfg| cfg.private_ip),
-            external_ip6_addr: dev
-                .vpc_cfg
-                .ipv6_cfg()
-                .map(|cfg| cfg.external_ips)
-                .flatten(),
+            external_ip6_addrs,
             state: dev.port.state().to_string(),
         });
     }